@@ -14,9 +14,9 @@ fn bench_client_request(b: &mut Bencher) {
 	let mut request = CoAPRequest::new();
 	request.set_version(1);
 	request.set_type(MessageType::Confirmable);
-	request.set_code("0.01");
+	request.set_code("0.01").unwrap();
 	request.set_message_id(1);
-	request.set_token(vec!(0x51, 0x55, 0x77, 0xE8));
+	request.set_token(vec!(0x51, 0x55, 0x77, 0xE8)).unwrap();
 	request.add_option(CoAPOption::UriPath, endpoint.to_string().into_bytes());
 
 	b.iter(|| {
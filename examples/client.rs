@@ -1,8 +1,7 @@
 extern crate coap;
 
 use std::io;
-use std::io::ErrorKind;
-use coap::{CoAPClient, CoAPRequest, IsMessage, Method};
+use coap::{CoAPClient, Error};
 
 fn main() {
     println!("Request by GET:");
@@ -28,69 +27,38 @@ fn example_get() {
             println!("Server reply: {}",
                      String::from_utf8(response.message.payload).unwrap());
         }
-        Err(e) => {
-            match e.kind() {
-                ErrorKind::WouldBlock => println!("Request timeout"),   // Unix
-                ErrorKind::TimedOut => println!("Request timeout"),     // Windows
-                _ => println!("Request error: {:?}", e),
-            }
-        }
+        Err(Error::Timeout) => println!("Request timeout"),
+        Err(e) => println!("Request error: {:?}", e),
     }
 }
 
 fn example_post() {
-    let addr = "127.0.0.1:5683";
-    let path = "/hello/post";
-
-    let mut request = CoAPRequest::new();
-    request.set_method(Method::Post);
-    request.set_path(path);
-    request.set_payload(b"data".to_vec());
-
-    let client = CoAPClient::new(addr).unwrap();
-    client.send(&request).unwrap();
-    println!("Client request: coap://{}/{}", addr, path);
+    let url = "coap://127.0.0.1:5683/hello/post";
+    println!("Client request: {}", url);
 
-    match client.receive() {
+    let client = CoAPClient::bind("0.0.0.0:0").unwrap();
+    match client.post(url).payload(b"data".to_vec()).send() {
         Ok(response) => {
             println!("Server reply: {}",
                      String::from_utf8(response.message.payload).unwrap());
         }
-        Err(e) => {
-            match e.kind() {
-                ErrorKind::WouldBlock => println!("Request timeout"),   // Unix
-                ErrorKind::TimedOut => println!("Request timeout"),     // Windows
-                _ => println!("Request error: {:?}", e),
-            }
-        }
+        Err(Error::Timeout) => println!("Request timeout"),
+        Err(e) => println!("Request error: {:?}", e),
     }
 }
 
 fn example_put() {
-    let addr = "127.0.0.1:5683";
-    let path = "/hello/put";
-
-    let mut request = CoAPRequest::new();
-    request.set_method(Method::Put);
-    request.set_path(path);
-    request.set_payload(b"data".to_vec());
-
-    let client = CoAPClient::new(addr).unwrap();
-    client.send(&request).unwrap();
-    println!("Client request: coap://{}/{}", addr, path);
+    let url = "coap://127.0.0.1:5683/hello/put";
+    println!("Client request: {}", url);
 
-    match client.receive() {
+    let client = CoAPClient::bind("0.0.0.0:0").unwrap();
+    match client.put(url).payload(b"data".to_vec()).send() {
         Ok(response) => {
             println!("Server reply: {}",
                      String::from_utf8(response.message.payload).unwrap());
         }
-        Err(e) => {
-            match e.kind() {
-                ErrorKind::WouldBlock => println!("Request timeout"),   // Unix
-                ErrorKind::TimedOut => println!("Request timeout"),     // Windows
-                _ => println!("Request error: {:?}", e),
-            }
-        }
+        Err(Error::Timeout) => println!("Request timeout"),
+        Err(e) => println!("Request error: {:?}", e),
     }
 }
 
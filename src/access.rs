@@ -0,0 +1,411 @@
+//! Access control for the resources of a server.
+//!
+//! An `AccessPolicy` holds rules which allow or deny methods on a resource
+//! and everything below it to a principal: any client, a network, or an
+//! OSCORE identity. The rules of the most specific resource a request falls
+//! under decide, the first matching one first; a request no rule matches is
+//! denied, and resources without rules are open. Denied requests are
+//! answered with 4.01 Unauthorized if an OSCORE identity could be allowed,
+//! and 4.03 Forbidden otherwise.
+//!
+//! Requests for the forward proxy, whatever their target, are checked
+//! against the rules of the resource `proxy` instead, and are open if it
+//! has none.
+//!
+//! Policies can be built in code or loaded from a file with one rule per
+//! line, `#` starting a comment:
+//!
+//! ```text
+//! # action  resource   methods   principal
+//! allow     /status    GET       *
+//! deny      /admin     *         192.168.1.13
+//! allow     /admin     GET,PUT   10.0.0.0/8
+//! allow     /admin     *         oscore:01
+//! allow     proxy      GET       10.0.0.0/8
+//! ```
+//!
+//! ```no_run
+//! use coap::CoAPServer;
+//! use coap::access::AccessPolicy;
+//!
+//! let mut server = CoAPServer::new("127.0.0.1:5683").unwrap();
+//! server.set_access_policy(AccessPolicy::load("access.conf").unwrap());
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::error::{Error, Result};
+use super::message::header::ResponseType as Status;
+use super::message::request::{CoAPRequest, Identity, Method};
+use super::message::response::CoAPResponse;
+use super::proxy;
+
+/// The resource of the rules for the forward proxy.
+const PROXY: &str = "proxy";
+
+/// Who a rule applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Principal {
+    /// Every client.
+    Any,
+    /// Clients whose source address is in the network `address/prefix_len`.
+    Network { address: IpAddr, prefix_len: u8 },
+    /// Clients authenticated with OSCORE under the given sender ID, or under
+    /// any sender ID if `None`.
+    Oscore(Option<Vec<u8>>),
+}
+
+impl Principal {
+    fn matches(&self, request: &CoAPRequest) -> bool {
+        match *self {
+            Principal::Any => true,
+            Principal::Network { address, prefix_len } => request
+                .source
+                .is_some_and(|source| in_network(source.ip(), address, prefix_len)),
+            Principal::Oscore(ref id) => match request.identity {
+                Some(Identity::Oscore { ref sender_id, .. }) => id.as_ref().is_none_or(|id| id == sender_id),
+                None => false,
+            },
+        }
+    }
+}
+
+/// Whether a principal is malformed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidPrincipal;
+
+impl fmt::Display for InvalidPrincipal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid principal")
+    }
+}
+
+impl FromStr for Principal {
+    type Err = InvalidPrincipal;
+
+    /// Parses `*`, an IP address, a network such as `10.0.0.0/8`, or
+    /// `oscore:` followed by a hexadecimal sender ID or `*`.
+    fn from_str(s: &str) -> std::result::Result<Principal, InvalidPrincipal> {
+        if s == "*" {
+            return Ok(Principal::Any);
+        }
+        if let Some(id) = s.strip_prefix("oscore:") {
+            return match id {
+                "*" => Ok(Principal::Oscore(None)),
+                _ => decode_hex(id).map(|id| Principal::Oscore(Some(id))).ok_or(InvalidPrincipal),
+            };
+        }
+
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| InvalidPrincipal)?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| InvalidPrincipal)?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(InvalidPrincipal);
+        }
+        Ok(Principal::Network { address, prefix_len })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Rule {
+    allow: bool,
+    /// The path segments of the resource, or `None` for the forward proxy.
+    resource: Option<Vec<String>>,
+    /// Every method if empty.
+    methods: Vec<Method>,
+    principal: Principal,
+}
+
+impl Rule {
+    fn matches(&self, request: &CoAPRequest) -> bool {
+        (self.methods.is_empty() || self.methods.contains(request.get_method())) && self.principal.matches(request)
+    }
+}
+
+/// Rules on which clients may use which methods on which resources.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccessPolicy {
+    rules: Vec<Rule>,
+}
+
+impl AccessPolicy {
+    pub fn new() -> AccessPolicy {
+        AccessPolicy::default()
+    }
+
+    /// Allows `methods` (every method if empty) on `resource` and below it to
+    /// `principal`.
+    pub fn allow(self, resource: &str, methods: &[Method], principal: Principal) -> AccessPolicy {
+        self.rule(true, resource, methods, principal)
+    }
+
+    /// Denies `methods` (every method if empty) on `resource` and below it to
+    /// `principal`.
+    pub fn deny(self, resource: &str, methods: &[Method], principal: Principal) -> AccessPolicy {
+        self.rule(false, resource, methods, principal)
+    }
+
+    /// Allows `methods` (every method if empty) through the forward proxy
+    /// to `principal`.
+    pub fn allow_proxy(self, methods: &[Method], principal: Principal) -> AccessPolicy {
+        self.rule(true, PROXY, methods, principal)
+    }
+
+    /// Denies `methods` (every method if empty) through the forward proxy
+    /// to `principal`.
+    pub fn deny_proxy(self, methods: &[Method], principal: Principal) -> AccessPolicy {
+        self.rule(false, PROXY, methods, principal)
+    }
+
+    fn rule(mut self, allow: bool, resource: &str, methods: &[Method], principal: Principal) -> AccessPolicy {
+        self.rules.push(Rule {
+            allow,
+            resource: match resource {
+                PROXY => None,
+                resource => Some(segments(resource)),
+            },
+            methods: methods.to_vec(),
+            principal,
+        });
+        self
+    }
+
+    /// Loads a policy from a file in the format described in the module
+    /// documentation.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<AccessPolicy> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Checks a request, returning 4.01 Unauthorized or 4.03 Forbidden if
+    /// it is denied.
+    pub fn check(&self, request: &CoAPRequest) -> std::result::Result<(), Status> {
+        let path = if proxy::is_proxy_request(request) {
+            None
+        } else {
+            Some(segments(&request.get_path()))
+        };
+        let resource = match self
+            .rules
+            .iter()
+            .filter(|rule| match (&path, &rule.resource) {
+                (Some(path), Some(resource)) => path.starts_with(resource),
+                (path, resource) => path.is_none() && resource.is_none(),
+            })
+            .map(|rule| &rule.resource)
+            .max_by_key(|resource| resource.as_ref().map_or(0, Vec::len))
+        {
+            Some(resource) => resource,
+            None => return Ok(()),
+        };
+
+        let mut rules = self.rules.iter().filter(|rule| rule.resource == *resource);
+        match rules.clone().find(|rule| rule.matches(request)) {
+            Some(rule) if rule.allow => Ok(()),
+            Some(_) => Err(Status::Forbidden),
+            None if request.identity.is_none() && rules.any(|rule| rule.allow && could_authenticate(rule, request)) => {
+                Err(Status::Unauthorized)
+            }
+            None => Err(Status::Forbidden),
+        }
+    }
+}
+
+/// The answer to a request the policy denies with `status`.
+pub(crate) fn denial(request: &CoAPRequest, status: Status) -> Option<CoAPResponse> {
+    let mut response = request.response.clone()?;
+    response.set_status(status);
+    response.message.payload.clear();
+    Some(response)
+}
+
+/// Whether a rule would match the request if it was sent with OSCORE.
+fn could_authenticate(rule: &Rule, request: &CoAPRequest) -> bool {
+    matches!(rule.principal, Principal::Oscore(_))
+        && (rule.methods.is_empty() || rule.methods.contains(request.get_method()))
+}
+
+impl FromStr for AccessPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<AccessPolicy> {
+        let mut policy = AccessPolicy::new();
+        for (index, line) in s.lines().enumerate() {
+            let invalid = |reason: &str| Error::InvalidPolicy(format!("line {}: {}", index + 1, reason));
+            let line = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            if fields.len() != 4 {
+                return Err(invalid("expected an action, a resource, methods and a principal"));
+            }
+
+            let allow = match fields[0] {
+                "allow" => true,
+                "deny" => false,
+                _ => return Err(invalid("the action is neither allow nor deny")),
+            };
+            let methods = match fields[2] {
+                "*" => Vec::new(),
+                methods => methods
+                    .split(',')
+                    .map(|method| parse_method(method).ok_or_else(|| invalid("unknown method")))
+                    .collect::<Result<_>>()?,
+            };
+            let principal = fields[3].parse().map_err(|e: InvalidPrincipal| invalid(&e.to_string()))?;
+            policy = policy.rule(allow, fields[1], &methods, principal);
+        }
+        Ok(policy)
+    }
+}
+
+fn parse_method(method: &str) -> Option<Method> {
+    match method {
+        "GET" => Some(Method::Get),
+        "POST" => Some(Method::Post),
+        "PUT" => Some(Method::Put),
+        "DELETE" => Some(Method::Delete),
+        _ => None,
+    }
+}
+
+fn segments(path: &str) -> Vec<String> {
+    path.split('/').filter(|s| !s.is_empty()).map(String::from).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::header::MessageType;
+    use super::super::message::packet::{CoAPOption, Packet};
+    use super::super::message::IsMessage;
+
+    fn request(method: Method, path: &str, source: &str) -> CoAPRequest {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let mut request = CoAPRequest::from_packet(packet, &source.parse().unwrap());
+        request.set_method(method);
+        request.set_path(path);
+        request
+    }
+
+    const POLICY: &str = "
+        # action  resource   methods   principal
+        allow     /status    GET       *
+        deny      /admin     *         10.1.2.3
+        allow     /admin     GET,PUT   10.0.0.0/8   # the office
+        allow     /admin     *         oscore:01
+        allow     /admin/log GET       ::1
+    ";
+
+    #[test]
+    fn test_check() {
+        let policy: AccessPolicy = POLICY.parse().unwrap();
+        let check = |method, path, source| policy.check(&request(method, path, source));
+
+        assert_eq!(check(Method::Get, "/other", "192.0.2.1:5683"), Ok(()));
+        assert_eq!(check(Method::Get, "/status", "192.0.2.1:5683"), Ok(()));
+        assert_eq!(check(Method::Put, "/status", "192.0.2.1:5683"), Err(Status::Forbidden));
+        assert_eq!(check(Method::Put, "/admin/users", "10.9.9.9:5683"), Ok(()));
+        assert_eq!(check(Method::Get, "/admin", "[::ffff:10.9.9.9]:5683"), Ok(()));
+        assert_eq!(check(Method::Get, "/admin", "10.1.2.3:5683"), Err(Status::Forbidden));
+        assert_eq!(check(Method::Delete, "/admin", "10.9.9.9:5683"), Err(Status::Unauthorized));
+        // Only the rules of the most specific resource apply.
+        assert_eq!(check(Method::Get, "/admin/log", "[::1]:5683"), Ok(()));
+        assert_eq!(check(Method::Get, "/admin/log", "10.9.9.9:5683"), Err(Status::Forbidden));
+
+        let mut authenticated = request(Method::Delete, "/admin", "192.0.2.1:5683");
+        authenticated.identity = Some(Identity::Oscore {
+            sender_id: vec![0x01],
+            id_context: None,
+        });
+        assert_eq!(policy.check(&authenticated), Ok(()));
+        authenticated.identity = Some(Identity::Oscore {
+            sender_id: vec![0x02],
+            id_context: None,
+        });
+        assert_eq!(policy.check(&authenticated), Err(Status::Forbidden));
+    }
+
+    #[test]
+    fn test_check_proxy() {
+        let proxy_request = |method, path, source| {
+            let mut request = request(method, path, source);
+            request.add_option(CoAPOption::ProxyUri, b"coap://192.0.2.9/status".to_vec());
+            request
+        };
+
+        // Without proxy rules, the forward proxy is open whatever the target.
+        let policy: AccessPolicy = POLICY.parse().unwrap();
+        assert_eq!(policy.check(&proxy_request(Method::Put, "/status", "192.0.2.1:5683")), Ok(()));
+
+        let policy: AccessPolicy = format!("{}
+deny proxy * 10.1.2.3
+allow proxy GET *", POLICY).parse().unwrap();
+        assert_eq!(policy.check(&proxy_request(Method::Get, "", "192.0.2.1:5683")), Ok(()));
+        assert_eq!(policy.check(&proxy_request(Method::Put, "", "192.0.2.1:5683")), Err(Status::Forbidden));
+        assert_eq!(policy.check(&proxy_request(Method::Get, "", "10.1.2.3:5683")), Err(Status::Forbidden));
+        // Nor do proxy rules apply to local resources.
+        assert_eq!(policy.check(&request(Method::Put, "/proxy", "10.1.2.3:5683")), Ok(()));
+        assert_eq!(
+            AccessPolicy::new().deny_proxy(&[], "10.1.2.3".parse().unwrap()),
+            "deny proxy * 10.1.2.3".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let policy = AccessPolicy::new()
+            .allow("/status", &[Method::Get], Principal::Any)
+            .deny("/admin", &[], "10.1.2.3".parse().unwrap());
+        assert_eq!("allow /status GET *\ndeny /admin * 10.1.2.3/32".parse::<AccessPolicy>().unwrap(), policy);
+
+        assert_eq!("oscore:0a0B".parse(), Ok(Principal::Oscore(Some(vec![0x0A, 0x0B]))));
+        assert_eq!("oscore:*".parse(), Ok(Principal::Oscore(None)));
+        assert!("10.0.0.0/33".parse::<Principal>().is_err());
+        assert!("oscore:1".parse::<Principal>().is_err());
+
+        for invalid in &["allow /a GET", "permit /a GET *", "allow /a FETCH *", "allow /a GET host"] {
+            match invalid.parse::<AccessPolicy>() {
+                Err(Error::InvalidPolicy(reason)) => assert!(reason.starts_with("line 1: "), "{}", reason),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
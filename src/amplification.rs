@@ -0,0 +1,147 @@
+//! Safeguards against the server being used to amplify attacks from spoofed
+//! source addresses (RFC 7252 §11.3, RFC 9175 §2.4).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// The most source addresses whose request rate is tracked at once; the
+/// addresses which could send a full burst again are forgotten first.
+const MAX_BUCKETS: usize = 4096;
+
+/// The limits a server applies to addresses which may be spoofed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AmplificationLimits {
+    /// The largest response sent to an address which has not been verified,
+    /// as a multiple of the size of its request. Larger responses are
+    /// replaced by a 4.01 Unauthorized challenge with an Echo option, which
+    /// verifies the address once it is returned. `None` sends responses of
+    /// any size.
+    pub max_ratio: Option<usize>,
+    /// How long an address stays verified after returning an Echo value.
+    pub verification_lifetime: Duration,
+    /// How many requests are accepted from one IP address, `None` for any
+    /// number.
+    pub rate_limit: Option<RateLimit>,
+    /// The most resources one IP address may observe. Further registrations
+    /// are answered like plain GET requests, without an Observe option.
+    /// `None` allows any number.
+    pub max_observations: Option<usize>,
+}
+
+impl Default for AmplificationLimits {
+    fn default() -> AmplificationLimits {
+        AmplificationLimits {
+            max_ratio: Some(3),
+            verification_lifetime: Duration::from_secs(300),
+            rate_limit: None,
+            max_observations: None,
+        }
+    }
+}
+
+/// A request rate limit: bursts of up to `requests` requests, refilled
+/// evenly over `period`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+    pub action: RateLimitAction,
+}
+
+/// What happens to a request over the rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Answer with 4.29 Too Many Requests, with a Max-Age of the seconds
+    /// until the next request is accepted (RFC 8516).
+    Reject,
+    /// Ignore the request.
+    Drop,
+}
+
+/// The requests an address may still send in a burst.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per source IP address.
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn action(&self) -> RateLimitAction {
+        self.limit.action
+    }
+
+    /// Accounts for a request from `ip`, or returns how long until the next
+    /// request from it is accepted.
+    pub fn check(&mut self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let burst = self.limit.requests as f64;
+        let rate = burst / self.limit.period.as_secs_f64();
+        if !(rate > 0.0 && rate.is_finite()) {
+            return if burst > 0.0 { Ok(()) } else { Err(self.limit.period) };
+        }
+
+        if self.buckets.len() >= MAX_BUCKETS && !self.buckets.contains_key(&ip) {
+            let period = self.limit.period;
+            self.buckets.retain(|_, bucket| now.duration_since(bucket.updated) < period);
+        }
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = burst.min(bucket.tokens + refilled);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            requests: 2,
+            period: Duration::from_secs(10),
+            action: RateLimitAction::Drop,
+        });
+        let ip = "127.0.0.1".parse().unwrap();
+        let other = "127.0.0.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert_eq!(limiter.check(ip, start), Ok(()));
+        assert_eq!(limiter.check(ip, start), Ok(()));
+        assert_eq!(limiter.check(ip, start), Err(Duration::from_secs(5)));
+        assert_eq!(limiter.check(other, start), Ok(()));
+        // One request every five seconds refills.
+        assert_eq!(limiter.check(ip, start + Duration::from_secs(5)), Ok(()));
+        assert!(limiter.check(ip, start + Duration::from_secs(6)).is_err());
+        assert_eq!(limiter.check(ip, start + Duration::from_secs(60)), Ok(()));
+        assert_eq!(limiter.check(ip, start + Duration::from_secs(60)), Ok(()));
+
+        let mut closed = RateLimiter::new(RateLimit {
+            requests: 0,
+            period: Duration::from_secs(1),
+            action: RateLimitAction::Reject,
+        });
+        assert_eq!(closed.check(ip, start), Err(Duration::from_secs(1)));
+    }
+}
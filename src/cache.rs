@@ -0,0 +1,319 @@
+//! A client-side response cache following RFC 7252 §5.6.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::message::header::{class_to_code, MessageClass, RequestType as Method, ResponseType as Status};
+use super::message::packet::{CoAPOption, Packet};
+use super::message::uri::decode_uint;
+
+/// The freshness of a response without a Max-Age option.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+const URI_HOST: usize = 3;
+const URI_PORT: usize = 7;
+const URI_PATH: usize = 11;
+const URI_QUERY: usize = 15;
+
+/// The cache key of a request: its destination, method and every option
+/// which is not marked NoCacheKey.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    peer_addr: SocketAddr,
+    code: u8,
+    options: Vec<(usize, Vec<u8>)>,
+}
+
+impl CacheKey {
+    pub fn new(peer_addr: &SocketAddr, request: &Packet) -> CacheKey {
+        CacheKey {
+            peer_addr: *peer_addr,
+            code: class_to_code(&request.header.code),
+            options: request
+                .options()
+                .filter(|&(number, _)| !is_no_cache_key(number))
+                .map(|(number, value)| (number, value.to_vec()))
+                .collect(),
+        }
+    }
+
+    /// Whether both keys identify the same resource, whatever the method and
+    /// other options.
+    fn same_resource(&self, other: &CacheKey) -> bool {
+        let uri = |key: &CacheKey| -> Vec<(usize, Vec<u8>)> {
+            key.options
+                .iter()
+                .filter(|&&(number, _)| [URI_HOST, URI_PORT, URI_PATH, URI_QUERY].contains(&number))
+                .cloned()
+                .collect()
+        };
+        self.peer_addr == other.peer_addr && uri(self) == uri(other)
+    }
+}
+
+/// Options with bits 1 to 4 of the number set to 0b1110 are not part of the
+/// cache key (RFC 7252 §5.4.6).
+fn is_no_cache_key(number: usize) -> bool {
+    number & 0x1E == 0x1C
+}
+
+#[derive(Debug)]
+struct Entry {
+    response: Packet,
+    fresh_until: Instant,
+    stored: Instant,
+}
+
+/// What the cache can do for a request.
+#[derive(Debug)]
+pub(crate) enum Lookup {
+    /// A fresh response to return as is.
+    Fresh(Packet),
+    /// A stale response which may be revalidated with its ETag.
+    Stale(Vec<u8>),
+    Miss,
+}
+
+#[derive(Debug)]
+pub(crate) struct Cache {
+    entries: HashMap<CacheKey, Entry>,
+    capacity: usize,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    pub fn lookup(&self, key: &CacheKey, now: Instant) -> Lookup {
+        match self.entries.get(key) {
+            Some(entry) if now < entry.fresh_until => Lookup::Fresh(entry.response.clone()),
+            Some(entry) => match entry.response.get_option(CoAPOption::ETag).and_then(|list| list.front()) {
+                Some(etag) => Lookup::Stale(etag.clone()),
+                None => Lookup::Miss,
+            },
+            None => Lookup::Miss,
+        }
+    }
+
+    /// Update the cache with the response to a request with `key`, returning
+    /// the response to hand out: a 2.03 Valid which revalidated a stored
+    /// response is replaced by the stored one.
+    pub fn update(&mut self, key: CacheKey, method: &Method, response: Packet, now: Instant) -> Packet {
+        let status = match response.header.code {
+            MessageClass::Response(ref status) => status.clone(),
+            _ => return response,
+        };
+
+        if *method != Method::Get {
+            if is_success(&status) {
+                self.entries.retain(|stored, _| !stored.same_resource(&key));
+            }
+            return response;
+        }
+
+        let max_age = max_age(&response);
+        if status == Status::Valid {
+            let revalidated = match self.entries.get_mut(&key) {
+                Some(entry) if Self::etag(&entry.response) == Self::etag(&response) => {
+                    entry.fresh_until = now + max_age;
+                    entry.response.clear_option(CoAPOption::MaxAge);
+                    if let Some(list) = response.get_option(CoAPOption::MaxAge) {
+                        entry.response.set_option(CoAPOption::MaxAge, list.clone());
+                    }
+                    Some(entry.response.clone())
+                }
+                _ => None,
+            };
+            return revalidated.unwrap_or(response);
+        }
+
+        if is_cacheable(&status) && max_age > Duration::from_secs(0) {
+            if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+                self.evict(now);
+            }
+            if self.capacity > 0 {
+                self.entries.insert(
+                    key,
+                    Entry {
+                        response: response.clone(),
+                        fresh_until: now + max_age,
+                        stored: now,
+                    },
+                );
+            }
+        } else {
+            self.entries.remove(&key);
+        }
+        response
+    }
+
+    /// Remove stale entries, or the oldest one if all are fresh.
+    fn evict(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| now < entry.fresh_until);
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    fn etag(packet: &Packet) -> Option<&Vec<u8>> {
+        packet.get_option(CoAPOption::ETag).and_then(|list| list.front())
+    }
+}
+
+fn max_age(response: &Packet) -> Duration {
+    match response.get_option(CoAPOption::MaxAge).and_then(|list| list.front()) {
+        Some(value) => Duration::from_secs(decode_uint(value) as u64),
+        None => DEFAULT_MAX_AGE,
+    }
+}
+
+fn is_success(status: &Status) -> bool {
+    matches!(
+        *status,
+        Status::Created | Status::Deleted | Status::Valid | Status::Changed | Status::Content
+    )
+}
+
+/// 2.05 Content and error responses may be cached (RFC 7252 §5.9).
+fn is_cacheable(status: &Status) -> bool {
+    *status == Status::Content || !is_success(status) && *status != Status::UnKnown
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::header::MessageType;
+
+    fn request(method: Method, path: &str) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(method);
+        packet.add_option(CoAPOption::UriPath, path.as_bytes().to_vec());
+        packet
+    }
+
+    fn response(status: Status, etag: Option<&[u8]>, max_age: Option<u8>, payload: &[u8]) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Acknowledgement);
+        packet.header.code = MessageClass::Response(status);
+        if let Some(etag) = etag {
+            packet.add_option(CoAPOption::ETag, etag.to_vec());
+        }
+        if let Some(max_age) = max_age {
+            packet.add_option(CoAPOption::MaxAge, vec![max_age]);
+        }
+        packet.payload = payload.to_vec();
+        packet
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:5683".parse().unwrap()
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let mut a = request(Method::Get, "a");
+        let b = request(Method::Get, "a");
+        a.add_option(CoAPOption::Size1, vec![10]);
+        assert_eq!(CacheKey::new(&peer(), &a), CacheKey::new(&peer(), &b));
+
+        a.add_option(CoAPOption::Accept, vec![50]);
+        assert_ne!(CacheKey::new(&peer(), &a), CacheKey::new(&peer(), &b));
+        assert_ne!(CacheKey::new(&peer(), &b), CacheKey::new(&"127.0.0.1:1".parse().unwrap(), &b));
+        assert_ne!(CacheKey::new(&peer(), &b), CacheKey::new(&peer(), &request(Method::Put, "a")));
+    }
+
+    #[test]
+    fn test_fresh_and_stale() {
+        let mut cache = Cache::new(8);
+        let key = CacheKey::new(&peer(), &request(Method::Get, "a"));
+        let now = Instant::now();
+        assert!(matches!(cache.lookup(&key, now), Lookup::Miss));
+
+        let stored = response(Status::Content, Some(b"v1"), Some(10), b"data");
+        cache.update(key.clone(), &Method::Get, stored.clone(), now);
+        match cache.lookup(&key, now + Duration::from_secs(9)) {
+            Lookup::Fresh(packet) => assert_eq!(packet.payload, b"data".to_vec()),
+            other => panic!("unexpected lookup {:?}", other),
+        }
+
+        let later = now + Duration::from_secs(10);
+        match cache.lookup(&key, later) {
+            Lookup::Stale(etag) => assert_eq!(etag, b"v1".to_vec()),
+            other => panic!("unexpected lookup {:?}", other),
+        }
+
+        // 2.03 Valid makes the stored response fresh again.
+        let valid = response(Status::Valid, Some(b"v1"), None, b"");
+        let revalidated = cache.update(key.clone(), &Method::Get, valid, later);
+        assert_eq!(revalidated.payload, b"data".to_vec());
+        match cache.lookup(&key, later + Duration::from_secs(59)) {
+            Lookup::Fresh(_) => {}
+            other => panic!("unexpected lookup {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_not_cached() {
+        let mut cache = Cache::new(8);
+        let key = CacheKey::new(&peer(), &request(Method::Get, "a"));
+        let now = Instant::now();
+
+        cache.update(key.clone(), &Method::Get, response(Status::Content, None, Some(0), b""), now);
+        assert!(matches!(cache.lookup(&key, now), Lookup::Miss));
+
+        cache.update(key.clone(), &Method::Get, response(Status::Content, None, Some(5), b""), now);
+        assert!(matches!(cache.lookup(&key, now + Duration::from_secs(5)), Lookup::Miss));
+
+        // Error responses are cached too.
+        cache.update(key.clone(), &Method::Get, response(Status::NotFound, None, None, b""), now);
+        match cache.lookup(&key, now) {
+            Lookup::Fresh(packet) => assert_eq!(packet.header.code, MessageClass::Response(Status::NotFound)),
+            other => panic!("unexpected lookup {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalidate_on_unsafe_method() {
+        let mut cache = Cache::new(8);
+        let now = Instant::now();
+        let mut accept = request(Method::Get, "a");
+        accept.add_option(CoAPOption::Accept, vec![50]);
+        for get in &[request(Method::Get, "a"), accept, request(Method::Get, "b")] {
+            let key = CacheKey::new(&peer(), get);
+            cache.update(key, &Method::Get, response(Status::Content, None, None, b""), now);
+        }
+
+        let put = CacheKey::new(&peer(), &request(Method::Put, "a"));
+        cache.update(put.clone(), &Method::Put, response(Status::BadRequest, None, None, b""), now);
+        assert_eq!(cache.entries.len(), 3);
+        cache.update(put, &Method::Put, response(Status::Changed, None, None, b""), now);
+        assert_eq!(cache.entries.len(), 1);
+        let b = CacheKey::new(&peer(), &request(Method::Get, "b"));
+        assert!(cache.entries.contains_key(&b));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cache = Cache::new(2);
+        let now = Instant::now();
+        for (i, path) in ["a", "b", "c"].iter().enumerate() {
+            let key = CacheKey::new(&peer(), &request(Method::Get, path));
+            cache.update(key, &Method::Get, response(Status::Content, None, None, b""), now + Duration::from_secs(i as u64));
+        }
+        assert_eq!(cache.entries.len(), 2);
+        let a = CacheKey::new(&peer(), &request(Method::Get, "a"));
+        assert!(!cache.entries.contains_key(&a));
+    }
+}
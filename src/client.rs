@@ -1,30 +1,155 @@
-use std::io::{Error, ErrorKind, Result};
+use std::io::ErrorKind;
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 use std::thread;
-use std::sync::mpsc;
-use url::Url;
-use num;
-use rand::{random, thread_rng, Rng};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use rand::{random, thread_rng, RngCore};
 use log::*;
-use super::message::packet::{Packet, ObserveOption};
-use super::message::header::MessageType;
+use super::message::packet::{CoAPOption, ContentFormat, ObserveOption, Packet};
+use super::message::header::{MessageClass, MessageType};
 use super::message::response::{CoAPResponse, Status};
 use super::message::request::{CoAPRequest, Method};
+use super::message::uri::{decode_uint, encode_uint, percent_decode, CoAPUri};
 use super::message::IsMessage;
-use regex::Regex;
+use super::error::{Error, ProtocolError, Result};
+use super::congestion::{Congestion, TransmissionParameters};
+use super::cache::{Cache, CacheKey, Lookup};
+use super::echo;
+use super::interceptor::{Interceptor, Next};
+#[cfg(feature = "oscore")]
+use super::oscore::SecurityContext;
 
 const DEFAULT_RECEIVE_TIMEOUT: u64 = 1; // 1s
+const DEFAULT_TOKEN_LENGTH: usize = 4;
+const MAX_TOKEN_LENGTH: usize = 8;
+/// How long message IDs received from a peer are remembered for
+/// deduplication, EXCHANGE_LIFETIME in RFC 7252 §4.8.2.
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+/// The most message IDs remembered per peer.
+const MAX_RECEIVED_MESSAGE_IDS: usize = 256;
+/// How long a message waits for the receiver it belongs to.
+const UNCLAIMED_LIFETIME: Duration = Duration::from_secs(5);
+const MAX_UNCLAIMED: usize = 64;
+/// The longest single socket read while waiting for a response, so waiters
+/// notice messages another thread received for them.
+const RECEIVE_SLICE: Duration = Duration::from_millis(50);
 
 enum ObserveMessage {
     Terminate,
 }
 
+/// The exchange state of one peer.
+struct PeerState {
+    next_message_id: u16,
+    /// Confirmable and non-confirmable message IDs received from the peer,
+    /// oldest first.
+    received: VecDeque<(u16, Instant)>,
+    congestion: Congestion,
+}
+
+/// Exchange state shared by everything using the client socket.
+#[derive(Default)]
+struct ClientState {
+    peers: HashMap<SocketAddr, PeerState>,
+    /// Messages received by one receiver on behalf of another.
+    unclaimed: VecDeque<(SocketAddr, Packet, Instant)>,
+}
+
+impl ClientState {
+    fn peer(&mut self, addr: &SocketAddr) -> &mut PeerState {
+        self.peers.entry(*addr).or_insert_with(|| PeerState {
+            next_message_id: random(),
+            received: VecDeque::new(),
+            congestion: Congestion::new(),
+        })
+    }
+
+    fn next_message_id(&mut self, addr: &SocketAddr) -> u16 {
+        let peer = self.peer(addr);
+        let message_id = peer.next_message_id;
+        peer.next_message_id = message_id.wrapping_add(1);
+        message_id
+    }
+
+    /// Record a message ID received from `addr`, returning whether it was
+    /// already received within EXCHANGE_LIFETIME.
+    fn is_duplicate(&mut self, addr: &SocketAddr, message_id: u16) -> bool {
+        let now = Instant::now();
+        let peer = self.peer(addr);
+        while let Some(&(_, received_at)) = peer.received.front() {
+            if now.duration_since(received_at) < EXCHANGE_LIFETIME
+                && peer.received.len() < MAX_RECEIVED_MESSAGE_IDS
+            {
+                break;
+            }
+            peer.received.pop_front();
+        }
+
+        if peer.received.iter().any(|&(id, _)| id == message_id) {
+            return true;
+        }
+        peer.received.push_back((message_id, now));
+        false
+    }
+
+    fn stash(&mut self, addr: SocketAddr, packet: Packet) {
+        let now = Instant::now();
+        self.unclaimed
+            .retain(|&(_, _, received_at)| now.duration_since(received_at) < UNCLAIMED_LIFETIME);
+        if self.unclaimed.len() >= MAX_UNCLAIMED {
+            self.unclaimed.pop_front();
+        }
+        self.unclaimed.push_back((addr, packet, now));
+    }
+
+    fn claim(&mut self, addr: &SocketAddr, request: &Packet) -> Option<Packet> {
+        let position = self
+            .unclaimed
+            .iter()
+            .position(|(source, packet, _)| source == addr && Self::belongs_to(packet, request))?;
+        self.unclaimed.remove(position).map(|(_, packet, _)| packet)
+    }
+
+    /// Whether `packet` answers `request`: a response with its token, or an
+    /// empty Acknowledgement or a Reset of its message ID.
+    fn belongs_to(packet: &Packet, request: &Packet) -> bool {
+        match (packet.header.get_type(), &packet.header.code) {
+            (MessageType::Reset, _) | (MessageType::Acknowledgement, MessageClass::Empty) => {
+                packet.header.get_message_id() == request.header.get_message_id()
+            }
+            _ => packet.get_token() == request.get_token(),
+        }
+    }
+}
+
+/// A CoAP client.
+///
+/// A client owns one socket and can be used for any number of requests to
+/// any number of peers, also from several threads at once. It keeps a
+/// message ID counter per peer and drops duplicated messages, so a long-lived
+/// client should be preferred over creating one per request.
+///
+/// Requests sent with `build_request` and its shorthands follow the
+/// congestion control of RFC 7252 §4.7: confirmable requests are
+/// retransmitted with exponential backoff, requests beyond NSTART outstanding
+/// ones per peer are queued, and a peer which stopped responding is probed at
+/// no more than PROBING_RATE.
 pub struct CoAPClient {
     socket: UdpSocket,
-    peer_addr: SocketAddr,
+    /// The peer of `send` and `observe`, if any.
+    peer_addr: Option<SocketAddr>,
     observe_sender: Option<mpsc::Sender<ObserveMessage>>,
     observe_thread: Option<thread::JoinHandle<()>>,
+    state: Arc<Mutex<ClientState>>,
+    /// Signalled whenever an interaction finishes.
+    finished: Condvar,
+    parameters: TransmissionParameters,
+    cache: Option<Mutex<Cache>>,
+    token_length: usize,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    #[cfg(feature = "oscore")]
+    oscore: Option<Mutex<SecurityContext>>,
 }
 
 impl CoAPClient {
@@ -33,32 +158,48 @@ impl CoAPClient {
         bind_addr: A,
         peer_addr: B,
     ) -> Result<CoAPClient> {
-        peer_addr
-            .to_socket_addrs()
-            .and_then(|mut iter| match iter.next() {
-                Some(paddr) => UdpSocket::bind(bind_addr).and_then(|s| {
-                    s.set_read_timeout(Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))
-                        .and_then(|_| {
-                            Ok(CoAPClient {
-                                socket: s,
-                                peer_addr: paddr,
-                                observe_sender: None,
-                                observe_thread: None,
-                            })
-                        })
-                }),
-                None => Err(Error::new(ErrorKind::Other, "no address")),
-            })
+        match peer_addr.to_socket_addrs()?.next() {
+            Some(paddr) => Self::with_socket(UdpSocket::bind(bind_addr)?, Some(paddr)),
+            None => Err(Error::NoAddress),
+        }
+    }
+
+    /// Create a CoAP client bound to `bind_addr` without a peer, for requests
+    /// by URL with `build_request` and its shorthands.
+    pub fn bind<A: ToSocketAddrs>(bind_addr: A) -> Result<CoAPClient> {
+        Self::with_socket(UdpSocket::bind(bind_addr)?, None)
+    }
+
+    fn with_socket(socket: UdpSocket, peer_addr: Option<SocketAddr>) -> Result<CoAPClient> {
+        socket.set_read_timeout(Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))?;
+        Ok(CoAPClient {
+            socket,
+            peer_addr,
+            observe_sender: None,
+            observe_thread: None,
+            state: Arc::new(Mutex::new(ClientState::default())),
+            finished: Condvar::new(),
+            parameters: TransmissionParameters::default(),
+            cache: None,
+            token_length: DEFAULT_TOKEN_LENGTH,
+            interceptors: Vec::new(),
+            #[cfg(feature = "oscore")]
+            oscore: None,
+        })
+    }
+
+    /// The peer of the client, which clients created with `bind` lack.
+    fn peer(&self) -> Result<SocketAddr> {
+        self.peer_addr.ok_or(Error::NoAddress)
     }
 
     /// Create a CoAP client with the peer address.
     pub fn new<A: ToSocketAddrs>(addr: A) -> Result<CoAPClient> {
-        addr.to_socket_addrs()
-            .and_then(|mut iter| match iter.next() {
-                Some(SocketAddr::V4(_)) => Self::new_with_specific_source("0.0.0.0:0", addr),
-                Some(SocketAddr::V6(_)) => Self::new_with_specific_source(":::0", addr),
-                None => Err(Error::new(ErrorKind::Other, "no address")),
-            })
+        match addr.to_socket_addrs()?.next() {
+            Some(SocketAddr::V4(_)) => Self::new_with_specific_source("0.0.0.0:0", addr),
+            Some(SocketAddr::V6(_)) => Self::new_with_specific_source(":::0", addr),
+            None => Err(Error::NoAddress),
+        }
     }
 
     /// Execute a get request
@@ -66,89 +207,111 @@ impl CoAPClient {
         Self::get_with_timeout(url, Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0))
     }
 
-    /// Execute a get request with the coap url and a specific timeout.
+    /// Execute a get request with the coap url and a specific timeout, from
+    /// a client of its own.
     pub fn get_with_timeout(url: &str, timeout: Duration) -> Result<CoAPResponse> {
-        let (domain, port, path) = Self::parse_coap_url(url)?;
-
-        let mut packet = CoAPRequest::new();
-        packet.set_path(path.as_str());
+        let (uri, peer_addr) = Self::resolve_url(url)?;
+        let client = Self::new(peer_addr)?;
+        RequestBuilder::new(&client, Method::Get, Ok((uri, peer_addr)))
+            .timeout(timeout)
+            .send()
+    }
 
-        let client = Self::new((domain.as_str(), port))?;
-        client.send(&packet)?;
+    /// Execute a get request with the coap url and a specific timeout, or the
+    /// default one.
+    #[deprecated(since = "0.6.0", note = "please use `get_with_timeout` instead")]
+    pub fn request_with_timeout(url: &str, timeout: Option<Duration>) -> Result<CoAPResponse> {
+        Self::get_with_timeout(url, timeout.unwrap_or(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))
+    }
 
-        client.set_receive_timeout(Some(timeout))?;
-        match client.receive() {
-            Ok(receive_packet) => Ok(receive_packet),
-            Err(e) => Err(e),
-        }
+    /// Execute a get request with the coap url.
+    #[deprecated(since = "0.6.0", note = "please use `get` instead")]
+    pub fn request(url: &str) -> Result<CoAPResponse> {
+        Self::get_with_timeout(url, Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0))
     }
 
     /// Observe a resource with the handler
-    pub fn observe<H: FnMut(Packet) + Send + 'static>(&mut self, resource_path: &str, mut handler: H) -> Result<()> {
+    pub fn observe<H: FnMut(Packet) + Send + 'static>(&mut self, resource_path: &str, handler: H) -> Result<()> {
+        self.observe_with_options(resource_path, &[], Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0), handler)
+    }
+
+    /// Observe a resource with the handler, registering with additional
+    /// options and waiting up to `timeout` for the first response.
+    pub(crate) fn observe_with_options<H: FnMut(Packet) + Send + 'static>(
+        &mut self,
+        resource_path: &str,
+        options: &[(CoAPOption, Vec<u8>)],
+        timeout: Duration,
+        mut handler: H,
+    ) -> Result<()> {
         // TODO: support observe multi resources at the same time
-        let mut message_id: u16 = 0;
+        let peer_addr = self.peer()?;
+        let token = self.gen_token();
         let mut register_packet = CoAPRequest::new();
+        for (option, value) in options {
+            register_packet.add_option(*option, value.clone());
+        }
         register_packet.set_observe(vec![ObserveOption::Register as u8]);
-        register_packet.set_message_id(Self::gen_message_id(&mut message_id));
+        register_packet.set_message_id(self.gen_message_id());
+        register_packet.set_token(token.clone())?;
         register_packet.set_path(resource_path);
 
         self.send(&register_packet)?;
 
-        self.set_receive_timeout(Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))?;
-        let response = self.receive()?;
+        let mut response = self.receive_response(&peer_addr, &register_packet, timeout)?;
+        if let Some(value) = echo::challenge_value(&response) {
+            register_packet.add_option(CoAPOption::Echo, value);
+            register_packet.set_message_id(self.gen_message_id());
+            self.send(&register_packet)?;
+            response = self.receive_response(&peer_addr, &register_packet, timeout)?;
+        }
         if *response.get_status() != Status::Content {
-            return Err(Error::new(ErrorKind::NotFound, "the resource not found"));
+            return Err(Error::Response(response.get_status().clone()));
         }
 
         handler(response.message);
 
-        let socket;
-        match self.socket.try_clone() {
-            Ok(good_socket) => socket = good_socket,
-            Err(_) => return Err(Error::new(ErrorKind::Other, "network error")),
-        }
-        let peer_addr = self.peer_addr.clone();
+        let socket = self.socket.try_clone()?;
         let (observe_sender, observe_receiver) = mpsc::channel();
         let observe_path = String::from(resource_path);
+        // The deregistration names the same resource, query included.
+        let observe_queries: Vec<Vec<u8>> = options
+            .iter()
+            .filter(|(option, _)| *option == CoAPOption::UriQuery)
+            .map(|(_, value)| value.clone())
+            .collect();
+        let state = self.state.clone();
+        let interceptors = self.interceptors.clone();
 
         let observe_thread = thread::spawn(move || loop {
-            match Self::receive_from_socket(&socket) {
-                Ok(packet) => {
-                    let receive_packet = CoAPRequest::from_packet(packet, &peer_addr);
-
-                    handler(receive_packet.message);
-
-                    if let Some(response) = receive_packet.response {
-                        let mut packet = Packet::new();
-                        packet.header.set_type(response.message.header.get_type());
-                        packet.header.set_message_id(response.message.header.get_message_id());
-                        packet.set_token(response.message.get_token().clone());
-
-                        match Self::send_with_socket(&socket, &peer_addr, &packet) {
-                            Ok(_) => (),
-                            Err(e) => {
-                                warn!("reply ack failed {}", e)
-                            }
-                        }
-                    }
-                },
-                Err(e) => {
-                    match e.kind() {
-                        ErrorKind::WouldBlock => (),                          // timeout
-                        _ => warn!("observe failed {:?}", e),
+            match Self::receive_message(&socket, &state, &interceptors) {
+                Ok(Some((source, packet))) => {
+                    if source == peer_addr && *packet.get_token() == token {
+                        handler(packet);
+                    } else {
+                        state.lock().unwrap().stash(source, packet);
                     }
                 },
+                Ok(None) | Err(Error::Timeout) => (),
+                Err(e) => warn!("observe failed {:?}", e),
             };
 
             match observe_receiver.try_recv() {
                 Ok(ObserveMessage::Terminate) => {
                     let mut deregister_packet = CoAPRequest::new();
-                    deregister_packet.set_message_id(Self::gen_message_id(&mut message_id));
+                    deregister_packet.set_message_id(state.lock().unwrap().next_message_id(&peer_addr));
+                    deregister_packet.set_token(token.clone()).unwrap();
                     deregister_packet.set_observe(vec![ObserveOption::Deregister as u8]);
                     deregister_packet.set_path(observe_path.as_str());
+                    for query in &observe_queries {
+                        deregister_packet.add_option(CoAPOption::UriQuery, query.clone());
+                    }
 
-                    Self::send_with_socket(&socket, &peer_addr, &deregister_packet.message).unwrap();
-                    Self::receive_from_socket(&socket).unwrap();
+                    if let Err(e) = Self::send_with_socket(&socket, &peer_addr, &deregister_packet.message, &interceptors)
+                        .and_then(|_| Self::receive_from_socket(&socket, &interceptors))
+                    {
+                        warn!("deregister failed {:?}", e);
+                    }
                     break;
                 },
                 _ => continue,
@@ -172,116 +335,513 @@ impl CoAPClient {
         }
     }
 
-    /// Execute a request with the coap url and a specific timeout. Default timeout is 5s.
-    #[deprecated(since = "0.6.0", note = "please use `get_with_timeout` instead")]
-    pub fn request_with_timeout(url: &str, timeout: Option<Duration>) -> Result<CoAPResponse> {
-        let (domain, port, path) = Self::parse_coap_url(url)?;
+    /// Start building a request with the given method and coap url.
+    ///
+    /// The request is sent to the host and port of the url, which need not be
+    /// the peer the client was created with.
+    pub fn build_request(&self, method: Method, url: &str) -> RequestBuilder<'_> {
+        RequestBuilder::new(self, method, Self::resolve_url(url))
+    }
+
+    /// Start building a POST request.
+    pub fn post(&self, url: &str) -> RequestBuilder<'_> {
+        self.build_request(Method::Post, url)
+    }
 
-        let mut packet = CoAPRequest::new();
-        packet.set_version(1);
-        packet.set_type(MessageType::Confirmable);
-        packet.set_method(Method::Get);
+    /// Start building a PUT request.
+    pub fn put(&self, url: &str) -> RequestBuilder<'_> {
+        self.build_request(Method::Put, url)
+    }
 
-        let message_id = thread_rng().gen_range(0, num::pow(2u32, 16)) as u16;
-        packet.set_message_id(message_id);
+    /// Start building a DELETE request.
+    pub fn delete(&self, url: &str) -> RequestBuilder<'_> {
+        self.build_request(Method::Delete, url)
+    }
 
-        let mut token: Vec<u8> = vec![1, 1, 1, 1];
-        for x in token.iter_mut() {
-            *x = random()
+    /// Set the length of the tokens generated for requests, from 0 to 8
+    /// bytes. The default is 4.
+    pub fn set_token_length(&mut self, length: usize) -> Result<()> {
+        if length > MAX_TOKEN_LENGTH {
+            return Err(Error::InvalidTokenLength(length));
         }
-        packet.set_token(token.clone());
-        packet.set_path(path.as_str());
+        self.token_length = length;
+        Ok(())
+    }
 
-        let client = r#try!(Self::new((domain.as_str(), port)));
-        r#try!(client.send(&packet));
+    /// Cache up to `capacity` responses to GET requests, following RFC 7252
+    /// §5.6: fresh responses are served without contacting the peer, stale
+    /// ones are revalidated with their ETag, and a successful POST, PUT or
+    /// DELETE invalidates the responses stored for its URI.
+    pub fn enable_cache(&mut self, capacity: usize) {
+        self.cache = Some(Mutex::new(Cache::new(capacity)));
+    }
 
-        r#try!(client.set_receive_timeout(timeout));
-        match client.receive() {
-            Ok(receive_packet) => {
-                if receive_packet.get_message_id() == message_id
-                    && *receive_packet.get_token() == token
-                {
-                    return Ok(receive_packet);
-                } else {
-                    return Err(Error::new(ErrorKind::Other, "receive invalid data"));
-                }
-            }
-            Err(e) => Err(e),
+    /// Stop caching and drop all cached responses.
+    pub fn disable_cache(&mut self) {
+        self.cache = None;
+    }
+
+    /// Protect the requests sent with `build_request` and the methods built on
+    /// it with OSCORE, and accept only protected responses, apart from the
+    /// unprotected errors of a server which could not verify a request.
+    /// Protected requests bypass the cache.
+    #[cfg(feature = "oscore")]
+    pub fn enable_oscore(&mut self, context: SecurityContext) {
+        self.oscore = Some(Mutex::new(context));
+    }
+
+    /// Send requests unprotected again.
+    #[cfg(feature = "oscore")]
+    pub fn disable_oscore(&mut self) {
+        self.oscore = None;
+    }
+
+    /// The OSCORE context in use, such as to store its sender sequence
+    /// number. Requests wait while it is held.
+    #[cfg(feature = "oscore")]
+    pub fn oscore_context(&self) -> Option<std::sync::MutexGuard<'_, SecurityContext>> {
+        self.oscore.as_ref().map(|context| context.lock().unwrap())
+    }
+
+    /// Pass the requests sent with `build_request` and the methods built on
+    /// it through `interceptor`, after the interceptors added before it, and
+    /// show it every datagram sent and received. Observations started
+    /// afterwards are watched too. See the `interceptor` module.
+    pub fn add_interceptor<I: Interceptor + 'static>(&mut self, interceptor: I) {
+        self.interceptors.push(Arc::new(interceptor));
+    }
+
+    /// Remove all interceptors.
+    pub fn clear_interceptors(&mut self) {
+        self.interceptors.clear();
+    }
+
+    /// Set the transmission parameters used for requests.
+    pub fn set_transmission_parameters(&mut self, parameters: TransmissionParameters) {
+        self.parameters = parameters;
+    }
+
+    /// Return the next message ID for the peer. IDs start at a random value
+    /// and increase by one per message; every peer has its own sequence.
+    /// Without a peer, the ID is random.
+    pub fn gen_message_id(&self) -> u16 {
+        match self.peer_addr {
+            Some(peer_addr) => self.next_message_id(&peer_addr),
+            None => random(),
         }
     }
 
-    /// Execute a request with the coap url.
-    #[deprecated(since = "0.6.0", note = "please use `get` instead")]
-    pub fn request(url: &str) -> Result<CoAPResponse> {
-        Self::get_with_timeout(url, Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0))
+    fn next_message_id(&self, peer_addr: &SocketAddr) -> u16 {
+        self.state.lock().unwrap().next_message_id(peer_addr)
+    }
+
+    /// Return a new random token of the configured length.
+    pub fn gen_token(&self) -> Vec<u8> {
+        let mut token = vec![0; self.token_length];
+        thread_rng().fill_bytes(&mut token);
+        token
     }
 
     /// Execute a request.
+    ///
+    /// The request is sent as is to the peer, which clients created with
+    /// `bind` lack; use `gen_message_id` and `gen_token` to fill in its
+    /// message ID and token.
     pub fn send(&self, request: &CoAPRequest) -> Result<()> {
-        Self::send_with_socket(&self.socket, &self.peer_addr, &request.message)
+        Self::send_with_socket(&self.socket, &self.peer()?, &request.message, &self.interceptors)
     }
 
     /// Receive a response.
+    ///
+    /// A Reset from the peer is reported as `Error::Reset`. Duplicated
+    /// messages are dropped.
     pub fn receive(&self) -> Result<CoAPResponse> {
-        let packet = Self::receive_from_socket(&self.socket)?;
-        Ok(CoAPResponse { message: packet })
+        loop {
+            if let Some((_, packet)) = Self::receive_message(&self.socket, &self.state, &self.interceptors)? {
+                if packet.header.get_type() == MessageType::Reset {
+                    return Err(Error::Reset);
+                }
+                return Ok(CoAPResponse { message: packet });
+            }
+        }
     }
 
-    /// Set the receive timeout.
-    pub fn set_receive_timeout(&self, dur: Option<Duration>) -> Result<()> {
-        self.socket.set_read_timeout(dur)
+    /// Send `request` to `peer_addr` with a new message ID and token, and
+    /// receive its response. A 4.01 Unauthorized challenge with an Echo
+    /// option is answered once by sending the request again with the Echo
+    /// value (RFC 9175 §2.3).
+    fn exchange_fresh(&self, peer_addr: &SocketAddr, request: &CoAPRequest, timeout: Duration) -> Result<CoAPResponse> {
+        let mut request = request.clone();
+        let mut echoed = false;
+        loop {
+            request.set_message_id(self.next_message_id(peer_addr));
+            request.set_token(self.gen_token())?;
+            let response = self.exchange_protected(peer_addr, &request, timeout)?;
+            match echo::challenge_value(&response) {
+                Some(value) if !echoed => {
+                    debug!("request challenged by {}", peer_addr);
+                    request.clear_option(CoAPOption::Echo);
+                    request.add_option(CoAPOption::Echo, value);
+                    echoed = true;
+                }
+                _ => return Ok(response),
+            }
+        }
+    }
+
+    /// Exchange `request`, protected with OSCORE if enabled.
+    fn exchange_protected(&self, peer_addr: &SocketAddr, request: &CoAPRequest, timeout: Duration) -> Result<CoAPResponse> {
+        #[cfg(feature = "oscore")]
+        {
+            if let Some(ref context) = self.oscore {
+                let mut request = request.clone();
+                let binding = context.lock().unwrap().protect_request(&mut request.message)?;
+                let mut response = self.exchange(peer_addr, &request, timeout)?;
+                context.lock().unwrap().unprotect_response(&mut response.message, &binding)?;
+                return Ok(response);
+            }
+        }
+        self.exchange(peer_addr, request, timeout)
     }
 
-    fn send_with_socket(socket: &UdpSocket, peer_addr: &SocketAddr, message: &Packet) -> Result<()> {
-        match message.to_bytes() {
-            Ok(bytes) => {
-                let size = r#try!(socket.send_to(&bytes[..], peer_addr));
-                if size == bytes.len() {
-                    Ok(())
-                } else {
-                    Err(Error::new(ErrorKind::Other, "send length error"))
+    /// Upload the payload of `request` in blocks of `block_size` bytes with
+    /// the Block1 option (RFC 7959 §2.5), each tagged with the same new
+    /// Request-Tag so that the server cannot mix the blocks up with those of
+    /// other uploads (RFC 9175 §3). Returns the response to the last block,
+    /// or the first response which is not 2.31 Continue.
+    fn upload(&self, peer_addr: &SocketAddr, request: &CoAPRequest, block_size: usize, timeout: Duration) -> Result<CoAPResponse> {
+        let payload = &request.message.payload;
+        let tag = random::<u32>().to_be_bytes().to_vec();
+        let mut block_size = block_size;
+        let mut offset = 0;
+        loop {
+            let end = payload.len().min(offset + block_size);
+            let more = end < payload.len();
+            let mut block = request.clone();
+            block.set_payload(payload[offset..end].to_vec());
+            block.clear_option(CoAPOption::Block1);
+            block.add_option(CoAPOption::Block1, encode_block(offset / block_size, more, block_size));
+            block.clear_option(CoAPOption::RequestTag);
+            block.add_option(CoAPOption::RequestTag, tag.clone());
+            if offset == 0 {
+                block.clear_option(CoAPOption::Size1);
+                block.add_option(CoAPOption::Size1, encode_uint(payload.len() as u32));
+            }
+
+            let response = self.exchange_fresh(peer_addr, &block, timeout)?;
+            if !more || *response.get_status() != Status::Continue {
+                return Ok(response);
+            }
+            // The server may ask for smaller blocks.
+            if let Some(size) = response
+                .get_option(CoAPOption::Block1)
+                .and_then(|list| list.front())
+                .and_then(|value| decode_block_size(value))
+            {
+                block_size = block_size.min(size);
+            }
+            offset = end;
+        }
+    }
+
+    /// Send `request` to `peer_addr` and receive its response, subject to
+    /// congestion control.
+    fn exchange(&self, peer_addr: &SocketAddr, request: &CoAPRequest, timeout: Duration) -> Result<CoAPResponse> {
+        let deadline = Instant::now() + timeout;
+        let bytes = request.message.to_bytes()?;
+        self.start_interaction(peer_addr, bytes.len(), deadline)?;
+
+        let mut sample = None;
+        let result = self.transmit(peer_addr, request, &bytes, deadline, &mut sample);
+
+        self.state
+            .lock()
+            .unwrap()
+            .peer(peer_addr)
+            .congestion
+            .finish(sample, Instant::now());
+        self.finished.notify_all();
+        result
+    }
+
+    /// Wait until NSTART and PROBING_RATE allow another interaction with
+    /// `peer_addr`.
+    fn start_interaction(&self, peer_addr: &SocketAddr, bytes: usize, deadline: Instant) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let retry = match state.peer(peer_addr).congestion.try_start(&self.parameters, bytes, now) {
+                Ok(()) => return Ok(()),
+                Err(retry) => retry.unwrap_or(deadline).min(deadline),
+            };
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            debug!("queue request to {}", peer_addr);
+            state = self.finished.wait_timeout(state, retry - now).unwrap().0;
+        }
+    }
+
+    /// Send `bytes`, retransmitting a confirmable request until it is
+    /// acknowledged, and receive the response. The first round-trip time
+    /// measured is stored in `sample`.
+    fn transmit(
+        &self,
+        peer_addr: &SocketAddr,
+        request: &CoAPRequest,
+        bytes: &[u8],
+        deadline: Instant,
+        sample: &mut Option<(Duration, u32)>,
+    ) -> Result<CoAPResponse> {
+        let started = Instant::now();
+        let mut acknowledged = request.get_type() != MessageType::Confirmable;
+        let mut retransmissions = 0;
+        let mut timeout = self
+            .state
+            .lock()
+            .unwrap()
+            .peer(peer_addr)
+            .congestion
+            .initial_timeout(&self.parameters, started);
+        let mut retransmit_at = started + timeout;
+
+        self.send_bytes(peer_addr, bytes)?;
+        loop {
+            let retransmit = !acknowledged && retransmissions < self.parameters.max_retransmit;
+            let wait_until = if retransmit { retransmit_at.min(deadline) } else { deadline };
+
+            match self.receive_until(peer_addr, request, wait_until)? {
+                Some(packet) => {
+                    if sample.is_none() {
+                        *sample = Some((started.elapsed(), retransmissions));
+                    }
+                    if packet.header.get_type() == MessageType::Acknowledgement
+                        && packet.header.code == MessageClass::Empty
+                    {
+                        // Wait for the separate response.
+                        acknowledged = true;
+                        continue;
+                    }
+                    return Self::check_response(packet, request);
+                }
+                None if wait_until >= deadline => return Err(Error::Timeout),
+                None => {
+                    retransmissions += 1;
+                    debug!("retransmit message {} ({})", request.get_message_id(), retransmissions);
+                    self.send_bytes(peer_addr, bytes)?;
+
+                    let mut state = self.state.lock().unwrap();
+                    timeout = state.peer(peer_addr).congestion.backoff(&self.parameters, timeout);
+                    retransmit_at = Instant::now() + timeout;
                 }
             }
-            Err(_) => Err(Error::new(ErrorKind::InvalidInput, "packet error")),
         }
     }
 
-    fn receive_from_socket(socket: &UdpSocket) -> Result<Packet> {
+    /// Receive the response to `request` from `peer_addr` until `timeout`
+    /// elapses, ignoring an empty acknowledgement.
+    fn receive_response(&self, peer_addr: &SocketAddr, request: &CoAPRequest, timeout: Duration) -> Result<CoAPResponse> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.receive_until(peer_addr, request, deadline)? {
+                Some(packet) => {
+                    if packet.header.get_type() != MessageType::Acknowledgement
+                        || packet.header.code != MessageClass::Empty
+                    {
+                        return Self::check_response(packet, request);
+                    }
+                }
+                None => return Err(Error::Timeout),
+            }
+        }
+    }
+
+    /// Receive the next message belonging to `request` from `peer_addr`, or
+    /// `None` once `deadline` passes. Messages for other exchanges are left
+    /// for their receivers.
+    fn receive_until(&self, peer_addr: &SocketAddr, request: &CoAPRequest, deadline: Instant) -> Result<Option<Packet>> {
+        loop {
+            let claimed = self.state.lock().unwrap().claim(peer_addr, &request.message);
+            if claimed.is_some() {
+                return Ok(claimed);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.set_receive_timeout(Some((deadline - now).min(RECEIVE_SLICE)))?;
+
+            match Self::receive_message(&self.socket, &self.state, &self.interceptors) {
+                Ok(Some((source, packet))) => {
+                    if source == *peer_addr && ClientState::belongs_to(&packet, &request.message) {
+                        return Ok(Some(packet));
+                    }
+                    debug!("keep message with token {:?} for another receiver", packet.get_token());
+                    self.state.lock().unwrap().stash(source, packet);
+                }
+                Ok(None) | Err(Error::Timeout) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn check_response(packet: Packet, request: &CoAPRequest) -> Result<CoAPResponse> {
+        match packet.header.get_type() {
+            MessageType::Reset => Err(Error::Reset),
+            // A piggybacked response must acknowledge the request itself.
+            MessageType::Acknowledgement
+                if packet.header.get_message_id() != request.get_message_id() =>
+            {
+                Err(ProtocolError::MessageIdMismatch.into())
+            }
+            _ => Ok(CoAPResponse { message: packet }),
+        }
+    }
+
+    /// Receive one message, acknowledging it if it is confirmable. Returns
+    /// `None` for a duplicate.
+    fn receive_message(
+        socket: &UdpSocket,
+        state: &Mutex<ClientState>,
+        interceptors: &[Arc<dyn Interceptor>],
+    ) -> Result<Option<(SocketAddr, Packet)>> {
         let mut buf = [0; 1500];
+        let (nread, source) = socket.recv_from(&mut buf)?;
+        for interceptor in interceptors {
+            interceptor.on_receive(&source, &buf[..nread]);
+        }
+        let packet = Packet::from_bytes(&buf[..nread])?;
+
+        let message_type = packet.header.get_type();
+        if message_type == MessageType::Confirmable || message_type == MessageType::NonConfirmable {
+            let duplicate = state
+                .lock()
+                .unwrap()
+                .is_duplicate(&source, packet.header.get_message_id());
 
-        let (nread, _src) = r#try!(socket.recv_from(&mut buf));
-        match Packet::from_bytes(&buf[..nread]) {
-            Ok(packet) => Ok(packet),
-            Err(_) => Err(Error::new(ErrorKind::InvalidInput, "packet error")),
+            if message_type == MessageType::Confirmable {
+                let mut ack = Packet::new();
+                ack.header.set_type(MessageType::Acknowledgement);
+                ack.header.code = MessageClass::Empty;
+                ack.header.set_message_id(packet.header.get_message_id());
+                if let Err(e) = Self::send_with_socket(socket, &source, &ack, interceptors) {
+                    warn!("reply ack failed {:?}", e);
+                }
+            }
+
+            if duplicate {
+                debug!("drop duplicate message {}", packet.header.get_message_id());
+                return Ok(None);
+            }
         }
+        Ok(Some((source, packet)))
     }
 
-    fn parse_coap_url(url: &str) -> Result<(String, u16, String)> {
-        let url_params = match Url::parse(url) {
-            Ok(url_params) => url_params,
-            Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "url error")),
-        };
+    /// Set the receive timeout.
+    pub fn set_receive_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        Ok(self.socket.set_read_timeout(dur)?)
+    }
+
+    fn send_bytes(&self, peer_addr: &SocketAddr, bytes: &[u8]) -> Result<()> {
+        Self::send_bytes_with_socket(&self.socket, peer_addr, bytes, &self.interceptors)
+    }
+
+    fn send_with_socket(
+        socket: &UdpSocket,
+        peer_addr: &SocketAddr,
+        message: &Packet,
+        interceptors: &[Arc<dyn Interceptor>],
+    ) -> Result<()> {
+        Self::send_bytes_with_socket(socket, peer_addr, &message.to_bytes()?, interceptors)
+    }
+
+    fn send_bytes_with_socket(
+        socket: &UdpSocket,
+        peer_addr: &SocketAddr,
+        bytes: &[u8],
+        interceptors: &[Arc<dyn Interceptor>],
+    ) -> Result<()> {
+        for interceptor in interceptors {
+            interceptor.on_send(peer_addr, bytes);
+        }
+        let size = socket.send_to(bytes, peer_addr)?;
+        if size == bytes.len() {
+            Ok(())
+        } else {
+            Err(Error::Io(ErrorKind::WriteZero.into()))
+        }
+    }
+
+    fn receive_from_socket(socket: &UdpSocket, interceptors: &[Arc<dyn Interceptor>]) -> Result<Packet> {
+        let mut buf = [0; 1500];
+
+        let (nread, source) = socket.recv_from(&mut buf)?;
+        for interceptor in interceptors {
+            interceptor.on_receive(&source, &buf[..nread]);
+        }
+        Ok(Packet::from_bytes(&buf[..nread])?)
+    }
 
-        let host = match url_params.host_str() {
-            Some("") => return Err(Error::new(ErrorKind::InvalidInput, "host error")),
-            Some(h) => h,
-            None => return Err(Error::new(ErrorKind::InvalidInput, "host error")),
+    /// Send a request built with `RequestBuilder`, through the cache if it
+    /// is enabled, and receive its response.
+    fn send_request(
+        &self,
+        peer_addr: SocketAddr,
+        mut request: CoAPRequest,
+        timeout: Duration,
+        block_size: Option<usize>,
+    ) -> Result<CoAPResponse> {
+        let transfer = |request: &CoAPRequest| match block_size {
+            Some(block_size) if request.message.payload.len() > block_size => {
+                self.upload(&peer_addr, request, block_size, timeout)
+            }
+            _ => self.exchange_fresh(&peer_addr, request, timeout),
         };
-        let host = Regex::new(r"^\[(.*?)]$").unwrap().replace(&host, "$1").to_string();
 
-        let port = match url_params.port() {
-            Some(p) => p,
-            None => 5683,
+        // Protected responses are not cached.
+        #[cfg(feature = "oscore")]
+        let cache = self.cache.as_ref().filter(|_| self.oscore.is_none());
+        #[cfg(not(feature = "oscore"))]
+        let cache = self.cache.as_ref();
+        let cache = match cache {
+            Some(cache) => cache,
+            None => return transfer(&request),
         };
 
-        let path = url_params.path().to_string();
+        let key = CacheKey::new(&peer_addr, &request.message);
+        let method = request.get_method().clone();
+        if method == Method::Get {
+            let lookup = cache.lock().unwrap().lookup(&key, Instant::now());
+            match lookup {
+                Lookup::Fresh(message) => return Ok(CoAPResponse { message }),
+                Lookup::Stale(etag) if request.get_option(CoAPOption::ETag).is_none_or(|list| list.is_empty()) => {
+                    request.add_option(CoAPOption::ETag, etag);
+                }
+                _ => {}
+            }
+        }
 
-        return Ok((host.to_string(), port, path));
+        let response = transfer(&request)?;
+        let message = cache
+            .lock()
+            .unwrap()
+            .update(key, &method, response.message, Instant::now());
+        Ok(CoAPResponse { message })
     }
 
-    fn gen_message_id(message_id: &mut u16) -> u16 {
-        (*message_id) += 1;
-        return *message_id;
+    /// Parse a coap url and resolve the address of its host.
+    fn resolve_url(url: &str) -> Result<(CoAPUri, SocketAddr)> {
+        let uri = CoAPUri::parse(url)?;
+        if uri.secure {
+            // DTLS is not supported.
+            return Err(Error::InvalidUrl);
+        }
+
+        match (uri.host.as_str(), uri.port).to_socket_addrs()?.next() {
+            Some(addr) => Ok((uri, addr)),
+            None => Err(Error::NoAddress),
+        }
     }
 }
 
@@ -291,31 +851,216 @@ impl Drop for CoAPClient {
     }
 }
 
+/// A request under construction, created by `CoAPClient::build_request` and
+/// its per-method shorthands.
+///
+/// ```no_run
+/// use coap::CoAPClient;
+/// use coap::message::packet::ContentFormat;
+///
+/// let client = CoAPClient::bind("0.0.0.0:0").unwrap();
+/// let response = client
+///     .post("coap://127.0.0.1:5683/sensors")
+///     .query("a=1")
+///     .content_format(ContentFormat::ApplicationJSON)
+///     .accept(ContentFormat::ApplicationCBOR)
+///     .payload(b"{}".to_vec())
+///     .confirmable(false)
+///     .send()
+///     .unwrap();
+/// ```
+pub struct RequestBuilder<'a> {
+    client: &'a CoAPClient,
+    peer_addr: Result<SocketAddr>,
+    request: CoAPRequest,
+    timeout: Duration,
+    /// The block size of Block1 uploads, if enabled.
+    block_size: Option<usize>,
+    /// The first error of a builder method, returned by `send`.
+    error: Option<Error>,
+}
+
+impl<'a> RequestBuilder<'a> {
+    pub(crate) fn new(client: &'a CoAPClient, method: Method, url: Result<(CoAPUri, SocketAddr)>) -> RequestBuilder<'a> {
+        let mut request = CoAPRequest::new();
+        request.set_method(method);
+
+        let peer_addr = url.map(|(uri, peer_addr)| {
+            uri.to_options(&mut request.message, &peer_addr);
+            peer_addr
+        });
+
+        RequestBuilder {
+            client,
+            peer_addr,
+            request,
+            timeout: Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0),
+            block_size: None,
+            error: None,
+        }
+    }
+
+    /// Add the arguments of a query such as `a=1&b=2`, percent-decoded as by
+    /// `CoAPRequest::set_query`.
+    pub fn query(mut self, query: &str) -> Self {
+        for argument in query.split('&').filter(|argument| !argument.is_empty()) {
+            match percent_decode(argument) {
+                Ok(argument) => self.request.add_option(CoAPOption::UriQuery, argument.into_bytes()),
+                Err(e) => {
+                    self.error.get_or_insert(e);
+                }
+            }
+        }
+        self
+    }
+
+    /// Set the Content-Format of the payload.
+    pub fn content_format(mut self, content_format: ContentFormat) -> Self {
+        self.request.message.set_content_format(content_format);
+        self
+    }
+
+    /// Set the Content-Format the response should use.
+    pub fn accept(mut self, content_format: ContentFormat) -> Self {
+        self.request.message.set_accept(content_format);
+        self
+    }
+
+    /// Set the payload to `body` encoded as JSON, with Content-Format
+    /// application/json.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::Serialize + ?Sized>(mut self, body: &T) -> Self {
+        if let Err(e) = self.request.set_json(body) {
+            self.error.get_or_insert(e.into());
+        }
+        self
+    }
+
+    /// Set the payload to `body` encoded as CBOR, with Content-Format
+    /// application/cbor.
+    #[cfg(feature = "cbor")]
+    pub fn cbor<T: serde::Serialize + ?Sized>(mut self, body: &T) -> Self {
+        if let Err(e) = self.request.set_cbor(body) {
+            self.error.get_or_insert(e.into());
+        }
+        self
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.request.set_payload(payload);
+        self
+    }
+
+    /// Send the request as a Confirmable (the default) or Non-confirmable
+    /// message.
+    pub fn confirmable(mut self, confirmable: bool) -> Self {
+        self.request.set_type(if confirmable {
+            MessageType::Confirmable
+        } else {
+            MessageType::NonConfirmable
+        });
+        self
+    }
+
+    /// Add an ETag, which for GET asks the server to validate a stored
+    /// representation.
+    pub fn etag(mut self, etag: Vec<u8>) -> Self {
+        self.request.add_option(CoAPOption::ETag, etag);
+        self
+    }
+
+    /// Only perform the request if the resource has the given ETag. An empty
+    /// ETag matches any existing representation.
+    pub fn if_match(mut self, etag: Vec<u8>) -> Self {
+        self.request.add_option(CoAPOption::IfMatch, etag);
+        self
+    }
+
+    /// Only perform the request if the resource does not exist.
+    pub fn if_none_match(mut self) -> Self {
+        self.request.clear_option(CoAPOption::IfNoneMatch);
+        self.request.add_option(CoAPOption::IfNoneMatch, Vec::new());
+        self
+    }
+
+    /// Add any other option.
+    pub fn option(mut self, tp: CoAPOption, value: Vec<u8>) -> Self {
+        self.request.add_option(tp, value);
+        self
+    }
+
+    /// Set how long to wait for the response.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Upload a payload larger than `block_size` bytes in blocks with the
+    /// Block1 option, tagged with a Request-Tag. The size must be a power of
+    /// two from 16 to 1024; the server may ask for smaller blocks.
+    pub fn block1(mut self, block_size: usize) -> Self {
+        if block_size.is_power_of_two() && (16..=1024).contains(&block_size) {
+            self.block_size = Some(block_size);
+        } else {
+            self.error.get_or_insert(Error::InvalidBlockSize(block_size));
+        }
+        self
+    }
+
+    /// Send the request and wait for its response.
+    pub fn send(self) -> Result<CoAPResponse> {
+        let RequestBuilder {
+            client,
+            peer_addr,
+            request,
+            timeout,
+            block_size,
+            error,
+        } = self;
+        let peer_addr = peer_addr?;
+        if let Some(error) = error {
+            return Err(error);
+        }
+
+        let exchange =
+            |peer_addr: SocketAddr, request: CoAPRequest| client.send_request(peer_addr, request, timeout, block_size);
+        Next::new(&client.interceptors, &exchange).run(peer_addr, request)
+    }
+}
+
+/// Encodes a Block1 or Block2 option value (RFC 7959 §2.2).
+fn encode_block(number: usize, more: bool, block_size: usize) -> Vec<u8> {
+    let szx = block_size.trailing_zeros() - 4;
+    encode_uint((number as u32) << 4 | (more as u32) << 3 | szx)
+}
+
+/// The block size of a Block1 or Block2 option value.
+fn decode_block_size(value: &[u8]) -> Option<usize> {
+    match decode_uint(value) & 0x07 {
+        7 => None,
+        szx => Some(1 << (szx + 4)),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use std::time::Duration;
-    use std::io::ErrorKind;
     use super::super::message::request::CoAPRequest;
     use super::super::message::response::CoAPResponse;
     use super::super::server::CoAPServer;
 
     #[test]
-    fn test_parse_coap_url_good_url() {
-        assert!(CoAPClient::parse_coap_url("coap://127.0.0.1").is_ok());
-        assert!(CoAPClient::parse_coap_url("coap://127.0.0.1:5683").is_ok());
-        assert!(CoAPClient::parse_coap_url("coap://[::1]").is_ok());
-        assert!(CoAPClient::parse_coap_url("coap://[::1]:5683").is_ok());
-        assert!(CoAPClient::parse_coap_url("coap://[bbbb::9329:f033:f558:7418]").is_ok());
-        assert!(CoAPClient::parse_coap_url("coap://[bbbb::9329:f033:f558:7418]:5683").is_ok());
-    }
+    fn test_resolve_url() {
+        let (uri, addr) = CoAPClient::resolve_url("coap://127.0.0.1:5684/a?b").unwrap();
+        assert_eq!(uri.path, vec!["a"]);
+        assert_eq!(addr, "127.0.0.1:5684".parse().unwrap());
+        assert_eq!(CoAPClient::resolve_url("coap://[::1]").unwrap().1, "[::1]:5683".parse().unwrap());
 
-    #[test]
-    fn test_parse_coap_url_bad_url() {
-        assert!(CoAPClient::parse_coap_url("coap://127.0.0.1:65536").is_err());
-        assert!(CoAPClient::parse_coap_url("coap://").is_err());
-        assert!(CoAPClient::parse_coap_url("coap://:5683").is_err());
-        assert!(CoAPClient::parse_coap_url("127.0.0.1").is_err());
+        match CoAPClient::resolve_url("coaps://127.0.0.1") {
+            Err(Error::InvalidUrl) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
     }
 
     fn request_handler(_: CoAPRequest) -> Option<CoAPResponse> {
@@ -329,10 +1074,9 @@ mod test {
 
         let error = CoAPClient::get("coap://127.0.0.1:5686/Rust")
             .unwrap_err();
-        if cfg!(windows) {
-            assert_eq!(error.kind(), ErrorKind::TimedOut);
-        } else {
-            assert_eq!(error.kind(), ErrorKind::WouldBlock);
+        match error {
+            Error::Timeout => {}
+            e => panic!("unexpected error {:?}", e),
         }
     }
 
@@ -343,10 +1087,565 @@ mod test {
 
         let error = CoAPClient::get_with_timeout("coap://127.0.0.1:5684/Rust", Duration::new(1, 0))
             .unwrap_err();
-        if cfg!(windows) {
-            assert_eq!(error.kind(), ErrorKind::TimedOut);
+        match error {
+            Error::Timeout => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_receive_reset() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = CoAPClient::new(server.local_addr().unwrap()).unwrap();
+
+        let mut request = CoAPRequest::new();
+        request.set_message_id(7);
+        client.send(&request).unwrap();
+
+        let mut buf = [0; 1500];
+        let (_, src) = server.recv_from(&mut buf).unwrap();
+        let mut reset = Packet::new();
+        reset.header.set_type(MessageType::Reset);
+        reset.header.code = MessageClass::Empty;
+        reset.header.set_message_id(7);
+        server.send_to(&reset.to_bytes().unwrap(), src).unwrap();
+
+        match client.receive() {
+            Err(Error::Reset) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_gen_token_and_message_id() {
+        let mut client = CoAPClient::new("127.0.0.1:5683").unwrap();
+        assert_eq!(client.gen_token().len(), DEFAULT_TOKEN_LENGTH);
+
+        client.set_token_length(8).unwrap();
+        assert_eq!(client.gen_token().len(), 8);
+        assert_ne!(client.gen_token(), client.gen_token());
+        client.set_token_length(0).unwrap();
+        assert!(client.gen_token().is_empty());
+        assert!(client.set_token_length(9).is_err());
+
+        let message_id = client.gen_message_id();
+        assert_eq!(client.gen_message_id(), message_id.wrapping_add(1));
+    }
+
+    #[test]
+    fn test_get_ignores_unexpected_token() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("coap://{}/Rust", server.local_addr().unwrap());
+        let client_thread = thread::spawn(move || CoAPClient::get(&url));
+
+        let mut buf = [0; 1500];
+        let (nread, src) = server.recv_from(&mut buf).unwrap();
+        let request = Packet::from_bytes(&buf[..nread]).unwrap();
+        assert_eq!(request.get_token().len(), DEFAULT_TOKEN_LENGTH);
+
+        let mut response = CoAPResponse::new(&request).unwrap();
+        response.set_token(vec![0xFF; 2]).unwrap();
+        response.set_payload(b"spoofed".to_vec());
+        server.send_to(&response.message.to_bytes().unwrap(), src).unwrap();
+
+        response.set_token(request.get_token().clone()).unwrap();
+        response.set_payload(b"genuine".to_vec());
+        server.send_to(&response.message.to_bytes().unwrap(), src).unwrap();
+
+        let response = client_thread.join().unwrap().unwrap();
+        assert_eq!(response.message.payload, b"genuine".to_vec());
+    }
+
+    #[test]
+    fn test_request_builder() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("coap://{}/sensors/temp", server.local_addr().unwrap());
+        let client_thread = thread::spawn(move || {
+            let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+            client
+                .post(&url)
+                .query("a=1&b=%26")
+                .query("c")
+                .content_format(ContentFormat::ApplicationJSON)
+                .accept(ContentFormat::ApplicationCBOR)
+                .if_none_match()
+                .payload(b"{}".to_vec())
+                .confirmable(false)
+                .send()
+        });
+
+        let mut buf = [0; 1500];
+        let (nread, src) = server.recv_from(&mut buf).unwrap();
+        let request = Packet::from_bytes(&buf[..nread]).unwrap();
+        assert_eq!(request.header.get_type(), MessageType::NonConfirmable);
+        assert_eq!(request.header.code, MessageClass::Request(Method::Post));
+        let uri_path: Vec<Vec<u8>> = request.get_option(CoAPOption::UriPath).unwrap().iter().cloned().collect();
+        assert_eq!(uri_path, vec![b"sensors".to_vec(), b"temp".to_vec()]);
+        let uri_query: Vec<Vec<u8>> = request.get_option(CoAPOption::UriQuery).unwrap().iter().cloned().collect();
+        assert_eq!(uri_query, vec![b"a=1".to_vec(), b"b=&".to_vec(), b"c".to_vec()]);
+        assert_eq!(request.get_content_format(), Some(ContentFormat::ApplicationJSON));
+        assert_eq!(request.get_accept(), Some(ContentFormat::ApplicationCBOR));
+        assert_eq!(request.get_option(CoAPOption::IfNoneMatch).unwrap().len(), 1);
+        assert_eq!(request.payload, b"{}".to_vec());
+
+        let mut response = CoAPResponse::new(&request).unwrap();
+        response.set_status(Status::Changed);
+        server.send_to(&response.message.to_bytes().unwrap(), src).unwrap();
+
+        let response = client_thread.join().unwrap().unwrap();
+        assert_eq!(*response.get_status(), Status::Changed);
+    }
+
+    #[test]
+    fn test_request_builder_message_id_mismatch() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("coap://{}/", server.local_addr().unwrap());
+        let client_thread = thread::spawn(move || {
+            let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+            client.delete(&url).send()
+        });
+
+        let mut buf = [0; 1500];
+        let (nread, src) = server.recv_from(&mut buf).unwrap();
+        let request = Packet::from_bytes(&buf[..nread]).unwrap();
+
+        let mut response = CoAPResponse::new(&request).unwrap();
+        response.set_message_id(request.header.get_message_id().wrapping_add(1));
+        server.send_to(&response.message.to_bytes().unwrap(), src).unwrap();
+
+        match client_thread.join().unwrap() {
+            Err(Error::Protocol(ProtocolError::MessageIdMismatch)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_request_builder_bad_url() {
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        match client.put("127.0.0.1/").send() {
+            Err(Error::InvalidUrl) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match client.put("coap://127.0.0.1:9/").query("a=%zz").send() {
+            Err(Error::InvalidUrl) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        // Only requests by URL can be sent without a peer.
+        match client.send(&CoAPRequest::new()) {
+            Err(Error::NoAddress) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+
+    #[test]
+    fn test_receive_drops_duplicates() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = CoAPClient::new(server.local_addr().unwrap()).unwrap();
+        client.send(&CoAPRequest::new()).unwrap();
+
+        let mut buf = [0; 1500];
+        let (_, src) = server.recv_from(&mut buf).unwrap();
+
+        let mut notification = Packet::new();
+        notification.header.set_type(MessageType::Confirmable);
+        notification.header.code = MessageClass::Response(Status::Content);
+        for (message_id, payload) in &[(1, b"first"), (1, b"again"), (2, b"secnd")] {
+            notification.header.set_message_id(*message_id);
+            notification.payload = payload.to_vec();
+            server.send_to(&notification.to_bytes().unwrap(), src).unwrap();
+        }
+
+        assert_eq!(client.receive().unwrap().message.payload, b"first".to_vec());
+        assert_eq!(client.receive().unwrap().message.payload, b"secnd".to_vec());
+
+        // Every copy of a confirmable message is acknowledged.
+        for message_id in &[1, 1, 2] {
+            let (nread, _) = server.recv_from(&mut buf).unwrap();
+            let ack = Packet::from_bytes(&buf[..nread]).unwrap();
+            assert_eq!(ack.header.get_type(), MessageType::Acknowledgement);
+            assert_eq!(ack.header.code, MessageClass::Empty);
+            assert_eq!(ack.header.get_message_id(), *message_id);
+        }
+    }
+
+    #[test]
+    fn test_message_ids_per_peer() {
+        let client = CoAPClient::new("127.0.0.1:5683").unwrap();
+        let first: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:2".parse().unwrap();
+
+        let message_id = client.next_message_id(&first);
+        client.next_message_id(&second);
+        client.next_message_id(&second);
+        assert_eq!(client.next_message_id(&first), message_id.wrapping_add(1));
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let url = format!("coap://{}/", server.local_addr().unwrap());
+        let mut client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        client.set_transmission_parameters(TransmissionParameters {
+            nstart: 2,
+            ..TransmissionParameters::default()
+        });
+        let client = Arc::new(client);
+
+        let threads: Vec<_> = (0..2)
+            .map(|i| {
+                let client = client.clone();
+                let url = url.clone();
+                thread::spawn(move || {
+                    client
+                        .post(&url)
+                        .payload(vec![i])
+                        .timeout(Duration::new(2, 0))
+                        .send()
+                })
+            })
+            .collect();
+
+        // Answer in reverse order, so each response is likely to be read by
+        // the thread waiting for the other one.
+        let mut buf = [0; 1500];
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            requests.push((Packet::from_bytes(&buf[..nread]).unwrap(), src));
+        }
+        for (request, src) in requests.iter().rev() {
+            let response = CoAPResponse::new(request).unwrap();
+            server.send_to(&response.message.to_bytes().unwrap(), src).unwrap();
+        }
+
+        for (i, thread) in threads.into_iter().enumerate() {
+            let response = thread.join().unwrap().unwrap();
+            assert_eq!(response.message.payload, vec![i as u8]);
+        }
+    }
+
+    #[test]
+    fn test_nstart_queues_requests() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let url = format!("coap://{}/", server.local_addr().unwrap());
+        let client = Arc::new(CoAPClient::bind("127.0.0.1:0").unwrap());
+
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                let url = url.clone();
+                thread::spawn(move || client.post(&url).timeout(Duration::new(3, 0)).send())
+            })
+            .collect();
+
+        let mut buf = [0; 1500];
+        for _ in 0..2 {
+            let (nread, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+
+            // The other request waits until this one is answered.
+            server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            assert!(server.recv_from(&mut buf).is_err());
+            server.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+
+            let response = CoAPResponse::new(&request).unwrap();
+            server.send_to(&response.message.to_bytes().unwrap(), src).unwrap();
+        }
+
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_retransmit_confirmable_request() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let url = format!("coap://{}/", server.local_addr().unwrap());
+        let client_thread = thread::spawn(move || {
+            let mut client = CoAPClient::bind("127.0.0.1:0").unwrap();
+            client.set_transmission_parameters(TransmissionParameters {
+                ack_timeout: Duration::from_millis(100),
+                ..TransmissionParameters::default()
+            });
+            client.post(&url).timeout(Duration::new(3, 0)).send()
+        });
+
+        let mut buf = [0; 1500];
+        let (nread, _) = server.recv_from(&mut buf).unwrap();
+        let first = Packet::from_bytes(&buf[..nread]).unwrap();
+        assert_eq!(first.header.get_type(), MessageType::Confirmable);
+
+        // Acknowledge the retransmission, then respond separately.
+        let (nread, src) = server.recv_from(&mut buf).unwrap();
+        let retransmission = Packet::from_bytes(&buf[..nread]).unwrap();
+        assert_eq!(retransmission.header.get_message_id(), first.header.get_message_id());
+
+        let mut ack = Packet::new();
+        ack.header.set_type(MessageType::Acknowledgement);
+        ack.header.code = MessageClass::Empty;
+        ack.header.set_message_id(first.header.get_message_id());
+        server.send_to(&ack.to_bytes().unwrap(), src).unwrap();
+
+        // No more retransmissions once acknowledged.
+        server.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert!(server.recv_from(&mut buf).is_err());
+
+        let mut response = Packet::new();
+        response.header.set_type(MessageType::Confirmable);
+        response.header.code = MessageClass::Response(Status::Created);
+        response.header.set_message_id(1);
+        response.set_token(first.get_token().clone()).unwrap();
+        server.send_to(&response.to_bytes().unwrap(), src).unwrap();
+
+        let response = client_thread.join().unwrap().unwrap();
+        assert_eq!(*response.get_status(), Status::Created);
+    }
+
+    #[test]
+    fn test_probing_rate() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("coap://{}/", server.local_addr().unwrap());
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+
+        let mut buf = [0; 1500];
+        for _ in 0..2 {
+            match client.post(&url).confirmable(false).timeout(Duration::from_millis(100)).send() {
+                Err(Error::Timeout) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+            server.recv_from(&mut buf).unwrap();
+        }
+
+        // The peer does not respond, so the next request has to wait for
+        // its size in seconds at one byte per second.
+        match client.post(&url).confirmable(false).timeout(Duration::from_millis(100)).send() {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert!(server.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_cache() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let url = format!("coap://{}/cached", server.local_addr().unwrap());
+        let client_thread = thread::spawn(move || {
+            let mut client = CoAPClient::bind("127.0.0.1:0").unwrap();
+            client.enable_cache(8);
+            let mut payloads = Vec::new();
+            for delay in &[0, 0, 1100] {
+                thread::sleep(Duration::from_millis(*delay));
+                let response = client.build_request(Method::Get, &url).send().unwrap();
+                assert_eq!(*response.get_status(), Status::Content);
+                payloads.push(response.message.payload);
+            }
+            payloads
+        });
+
+        let mut buf = [0; 1500];
+        let (nread, src) = server.recv_from(&mut buf).unwrap();
+        let request = Packet::from_bytes(&buf[..nread]).unwrap();
+        assert!(request.get_option(CoAPOption::ETag).is_none());
+        let mut response = CoAPResponse::new(&request).unwrap();
+        response.add_option(CoAPOption::ETag, b"v1".to_vec());
+        response.add_option(CoAPOption::MaxAge, vec![1]);
+        response.set_payload(b"data".to_vec());
+        server.send_to(&response.message.to_bytes().unwrap(), src).unwrap();
+
+        // The second request is served from the cache, the third revalidates
+        // the stale response.
+        let (nread, src) = server.recv_from(&mut buf).unwrap();
+        let request = Packet::from_bytes(&buf[..nread]).unwrap();
+        assert_eq!(request.get_option(CoAPOption::ETag).unwrap().front(), Some(&b"v1".to_vec()));
+        let mut response = CoAPResponse::new(&request).unwrap();
+        response.set_status(Status::Valid);
+        response.add_option(CoAPOption::ETag, b"v1".to_vec());
+        response.set_payload(Vec::new());
+        server.send_to(&response.message.to_bytes().unwrap(), src).unwrap();
+
+        assert_eq!(client_thread.join().unwrap(), vec![b"data".to_vec(); 3]);
+    }
+
+    #[cfg(feature = "json")]
+    fn sum_handler(request: CoAPRequest) -> Option<CoAPResponse> {
+        let mut response = request.response.clone()?;
+        match request.json::<Vec<i64>>() {
+            Ok(numbers) => response.set_json(&numbers.iter().sum::<i64>()).unwrap(),
+            Err(e) => response.set_payload_error(&e),
+        }
+        Some(response)
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.handle(sum_handler).unwrap();
+        let url = format!("coap://{}/sum", server.socket_addr().unwrap());
+
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        let response = client.post(&url).json(&[1, 2, 3]).send().unwrap();
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::ApplicationJSON));
+        assert_eq!(response.json::<i64>().unwrap(), 6);
+
+        let response = client.post(&url).payload(b"[1,".to_vec()).send().unwrap();
+        assert_eq!(*response.get_status(), Status::BadRequest);
+
+        let response = client
+            .post(&url)
+            .content_format(ContentFormat::TextPlain)
+            .payload(b"1 2 3".to_vec())
+            .send()
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::UnsupportedContentFormat);
+        assert!(response.message.payload.is_empty());
+    }
+
+    static UPLOADS: Mutex<Vec<(Vec<u8>, Vec<u8>)>> = Mutex::new(Vec::new());
+
+    /// Counts the datagrams sent and received.
+    #[derive(Default)]
+    struct WireCounter {
+        sent: std::sync::atomic::AtomicUsize,
+        received: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Interceptor for Arc<WireCounter> {
+        fn on_send(&self, _peer_addr: &SocketAddr, bytes: &[u8]) {
+            assert!(Packet::from_bytes(bytes).is_ok());
+            self.sent.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        fn on_receive(&self, _source: &SocketAddr, _bytes: &[u8]) {
+            self.received.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    fn path_handler(request: CoAPRequest) -> Option<CoAPResponse> {
+        let query = request.get_option(CoAPOption::UriQuery).and_then(|list| list.front().cloned());
+        let mut response = request.response.clone()?;
+        let mut payload = request.get_path().into_bytes();
+        payload.extend(query.unwrap_or_default());
+        response.set_payload(payload);
+        Some(response)
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_request() {
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.handle(path_handler).unwrap();
+        let url = format!("coap://{}/old", server.socket_addr().unwrap());
+
+        assert_eq!(CoAPClient::request(&url).unwrap().message.payload, b"old".to_vec());
+        let response = CoAPClient::request_with_timeout(&url, Some(Duration::new(5, 0))).unwrap();
+        assert_eq!(response.message.payload, b"old".to_vec());
+    }
+
+    #[test]
+    fn test_interceptors() {
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.handle(path_handler).unwrap();
+        let url = format!("coap://{}/", server.socket_addr().unwrap());
+
+        let counter = Arc::new(WireCounter::default());
+        let mut client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        client.add_interceptor(counter.clone());
+        client.add_interceptor(|peer_addr: SocketAddr, mut request: CoAPRequest, next: &Next| {
+            if request.get_path() == "offline" {
+                return Err(Error::NoAddress);
+            }
+            if request.get_path() == "old" {
+                request.set_path("new");
+            }
+            request.add_option(CoAPOption::UriQuery, b"?key".to_vec());
+            let mut response = next.run(peer_addr, request)?;
+            response.message.payload.push(b'!');
+            Ok(response)
+        });
+
+        let response = client.build_request(Method::Get, &format!("{}old", url)).send().unwrap();
+        assert_eq!(response.message.payload, b"new?key!".to_vec());
+        assert_eq!(counter.sent.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(counter.received.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        match client.build_request(Method::Get, &format!("{}offline", url)).send() {
+            Err(Error::NoAddress) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(counter.sent.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        client.clear_interceptors();
+        let response = client.build_request(Method::Get, &format!("{}old", url)).send().unwrap();
+        assert_eq!(response.message.payload, b"old".to_vec());
+        assert_eq!(counter.sent.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    /// Reassembles Block1 uploads by their Request-Tag and answers the last
+    /// block with the whole payload.
+    fn upload_handler(request: CoAPRequest) -> Option<CoAPResponse> {
+        let tag = request.get_option(CoAPOption::RequestTag)?.front()?.clone();
+        let block = request.get_option(CoAPOption::Block1)?.front()?.clone();
+        let mut uploads = UPLOADS.lock().unwrap();
+        let index = match uploads.iter().position(|(t, _)| *t == tag) {
+            Some(index) => index,
+            None => {
+                uploads.push((tag, Vec::new()));
+                uploads.len() - 1
+            }
+        };
+        uploads[index].1.extend_from_slice(&request.message.payload);
+
+        let mut response = request.response?;
+        if decode_uint(&block) & 0x08 != 0 {
+            response.set_status(Status::Continue);
+            response.message.payload.clear();
+            response.message.add_option(CoAPOption::Block1, block);
         } else {
-            assert_eq!(error.kind(), ErrorKind::WouldBlock);
+            response.set_status(Status::Changed);
+            response.message.payload = uploads.remove(index).1;
+        }
+        Some(response)
+    }
+
+    #[test]
+    fn test_block1() {
+        assert_eq!(encode_block(2, true, 64), vec![0x2A]);
+        assert_eq!(encode_block(0, false, 16), Vec::<u8>::new());
+        assert_eq!(decode_block_size(&[0x2A]), Some(64));
+        assert_eq!(decode_block_size(&[0x07]), None);
+
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.handle(upload_handler).unwrap();
+        let url = format!("coap://{}/upload", server.socket_addr().unwrap());
+
+        // Concurrent uploads from the same client are told apart.
+        let client = Arc::new(CoAPClient::bind("127.0.0.1:0").unwrap());
+        let uploads: Vec<_> = (b'a'..=b'c')
+            .map(|byte| {
+                let client = client.clone();
+                let url = url.clone();
+                thread::spawn(move || {
+                    let payload = vec![byte; 100];
+                    let response = client.post(&url).payload(payload.clone()).block1(32).send().unwrap();
+                    assert_eq!(*response.get_status(), Status::Changed);
+                    assert_eq!(response.message.payload, payload);
+                })
+            })
+            .collect();
+        for upload in uploads {
+            upload.join().unwrap();
+        }
+
+        match client.post(&url).block1(100).send() {
+            Err(Error::InvalidBlockSize(100)) => {}
+            other => panic!("unexpected result {:?}", other),
         }
     }
 }
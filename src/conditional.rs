@@ -0,0 +1,297 @@
+//! Conditional requests on the server: ETag generation, validation of GET
+//! requests (RFC 7252 §5.10.6), and If-Match and If-None-Match (§5.10.8).
+//!
+//! The preconditions of PUT, POST and DELETE requests are evaluated against
+//! the state of the resource the server last saw in a response: the ETag of
+//! a 2.05 Content, 2.01 Created or 2.04 Changed, or its absence after a 4.04
+//! Not Found or 2.02 Deleted. Failing requests are answered with 4.12
+//! Precondition Failed without reaching the handler. Conditional requests
+//! are handled one at a time, so two of them cannot both pass before either
+//! updates the resource. Preconditions on a resource whose state is unknown
+//! are left to the handler, which can evaluate them with
+//! `CoAPRequest::preconditions_met`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::message::header::{RequestType as Method, ResponseType as Status};
+use super::message::packet::CoAPOption;
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+use super::message::IsMessage;
+use super::middleware::{Middleware, Next};
+use super::observer;
+use super::server::ETagPolicy;
+
+/// The most resources whose state is remembered.
+const MAX_RESOURCES: usize = 1024;
+
+/// The state of a resource as of its last response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Resource {
+    /// The ETag of its current representation.
+    Exists(Vec<u8>),
+    Missing,
+}
+
+/// The innermost middleware of a server, which generates ETags as its
+/// policy says, answers GET requests for a representation the client
+/// already has with 2.03 Valid, and enforces If-Match and If-None-Match.
+pub(crate) struct Conditional {
+    policy: ETagPolicy,
+    /// By the key of the resource, as `observer::resource_key` returns it.
+    resources: Mutex<HashMap<String, Resource>>,
+}
+
+impl Conditional {
+    pub fn new(policy: ETagPolicy) -> Conditional {
+        Conditional {
+            policy,
+            resources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the state of the resource `key` a response shows, if any.
+    fn record(resources: &mut HashMap<String, Resource>, key: String, response: &CoAPResponse) {
+        let state = match *response.get_status() {
+            Status::Content | Status::Created | Status::Changed => response_etag(response).map(Resource::Exists),
+            Status::NotFound | Status::Deleted => Some(Resource::Missing),
+            _ => return,
+        };
+        match state {
+            Some(state) => {
+                if resources.len() >= MAX_RESOURCES && !resources.contains_key(&key) {
+                    let evicted = resources.keys().next().cloned();
+                    if let Some(evicted) = evicted {
+                        resources.remove(&evicted);
+                    }
+                }
+                resources.insert(key, state);
+            }
+            // An update without an ETag leaves the representation unknown.
+            None => {
+                resources.remove(&key);
+            }
+        }
+    }
+}
+
+impl Middleware for Conditional {
+    fn handle(&self, request: CoAPRequest, next: &Next) -> Option<CoAPResponse> {
+        let key = observer::resource_key(&request);
+        let conditional = matches!(*request.get_method(), Method::Put | Method::Post | Method::Delete)
+            && (request.get_option(CoAPOption::IfMatch).is_some() || request.get_option(CoAPOption::IfNoneMatch).is_some());
+        if !conditional {
+            let response = handle(next, self.policy, request)?;
+            Self::record(&mut self.resources.lock().unwrap(), key, &response);
+            return Some(response);
+        }
+
+        // Held until the response is recorded, so that the check and the
+        // update are not interleaved with another conditional request.
+        let mut resources = self.resources.lock().unwrap();
+        let checked = match resources.get(&key) {
+            Some(Resource::Exists(etag)) => request.preconditions_met(true, Some(etag)),
+            Some(Resource::Missing) => request.preconditions_met(false, None),
+            None => Ok(()),
+        };
+        if let Err(status) = checked {
+            let mut response = request.response?;
+            response.set_status(status);
+            response.message.payload.clear();
+            return Some(response);
+        }
+        let response = handle(next, self.policy, request)?;
+        Self::record(&mut resources, key, &response);
+        Some(response)
+    }
+}
+
+fn handle(next: &Next, policy: ETagPolicy, request: CoAPRequest) -> Option<CoAPResponse> {
+    let etags = match *request.get_method() {
+        Method::Get => option_values(&request, CoAPOption::ETag),
+        _ => Vec::new(),
+    };
+    let mut response = next.run(request)?;
+    add_etag(&mut response, policy);
+    if *response.get_status() == Status::Content {
+        if let Some(etag) = response_etag(&response) {
+            if etags.contains(&etag) {
+                validate(&mut response);
+            }
+        }
+    }
+    Some(response)
+}
+
+/// The ETag a 2.05 Content `response` is sent with under `policy`.
+pub(crate) fn etag(response: &CoAPResponse, policy: ETagPolicy) -> Option<Vec<u8>> {
+    match response_etag(response) {
+        Some(etag) => Some(etag),
+        None if policy == ETagPolicy::Hash => Some(hash(response)),
+        None => None,
+    }
+}
+
+/// Turn a 2.05 Content into a 2.03 Valid, which carries no representation.
+fn validate(response: &mut CoAPResponse) {
+    response.set_status(Status::Valid);
+    response.message.payload.clear();
+    response.clear_option(CoAPOption::ContentFormat);
+}
+
+fn add_etag(response: &mut CoAPResponse, policy: ETagPolicy) {
+    if policy == ETagPolicy::Hash && *response.get_status() == Status::Content && response_etag(response).is_none() {
+        let etag = hash(response);
+        response.add_option(CoAPOption::ETag, etag);
+    }
+}
+
+fn response_etag(response: &CoAPResponse) -> Option<Vec<u8>> {
+    response.get_option(CoAPOption::ETag).and_then(|list| list.front().cloned())
+}
+
+fn option_values(request: &CoAPRequest, option: CoAPOption) -> Vec<Vec<u8>> {
+    request
+        .get_option(option)
+        .map(|list| list.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// A 64-bit FNV-1a hash of the Content-Format and payload, so the ETag is
+/// stable across restarts of the server.
+fn hash(response: &CoAPResponse) -> Vec<u8> {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    let content_format = response.get_option(CoAPOption::ContentFormat).and_then(|list| list.front().cloned());
+    let mut hash = OFFSET_BASIS;
+    for byte in content_format.iter().flatten().chain(&[0xFF]).chain(&response.message.payload) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(PRIME);
+    }
+    hash.to_be_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use super::*;
+    use super::super::message::header::MessageType;
+    use super::super::message::packet::Packet;
+
+    fn request(method: Method, payload: &[u8]) -> CoAPRequest {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.add_option(CoAPOption::UriPath, b"resource".to_vec());
+        packet.payload = payload.to_vec();
+        let source: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let mut request = CoAPRequest::from_packet(packet, &source);
+        request.set_method(method);
+        request.response = CoAPResponse::new(&request.message);
+        request
+    }
+
+    /// Serves "value" on GET and accepts every update.
+    fn handler(request: CoAPRequest) -> Option<CoAPResponse> {
+        let is_get = *request.get_method() == Method::Get;
+        let mut response = request.response?;
+        if is_get {
+            response.set_payload(b"value".to_vec());
+        } else {
+            response.set_status(Status::Changed);
+            response.message.payload.clear();
+        }
+        Some(response)
+    }
+
+    fn handle(handler: &dyn Fn(CoAPRequest) -> Option<CoAPResponse>, policy: ETagPolicy, request: CoAPRequest) -> Option<CoAPResponse> {
+        Conditional::new(policy).handle(request, &Next::new(&[], handler))
+    }
+
+    fn etag_of_value() -> Vec<u8> {
+        let response = handle(&handler, ETagPolicy::Hash, request(Method::Get, b"")).unwrap();
+        response_etag(&response).unwrap()
+    }
+
+    #[test]
+    fn test_generate_etag() {
+        let response = handle(&handler, ETagPolicy::Handler, request(Method::Get, b"")).unwrap();
+        assert!(response_etag(&response).is_none());
+
+        let etag = etag_of_value();
+        assert_eq!(etag.len(), 8);
+        assert_eq!(etag, etag_of_value());
+
+        let mut other = CoAPResponse::new(&request(Method::Get, b"").message).unwrap();
+        other.set_payload(b"other".to_vec());
+        assert_ne!(hash(&other), etag);
+    }
+
+    #[test]
+    fn test_valid() {
+        let mut get = request(Method::Get, b"");
+        get.add_option(CoAPOption::ETag, b"stale".to_vec());
+        get.add_option(CoAPOption::ETag, etag_of_value());
+        let response = handle(&handler, ETagPolicy::Hash, get).unwrap();
+        assert_eq!(*response.get_status(), Status::Valid);
+        assert!(response.message.payload.is_empty());
+        assert_eq!(response_etag(&response), Some(etag_of_value()));
+
+        let mut get = request(Method::Get, b"");
+        get.add_option(CoAPOption::ETag, b"stale".to_vec());
+        let response = handle(&handler, ETagPolicy::Hash, get).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, b"value".to_vec());
+    }
+
+    #[test]
+    fn test_preconditions() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = AtomicUsize::new(0);
+        let counting = |request: CoAPRequest| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            handler(request)
+        };
+        let conditional = Conditional::new(ETagPolicy::Hash);
+        let handle = |request: CoAPRequest| conditional.handle(request, &Next::new(&[], &counting)).unwrap();
+        let conditional_request = |method: Method, option: CoAPOption, value: &[u8]| {
+            let mut request = request(method, b"new");
+            request.add_option(option, value.to_vec());
+            request
+        };
+
+        // Before the resource is known, the handler decides.
+        let response = handle(conditional_request(Method::Put, CoAPOption::IfMatch, b"stale"));
+        assert_eq!(*response.get_status(), Status::Changed);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        handle(request(Method::Get, b""));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        for method in [Method::Put, Method::Post, Method::Delete] {
+            let response = handle(conditional_request(method.clone(), CoAPOption::IfMatch, b"stale"));
+            assert_eq!(*response.get_status(), Status::PreconditionFailed);
+            let response = handle(conditional_request(method, CoAPOption::IfNoneMatch, b""));
+            assert_eq!(*response.get_status(), Status::PreconditionFailed);
+        }
+        // Failed preconditions do not reach the handler.
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let response = handle(conditional_request(Method::Put, CoAPOption::IfMatch, &etag_of_value()));
+        assert_eq!(*response.get_status(), Status::Changed);
+        assert!(response.message.payload.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        // The update carried no ETag, so the new representation is unknown.
+        let response = handle(conditional_request(Method::Put, CoAPOption::IfMatch, &etag_of_value()));
+        assert_eq!(*response.get_status(), Status::Changed);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_etag() {
+        let response = handle(&handler, ETagPolicy::Handler, request(Method::Get, b"")).unwrap();
+        assert_eq!(etag(&response, ETagPolicy::Handler), None);
+        assert_eq!(etag(&response, ETagPolicy::Hash), Some(etag_of_value()));
+    }
+}
@@ -0,0 +1,319 @@
+//! Congestion control as described in RFC 7252 §4.7 and §4.8, with optional
+//! RTO estimation as in CoCoA (draft-ietf-core-cocoa).
+
+use std::time::{Duration, Instant};
+use rand::{thread_rng, Rng};
+
+/// The transmission parameters of RFC 7252 §4.8.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransmissionParameters {
+    /// The initial retransmission timeout of a confirmable message.
+    pub ack_timeout: Duration,
+    /// The initial timeout is chosen at random between `ack_timeout` and
+    /// `ack_timeout * ack_random_factor`.
+    pub ack_random_factor: f64,
+    /// How often a confirmable message is retransmitted.
+    pub max_retransmit: u32,
+    /// The most outstanding interactions with one peer.
+    pub nstart: usize,
+    /// The average data rate in bytes per second at which a peer that does
+    /// not respond is probed.
+    pub probing_rate: u32,
+    /// Estimate the retransmission timeout from measured round-trip times
+    /// instead of using `ack_timeout`.
+    pub rto_estimation: bool,
+}
+
+impl Default for TransmissionParameters {
+    fn default() -> TransmissionParameters {
+        TransmissionParameters {
+            ack_timeout: Duration::from_secs(2),
+            ack_random_factor: 1.5,
+            max_retransmit: 4,
+            nstart: 1,
+            probing_rate: 1,
+            rto_estimation: false,
+        }
+    }
+}
+
+impl TransmissionParameters {
+    /// A random factor between 1 and ACK_RANDOM_FACTOR for the initial
+    /// timeout of a confirmable message.
+    pub(crate) fn random_factor(&self) -> f64 {
+        thread_rng().gen_range(1.0..=self.ack_random_factor.max(1.0))
+    }
+}
+
+/// The congestion state of one peer.
+#[derive(Debug)]
+pub(crate) struct Congestion {
+    outstanding: usize,
+    /// Set when an interaction ended without any response, cleared by the
+    /// next response.
+    unresponsive: bool,
+    next_probe: Option<Instant>,
+    rto: RtoEstimator,
+}
+
+impl Congestion {
+    pub fn new() -> Congestion {
+        Congestion {
+            outstanding: 0,
+            unresponsive: false,
+            next_probe: None,
+            rto: RtoEstimator::new(),
+        }
+    }
+
+    /// Start an interaction sending `bytes` bytes, if NSTART and
+    /// PROBING_RATE allow it now.
+    ///
+    /// Otherwise returns when to try again, or `None` to wait for another
+    /// interaction to finish.
+    pub fn try_start(&mut self, parameters: &TransmissionParameters, bytes: usize, now: Instant) -> Result<(), Option<Instant>> {
+        if self.outstanding >= parameters.nstart {
+            return Err(None);
+        }
+        if self.unresponsive {
+            match self.next_probe {
+                Some(next_probe) if next_probe > now => return Err(Some(next_probe)),
+                _ => {
+                    let rate = parameters.probing_rate.max(1) as f64;
+                    self.next_probe = Some(now + Duration::from_secs_f64(bytes as f64 / rate));
+                }
+            }
+        }
+
+        self.outstanding += 1;
+        Ok(())
+    }
+
+    /// Finish an interaction. `sample` is the time until the first response
+    /// or acknowledgement and the number of retransmissions before it, or
+    /// `None` if the peer did not respond.
+    pub fn finish(&mut self, sample: Option<(Duration, u32)>, now: Instant) {
+        self.outstanding = self.outstanding.saturating_sub(1);
+        match sample {
+            Some((rtt, retransmissions)) => {
+                self.unresponsive = false;
+                self.next_probe = None;
+                self.rto.update(rtt, retransmissions, now);
+            }
+            None => self.unresponsive = true,
+        }
+    }
+
+    /// The timeout before the first retransmission of a confirmable message.
+    pub fn initial_timeout(&mut self, parameters: &TransmissionParameters, now: Instant) -> Duration {
+        let base = if parameters.rto_estimation {
+            self.rto.rto(now)
+        } else {
+            parameters.ack_timeout
+        };
+        base.mul_f64(parameters.random_factor())
+    }
+
+    /// The timeout after a retransmission which followed `timeout`.
+    pub fn backoff(&self, parameters: &TransmissionParameters, timeout: Duration) -> Duration {
+        if parameters.rto_estimation {
+            timeout.mul_f64(self.rto.backoff_factor())
+        } else {
+            timeout * 2
+        }
+    }
+}
+
+/// The smoothed round-trip time and variation of one kind of sample, as in
+/// RFC 6298.
+#[derive(Debug)]
+struct Estimator {
+    srtt: f64,
+    rttvar: f64,
+    k: f64,
+    initialized: bool,
+}
+
+impl Estimator {
+    fn new(k: f64) -> Estimator {
+        Estimator {
+            srtt: 0.0,
+            rttvar: 0.0,
+            k,
+            initialized: false,
+        }
+    }
+
+    /// Add a sample in seconds and return the new estimate.
+    fn update(&mut self, rtt: f64) -> f64 {
+        if self.initialized {
+            self.rttvar = 0.75 * self.rttvar + 0.25 * (self.srtt - rtt).abs();
+            self.srtt = 0.875 * self.srtt + 0.125 * rtt;
+        } else {
+            self.srtt = rtt;
+            self.rttvar = rtt / 2.0;
+            self.initialized = true;
+        }
+        self.srtt + self.k * self.rttvar
+    }
+}
+
+const INITIAL_RTO: f64 = 2.0;
+const MAX_RTO: f64 = 60.0;
+
+/// The CoCoA retransmission timeout estimator: a strong estimator fed by
+/// exchanges without retransmissions, a weak one fed by exchanges with one
+/// or two, and an overall RTO blending both.
+#[derive(Debug)]
+struct RtoEstimator {
+    strong: Estimator,
+    weak: Estimator,
+    /// The overall RTO in seconds.
+    rto: f64,
+    updated: Option<Instant>,
+}
+
+impl RtoEstimator {
+    fn new() -> RtoEstimator {
+        RtoEstimator {
+            strong: Estimator::new(4.0),
+            weak: Estimator::new(1.0),
+            rto: INITIAL_RTO,
+            updated: None,
+        }
+    }
+
+    fn update(&mut self, rtt: Duration, retransmissions: u32, now: Instant) {
+        let rtt = rtt.as_secs_f64();
+        let rto = match retransmissions {
+            0 => 0.5 * self.strong.update(rtt) + 0.5 * self.rto,
+            1 | 2 => 0.25 * self.weak.update(rtt) + 0.75 * self.rto,
+            // The sample cannot be attributed to a transmission.
+            _ => return,
+        };
+        self.rto = rto.min(MAX_RTO);
+        self.updated = Some(now);
+    }
+
+    /// The current RTO, aged if it has not been updated for a while.
+    fn rto(&mut self, now: Instant) -> Duration {
+        if let Some(updated) = self.updated {
+            let idle = now.duration_since(updated).as_secs_f64();
+            if self.rto < 1.0 && idle > 16.0 * self.rto {
+                self.rto *= 2.0;
+                self.updated = Some(now);
+            } else if self.rto > 3.0 && idle > 4.0 * self.rto {
+                self.rto = 1.0 + 0.5 * self.rto;
+                self.updated = Some(now);
+            }
+        }
+        Duration::from_secs_f64(self.rto)
+    }
+
+    /// The variable backoff factor: short timeouts back off faster.
+    fn backoff_factor(&self) -> f64 {
+        if self.rto < 1.0 {
+            3.0
+        } else if self.rto > 3.0 {
+            1.5
+        } else {
+            2.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nstart() {
+        let parameters = TransmissionParameters {
+            nstart: 2,
+            ..TransmissionParameters::default()
+        };
+        let now = Instant::now();
+        let mut congestion = Congestion::new();
+
+        assert_eq!(congestion.try_start(&parameters, 10, now), Ok(()));
+        assert_eq!(congestion.try_start(&parameters, 10, now), Ok(()));
+        assert_eq!(congestion.try_start(&parameters, 10, now), Err(None));
+        congestion.finish(Some((Duration::from_millis(10), 0)), now);
+        assert_eq!(congestion.try_start(&parameters, 10, now), Ok(()));
+    }
+
+    #[test]
+    fn test_probing_rate() {
+        let parameters = TransmissionParameters {
+            probing_rate: 5,
+            ..TransmissionParameters::default()
+        };
+        let now = Instant::now();
+        let mut congestion = Congestion::new();
+
+        congestion.try_start(&parameters, 10, now).unwrap();
+        congestion.finish(None, now);
+
+        // The first probe may go at once, the next after 10 bytes / 5 bytes/s.
+        congestion.try_start(&parameters, 10, now).unwrap();
+        congestion.finish(None, now);
+        let next_probe = now + Duration::from_secs(2);
+        assert_eq!(congestion.try_start(&parameters, 10, now), Err(Some(next_probe)));
+        assert_eq!(congestion.try_start(&parameters, 10, next_probe), Ok(()));
+
+        // A response ends probing.
+        congestion.finish(Some((Duration::from_millis(10), 0)), next_probe);
+        congestion.try_start(&parameters, 10, next_probe).unwrap();
+        congestion.finish(Some((Duration::from_millis(10), 0)), next_probe);
+        assert_eq!(congestion.try_start(&parameters, 10, next_probe), Ok(()));
+    }
+
+    #[test]
+    fn test_initial_timeout_and_backoff() {
+        let parameters = TransmissionParameters::default();
+        let mut congestion = Congestion::new();
+
+        let timeout = congestion.initial_timeout(&parameters, Instant::now());
+        assert!(timeout >= Duration::from_secs(2) && timeout <= Duration::from_secs(3));
+        assert_eq!(congestion.backoff(&parameters, timeout), timeout * 2);
+    }
+
+    #[test]
+    fn test_rto_estimation() {
+        let parameters = TransmissionParameters {
+            ack_random_factor: 1.0,
+            rto_estimation: true,
+            ..TransmissionParameters::default()
+        };
+        let now = Instant::now();
+        let mut congestion = Congestion::new();
+        assert_eq!(congestion.initial_timeout(&parameters, now), Duration::from_secs(2));
+
+        for _ in 0..20 {
+            congestion.try_start(&parameters, 10, now).unwrap();
+            congestion.finish(Some((Duration::from_millis(100), 0)), now);
+        }
+        let timeout = congestion.initial_timeout(&parameters, now);
+        assert!(timeout < Duration::from_millis(200), "{:?}", timeout);
+        assert_eq!(congestion.backoff(&parameters, timeout), timeout.mul_f64(3.0));
+
+        // A small RTO doubles when it goes unused.
+        let later = now + timeout * 17;
+        let aged = congestion.initial_timeout(&parameters, later);
+        assert!((aged.as_secs_f64() - 2.0 * timeout.as_secs_f64()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_rto_weak_samples() {
+        let mut rto = RtoEstimator::new();
+        let now = Instant::now();
+
+        rto.update(Duration::from_secs(4), 1, now);
+        // 0.25 * (4 + 1 * 2) + 0.75 * 2
+        assert!((rto.rto - 3.0).abs() < 1e-9);
+        assert_eq!(rto.backoff_factor(), 2.0);
+
+        rto.update(Duration::from_secs(10), 3, now);
+        assert!((rto.rto - 3.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,529 @@
+//! An HTTP-CoAP cross-proxy (RFC 8075).
+//!
+//! `HttpProxy` accepts HTTP requests for `/hc/<coap-uri>` and forwards them
+//! to the CoAP server named by the URI. In the other direction,
+//! `CoAPServer::enable_forward_proxy` also forwards requests whose
+//! Proxy-Uri is an `http` URL.
+//!
+//! Both directions use the mappings below for the method, the response
+//! code, Content-Type and Content-Format, and Cache-Control and Max-Age.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use coap::cross_proxy::HttpProxy;
+//!
+//! let mut proxy = HttpProxy::new("127.0.0.1:8080", Duration::from_secs(5)).unwrap();
+//! proxy.start().unwrap();
+//! // GET http://127.0.0.1:8080/hc/coap://[::1]/temperature
+//! ```
+
+use std::io::{self, BufReader};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+use threadpool::ThreadPool;
+
+use super::error::{Error, Result};
+use super::http::{self, HttpRequest, HttpResponse, HttpUrl};
+use super::message::header::{RequestType as Method, ResponseType as Status};
+use super::message::packet::CoAPOption;
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+use super::message::uri::{decode_uint, encode_uint, CoAPUri};
+use super::message::IsMessage;
+use super::proxy::{ForwardProxy, DEFAULT_HOP_LIMIT};
+
+/// The path prefix of the URIs mapped to CoAP targets.
+pub const HC_PREFIX: &str = "/hc/";
+
+/// The Max-Age of CoAP responses without the option (RFC 7252 §5.10.5).
+const DEFAULT_MAX_AGE: u32 = 60;
+
+const DEFAULT_WORKER_NUM: usize = 4;
+
+/// The media types with a registered content format.
+const MEDIA_TYPES: [(&str, u16); 15] = [
+    ("text/plain;charset=utf-8", 0),
+    ("application/link-format", 40),
+    ("application/xml", 41),
+    ("application/octet-stream", 42),
+    ("application/exi", 47),
+    ("application/json", 50),
+    ("application/cbor", 60),
+    ("application/senml+json", 110),
+    ("application/sensml+json", 111),
+    ("application/senml+cbor", 112),
+    ("application/sensml+cbor", 113),
+    ("application/senml-exi", 114),
+    ("application/sensml-exi", 115),
+    ("application/senml+xml", 310),
+    ("application/sensml+xml", 311),
+];
+
+/// The HTTP method for a CoAP method.
+pub fn http_method(method: &Method) -> Option<&'static str> {
+    match *method {
+        Method::Get => Some("GET"),
+        Method::Post => Some("POST"),
+        Method::Put => Some("PUT"),
+        Method::Delete => Some("DELETE"),
+        Method::UnKnown => None,
+    }
+}
+
+/// The CoAP method for an HTTP method; HEAD is mapped to GET.
+pub fn coap_method(method: &str) -> Option<Method> {
+    match method {
+        "GET" | "HEAD" => Some(Method::Get),
+        "POST" => Some(Method::Post),
+        "PUT" => Some(Method::Put),
+        "DELETE" => Some(Method::Delete),
+        _ => None,
+    }
+}
+
+/// The HTTP status code for a CoAP response code (RFC 8075 §7.1).
+pub fn http_status(status: &Status) -> u16 {
+    match *status {
+        Status::Created => 201,
+        Status::Deleted | Status::Changed => 204,
+        Status::Valid => 304,
+        Status::Content => 200,
+        Status::Continue => 502,
+        Status::BadRequest | Status::BadOption | Status::MethodNotAllowed => 400,
+        Status::Unauthorized | Status::Forbidden => 403,
+        Status::NotFound => 404,
+        Status::NotAcceptable => 406,
+        Status::PreconditionFailed => 412,
+        Status::RequestEntityTooLarge => 413,
+        Status::UnsupportedContentFormat => 415,
+        Status::RequestEntityIncomplete => 400,
+        Status::TooManyRequests => 429,
+        Status::InternalServerError => 500,
+        Status::NotImplemented => 501,
+        Status::BadGateway | Status::ProxyingNotSupported => 502,
+        Status::HopLimitReached => 508,
+        Status::ServiceUnavailable => 503,
+        Status::GatewayTimeout => 504,
+        Status::UnKnown => 502,
+    }
+}
+
+/// The CoAP response code for an HTTP status code answering `method`.
+pub fn coap_status(status: u16, method: &Method) -> Status {
+    match status {
+        200 | 204 => match *method {
+            Method::Get => Status::Content,
+            Method::Delete => Status::Deleted,
+            _ => Status::Changed,
+        },
+        201 => Status::Created,
+        202..=299 => Status::Changed,
+        304 => Status::Valid,
+        400 | 411 | 414 | 431 => Status::BadRequest,
+        401 | 403 => Status::Forbidden,
+        404 | 410 => Status::NotFound,
+        405 => Status::MethodNotAllowed,
+        406 => Status::NotAcceptable,
+        412 => Status::PreconditionFailed,
+        413 => Status::RequestEntityTooLarge,
+        415 => Status::UnsupportedContentFormat,
+        429 => Status::TooManyRequests,
+        400..=499 => Status::BadRequest,
+        501 => Status::NotImplemented,
+        503 => Status::ServiceUnavailable,
+        504 => Status::GatewayTimeout,
+        500..=599 => Status::InternalServerError,
+        _ => Status::BadGateway,
+    }
+}
+
+/// The Content-Type for a content format. Formats without a registered
+/// media type use `application/coap-payload` (RFC 8075 §6.2).
+pub fn content_type(format: u16) -> String {
+    MEDIA_TYPES
+        .iter()
+        .find(|&&(_, number)| number == format)
+        .map(|&(media_type, _)| media_type.to_string())
+        .unwrap_or_else(|| format!("application/coap-payload;cf={}", format))
+}
+
+/// The content format for a Content-Type, ignoring case and whitespace.
+/// Parameters other than a UTF-8 charset make the type unknown.
+pub fn content_format(content_type: &str) -> Option<u16> {
+    let normalized: String = content_type
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if let Some(format) = normalized.strip_prefix("application/coap-payload;cf=") {
+        return format.parse().ok();
+    }
+    let normalized = match normalized.trim_end_matches(";charset=utf-8") {
+        "text/plain" => "text/plain;charset=utf-8",
+        other => other,
+    };
+    MEDIA_TYPES
+        .iter()
+        .find(|&&(media_type, _)| media_type == normalized)
+        .map(|&(_, number)| number)
+}
+
+/// The Cache-Control value for a Max-Age.
+pub fn cache_control(max_age: u32) -> String {
+    format!("max-age={}", max_age)
+}
+
+/// The Max-Age for a Cache-Control value. Responses which must not be
+/// cached, or which have no explicit lifetime, get a Max-Age of 0.
+pub fn max_age(cache_control: Option<&str>) -> u32 {
+    let mut max_age = 0;
+    for directive in cache_control.unwrap_or("").split(',') {
+        let directive = directive.trim().to_ascii_lowercase();
+        if directive == "no-cache" || directive == "no-store" || directive == "private" {
+            return 0;
+        }
+        if let Some(seconds) = directive.strip_prefix("max-age=") {
+            max_age = seconds.trim_matches('"').parse().unwrap_or(0);
+        }
+    }
+    max_age
+}
+
+/// Translates a CoAP request into an HTTP request without a target, or
+/// `None` if the method has no HTTP equivalent.
+pub(crate) fn to_http_request(request: &CoAPRequest) -> Option<HttpRequest> {
+    let mut headers = Vec::new();
+    if let Some(format) = request.message.get_content_format_number() {
+        headers.push(("Content-Type".to_string(), content_type(format)));
+    }
+    if let Some(format) = request.message.get_accept_number() {
+        headers.push(("Accept".to_string(), content_type(format)));
+    }
+    Some(HttpRequest {
+        method: http_method(request.get_method())?.to_string(),
+        target: String::new(),
+        headers,
+        body: request.message.payload.clone(),
+    })
+}
+
+/// Translates an HTTP response into `response`, which answers a request
+/// with `method`.
+pub(crate) fn from_http_response(http: HttpResponse, method: &Method, response: &mut CoAPResponse) {
+    response.set_status(coap_status(http.status, method));
+    response.message.clear_option(CoAPOption::ContentFormat);
+    if !http.body.is_empty() {
+        let format = http::header(&http.headers, "Content-Type")
+            .and_then(content_format)
+            .unwrap_or(42);
+        response.message.set_content_format_number(format);
+    }
+    let max_age = max_age(http::header(&http.headers, "Cache-Control"));
+    response.message.clear_option(CoAPOption::MaxAge);
+    response.message.add_option(CoAPOption::MaxAge, encode_uint(max_age));
+    response.message.payload = http.body;
+}
+
+/// Forwards a CoAP request to an `http` URL.
+pub(crate) fn forward_http(request: &CoAPRequest, url: &HttpUrl, response: &mut CoAPResponse, timeout: Duration) -> Result<()> {
+    let http_request = match to_http_request(request) {
+        Some(http_request) => http_request,
+        None => {
+            response.set_status(Status::MethodNotAllowed);
+            response.message.payload.clear();
+            return Ok(());
+        }
+    };
+    let http_response = http::send(url, http_request, timeout).map_err(|e| match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+        _ => Error::Io(e),
+    })?;
+    from_http_response(http_response, request.get_method(), response);
+    Ok(())
+}
+
+fn error(status: u16) -> HttpResponse {
+    HttpResponse {
+        status,
+        ..HttpResponse::default()
+    }
+}
+
+/// Forwards an HTTP request to the CoAP target in its path.
+fn handle(proxy: &ForwardProxy, request: HttpRequest) -> HttpResponse {
+    let uri = match request.target.strip_prefix(HC_PREFIX).map(CoAPUri::parse) {
+        Some(Ok(uri)) if uri.secure => return error(501),
+        Some(Ok(uri)) => uri,
+        _ => return error(400),
+    };
+    let method = match coap_method(&request.method) {
+        Some(method) => method,
+        None => return error(501),
+    };
+
+    let mut coap = CoAPRequest::new();
+    coap.set_method(method.clone());
+    if !request.body.is_empty() {
+        match http::header(&request.headers, "Content-Type").map(content_format) {
+            Some(Some(format)) => coap.message.set_content_format_number(format),
+            Some(None) => return error(415),
+            None => coap.message.set_content_format_number(42),
+        }
+    }
+    // Only a single media type can be mapped to Accept.
+    if let Some(format) = http::header(&request.headers, "Accept").and_then(content_format) {
+        coap.message.set_accept_number(format);
+    }
+    coap.message.payload = request.body;
+
+    let response = match proxy.forward(&coap, uri, DEFAULT_HOP_LIMIT) {
+        Ok(response) => response,
+        Err(Error::Timeout) => return error(504),
+        Err(e) => {
+            debug!("Forwarding failed, {}", e);
+            return error(502);
+        }
+    };
+
+    let status = http_status(response.get_status());
+    let mut headers = Vec::new();
+    if let Some(format) = response.message.get_content_format_number() {
+        headers.push(("Content-Type".to_string(), content_type(format)));
+    }
+    let max_age = response
+        .get_option(CoAPOption::MaxAge)
+        .and_then(|list| list.front())
+        .map_or(DEFAULT_MAX_AGE, |value| decode_uint(value));
+    headers.push(("Cache-Control".to_string(), cache_control(max_age)));
+    let body = if request.method == "HEAD" || status == 204 || status == 304 {
+        Vec::new()
+    } else {
+        response.message.payload
+    };
+    HttpResponse { status, headers, body }
+}
+
+fn serve(proxy: &ForwardProxy, stream: TcpStream, timeout: Duration) -> io::Result<()> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let response = match http::read_request(&mut BufReader::new(&stream)) {
+        Ok(request) => handle(proxy, request),
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => error(400),
+        Err(e) => return Err(e),
+    };
+    http::write_response(&mut &stream, &response)?;
+    stream.shutdown(Shutdown::Write)
+}
+
+/// An HTTP server which forwards requests for `/hc/<coap-uri>` to CoAP
+/// servers.
+///
+/// A target which does not respond within the timeout is answered with
+/// 504 Gateway Timeout, any other failure with 502 Bad Gateway.
+pub struct HttpProxy {
+    listener: TcpListener,
+    proxy: Arc<ForwardProxy>,
+    timeout: Duration,
+    worker_num: usize,
+    running: Arc<AtomicBool>,
+    accept_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl HttpProxy {
+    /// Creates a proxy listening on the given address which waits up to
+    /// `timeout` for CoAP responses.
+    pub fn new<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<HttpProxy> {
+        Ok(HttpProxy {
+            listener: TcpListener::bind(addr)?,
+            proxy: Arc::new(ForwardProxy::new(0, timeout)),
+            timeout,
+            worker_num: DEFAULT_WORKER_NUM,
+            running: Arc::new(AtomicBool::new(false)),
+            accept_thread: None,
+        })
+    }
+
+    /// Cache up to `capacity` CoAP responses as their Max-Age allows. Takes
+    /// effect for the next call to `start`.
+    pub fn enable_cache(&mut self, capacity: usize) {
+        self.proxy = Arc::new(ForwardProxy::new(capacity, self.timeout));
+    }
+
+    /// Set the number of threads for handling requests
+    pub fn set_worker_num(&mut self, worker_num: usize) {
+        self.worker_num = worker_num;
+    }
+
+    /// Starts accepting connections in the background.
+    pub fn start(&mut self) -> Result<()> {
+        if self.accept_thread.is_some() {
+            return Ok(());
+        }
+        let listener = self.listener.try_clone()?;
+        let proxy = self.proxy.clone();
+        let running = self.running.clone();
+        let timeout = self.timeout;
+        let pool = ThreadPool::new(self.worker_num);
+        running.store(true, Ordering::SeqCst);
+
+        self.accept_thread = Some(thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !running.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let proxy = proxy.clone();
+                        pool.execute(move || {
+                            if let Err(e) = serve(&proxy, stream, timeout) {
+                                debug!("HTTP connection failed, {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Accepting a connection failed, {}", e),
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    /// Stops accepting connections.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.accept_thread.take() {
+            self.running.store(false, Ordering::SeqCst);
+            // Wake the accept thread up.
+            if let Ok(addr) = self.local_addr() {
+                let _ = TcpStream::connect(addr);
+            }
+            thread.join().unwrap();
+        }
+    }
+
+    /// Return the local address that the proxy is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+}
+
+impl Drop for HttpProxy {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::*;
+    use super::super::server::CoAPServer;
+
+    #[test]
+    fn test_mapping() {
+        assert_eq!(http_status(&Status::Content), 200);
+        assert_eq!(http_status(&Status::Valid), 304);
+        assert_eq!(http_status(&Status::Unauthorized), 403);
+        assert_eq!(http_status(&Status::ProxyingNotSupported), 502);
+        assert_eq!(coap_status(200, &Method::Get), Status::Content);
+        assert_eq!(coap_status(200, &Method::Post), Status::Changed);
+        assert_eq!(coap_status(204, &Method::Delete), Status::Deleted);
+        assert_eq!(coap_status(418, &Method::Get), Status::BadRequest);
+        assert_eq!(coap_status(502, &Method::Get), Status::InternalServerError);
+
+        assert_eq!(content_type(50), "application/json");
+        assert_eq!(content_type(65000), "application/coap-payload;cf=65000");
+        assert_eq!(content_format("Application/JSON; charset=UTF-8"), Some(50));
+        assert_eq!(content_format("text/plain"), Some(0));
+        assert_eq!(content_format("application/coap-payload; cf=65000"), Some(65000));
+        assert_eq!(content_format("text/html"), None);
+
+        assert_eq!(max_age(Some("public, max-age=30")), 30);
+        assert_eq!(max_age(Some("max-age=30, no-store")), 0);
+        assert_eq!(max_age(None), 0);
+    }
+
+    fn coap_handler(request: CoAPRequest) -> Option<CoAPResponse> {
+        let path = request.get_path();
+        let mut response = request.response?;
+        if path == "missing" {
+            response.set_status(Status::NotFound);
+            response.message.payload.clear();
+            return Some(response);
+        }
+        response.message.set_content_format_number(50);
+        response.message.add_option(CoAPOption::MaxAge, vec![30]);
+        response.message.payload = format!("{{\"path\":\"{}\"}}", path).into_bytes();
+        Some(response)
+    }
+
+    fn http_get(proxy: SocketAddr, target: &str) -> String {
+        let mut stream = TcpStream::connect(proxy).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: proxy\r\nAccept: application/json\r\n\r\n", target).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_http_to_coap() {
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.handle(coap_handler).unwrap();
+        let mut proxy = HttpProxy::new("127.0.0.1:0", Duration::from_millis(500)).unwrap();
+        proxy.start().unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let origin = format!("coap://{}", server.socket_addr().unwrap());
+
+        let response = http_get(proxy_addr, &format!("/hc/{}/a/b", origin));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.contains("Cache-Control: max-age=30\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"path\":\"a/b\"}"));
+
+        let response = http_get(proxy_addr, &format!("/hc/{}/missing", origin));
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(http_get(proxy_addr, "/other").starts_with("HTTP/1.1 400 "));
+
+        proxy.stop();
+    }
+
+    #[test]
+    fn test_coap_to_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/resource", listener.local_addr().unwrap());
+        let origin = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let request = http::read_request(&mut BufReader::new(&stream)).unwrap();
+            assert_eq!(request.method, "PUT");
+            assert_eq!(request.target, "/resource");
+            assert_eq!(http::header(&request.headers, "Content-Type"), Some("application/cbor"));
+            assert_eq!(request.body, vec![0xF5]);
+            let response = HttpResponse {
+                status: 200,
+                headers: vec![
+                    ("Content-Type".to_string(), "text/plain; charset=utf-8".to_string()),
+                    ("Cache-Control".to_string(), "max-age=5".to_string()),
+                ],
+                body: b"stored".to_vec(),
+            };
+            http::write_response(&mut &stream, &response).unwrap();
+        });
+
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Put);
+        request.message.set_content_format_number(60);
+        request.message.payload = vec![0xF5];
+        let mut response = CoAPResponse { message: request.message.clone() };
+        let url = HttpUrl::parse(&url).unwrap();
+        forward_http(&request, &url, &mut response, Duration::from_secs(1)).unwrap();
+        origin.join().unwrap();
+
+        assert_eq!(*response.get_status(), Status::Changed);
+        assert_eq!(response.message.get_content_format_number(), Some(0));
+        assert_eq!(response.get_option(CoAPOption::MaxAge).unwrap().front(), Some(&vec![5]));
+        assert_eq!(response.message.payload, b"stored".to_vec());
+    }
+}
@@ -0,0 +1,144 @@
+//! Resource discovery on the server: `/.well-known/core` in the CoRE Link
+//! Format (RFC 6690), with the query filtering of §4.1.
+
+use super::message::header::{RequestType as Method, ResponseType as Status};
+use super::message::packet::ContentFormat;
+use super::message::request::{CoAPRequest, QueryFilter};
+use super::message::response::CoAPResponse;
+use super::middleware::{Middleware, Next};
+
+/// The path of the discovery resource, as `CoAPRequest::get_path` returns it.
+const WELL_KNOWN_CORE: &str = ".well-known/core";
+
+/// A resource advertised by a server, with its target attributes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Link {
+    pub path: String,
+    pub attributes: Vec<(String, String)>,
+}
+
+impl Link {
+    pub fn new(path: &str, attributes: &[(&str, &str)]) -> Link {
+        Link {
+            path: format!("/{}", path.trim_start_matches('/')),
+            attributes: attributes
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Whether the link passes `filter`, which may name `href` or any of its
+    /// attributes.
+    fn matches(&self, filter: &QueryFilter) -> bool {
+        filter.matches("href", &self.path) || self.attributes.iter().any(|(name, value)| filter.matches(name, value))
+    }
+
+    fn write(&self, out: &mut String) {
+        out.push('<');
+        out.push_str(&self.path);
+        out.push('>');
+        for (name, value) in &self.attributes {
+            out.push(';');
+            out.push_str(name);
+            if !value.is_empty() {
+                out.push_str("=\"");
+                out.push_str(value);
+                out.push('"');
+            }
+        }
+    }
+}
+
+/// The middleware of a server which answers GET requests for
+/// `/.well-known/core` with the links it was given, and passes on the rest.
+pub(crate) struct Discovery(pub Vec<Link>);
+
+impl Middleware for Discovery {
+    fn handle(&self, request: CoAPRequest, next: &Next) -> Option<CoAPResponse> {
+        if request.get_path() != WELL_KNOWN_CORE {
+            return next.run(request);
+        }
+
+        let mut response = request.response.clone()?;
+        if *request.get_method() != Method::Get {
+            response.set_status(Status::MethodNotAllowed);
+            return Some(response);
+        }
+
+        let filters: Vec<QueryFilter> = request
+            .get_queries()
+            .iter()
+            .map(|(name, value)| {
+                if value.is_empty() {
+                    QueryFilter::new(name)
+                } else {
+                    QueryFilter::new(&format!("{}={}", name, value))
+                }
+            })
+            .collect();
+        let mut payload = String::new();
+        for link in self.0.iter().filter(|link| filters.iter().all(|filter| link.matches(filter))) {
+            if !payload.is_empty() {
+                payload.push(',');
+            }
+            link.write(&mut payload);
+        }
+
+        response.set_status(Status::Content);
+        response.message.set_content_format(ContentFormat::ApplicationLinkFormat);
+        response.message.payload = payload.into_bytes();
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::header::MessageType;
+    use super::super::message::packet::Packet;
+
+    fn request(method: Method, path: &str, queries: &[&str]) -> CoAPRequest {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let mut request = CoAPRequest::from_packet(packet, &"127.0.0.1:5683".parse().unwrap());
+        request.set_method(method);
+        request.set_path(path);
+        request.set_query(&queries.join("&")).unwrap();
+        request.response = CoAPResponse::new(&request.message);
+        request
+    }
+
+    fn handler(request: CoAPRequest) -> Option<CoAPResponse> {
+        let mut response = request.response?;
+        response.message.payload = b"handler".to_vec();
+        Some(response)
+    }
+
+    #[test]
+    fn test_discovery() {
+        let discovery = Discovery(vec![
+            Link::new("sensors/temp", &[("rt", "temperature-c"), ("if", "sensor")]),
+            Link::new("/sensors/light", &[("rt", "light-lux"), ("obs", "")]),
+        ]);
+        let discover = |path: &str, queries: &[&str]| {
+            let response = discovery.handle(request(Method::Get, path, queries), &Next::new(&[], &handler)).unwrap();
+            String::from_utf8(response.message.payload).unwrap()
+        };
+
+        assert_eq!(
+            discover("/.well-known/core", &[]),
+            "</sensors/temp>;rt=\"temperature-c\";if=\"sensor\",</sensors/light>;rt=\"light-lux\";obs"
+        );
+        assert_eq!(discover("/.well-known/core", &["rt=temp*"]), "</sensors/temp>;rt=\"temperature-c\";if=\"sensor\"");
+        assert_eq!(discover("/.well-known/core", &["obs"]), "</sensors/light>;rt=\"light-lux\";obs");
+        assert_eq!(discover("/.well-known/core", &["href=/sensors/l*"]), "</sensors/light>;rt=\"light-lux\";obs");
+        assert_eq!(discover("/.well-known/core", &["rt=humidity"]), "");
+        assert_eq!(discover("/sensors/temp", &[]), "handler");
+
+        let response = discovery
+            .handle(request(Method::Post, "/.well-known/core", &[]), &Next::new(&[], &handler))
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::MethodNotAllowed);
+    }
+}
@@ -0,0 +1,176 @@
+//! Request freshness and address verification with the Echo option
+//! (RFC 9175 §2).
+//!
+//! The server challenges a request by answering 4.01 Unauthorized with an
+//! Echo value, which the client repeats in its next request. Values are
+//! random and remembered with the address they were sent to, so a request
+//! carrying one proves both that it was sent recently and that its source
+//! address is not spoofed.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::random;
+
+use super::message::header::{RequestType as Method, ResponseType as Status};
+use super::message::packet::{CoAPOption, Packet};
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+use super::message::IsMessage;
+
+const ECHO_LEN: usize = 8;
+/// The most Echo values remembered at once; the oldest is forgotten first.
+const MAX_ISSUED: usize = 1024;
+
+#[derive(Default)]
+struct State {
+    /// The address and time each Echo value was sent.
+    issued: HashMap<[u8; ECHO_LEN], (SocketAddr, Instant)>,
+    /// When each address last returned an Echo value.
+    verified: HashMap<SocketAddr, Instant>,
+}
+
+pub(crate) struct EchoVerifier {
+    /// How long Echo values and verified addresses are remembered.
+    retention: Duration,
+    state: Mutex<State>,
+}
+
+impl EchoVerifier {
+    pub fn new(retention: Duration) -> EchoVerifier {
+        EchoVerifier {
+            retention,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The age of the Echo value of a request, if it was sent to the source
+    /// of the request, which then counts as verified.
+    pub fn verify(&self, request: &CoAPRequest) -> Option<Duration> {
+        let source = request.source?;
+        let value = request.get_option(CoAPOption::Echo)?.front()?;
+        let value = <[u8; ECHO_LEN]>::try_from(value.as_slice()).ok()?;
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let age = match state.issued.get(&value) {
+            Some(&(addr, sent)) if addr == source => now.duration_since(sent),
+            _ => return None,
+        };
+        if age > self.retention {
+            return None;
+        }
+        state.verified.insert(source, now);
+        Some(age)
+    }
+
+    /// Whether an address returned an Echo value within `lifetime`.
+    pub fn is_verified(&self, addr: &SocketAddr, lifetime: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .verified
+            .get(addr)
+            .is_some_and(|&verified| verified.elapsed() <= lifetime)
+    }
+
+    /// Whether a request may only be handled with a fresh Echo value: one
+    /// with an unsafe method.
+    pub fn requires_freshness(request: &CoAPRequest) -> bool {
+        matches!(*request.get_method(), Method::Post | Method::Put | Method::Delete)
+    }
+
+    /// Turns a response into a 4.01 Unauthorized challenge with a new Echo
+    /// value, keeping only its type, message ID and token.
+    pub fn challenge(&self, response: CoAPResponse, addr: &SocketAddr) -> CoAPResponse {
+        let value: [u8; ECHO_LEN] = random();
+        let now = Instant::now();
+        {
+            let mut state = self.state.lock().unwrap();
+            let retention = self.retention;
+            state.issued.retain(|_, &mut (_, sent)| now.duration_since(sent) <= retention);
+            state.verified.retain(|_, &mut verified| now.duration_since(verified) <= retention);
+            if state.issued.len() >= MAX_ISSUED {
+                let oldest = state.issued.iter().min_by_key(|(_, &(_, sent))| sent).map(|(value, _)| *value);
+                if let Some(oldest) = oldest {
+                    state.issued.remove(&oldest);
+                }
+            }
+            state.issued.insert(value, (*addr, now));
+        }
+
+        let mut message = Packet::new();
+        message.header = response.message.header.clone();
+        message.set_token(response.message.get_token().clone()).unwrap();
+        message.add_option(CoAPOption::Echo, value.to_vec());
+        let mut challenge = CoAPResponse { message };
+        challenge.set_status(Status::Unauthorized);
+        challenge
+    }
+}
+
+/// The Echo value of a 4.01 Unauthorized challenge.
+pub(crate) fn challenge_value(response: &CoAPResponse) -> Option<Vec<u8>> {
+    if *response.get_status() != Status::Unauthorized {
+        return None;
+    }
+    response.get_option(CoAPOption::Echo).and_then(|list| list.front()).cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::header::MessageType;
+
+    fn new_request(method: Method, source: &str) -> CoAPRequest {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let mut request = CoAPRequest::from_packet(packet, &source.parse().unwrap());
+        request.set_method(method);
+        request
+    }
+
+    #[test]
+    fn test_challenge() {
+        let verifier = EchoVerifier::new(Duration::from_secs(10));
+        let lifetime = Duration::from_secs(10);
+        let addr = "127.0.0.1:5683".parse().unwrap();
+        let mut request = new_request(Method::Post, "127.0.0.1:5683");
+        assert!(EchoVerifier::requires_freshness(&request));
+        assert_eq!(verifier.verify(&request), None);
+        assert!(!verifier.is_verified(&addr, lifetime));
+
+        let mut response = request.response.clone().unwrap();
+        response.set_payload(b"secret".to_vec());
+        let challenge = verifier.challenge(response, &addr);
+        assert_eq!(*challenge.get_status(), Status::Unauthorized);
+        assert!(challenge.message.payload.is_empty());
+        assert_eq!(challenge.get_token(), request.get_token());
+        let echo = challenge_value(&challenge).unwrap();
+        assert_eq!(echo.len(), ECHO_LEN);
+
+        request.add_option(CoAPOption::Echo, echo.clone());
+        assert!(verifier.verify(&request).unwrap() < lifetime);
+        assert!(verifier.is_verified(&addr, lifetime));
+        assert!(!verifier.is_verified(&addr, Duration::from_secs(0)));
+
+        // The value is bound to the address it was sent to.
+        let mut other = new_request(Method::Post, "127.0.0.2:5683");
+        other.add_option(CoAPOption::Echo, echo);
+        assert_eq!(verifier.verify(&other), None);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let verifier = EchoVerifier::new(Duration::from_millis(0));
+        let addr = "127.0.0.1:5683".parse().unwrap();
+        let mut request = new_request(Method::Get, "127.0.0.1:5683");
+        assert!(!EchoVerifier::requires_freshness(&request));
+        let challenge = verifier.challenge(request.response.clone().unwrap(), &addr);
+        request.add_option(CoAPOption::Echo, challenge_value(&challenge).unwrap());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(verifier.verify(&request), None);
+    }
+}
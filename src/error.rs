@@ -0,0 +1,225 @@
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io;
+
+use super::message::header::ResponseType as Status;
+use super::message::packet::{PackageError, ParseError};
+#[cfg(any(feature = "json", feature = "cbor"))]
+use super::message::body::PayloadError;
+#[cfg(feature = "oscore")]
+use super::oscore::OscoreError;
+
+/// The error type of every fallible operation in this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// A received message is malformed.
+    Parse(ParseError),
+    /// A message cannot be encoded.
+    Package(PackageError),
+    /// A message code is not of the form `c.dd`, with a class up to 7 and a
+    /// detail up to 31.
+    InvalidCode,
+    /// A token is longer than the token length field allows.
+    InvalidTokenLength(usize),
+    /// A block size is not a power of two from 16 to 1024.
+    InvalidBlockSize(usize),
+    /// A URL is not a valid CoAP URL.
+    InvalidUrl,
+    /// An address did not resolve to any socket address.
+    NoAddress,
+    /// No response arrived before the receive timeout.
+    Timeout,
+    /// The peer rejected the message with a Reset.
+    Reset,
+    /// The peer sent a message which does not belong to the exchange.
+    Protocol(ProtocolError),
+    /// The peer answered with an unexpected response code.
+    Response(Status),
+    /// A typed payload could not be encoded or decoded.
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    Payload(PayloadError),
+    /// A message could not be protected or unprotected with OSCORE.
+    #[cfg(feature = "oscore")]
+    Oscore(OscoreError),
+    /// An access policy is malformed; the reason starts with the line it is
+    /// on.
+    #[cfg(feature = "std")]
+    InvalidPolicy(String),
+    /// The underlying socket failed.
+    #[cfg(feature = "std")]
+    Io(io::Error),
+}
+
+/// The ways a peer can violate the protocol during an exchange.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The response carries a different message ID than the request.
+    MessageIdMismatch,
+    /// The response carries a different token than the request.
+    TokenMismatch,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parse(ref e) => write!(f, "invalid message: {}", e),
+            Error::Package(ref e) => write!(f, "cannot encode message: {}", e),
+            Error::InvalidCode => write!(f, "invalid message code"),
+            Error::InvalidTokenLength(length) => write!(f, "invalid token length {}", length),
+            Error::InvalidBlockSize(size) => write!(f, "invalid block size {}", size),
+            Error::InvalidUrl => write!(f, "invalid CoAP URL"),
+            Error::NoAddress => write!(f, "no address"),
+            Error::Timeout => write!(f, "timed out waiting for a response"),
+            Error::Reset => write!(f, "the peer reset the exchange"),
+            Error::Protocol(ref e) => write!(f, "protocol violation: {}", e),
+            Error::Response(ref status) => write!(f, "unexpected response {:?}", status),
+            #[cfg(any(feature = "json", feature = "cbor"))]
+            Error::Payload(ref e) => write!(f, "{}", e),
+            #[cfg(feature = "oscore")]
+            Error::Oscore(ref e) => write!(f, "{}", e),
+            #[cfg(feature = "std")]
+            Error::InvalidPolicy(ref reason) => write!(f, "invalid access policy: {}", reason),
+            #[cfg(feature = "std")]
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::MessageIdMismatch => write!(f, "message ID mismatch"),
+            ProtocolError::TokenMismatch => write!(f, "token mismatch"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::InvalidHeader => write!(f, "truncated header"),
+            ParseError::InvalidVersion(version) => write!(f, "unsupported version {}", version),
+            ParseError::InvalidTokenLength(length) => write!(f, "invalid token length {}", length),
+            ParseError::InvalidEmptyMessage => write!(f, "Empty message with content"),
+            ParseError::InvalidOptionDelta { offset } => {
+                write!(f, "invalid option delta at offset {}", offset)
+            }
+            ParseError::InvalidOptionLength { offset } => {
+                write!(f, "invalid option length at offset {}", offset)
+            }
+            ParseError::EmptyPayload { offset } => {
+                write!(f, "payload marker without payload at offset {}", offset)
+            }
+        }
+    }
+}
+
+impl fmt::Display for PackageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PackageError::InvalidHeader => write!(f, "invalid header"),
+            PackageError::InvalidPacketLength => write!(f, "message too long"),
+            PackageError::BufferTooSmall => write!(f, "buffer too small"),
+            PackageError::InvalidOptionOrder => write!(f, "options out of order"),
+            PackageError::InvalidOptionLength => write!(f, "option delta or length too large"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Parse(ref e) => Some(e),
+            Error::Package(ref e) => Some(e),
+            #[cfg(any(feature = "json", feature = "cbor"))]
+            Error::Payload(ref e) => Some(e),
+            #[cfg(feature = "oscore")]
+            Error::Oscore(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ProtocolError {}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PackageError {}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        Error::Parse(e)
+    }
+}
+
+impl From<PackageError> for Error {
+    fn from(e: PackageError) -> Error {
+        Error::Package(e)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Error {
+        Error::Protocol(e)
+    }
+}
+
+#[cfg(any(feature = "json", feature = "cbor"))]
+impl From<PayloadError> for Error {
+    fn from(e: PayloadError) -> Error {
+        Error::Payload(e)
+    }
+}
+
+#[cfg(feature = "oscore")]
+impl From<OscoreError> for Error {
+    fn from(e: OscoreError) -> Error {
+        Error::Oscore(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for Error {
+    /// A socket read timeout is reported as `WouldBlock` on Unix and as
+    /// `TimedOut` on Windows; both become `Error::Timeout`.
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_io_error_conversion() {
+        match Error::from(io::Error::from(io::ErrorKind::WouldBlock)) {
+            Error::Timeout => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        match Error::from(io::Error::from(io::ErrorKind::TimedOut)) {
+            Error::Timeout => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        match Error::from(io::Error::from(io::ErrorKind::AddrInUse)) {
+            Error::Io(ref e) if e.kind() == io::ErrorKind::AddrInUse => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let error = Error::from(ParseError::InvalidOptionDelta { offset: 6 });
+        assert_eq!(error.to_string(), "invalid message: invalid option delta at offset 6");
+    }
+}
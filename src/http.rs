@@ -0,0 +1,366 @@
+//! Just enough HTTP/1.1 (RFC 9112) for the cross-proxy: one request per
+//! connection, bodies delimited by Content-Length, chunked encoding or the
+//! end of the connection.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// The longest request or status line and header section accepted.
+const MAX_HEAD: usize = 8 * 1024;
+/// The largest body accepted; CoAP payloads are far smaller.
+const MAX_BODY: usize = 1024 * 1024;
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// The value of the first header called `name`, ignoring case.
+pub(crate) fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> io::Result<String> {
+    let mut line = Vec::new();
+    let read = reader.by_ref().take(*budget as u64 + 1).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    if read > *budget || line.last() != Some(&b'\n') {
+        return Err(invalid("header section too long or truncated"));
+    }
+    *budget -= read;
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| invalid("header section is not UTF-8"))
+}
+
+/// Reads the start line and the headers.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(String, Vec<(String, String)>)> {
+    let mut budget = MAX_HEAD;
+    let start = read_line(reader, &mut budget)?;
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, &mut budget)?;
+        if line.is_empty() {
+            return Ok((start, headers));
+        }
+        let colon = line.find(':').ok_or_else(|| invalid("malformed header"))?;
+        let name = line[..colon].to_string();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(invalid("malformed header name"));
+        }
+        headers.push((name, line[colon + 1..].trim().to_string()));
+    }
+}
+
+/// Reads a body as framed by the headers. Without framing, a response body
+/// extends to the end of the connection and a request has none.
+fn read_body<R: BufRead>(reader: &mut R, headers: &[(String, String)], until_eof: bool) -> io::Result<Vec<u8>> {
+    let chunked = header(headers, "Transfer-Encoding").is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        return read_chunked(reader);
+    }
+
+    let mut body = Vec::new();
+    match header(headers, "Content-Length") {
+        Some(length) => {
+            let length: usize = length.parse().map_err(|_| invalid("invalid Content-Length"))?;
+            if length > MAX_BODY {
+                return Err(invalid("body too large"));
+            }
+            body.resize(length, 0);
+            reader.read_exact(&mut body)?;
+        }
+        None if until_eof => {
+            reader.take(MAX_BODY as u64 + 1).read_to_end(&mut body)?;
+            if body.len() > MAX_BODY {
+                return Err(invalid("body too large"));
+            }
+        }
+        None => {}
+    }
+    Ok(body)
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut budget = MAX_HEAD;
+    loop {
+        let line = read_line(reader, &mut budget)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+        if size == 0 {
+            // Skip the trailer section.
+            while !read_line(reader, &mut budget)?.is_empty() {}
+            return Ok(body);
+        }
+        if body.len().checked_add(size).is_none_or(|length| length > MAX_BODY) {
+            return Err(invalid("body too large"));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        read_line(reader, &mut budget)?;
+    }
+}
+
+pub(crate) fn read_request<R: BufRead>(reader: &mut R) -> io::Result<HttpRequest> {
+    let (start, headers) = read_head(reader)?;
+    let mut parts = start.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(invalid("malformed request line")),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid("unsupported HTTP version"));
+    }
+    let body = read_body(reader, &headers, false)?;
+    Ok(HttpRequest {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+        body,
+    })
+}
+
+pub(crate) fn read_response<R: BufRead>(reader: &mut R, method: &str) -> io::Result<HttpResponse> {
+    let (start, headers) = read_head(reader)?;
+    let mut parts = start.splitn(3, ' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => {
+            status.parse().map_err(|_| invalid("invalid status code"))?
+        }
+        _ => return Err(invalid("malformed status line")),
+    };
+    // Responses to HEAD and 1xx, 204 and 304 responses have no body.
+    let body = if method == "HEAD" || status < 200 || status == 204 || status == 304 {
+        Vec::new()
+    } else {
+        read_body(reader, &headers, true)?
+    };
+    Ok(HttpResponse { status, headers, body })
+}
+
+fn write_message<W: Write>(writer: &mut W, start: &str, headers: &[(String, String)], body: &[u8]) -> io::Result<()> {
+    let mut head = format!("{}\r\n", start);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()));
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+pub(crate) fn write_request<W: Write>(writer: &mut W, request: &HttpRequest) -> io::Result<()> {
+    let start = format!("{} {} HTTP/1.1", request.method, request.target);
+    write_message(writer, &start, &request.headers, &request.body)
+}
+
+pub(crate) fn write_response<W: Write>(writer: &mut W, response: &HttpResponse) -> io::Result<()> {
+    let start = format!("HTTP/1.1 {} {}", response.status, reason(response.status));
+    write_message(writer, &start, &response.headers, &response.body)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// An `http` URL split into the authority to connect to and the target of
+/// the request line.
+#[derive(Debug, PartialEq)]
+pub(crate) struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    pub target: String,
+}
+
+impl HttpUrl {
+    /// Parses an `http` URL; `https` and other schemes yield `None`.
+    pub fn parse(url: &str) -> Option<HttpUrl> {
+        let scheme_end = url.find("://")?;
+        if !url[..scheme_end].eq_ignore_ascii_case("http") {
+            return None;
+        }
+        let rest = &url[scheme_end + 3..];
+        let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, target) = rest.split_at(authority_end);
+        let target = target.split('#').next().unwrap_or("");
+
+        if authority.contains('@') {
+            return None;
+        }
+        let (host, port) = match authority.rfind(':') {
+            Some(colon) if !authority[colon..].contains(']') => (&authority[..colon], Some(&authority[colon + 1..])),
+            _ => (authority, None),
+        };
+        let port = match port {
+            Some(port) => port.parse().ok()?,
+            None => 80,
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(HttpUrl {
+            host: host.to_string(),
+            port,
+            target: if target.starts_with('/') {
+                target.to_string()
+            } else {
+                format!("/{}", target)
+            },
+        })
+    }
+
+    /// The value of the Host header.
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        if self.port == 80 {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+/// Sends `request` to `url` and reads the response, all within `timeout` per
+/// socket operation.
+pub(crate) fn send(url: &HttpUrl, mut request: HttpRequest, timeout: Duration) -> io::Result<HttpResponse> {
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    request.target = url.target.clone();
+    request.headers.insert(0, ("Host".to_string(), url.authority()));
+    write_request(&mut stream, &request)?;
+    read_response(&mut BufReader::new(stream), &request.method)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let mut input = &b"POST /hc/coap://[::1]/a HTTP/1.1\r\nHost: proxy\r\ncontent-length: 3\r\n\r\nabcdef"[..];
+        let request = read_request(&mut input).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/hc/coap://[::1]/a");
+        assert_eq!(header(&request.headers, "Content-Length"), Some("3"));
+        assert_eq!(request.body, b"abc".to_vec());
+
+        assert!(read_request(&mut &b"GET / HTTP/2\r\n\r\n"[..]).is_err());
+        assert!(read_request(&mut &b"GET /\r\n\r\n"[..]).is_err());
+        assert!(read_request(&mut &b"GET / HTTP/1.1\r\nHost"[..]).is_err());
+    }
+
+    #[test]
+    fn test_read_response() {
+        let mut input = &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3;x=y\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"[..];
+        let response = read_response(&mut input, "GET").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"abcde".to_vec());
+
+        let mut input = &b"HTTP/1.0 404 Not Found\r\n\r\nuntil the end"[..];
+        let response = read_response(&mut input, "GET").unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"until the end".to_vec());
+
+        let mut input = &b"HTTP/1.1 204 No Content\r\n\r\n"[..];
+        assert!(read_response(&mut input, "DELETE").unwrap().body.is_empty());
+    }
+
+    #[test]
+    fn test_read_chunked_too_large() {
+        let mut input = &b"1\r\na\r\nffffffffffffffff\r\n"[..];
+        let error = read_chunked(&mut input).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "body too large");
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut output = Vec::new();
+        let response = HttpResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: b"hi".to_vec(),
+        };
+        write_response(&mut output, &response).unwrap();
+        assert_eq!(
+            output,
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi".to_vec()
+        );
+        assert_eq!(read_response(&mut &output[..], "GET").unwrap(), response_with_framing(response));
+    }
+
+    fn response_with_framing(mut response: HttpResponse) -> HttpResponse {
+        response.headers.push(("Content-Length".to_string(), "2".to_string()));
+        response.headers.push(("Connection".to_string(), "close".to_string()));
+        response
+    }
+
+    #[test]
+    fn test_url() {
+        let url = HttpUrl::parse("HTTP://example.com:8080/a/b?c=d#e").unwrap();
+        assert_eq!(url, HttpUrl { host: "example.com".to_string(), port: 8080, target: "/a/b?c=d".to_string() });
+        assert_eq!(url.authority(), "example.com:8080");
+
+        let url = HttpUrl::parse("http://[::1]?q").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.target.as_str()), ("::1", 80, "/?q"));
+        assert_eq!(url.authority(), "[::1]");
+
+        assert!(HttpUrl::parse("https://example.com/").is_none());
+        assert!(HttpUrl::parse("http://user@example.com/").is_none());
+        assert!(HttpUrl::parse("http:///").is_none());
+    }
+}
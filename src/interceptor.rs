@@ -0,0 +1,87 @@
+//! Interceptors around the requests of a client.
+//!
+//! An interceptor sees each request sent with `CoAPClient::build_request` and
+//! its shorthands before it is sent, and can change it or its destination,
+//! answer it itself, or pass it on with `Next::run` and change the response.
+//! Interceptors added first run first. The request has no message ID or
+//! token yet; it is looked up in the cache, split into blocks, protected with
+//! OSCORE and retried on Echo challenges further down the chain.
+//!
+//! Interceptors can also watch the datagrams the client sends and receives,
+//! including acknowledgements, retransmissions and notifications.
+//!
+//! ```no_run
+//! use std::net::SocketAddr;
+//! use std::time::Instant;
+//! use coap::{CoAPClient, CoAPOption, CoAPRequest, CoAPResponse, IsMessage, Method, Result};
+//! use coap::interceptor::Next;
+//!
+//! fn authorize(peer_addr: SocketAddr, mut request: CoAPRequest, next: &Next) -> Result<CoAPResponse> {
+//!     request.add_option(CoAPOption::UriQuery, b"key=secret".to_vec());
+//!     let start = Instant::now();
+//!     let response = next.run(peer_addr, request);
+//!     println!("{} answered in {:?}", peer_addr, start.elapsed());
+//!     response
+//! }
+//!
+//! let mut client = CoAPClient::new("127.0.0.1:5683").unwrap();
+//! client.add_interceptor(authorize);
+//! client.build_request(Method::Get, "coap://127.0.0.1:5683/status").send().unwrap();
+//! ```
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::error::Result;
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+
+/// A step between a client and the network.
+pub trait Interceptor: Sync + Send {
+    /// Exchanges a request with `peer_addr`, usually by passing it on to
+    /// `next`.
+    fn intercept(&self, peer_addr: SocketAddr, request: CoAPRequest, next: &Next) -> Result<CoAPResponse> {
+        next.run(peer_addr, request)
+    }
+
+    /// Called with each datagram the client sends.
+    fn on_send(&self, _peer_addr: &SocketAddr, _bytes: &[u8]) {}
+
+    /// Called with each datagram the client receives.
+    fn on_receive(&self, _source: &SocketAddr, _bytes: &[u8]) {}
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(SocketAddr, CoAPRequest, &Next) -> Result<CoAPResponse>,
+    F: Sync + Send,
+{
+    fn intercept(&self, peer_addr: SocketAddr, request: CoAPRequest, next: &Next) -> Result<CoAPResponse> {
+        self(peer_addr, request, next)
+    }
+}
+
+/// The rest of the chain: the interceptors after the current one, then the
+/// exchange.
+pub struct Next<'a> {
+    interceptors: &'a [Arc<dyn Interceptor>],
+    exchange: &'a dyn Fn(SocketAddr, CoAPRequest) -> Result<CoAPResponse>,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(
+        interceptors: &'a [Arc<dyn Interceptor>],
+        exchange: &'a dyn Fn(SocketAddr, CoAPRequest) -> Result<CoAPResponse>,
+    ) -> Next<'a> {
+        Next { interceptors, exchange }
+    }
+
+    /// Passes a request down the chain and returns its response. It may be
+    /// called more than once, such as to retry a request.
+    pub fn run(&self, peer_addr: SocketAddr, request: CoAPRequest) -> Result<CoAPResponse> {
+        match self.interceptors.split_first() {
+            Some((first, rest)) => first.intercept(peer_addr, request, &Next::new(rest, self.exchange)),
+            None => (self.exchange)(peer_addr, request),
+        }
+    }
+}
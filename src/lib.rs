@@ -16,6 +16,26 @@
 //! coap = "0.7"
 //! ```
 //!
+//! The message codec in the `message` module also builds under `#![no_std]`.
+//! Disable the default `std` feature to leave out the client and the server,
+//! and enable `alloc` to keep the owned message types:
+//!
+//! ```toml
+//! [dependencies]
+//! coap = { version = "0.7", default-features = false, features = ["alloc"] }
+//! ```
+//!
+//! Without `alloc`, messages are decoded with `PacketRef` and encoded with
+//! `PacketWriter` into fixed-size buffers.
+//!
+//! The `json` and `cbor` features add typed payloads with serde, such as
+//! `CoAPRequest::json` and `RequestBuilder::json`, and SenML packs in the
+//! `message::senml` module.
+//!
+//! The `oscore` feature adds end-to-end protection of requests and responses
+//! with OSCORE ([RFC 8613](https://tools.ietf.org/rfc/rfc8613.txt)), see the
+//! `oscore` module.
+//!
 //! Then, add this to your crate root:
 //!
 //! ```
@@ -72,21 +92,72 @@
 //! }
 //! ```
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 #[cfg(test)]
 extern crate quickcheck;
 
-pub use self::client::CoAPClient;
+#[cfg(feature = "std")]
+pub use self::amplification::AmplificationLimits;
+#[cfg(feature = "std")]
+pub use self::client::{CoAPClient, RequestBuilder};
+#[cfg(feature = "std")]
+pub use self::congestion::TransmissionParameters;
+pub use self::error::{Error, Result};
 pub use self::message::header::MessageType;
+#[cfg(feature = "alloc")]
 pub use self::message::IsMessage;
 pub use self::message::packet::CoAPOption;
-pub use self::message::request::CoAPRequest;
-pub use self::message::request::Method;
+#[cfg(feature = "alloc")]
+pub use self::message::request::{AppState, CoAPRequest, Identity};
+pub use self::message::header::RequestType as Method;
+#[cfg(feature = "alloc")]
 pub use self::message::response::CoAPResponse;
-pub use self::message::response::Status;
-pub use self::server::CoAPServer;
+pub use self::message::header::ResponseType as Status;
+#[cfg(feature = "std")]
+pub use self::negotiation::ContentNegotiation;
+#[cfg(feature = "std")]
+pub use self::server::{CoAPServer, ETagPolicy};
+pub mod error;
 pub mod message;
+#[cfg(feature = "std")]
+pub mod access;
+#[cfg(feature = "std")]
+pub mod amplification;
+#[cfg(feature = "std")]
+mod cache;
+#[cfg(feature = "std")]
 pub mod client;
+#[cfg(feature = "std")]
+mod conditional;
+#[cfg(feature = "std")]
+pub mod congestion;
+#[cfg(feature = "std")]
+pub mod cross_proxy;
+#[cfg(feature = "std")]
+mod discovery;
+#[cfg(feature = "std")]
+mod echo;
+#[cfg(feature = "std")]
+mod http;
+#[cfg(feature = "std")]
+pub mod interceptor;
+#[cfg(feature = "std")]
+pub mod middleware;
+#[cfg(feature = "std")]
+pub mod negotiation;
+#[cfg(feature = "oscore")]
+pub mod oscore;
+#[cfg(feature = "std")]
+mod proxy;
+#[cfg(feature = "std")]
+mod reverse_proxy;
+#[cfg(feature = "std")]
 pub mod server;
+#[cfg(feature = "std")]
 mod observer;
 
 
@@ -0,0 +1,204 @@
+//! Typed payloads: JSON with the `json` feature, CBOR with the `cbor`
+//! feature, and SenML packs with either.
+//!
+//! Decoding checks the Content-Format option and encoding sets it. A
+//! `PayloadError` knows the response code a server should answer with, so a
+//! handler can reject a bad request body in one line.
+
+use alloc::string::String;
+use alloc::string::ToString;
+#[cfg(feature = "cbor")]
+use alloc::vec::Vec;
+use core::fmt;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::header::ResponseType as Status;
+use num_traits::FromPrimitive;
+
+use super::packet::{ContentFormat, Packet};
+use super::senml::Pack;
+
+/// Why a payload could not be decoded or encoded.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PayloadError {
+    /// The Content-Format option names another format.
+    UnsupportedContentFormat(u16),
+    /// The payload is malformed, or does not match the expected type.
+    Invalid(String),
+    /// The value cannot be represented in the format.
+    Encode(String),
+}
+
+impl PayloadError {
+    /// The response code for a request whose payload failed to decode:
+    /// 4.15 Unsupported Content-Format, 4.00 Bad Request, or 5.00 Internal
+    /// Server Error if the response failed to encode.
+    pub fn status(&self) -> Status {
+        match *self {
+            PayloadError::UnsupportedContentFormat(_) => Status::UnsupportedContentFormat,
+            PayloadError::Invalid(_) => Status::BadRequest,
+            PayloadError::Encode(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PayloadError::UnsupportedContentFormat(number) => write!(f, "unsupported content format {}", number),
+            PayloadError::Invalid(ref e) => write!(f, "invalid payload: {}", e),
+            PayloadError::Encode(ref e) => write!(f, "cannot encode payload: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PayloadError {}
+
+/// A payload without Content-Format is assumed to be in the expected format.
+fn check_format(packet: &Packet, expected: ContentFormat) -> Result<(), PayloadError> {
+    match packet.get_content_format_number() {
+        Some(number) if number != expected as u16 => Err(PayloadError::UnsupportedContentFormat(number)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(feature = "json")]
+pub(crate) fn from_json<T: DeserializeOwned>(packet: &Packet) -> Result<T, PayloadError> {
+    check_format(packet, ContentFormat::ApplicationJSON)?;
+    serde_json::from_slice(&packet.payload).map_err(|e| PayloadError::Invalid(e.to_string()))
+}
+
+#[cfg(feature = "json")]
+pub(crate) fn to_json<T: Serialize + ?Sized>(packet: &mut Packet, value: &T) -> Result<(), PayloadError> {
+    packet.payload = serde_json::to_vec(value).map_err(|e| PayloadError::Encode(e.to_string()))?;
+    packet.set_content_format(ContentFormat::ApplicationJSON);
+    Ok(())
+}
+
+#[cfg(feature = "cbor")]
+pub(crate) fn from_cbor<T: DeserializeOwned>(packet: &Packet) -> Result<T, PayloadError> {
+    check_format(packet, ContentFormat::ApplicationCBOR)?;
+    ciborium::de::from_reader(&packet.payload[..]).map_err(|e| PayloadError::Invalid(e.to_string()))
+}
+
+#[cfg(feature = "cbor")]
+pub(crate) fn to_cbor<T: Serialize + ?Sized>(packet: &mut Packet, value: &T) -> Result<(), PayloadError> {
+    let mut payload = Vec::new();
+    ciborium::ser::into_writer(value, &mut payload).map_err(|e| PayloadError::Encode(e.to_string()))?;
+    packet.payload = payload;
+    packet.set_content_format(ContentFormat::ApplicationCBOR);
+    Ok(())
+}
+
+/// Decodes a SenML pack in the representation named by the Content-Format.
+/// Without Content-Format, JSON is assumed if the `json` feature is enabled.
+pub(crate) fn from_senml(packet: &Packet) -> Result<Pack, PayloadError> {
+    let default = if cfg!(feature = "json") {
+        ContentFormat::ApplicationSenmlJSON
+    } else {
+        ContentFormat::ApplicationSenmlCBOR
+    };
+    let number = packet.get_content_format_number().unwrap_or(default as u16);
+    match ContentFormat::from_u16(number) {
+        #[cfg(feature = "json")]
+        Some(ContentFormat::ApplicationSenmlJSON) | Some(ContentFormat::ApplicationSensmlJSON) => {
+            Pack::from_json(&packet.payload)
+        }
+        #[cfg(feature = "cbor")]
+        Some(ContentFormat::ApplicationSenmlCBOR) | Some(ContentFormat::ApplicationSensmlCBOR) => {
+            Pack::from_cbor(&packet.payload)
+        }
+        _ => Err(PayloadError::UnsupportedContentFormat(number)),
+    }
+}
+
+/// Encodes a SenML pack in the representation of `format`, which must be
+/// one of the SenML or SensML JSON and CBOR formats.
+pub(crate) fn to_senml(packet: &mut Packet, pack: &Pack, format: ContentFormat) -> Result<(), PayloadError> {
+    packet.payload = match format {
+        #[cfg(feature = "json")]
+        ContentFormat::ApplicationSenmlJSON | ContentFormat::ApplicationSensmlJSON => pack.to_json(),
+        #[cfg(feature = "cbor")]
+        ContentFormat::ApplicationSenmlCBOR | ContentFormat::ApplicationSensmlCBOR => pack.to_cbor()?,
+        _ => return Err(PayloadError::UnsupportedContentFormat(format as u16)),
+    };
+    packet.set_content_format(format);
+    Ok(())
+}
+
+#[cfg(all(test, feature = "json", feature = "cbor"))]
+mod test {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use alloc::vec;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        name: String,
+        value: f64,
+    }
+
+    fn reading() -> Reading {
+        Reading {
+            name: "temp".to_string(),
+            value: 21.5,
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let mut packet = Packet::new();
+        to_json(&mut packet, &reading()).unwrap();
+        assert_eq!(packet.get_content_format(), Some(ContentFormat::ApplicationJSON));
+        assert_eq!(packet.payload, br#"{"name":"temp","value":21.5}"#.to_vec());
+        assert_eq!(from_json::<Reading>(&packet), Ok(reading()));
+
+        packet.payload = b"{".to_vec();
+        let error = from_json::<Reading>(&packet).unwrap_err();
+        assert_eq!(error.status(), Status::BadRequest);
+
+        let mut map = BTreeMap::new();
+        map.insert(vec![1u8], 1);
+        assert_eq!(to_json(&mut packet, &map).unwrap_err().status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn test_cbor() {
+        let mut packet = Packet::new();
+        to_cbor(&mut packet, &reading()).unwrap();
+        assert_eq!(packet.get_content_format(), Some(ContentFormat::ApplicationCBOR));
+        assert_eq!(from_cbor::<Reading>(&packet), Ok(reading()));
+        assert_eq!(from_json::<Reading>(&packet), Err(PayloadError::UnsupportedContentFormat(60)));
+
+        packet.clear_option(super::super::packet::CoAPOption::ContentFormat);
+        packet.payload = vec![0xFF];
+        assert_eq!(from_cbor::<Reading>(&packet).unwrap_err().status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn test_senml() {
+        use super::super::senml::{Record, Value};
+
+        let pack = Pack::new(vec![Record {
+            name: Some("temp".to_string()),
+            unit: Some("Cel".to_string()),
+            value: Some(Value::Number(21.5)),
+            ..Record::default()
+        }]);
+        for &number in &[110, 112] {
+            let mut packet = Packet::new();
+            to_senml(&mut packet, &pack, ContentFormat::from_u16(number).unwrap()).unwrap();
+            assert_eq!(packet.get_content_format_number(), Some(number));
+            assert_eq!(from_senml(&packet), Ok(pack.clone()));
+        }
+
+        let mut packet = Packet::new();
+        packet.payload = br#"[{"n":"temp","v":1}]"#.to_vec();
+        assert_eq!(from_senml(&packet).unwrap().records.len(), 1);
+        packet.set_content_format(ContentFormat::ApplicationJSON);
+        assert_eq!(from_senml(&packet), Err(PayloadError::UnsupportedContentFormat(50)));
+        assert!(to_senml(&mut packet, &pack, ContentFormat::ApplicationSenmlXML).is_err());
+    }
+}
@@ -1,6 +1,9 @@
-use serde::{Serialize, Deserialize};
+#[cfg(feature = "alloc")]
+use alloc::{format, string::String};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::error::Error;
+
+#[derive(Debug)]
 pub struct HeaderRaw {
     ver_type_tkl: u8,
     code: u8,
@@ -63,6 +66,7 @@ pub enum ResponseType {
     RequestEntityTooLarge,
     UnsupportedContentFormat,
     RequestEntityIncomplete,
+    TooManyRequests,
 
     // 500 Codes
     InternalServerError,
@@ -71,6 +75,7 @@ pub enum ResponseType {
     ServiceUnavailable,
     GatewayTimeout,
     ProxyingNotSupported,
+    HopLimitReached,
 
     UnKnown,
 }
@@ -105,6 +110,21 @@ impl Header {
         };
     }
 
+    /// Decodes the four-byte fixed header at the start of an encoded message.
+    pub fn from_bytes(buf: &[u8; 4]) -> Header {
+        Header::from_raw(&HeaderRaw {
+            ver_type_tkl: buf[0],
+            code: buf[1],
+            message_id: u16::from_be_bytes([buf[2], buf[3]]),
+        })
+    }
+
+    /// Encodes the fixed header.
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let message_id = self.message_id.to_be_bytes();
+        [self.ver_type_tkl, class_to_code(&self.code), message_id[0], message_id[1]]
+    }
+
     #[inline]
     pub fn set_version(&mut self, v: u8) {
         let type_tkl = 0x3F & self.ver_type_tkl;
@@ -142,12 +162,16 @@ impl Header {
         }
     }
 
+    /// Sets the token length field. Lengths 9 to 15 are reserved.
     #[inline]
-    pub fn set_token_length(&mut self, tkl: u8) {
-        assert_eq!(0xF0 & tkl, 0);
+    pub fn set_token_length(&mut self, tkl: u8) -> Result<(), Error> {
+        if tkl > 8 {
+            return Err(Error::InvalidTokenLength(tkl as usize));
+        }
 
         let ver_type = 0xF0 & self.ver_type_tkl;
         self.ver_type_tkl = tkl | ver_type;
+        Ok(())
     }
 
     #[inline]
@@ -155,18 +179,24 @@ impl Header {
         return 0x0F & self.ver_type_tkl;
     }
 
-    pub fn set_code(&mut self, code: &str) {
-        let code_vec: Vec<&str> = code.split('.').collect();
-        assert_eq!(code_vec.len(), 2);
-
-        let class_code = code_vec[0].parse::<u8>().unwrap();
-        let detail_code = code_vec[1].parse::<u8>().unwrap();
-        assert_eq!(0xF8 & class_code, 0);
-        assert_eq!(0xE0 & detail_code, 0);
+    /// Sets the code from its `c.dd` string form, e.g. `"2.05"`.
+    pub fn set_code(&mut self, code: &str) -> Result<(), Error> {
+        let mut code_iter = code.split('.');
+        let (class_code, detail_code) = match (code_iter.next(), code_iter.next(), code_iter.next()) {
+            (Some(class_code), Some(detail_code), None) => (class_code.parse::<u8>(), detail_code.parse::<u8>()),
+            _ => return Err(Error::InvalidCode),
+        };
 
-        self.code = code_to_class(&(class_code << 5 | detail_code));
+        match (class_code, detail_code) {
+            (Ok(class_code), Ok(detail_code)) if class_code <= 7 && detail_code <= 31 => {
+                self.code = code_to_class(&(class_code << 5 | detail_code));
+                Ok(())
+            }
+            _ => Err(Error::InvalidCode),
+        }
     }
 
+    #[cfg(feature = "alloc")]
     pub fn get_code(&self) -> String {
         class_to_str(&self.code)
     }
@@ -209,6 +239,7 @@ pub fn class_to_code(class: &MessageClass) -> u8 {
         MessageClass::Response(ResponseType::RequestEntityTooLarge) => 0x8D,
         MessageClass::Response(ResponseType::UnsupportedContentFormat) => 0x8F,
         MessageClass::Response(ResponseType::RequestEntityIncomplete) => 0x88,
+        MessageClass::Response(ResponseType::TooManyRequests) => 0x9D,
 
         MessageClass::Response(ResponseType::InternalServerError) => 0x90,
         MessageClass::Response(ResponseType::NotImplemented) => 0x91,
@@ -216,6 +247,7 @@ pub fn class_to_code(class: &MessageClass) -> u8 {
         MessageClass::Response(ResponseType::ServiceUnavailable) => 0x93,
         MessageClass::Response(ResponseType::GatewayTimeout) => 0x94,
         MessageClass::Response(ResponseType::ProxyingNotSupported) => 0x95,
+        MessageClass::Response(ResponseType::HopLimitReached) => 0x98,
 
         _ => 0xFF,
     } as u8;
@@ -248,6 +280,7 @@ pub fn code_to_class(code: &u8) -> MessageClass {
         0x8D => MessageClass::Response(ResponseType::RequestEntityTooLarge),
         0x8F => MessageClass::Response(ResponseType::UnsupportedContentFormat),
         0x88 => MessageClass::Response(ResponseType::RequestEntityIncomplete),
+        0x9D => MessageClass::Response(ResponseType::TooManyRequests),
 
         0x90 => MessageClass::Response(ResponseType::InternalServerError),
         0x91 => MessageClass::Response(ResponseType::NotImplemented),
@@ -255,10 +288,12 @@ pub fn code_to_class(code: &u8) -> MessageClass {
         0x93 => MessageClass::Response(ResponseType::ServiceUnavailable),
         0x94 => MessageClass::Response(ResponseType::GatewayTimeout),
         0x95 => MessageClass::Response(ResponseType::ProxyingNotSupported),
+        0x98 => MessageClass::Response(ResponseType::HopLimitReached),
         _ => MessageClass::Reserved,
     }
 }
 
+#[cfg(feature = "alloc")]
 pub fn code_to_str(code: &u8) -> String {
     let class_code = (0xE0 & code) >> 5;
     let detail_code = 0x1F & code;
@@ -266,6 +301,7 @@ pub fn code_to_str(code: &u8) -> String {
     return format!("{}.{:02}", class_code, detail_code);
 }
 
+#[cfg(feature = "alloc")]
 pub fn class_to_str(class: &MessageClass) -> String {
     return code_to_str(&class_to_code(class));
 }
@@ -289,4 +325,33 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_header_setters() {
+        let mut header = Header::new();
+        assert!(header.set_code("2.05").is_ok());
+        assert_eq!(header.code, MessageClass::Response(ResponseType::Content));
+        assert!(header.set_code("8.00").is_err());
+        assert!(header.set_code("2.32").is_err());
+        assert!(header.set_code("2").is_err());
+        assert!(header.set_code("2.05.1").is_err());
+        assert!(header.set_code("a.05").is_err());
+        assert_eq!(header.code, MessageClass::Response(ResponseType::Content));
+
+        assert!(header.set_token_length(8).is_ok());
+        assert_eq!(header.get_token_length(), 8);
+        assert!(header.set_token_length(9).is_err());
+        assert_eq!(header.get_token_length(), 8);
+    }
+
+    #[test]
+    fn test_header_bytes() {
+        let header = Header::from_bytes(&[0x64, 0x45, 0x13, 0xFD]);
+        assert_eq!(header.get_version(), 1);
+        assert_eq!(header.get_type(), MessageType::Acknowledgement);
+        assert_eq!(header.get_token_length(), 4);
+        assert_eq!(header.code, MessageClass::Response(ResponseType::Content));
+        assert_eq!(header.get_message_id(), 5117);
+        assert_eq!(header.to_bytes(), [0x64, 0x45, 0x13, 0xFD]);
+    }
 }
@@ -1,20 +1,39 @@
+#[cfg(any(feature = "json", feature = "cbor"))]
+pub mod body;
 pub mod header;
+#[cfg(any(feature = "json", feature = "cbor"))]
+pub mod senml;
+#[cfg(feature = "alloc")]
 pub mod request;
+#[cfg(feature = "alloc")]
 pub mod response;
 pub mod packet;
+pub mod packet_ref;
+#[cfg(feature = "alloc")]
+pub mod uri;
 
+#[cfg(feature = "alloc")]
 use self::packet::Packet;
+#[cfg(feature = "alloc")]
 use self::header::Header;
-use std::collections::LinkedList;
+#[cfg(feature = "alloc")]
+use alloc::collections::LinkedList;
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+#[cfg(feature = "alloc")]
+use crate::error::Error;
 
+#[cfg(feature = "alloc")]
 pub trait IsMessage {
     fn get_message(&self) -> &Packet;
     fn get_mut_message(&mut self) -> &mut Packet;
     fn get_header(&self) -> &Header;
     fn get_mut_header(&mut self) -> &mut Header;
 
-    fn set_token(&mut self, token: Vec<u8>) {
-        self.get_mut_message().set_token(token);
+    fn set_token(&mut self, token: Vec<u8>) -> Result<(), Error> {
+        self.get_mut_message().set_token(token)
     }
     fn get_token(&self) -> &Vec<u8> {
         return self.get_message().get_token();
@@ -62,7 +81,7 @@ pub trait IsMessage {
     fn get_code(&self) -> String {
         return self.get_message().header.get_code();
     }
-    fn set_code(&mut self, code: &str) {
-        self.get_mut_message().header.set_code(code);
+    fn set_code(&mut self, code: &str) -> Result<(), Error> {
+        self.get_mut_message().header.set_code(code)
     }
 }
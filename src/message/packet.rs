@@ -1,19 +1,24 @@
-use bincode;
-use std::collections::BTreeMap;
-use std::collections::LinkedList;
+#[cfg(feature = "alloc")]
+use alloc::collections::{BTreeMap, LinkedList};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+#[cfg(feature = "alloc")]
+use alloc::vec;
 
 use num_derive::FromPrimitive;
+#[cfg(feature = "alloc")]
 use num_traits::FromPrimitive;
 
+#[cfg(feature = "alloc")]
 use super::header;
-
-macro_rules! u8_to_unsigned_be {
-    ($src:ident, $start:expr, $end:expr, $t:ty) => ({
-        (0 .. $end - $start + 1).rev().fold(0, |acc, i| acc | $src[$start+i] as $t << i * 8)
-    })
-}
-
-#[derive(PartialEq, Eq, Debug)]
+#[cfg(feature = "alloc")]
+use crate::error::Error;
+#[cfg(feature = "alloc")]
+use super::packet_ref::PacketRef;
+#[cfg(feature = "alloc")]
+use super::uri::{decode_uint, encode_uint};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum CoAPOption {
     IfMatch,
     UriHost,
@@ -35,6 +40,10 @@ pub enum CoAPOption {
     Size1,
     Size2,
     NoResponse,
+    HopLimit,
+    Oscore,
+    Echo,
+    RequestTag,
 }
 
 #[derive(PartialEq, Eq, Debug, FromPrimitive)]
@@ -66,16 +75,48 @@ pub enum ObserveOption {
 pub enum PackageError {
     InvalidHeader,
     InvalidPacketLength,
+    BufferTooSmall,
+    InvalidOptionOrder,
+    /// An option delta or value length is 65805 or more, beyond what an
+    /// option header can encode.
+    InvalidOptionLength,
 }
 
-#[derive(Debug)]
+/// The reasons a message can be rejected by `Packet::from_bytes`.
+///
+/// Offsets are byte positions in the decoded buffer.
+#[derive(Debug, PartialEq, Eq)]
 pub enum ParseError {
+    /// The buffer is shorter than the four-byte fixed header.
     InvalidHeader,
-    InvalidTokenLength,
-    InvalidOptionDelta,
-    InvalidOptionLength,
+    /// The version field is not 1.
+    InvalidVersion(u8),
+    /// The token length is 9-15, or the token is truncated.
+    InvalidTokenLength(u8),
+    /// An Empty message (code 0.00) carries a token, options or a payload.
+    InvalidEmptyMessage,
+    /// The option header at `offset` uses the reserved delta 15, is
+    /// truncated, or yields an option number above 65535.
+    InvalidOptionDelta { offset: usize },
+    /// The option header at `offset` uses the reserved length 15, or the
+    /// option value is truncated.
+    InvalidOptionLength { offset: usize },
+    /// The payload marker at `offset` is not followed by any payload.
+    EmptyPayload { offset: usize },
+}
+
+/// Controls how strictly `Packet::from_bytes_with_mode` checks its input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Reject every message format error of RFC 7252 section 3.
+    Strict,
+    /// Only reject input which cannot be decoded at all. The version, the
+    /// contents of Empty messages and zero-length payloads are not checked,
+    /// which is useful for sniffing traffic.
+    Lenient,
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Clone, Debug)]
 pub struct Packet {
     pub header: header::Header,
@@ -84,6 +125,7 @@ pub struct Packet {
     pub payload: Vec<u8>,
 }
 
+#[cfg(feature = "alloc")]
 impl Packet {
     pub fn new() -> Packet {
         Packet {
@@ -94,9 +136,15 @@ impl Packet {
         }
     }
 
-    pub fn set_token(&mut self, token: Vec<u8>) {
-        self.header.set_token_length(token.len() as u8);
+    /// Sets the token, which is at most 8 bytes long.
+    pub fn set_token(&mut self, token: Vec<u8>) -> Result<(), Error> {
+        if token.len() > 8 {
+            return Err(Error::InvalidTokenLength(token.len()));
+        }
+
+        self.header.set_token_length(token.len() as u8)?;
         self.token = token;
+        Ok(())
     }
 
     pub fn get_token(&self) -> &Vec<u8> {
@@ -104,17 +152,29 @@ impl Packet {
     }
 
     pub fn set_option(&mut self, tp: CoAPOption, value: LinkedList<Vec<u8>>) {
-        let num = Self::get_option_number(tp);
+        let num = get_option_number(tp);
         self.options.insert(num, value);
     }
 
     pub fn set_content_format(&mut self, cf: ContentFormat) {
-        let content_format = cf as u16;
-        let msb = (content_format >> 8) as u8;
-        let lsb = (content_format & 0xFF) as u8;
+        self.set_content_format_number(cf as u16);
+    }
+
+    /// Sets the Content-Format option to a number, which need not be one of
+    /// the formats of `ContentFormat`.
+    pub fn set_content_format_number(&mut self, number: u16) {
+        self.clear_option(CoAPOption::ContentFormat);
+        self.add_option(CoAPOption::ContentFormat, encode_uint(number as u32));
+    }
 
-        let content_format: Vec<u8> = vec![msb, lsb];
-        self.add_option(CoAPOption::ContentFormat, content_format);
+    pub fn set_accept(&mut self, cf: ContentFormat) {
+        self.set_accept_number(cf as u16);
+    }
+
+    /// Sets the Accept option to a content format number.
+    pub fn set_accept_number(&mut self, number: u16) {
+        self.clear_option(CoAPOption::Accept);
+        self.add_option(CoAPOption::Accept, encode_uint(number as u32));
     }
 
     pub fn set_payload(&mut self, payload: Vec<u8>) {
@@ -122,7 +182,7 @@ impl Packet {
     }
 
     pub fn add_option(&mut self, tp: CoAPOption, value: Vec<u8>) {
-        let num = Self::get_option_number(tp);
+        let num = get_option_number(tp);
         match self.options.get_mut(&num) {
             Some(list) => {
                 list.push_back(value);
@@ -137,29 +197,55 @@ impl Packet {
     }
 
     pub fn get_option(&self, tp: CoAPOption) -> Option<&LinkedList<Vec<u8>>> {
-        let num = Self::get_option_number(tp);
+        let num = get_option_number(tp);
         self.options.get(&num)
     }
 
+    /// Iterates over all options in option number order as `(number, value)`
+    /// pairs.
+    pub fn options(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.options
+            .iter()
+            .flat_map(|(&number, values)| values.iter().map(move |value| (number, value.as_slice())))
+    }
+
     pub fn clear_option(&mut self, tp: CoAPOption) {
-        let num = Self::get_option_number(tp);
+        let num = get_option_number(tp);
         if let Some(list) = self.options.get_mut(&num) {
             list.clear()
         }
     }
 
+    /// Returns the Content-Format option, or `None` if it is absent or not
+    /// one of the formats of `ContentFormat`.
     pub fn get_content_format(&self) -> Option<ContentFormat> {
-        if let Some(list) = self.get_option(CoAPOption::ContentFormat) {
-            if let Some(vector) = list.front() {
-                let msb = vector[0] as u16;
-                let lsb = vector[1] as u16;
-                let number = (msb << 8) + lsb;
+        self.get_content_format_number().and_then(ContentFormat::from_u16)
+    }
 
-                return ContentFormat::from_u16(number);
-            }
-        }
+    /// Returns the number of the Content-Format option, or `None` if it is
+    /// absent or longer than two bytes.
+    pub fn get_content_format_number(&self) -> Option<u16> {
+        self.get_u16_option(CoAPOption::ContentFormat)
+    }
 
-        None
+    /// Returns the Accept option, or `None` if it is absent or not one of
+    /// the formats of `ContentFormat`.
+    pub fn get_accept(&self) -> Option<ContentFormat> {
+        self.get_accept_number().and_then(ContentFormat::from_u16)
+    }
+
+    /// Returns the number of the Accept option, or `None` if it is absent or
+    /// longer than two bytes.
+    pub fn get_accept_number(&self) -> Option<u16> {
+        self.get_u16_option(CoAPOption::Accept)
+    }
+
+    /// Decodes a uint option of at most two bytes; an empty value is 0.
+    fn get_u16_option(&self, tp: CoAPOption) -> Option<u16> {
+        match self.get_option(tp).and_then(|list| list.front()) {
+            Some(value) if value.len() <= 2 => Some(decode_uint(value) as u16),
+            _ => None,
+        }
     }
 
     pub fn set_observe(&mut self, value: Vec<u8>) {
@@ -178,256 +264,218 @@ impl Packet {
     }
 
     /// Decodes a byte slice and construct the equivalent Packet.
+    ///
+    /// The message must satisfy every format rule of RFC 7252 section 3; use
+    /// `from_bytes_with_mode` with `ParseMode::Lenient` to inspect messages
+    /// which do not.
     pub fn from_bytes(buf: &[u8]) -> Result<Packet, ParseError> {
-        
-        let header_result: bincode::Result<header::HeaderRaw> = bincode::config().big_endian().deserialize(buf);
-        match header_result {
-            Ok(raw_header) => {
-                let header = header::Header::from_raw(&raw_header);
-                let token_length = header.get_token_length();
-                let options_start: usize = 4 + token_length as usize;
-
-                if token_length > 8 {
-                    return Err(ParseError::InvalidTokenLength);
-                }
-
-                if options_start > buf.len() {
-                    return Err(ParseError::InvalidTokenLength);
-                }
-
-                let token = buf[4..options_start].to_vec();
-
-                let mut idx = options_start;
-                let mut options_number = 0;
-                let mut options: BTreeMap<usize, LinkedList<Vec<u8>>> = BTreeMap::new();
-                while idx < buf.len() {
-                    let byte = buf[idx];
-
-                    if byte == 255 || idx > buf.len() {
-                        break;
-                    }
-
-                    let mut delta = (byte >> 4) as usize;
-                    let mut length = (byte & 0xF) as usize;
-
-                    idx += 1;
-
-                    // Check for special delta characters
-                    match delta {
-                        13 => {
-                            if idx >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-                            delta = buf[idx] as usize + 13;
-                            idx += 1;
-                        }
-                        14 => {
-                            if idx + 1 >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-
-                            delta = (u16::from_be(u8_to_unsigned_be!(buf, idx, idx + 1, u16)) +
-                                     269) as usize;
-                            idx += 2;
-                        }
-                        15 => {
-                            return Err(ParseError::InvalidOptionDelta);
-                        }
-                        _ => {}
-                    };
-
-                    // Check for special length characters
-                    match length {
-                        13 => {
-                            if idx >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-
-                            length = buf[idx] as usize + 13;
-                            idx += 1;
-                        }
-                        14 => {
-                            if idx + 1 >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-
-                            length = (u16::from_be(u8_to_unsigned_be!(buf, idx, idx + 1, u16)) +
-                                      269) as usize;
-                            idx += 2;
-                        }
-                        15 => {
-                            return Err(ParseError::InvalidOptionLength);
-                        }
-                        _ => {}
-                    };
-
-                    options_number += delta;
-
-                    let end = idx + length;
-                    if end > buf.len() {
-                        return Err(ParseError::InvalidOptionLength);
-                    }
-                    let options_value = buf[idx..end].to_vec();
-
-                    if options.contains_key(&options_number) {
-                        let options_list = options.get_mut(&options_number).unwrap();
-                        options_list.push_back(options_value);
-                    } else {
-                        let mut list = LinkedList::new();
-                        list.push_back(options_value);
-                        options.insert(options_number, list);
-                    }
-
-                    idx += length;
-                }
-
-                let mut payload = Vec::new();
-                if idx < buf.len() {
-                    payload = buf[(idx + 1)..buf.len()].to_vec();
-                }
+        Self::from_bytes_with_mode(buf, ParseMode::Strict)
+    }
 
+    /// Decodes a byte slice with the given parse mode.
+    pub fn from_bytes_with_mode(buf: &[u8], mode: ParseMode) -> Result<Packet, ParseError> {
+        PacketRef::from_bytes_with_mode(buf, mode).map(|packet| packet.to_packet())
+    }
 
-                Ok(Packet {
-                    header: header,
-                    token: token,
-                    options: options,
-                    payload: payload,
-                })
-            }
-            Err(_) => Err(ParseError::InvalidHeader),
+    pub(crate) fn from_parts(header: header::Header,
+                             token: Vec<u8>,
+                             options: BTreeMap<usize, LinkedList<Vec<u8>>>,
+                             payload: Vec<u8>)
+                             -> Packet {
+        Packet {
+            header,
+            token,
+            options,
+            payload,
         }
     }
 
     /// Returns a vector of bytes representing the Packet.
     pub fn to_bytes(&self) -> Result<Vec<u8>, PackageError> {
-        let mut options_delta_length = 0;
-        let mut options_bytes: Vec<u8> = Vec::new();
-        for (number, value_list) in self.options.iter() {
-            for value in value_list.iter() {
-                let mut header: Vec<u8> = Vec::with_capacity(1 + 2 + 2);
-                let delta = number - options_delta_length;
-
-                let mut byte: u8 = 0;
-                if delta <= 12 {
-                    byte |= (delta << 4) as u8;
-                } else if delta < 269 {
-                    byte |= 13 << 4;
-                } else {
-                    byte |= 14 << 4;
-                }
-                if value.len() <= 12 {
-                    byte |= value.len() as u8;
-                } else if value.len() < 269 {
-                    byte |= 13;
-                } else {
-                    byte |= 14;
-                }
-                header.push(byte);
-
-                if delta > 12 && delta < 269 {
-                    header.push((delta - 13) as u8);
-                } else if delta >= 269 {
-                    let fix = (delta - 269) as u16;
-                    header.push((fix >> 8) as u8);
-                    header.push((fix & 0xFF) as u8);
-                }
+        let buf_length = self.encoded_len();
+        if buf_length > 1280 {
+            return Err(PackageError::InvalidPacketLength);
+        }
 
-                if value.len() > 12 && value.len() < 269 {
-                    header.push((value.len() - 13) as u8);
-                } else if value.len() >= 269 {
-                    let fix = (value.len() - 269) as u16;
-                    header.push((fix >> 8) as u8);
-                    header.push((fix & 0xFF) as u8);
-                }
+        let mut buf = vec![0; buf_length];
+        self.encode_into(&mut buf)?;
+        Ok(buf)
+    }
 
-                options_delta_length += delta;
-
-                options_bytes.reserve(header.len() + value.len());
-                unsafe {
-                    use std::ptr;
-                    let buf_len = options_bytes.len();
-                    ptr::copy(header.as_ptr(),
-                              options_bytes.as_mut_ptr().offset(buf_len as isize),
-                              header.len());
-                    ptr::copy(value.as_ptr(),
-                              options_bytes.as_mut_ptr().offset((buf_len + header.len()) as isize),
-                              value.len());
-                    options_bytes.set_len(buf_len + header.len() + value.len());
-                }
+    /// Returns the number of bytes `encode_into` writes.
+    pub fn encoded_len(&self) -> usize {
+        let mut length = 4 + self.token.len();
+
+        let mut last_number = 0;
+        for (number, value_list) in self.options.iter() {
+            for value in value_list.iter() {
+                length += option_header_len(number - last_number, value.len()) + value.len();
+                last_number = *number;
             }
         }
 
-        let mut buf_length = 4 + self.payload.len() + self.token.len();
-        if self.header.code != header::MessageClass::Empty && self.payload.len() != 0 {
-            buf_length += 1;
+        if self.has_payload() {
+            length += 1 + self.payload.len();
         }
-        buf_length += options_bytes.len();
+        length
+    }
 
-        if buf_length > 1280 {
-            return Err(PackageError::InvalidPacketLength);
+    /// Encodes the Packet into the start of `buf` without allocating, and
+    /// returns the number of bytes written.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, PackageError> {
+        let length = self.encoded_len();
+        if buf.len() < length {
+            return Err(PackageError::BufferTooSmall);
         }
 
-        let mut buf: Vec<u8> = Vec::with_capacity(buf_length);
-        let header_result: bincode::Result<()> =
-            bincode::config().big_endian().serialize_into(&mut buf, &self.header.to_raw());
-
-
-        match header_result {
-            Ok(_) => {
-                buf.reserve(self.token.len() + options_bytes.len());
-                unsafe {
-                    use std::ptr;
-                    let buf_len = buf.len();
-                    ptr::copy(self.token.as_ptr(),
-                              buf.as_mut_ptr().offset(buf_len as isize),
-                              self.token.len());
-                    ptr::copy(options_bytes.as_ptr(),
-                              buf.as_mut_ptr().offset((buf_len + self.token.len()) as isize),
-                              options_bytes.len());
-                    buf.set_len(buf_len + self.token.len() + options_bytes.len());
-                }
+        self.encode(&mut SliceSink { buf, pos: 0 })?;
+        Ok(length)
+    }
 
-                if self.header.code != header::MessageClass::Empty && self.payload.len() != 0 {
-                    buf.push(0xFF);
-                    buf.reserve(self.payload.len());
-                    unsafe {
-                        use std::ptr;
-                        let buf_len = buf.len();
-                        ptr::copy(self.payload.as_ptr(),
-                                  buf.as_mut_ptr().offset(buf.len() as isize),
-                                  self.payload.len());
-                        buf.set_len(buf_len + self.payload.len());
-                    }
-                }
-                Ok(buf)
-            }
-            Err(_) => Err(PackageError::InvalidHeader),
+    /// Appends the encoded Packet to `buf`, and returns the number of bytes
+    /// written.
+    #[cfg(feature = "bytes")]
+    pub fn encode_to<B: bytes::BufMut>(&self, buf: &mut B) -> Result<usize, PackageError> {
+        let length = self.encoded_len();
+        if buf.remaining_mut() < length {
+            return Err(PackageError::BufferTooSmall);
         }
+
+        self.encode(buf)?;
+        Ok(length)
     }
 
-    fn get_option_number(tp: CoAPOption) -> usize {
-        match tp {
-            CoAPOption::IfMatch => 1,
-            CoAPOption::UriHost => 3,
-            CoAPOption::ETag => 4,
-            CoAPOption::IfNoneMatch => 5,
-            CoAPOption::Observe => 6,
-            CoAPOption::UriPort => 7,
-            CoAPOption::LocationPath => 8,
-            CoAPOption::UriPath => 11,
-            CoAPOption::ContentFormat => 12,
-            CoAPOption::MaxAge => 14,
-            CoAPOption::UriQuery => 15,
-            CoAPOption::Accept => 17,
-            CoAPOption::LocationQuery => 20,
-            CoAPOption::Block2 => 23,
-            CoAPOption::Block1 => 27,
-            CoAPOption::ProxyUri => 35,
-            CoAPOption::ProxyScheme => 39,
-            CoAPOption::Size1 => 60,
-            CoAPOption::Size2 => 28,
-            CoAPOption::NoResponse => 258
+    fn encode<S: Sink>(&self, sink: &mut S) -> Result<(), PackageError> {
+        sink.put(&self.header.to_bytes());
+        sink.put(&self.token);
+
+        let mut last_number = 0;
+        for (number, value_list) in self.options.iter() {
+            for value in value_list.iter() {
+                let (option_header, option_header_len) = option_header(number - last_number, value.len())?;
+                sink.put(&option_header[..option_header_len]);
+                sink.put(value);
+                last_number = *number;
+            }
         }
+
+        if self.has_payload() {
+            sink.put(&[0xFF]);
+            sink.put(&self.payload);
+        }
+        Ok(())
+    }
+
+    fn has_payload(&self) -> bool {
+        self.header.code != header::MessageClass::Empty && !self.payload.is_empty()
+    }
+}
+
+pub(crate) fn get_option_number(tp: CoAPOption) -> usize {
+    match tp {
+        CoAPOption::IfMatch => 1,
+        CoAPOption::UriHost => 3,
+        CoAPOption::ETag => 4,
+        CoAPOption::IfNoneMatch => 5,
+        CoAPOption::Observe => 6,
+        CoAPOption::UriPort => 7,
+        CoAPOption::LocationPath => 8,
+        CoAPOption::UriPath => 11,
+        CoAPOption::ContentFormat => 12,
+        CoAPOption::MaxAge => 14,
+        CoAPOption::UriQuery => 15,
+        CoAPOption::Accept => 17,
+        CoAPOption::LocationQuery => 20,
+        CoAPOption::Block2 => 23,
+        CoAPOption::Block1 => 27,
+        CoAPOption::ProxyUri => 35,
+        CoAPOption::ProxyScheme => 39,
+        CoAPOption::Size1 => 60,
+        CoAPOption::Size2 => 28,
+        CoAPOption::NoResponse => 258,
+        CoAPOption::HopLimit => 16,
+        CoAPOption::Oscore => 9,
+        CoAPOption::Echo => 252,
+        CoAPOption::RequestTag => 292,
+    }
+}
+
+/// The largest option delta or value length an option header can encode.
+const MAX_OPTION_FIELD: usize = 269 + u16::MAX as usize;
+
+/// Encodes an option header for the given delta and value length, returning
+/// the header bytes and how many of them are used.
+pub(crate) fn option_header(delta: usize, length: usize) -> Result<([u8; 5], usize), PackageError> {
+    if delta > MAX_OPTION_FIELD || length > MAX_OPTION_FIELD {
+        return Err(PackageError::InvalidOptionLength);
+    }
+
+    let mut header = [0; 5];
+    let mut used = 1;
+
+    let (delta_nibble, length_nibble);
+    if delta <= 12 {
+        delta_nibble = delta as u8;
+    } else if delta < 269 {
+        delta_nibble = 13;
+        header[used] = (delta - 13) as u8;
+        used += 1;
+    } else {
+        delta_nibble = 14;
+        header[used..used + 2].copy_from_slice(&((delta - 269) as u16).to_be_bytes());
+        used += 2;
+    }
+    if length <= 12 {
+        length_nibble = length as u8;
+    } else if length < 269 {
+        length_nibble = 13;
+        header[used] = (length - 13) as u8;
+        used += 1;
+    } else {
+        length_nibble = 14;
+        header[used..used + 2].copy_from_slice(&((length - 269) as u16).to_be_bytes());
+        used += 2;
+    }
+    header[0] = delta_nibble << 4 | length_nibble;
+
+    Ok((header, used))
+}
+
+/// The length of the option header `option_header` encodes, whether or not
+/// the delta and length fit.
+fn option_header_len(delta: usize, length: usize) -> usize {
+    let extended = |field: usize| match field {
+        0..=12 => 0,
+        13..=268 => 1,
+        _ => 2,
+    };
+    1 + extended(delta) + extended(length)
+}
+
+/// Destination of the packet encoder. Callers check the capacity up front.
+#[cfg(feature = "alloc")]
+trait Sink {
+    fn put(&mut self, bytes: &[u8]);
+}
+
+#[cfg(feature = "alloc")]
+struct SliceSink<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> Sink for SliceSink<'a> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl<B: bytes::BufMut> Sink for B {
+    fn put(&mut self, bytes: &[u8]) {
+        self.put_slice(bytes);
     }
 }
 
@@ -496,7 +544,7 @@ mod test {
         packet.header.set_type(header::MessageType::Confirmable);
         packet.header.code = header::MessageClass::Request(header::RequestType::Get);
         packet.header.set_message_id(33950);
-        packet.set_token(vec![0x51, 0x55, 0x77, 0xE8]);
+        packet.set_token(vec![0x51, 0x55, 0x77, 0xE8]).unwrap();
         packet.add_option(CoAPOption::UriPath, b"Hi".to_vec());
         packet.add_option(CoAPOption::UriPath, b"Test".to_vec());
         packet.add_option(CoAPOption::UriQuery, b"a=1".to_vec());
@@ -505,6 +553,22 @@ mod test {
                         0x54, 0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31]);
     }
 
+    #[test]
+    fn test_encode_oversized_option() {
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::UriQuery, vec![b'a'; 65805]);
+        let mut buf = vec![0; packet.encoded_len()];
+        match packet.encode_into(&mut buf) {
+            Err(PackageError::InvalidOptionLength) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::UriQuery, vec![b'a'; 65804]);
+        let mut buf = vec![0; packet.encoded_len()];
+        assert_eq!(packet.encode_into(&mut buf).unwrap(), buf.len());
+    }
+
     #[test]
     fn test_encode_packet_with_payload() {
         let mut packet = Packet::new();
@@ -512,13 +576,66 @@ mod test {
         packet.header.set_type(header::MessageType::Acknowledgement);
         packet.header.code = header::MessageClass::Response(header::ResponseType::Content);
         packet.header.set_message_id(5117);
-        packet.set_token(vec![0xD0, 0xE2, 0x4D, 0xAC]);
+        packet.set_token(vec![0xD0, 0xE2, 0x4D, 0xAC]).unwrap();
         packet.payload = "Hello".as_bytes().to_vec();
         assert_eq!(packet.to_bytes().unwrap(),
                    vec![0x64, 0x45, 0x13, 0xFD, 0xD0, 0xE2, 0x4D, 0xAC, 0xFF, 0x48, 0x65, 0x6C,
                         0x6C, 0x6F]);
     }
 
+    #[test]
+    fn test_encode_into_slice() {
+        let mut packet = Packet::new();
+        packet.header.set_type(header::MessageType::Acknowledgement);
+        packet.header.code = header::MessageClass::Response(header::ResponseType::Content);
+        packet.header.set_message_id(5117);
+        packet.set_token(vec![0xD0, 0xE2, 0x4D, 0xAC]).unwrap();
+        packet.add_option(CoAPOption::LocationPath, vec![b'a'; 20]);
+        packet.add_option(CoAPOption::NoResponse, vec![b'b'; 300]);
+        packet.payload = "Hello".as_bytes().to_vec();
+
+        let expected = packet.to_bytes().unwrap();
+        assert_eq!(packet.encoded_len(), expected.len());
+
+        let mut buf = [0; 1024];
+        let length = packet.encode_into(&mut buf).unwrap();
+        assert_eq!(&buf[..length], &expected[..]);
+
+        let decoded = Packet::from_bytes(&buf[..length]).unwrap();
+        assert_eq!(*decoded.get_option(CoAPOption::NoResponse).unwrap().front().unwrap(),
+                   vec![b'b'; 300]);
+
+        let mut small = [0; 16];
+        match packet.encode_into(&mut small) {
+            Err(PackageError::BufferTooSmall) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_encode_to_buf_mut() {
+        let mut packet = Packet::new();
+        packet.set_token(vec![0x51, 0x55]).unwrap();
+        packet.add_option(CoAPOption::UriPath, b"Hi".to_vec());
+        packet.payload = b"data".to_vec();
+
+        let mut buf = bytes::BytesMut::new();
+        buf.extend_from_slice(b"prefix");
+        let length = packet.encode_to(&mut buf).unwrap();
+        assert_eq!(length, buf.len() - 6);
+        assert_eq!(&buf[6..], &packet.to_bytes().unwrap()[..]);
+    }
+
+    #[test]
+    fn test_set_token() {
+        let mut packet = Packet::new();
+        assert!(packet.set_token(vec![0; 8]).is_ok());
+        assert_eq!(packet.header.get_token_length(), 8);
+        assert!(packet.set_token(vec![0; 9]).is_err());
+        assert_eq!(packet.get_token().len(), 8);
+    }
+
     #[test]
     fn test_encode_decode_content_format() {
         let mut packet = Packet::new();
@@ -526,16 +643,103 @@ mod test {
         assert_eq!(ContentFormat::ApplicationJSON, packet.get_content_format().unwrap())
     }
 
+    #[test]
+    fn test_decode_short_content_format() {
+        let mut packet = Packet::new();
+        packet.set_content_format(ContentFormat::TextPlain);
+        assert_eq!(packet.get_option(CoAPOption::ContentFormat).unwrap().front().unwrap().len(), 0);
+        assert_eq!(packet.get_content_format(), Some(ContentFormat::TextPlain));
+
+        packet.set_content_format(ContentFormat::ApplicationCBOR);
+        assert_eq!(packet.get_option(CoAPOption::ContentFormat).unwrap().len(), 1);
+        assert_eq!(packet.get_content_format(), Some(ContentFormat::ApplicationCBOR));
+
+        packet.set_content_format_number(11050);
+        assert_eq!(packet.get_content_format(), None);
+        assert_eq!(packet.get_content_format_number(), Some(11050));
+
+        packet.clear_option(CoAPOption::ContentFormat);
+        packet.add_option(CoAPOption::ContentFormat, vec![0, 0, 50]);
+        assert_eq!(packet.get_content_format_number(), None);
+
+        packet.set_accept_number(50);
+        assert_eq!(packet.get_accept(), Some(ContentFormat::ApplicationJSON));
+        assert_eq!(packet.get_option(CoAPOption::Accept).unwrap().front().unwrap(), &vec![50]);
+    }
+
     #[test]
     fn test_decode_empty_content_format() {
         let packet = Packet::new();
         assert!(packet.get_content_format().is_none());
     }
 
+    #[test]
+    fn test_decode_strict_errors() {
+        // version 2
+        assert_eq!(Packet::from_bytes(&[0x80, 0x01, 0x00, 0x01]).unwrap_err(),
+                   ParseError::InvalidVersion(2));
+        // token length 9
+        assert_eq!(Packet::from_bytes(&[0x49, 0x01, 0x00, 0x01]).unwrap_err(),
+                   ParseError::InvalidTokenLength(9));
+        // token length 4 but only 2 token bytes
+        assert_eq!(Packet::from_bytes(&[0x44, 0x01, 0x00, 0x01, 0x01, 0x02]).unwrap_err(),
+                   ParseError::InvalidTokenLength(4));
+        // Empty message with a token
+        assert_eq!(Packet::from_bytes(&[0x61, 0x00, 0x00, 0x01, 0xAA]).unwrap_err(),
+                   ParseError::InvalidEmptyMessage);
+        // Empty message with an option
+        assert_eq!(Packet::from_bytes(&[0x60, 0x00, 0x00, 0x01, 0x60]).unwrap_err(),
+                   ParseError::InvalidEmptyMessage);
+        // reserved delta 15 in the second option
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xB1, 0x61, 0xF1, 0x00]).unwrap_err(),
+                   ParseError::InvalidOptionDelta { offset: 6 });
+        // reserved length 15
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xBF, 0x61]).unwrap_err(),
+                   ParseError::InvalidOptionLength { offset: 4 });
+        // extended length byte missing
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xBD]).unwrap_err(),
+                   ParseError::InvalidOptionLength { offset: 4 });
+        // option value truncated
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xB3, 0x61]).unwrap_err(),
+                   ParseError::InvalidOptionLength { offset: 4 });
+        // option number above 65535
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xE0, 0x00, 0x00, 0xE0, 0xFF, 0xFF])
+                       .unwrap_err(),
+                   ParseError::InvalidOptionDelta { offset: 7 });
+        // payload marker without payload
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xB1, 0x61, 0xFF]).unwrap_err(),
+                   ParseError::EmptyPayload { offset: 6 });
+        // too short for a header
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00]).unwrap_err(),
+                   ParseError::InvalidHeader);
+    }
+
+    #[test]
+    fn test_decode_lenient() {
+        let packet = Packet::from_bytes_with_mode(&[0x80, 0x01, 0x00, 0x01], ParseMode::Lenient)
+            .unwrap();
+        assert_eq!(packet.header.get_version(), 2);
+
+        let packet = Packet::from_bytes_with_mode(&[0x61, 0x00, 0x00, 0x01, 0xAA, 0x60],
+                                                  ParseMode::Lenient)
+            .unwrap();
+        assert_eq!(*packet.get_token(), vec![0xAA]);
+        assert!(packet.get_observe().is_some());
+
+        let packet = Packet::from_bytes_with_mode(&[0x40, 0x01, 0x00, 0x01, 0xFF],
+                                                  ParseMode::Lenient)
+            .unwrap();
+        assert!(packet.payload.is_empty());
+
+        assert_eq!(Packet::from_bytes_with_mode(&[0x40, 0x01, 0x00, 0x01, 0xBF, 0x61],
+                                                ParseMode::Lenient)
+                       .unwrap_err(),
+                   ParseError::InvalidOptionLength { offset: 4 });
+    }
+
     #[test]
     fn test_malicious_packet() {
-        use rand;
-        use quickcheck::{QuickCheck, StdGen, TestResult};
+        use quickcheck::{Gen, QuickCheck, TestResult};
 
         fn run(x: Vec<u8>) -> TestResult {
             match Packet::from_bytes(&x[..]) {
@@ -548,7 +752,7 @@ mod test {
         }
         QuickCheck::new()
             .tests(10000)
-            .gen(StdGen::new(rand::thread_rng(), 1500))
+            .rng(Gen::new(1500))
             .quickcheck(run as fn(Vec<u8>) -> TestResult)
     }
 }
@@ -0,0 +1,354 @@
+#[cfg(feature = "alloc")]
+use alloc::collections::{BTreeMap, LinkedList};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use super::header::{Header, MessageClass};
+#[cfg(feature = "alloc")]
+use super::packet::Packet;
+use super::packet::{get_option_number, option_header, CoAPOption, PackageError, ParseError, ParseMode};
+
+/// A borrowed view of an encoded message.
+///
+/// Decoding validates the whole buffer up front but copies nothing: the
+/// token, option values and payload are slices of the input, and options are
+/// decoded lazily while iterating.
+#[derive(Clone, Copy, Debug)]
+pub struct PacketRef<'a> {
+    buf: &'a [u8],
+    options_start: usize,
+    payload_start: usize,
+}
+
+impl<'a> PacketRef<'a> {
+    /// Decodes a byte slice, rejecting any message format error.
+    pub fn from_bytes(buf: &'a [u8]) -> Result<PacketRef<'a>, ParseError> {
+        Self::from_bytes_with_mode(buf, ParseMode::Strict)
+    }
+
+    /// Decodes a byte slice with the given parse mode.
+    pub fn from_bytes_with_mode(buf: &'a [u8], mode: ParseMode) -> Result<PacketRef<'a>, ParseError> {
+        let strict = mode == ParseMode::Strict;
+
+        let header = Self::decode_header(buf)?;
+
+        let version = header.get_version();
+        if strict && version != 1 {
+            return Err(ParseError::InvalidVersion(version));
+        }
+
+        let token_length = header.get_token_length();
+        let options_start: usize = 4 + token_length as usize;
+        if token_length > 8 || options_start > buf.len() {
+            return Err(ParseError::InvalidTokenLength(token_length));
+        }
+
+        if strict && header.code == MessageClass::Empty && buf.len() > 4 {
+            return Err(ParseError::InvalidEmptyMessage);
+        }
+
+        let mut idx = options_start;
+        let mut number = 0;
+        while let Some((delta, _, value_end)) = read_option(buf, idx)? {
+            number += delta;
+            if strict && number > u16::MAX as usize {
+                return Err(ParseError::InvalidOptionDelta { offset: idx });
+            }
+            idx = value_end;
+        }
+
+        if strict && idx + 1 == buf.len() {
+            return Err(ParseError::EmptyPayload { offset: idx });
+        }
+
+        Ok(PacketRef {
+            buf,
+            options_start,
+            payload_start: (idx + 1).min(buf.len()),
+        })
+    }
+
+    fn decode_header(buf: &[u8]) -> Result<Header, ParseError> {
+        if buf.len() < 4 {
+            return Err(ParseError::InvalidHeader);
+        }
+        Ok(Header::from_bytes(&[buf[0], buf[1], buf[2], buf[3]]))
+    }
+
+    /// Returns the fixed header.
+    pub fn header(&self) -> Header {
+        // The header was decoded successfully when the view was created.
+        Self::decode_header(self.buf).unwrap()
+    }
+
+    pub fn get_token(&self) -> &'a [u8] {
+        &self.buf[4..self.options_start]
+    }
+
+    /// Iterates over all options in wire order as `(number, value)` pairs.
+    pub fn options(&self) -> Options<'a> {
+        Options {
+            buf: self.buf,
+            idx: self.options_start,
+            number: 0,
+        }
+    }
+
+    /// Iterates over the values of one option.
+    pub fn get_option(&self, tp: CoAPOption) -> impl Iterator<Item = &'a [u8]> {
+        let num = get_option_number(tp);
+        self.options()
+            .skip_while(move |&(n, _)| n < num)
+            .take_while(move |&(n, _)| n == num)
+            .map(|(_, value)| value)
+    }
+
+    pub fn get_payload(&self) -> &'a [u8] {
+        &self.buf[self.payload_start..]
+    }
+
+    /// Returns the whole encoded message.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    /// Copies the view into an owned `Packet`.
+    #[cfg(feature = "alloc")]
+    pub fn to_packet(&self) -> Packet {
+        let mut options: BTreeMap<usize, LinkedList<Vec<u8>>> = BTreeMap::new();
+        for (number, value) in self.options() {
+            options.entry(number).or_default().push_back(value.to_vec());
+        }
+
+        Packet::from_parts(
+            self.header(),
+            self.get_token().to_vec(),
+            options,
+            self.get_payload().to_vec(),
+        )
+    }
+}
+
+/// Iterator over the options of a `PacketRef`.
+#[derive(Clone, Debug)]
+pub struct Options<'a> {
+    buf: &'a [u8],
+    idx: usize,
+    number: usize,
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (usize, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The options were validated when the view was created.
+        match read_option(self.buf, self.idx) {
+            Ok(Some((delta, start, end))) => {
+                self.number += delta;
+                self.idx = end;
+                Some((self.number, &self.buf[start..end]))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Encodes a message straight into a caller-provided buffer.
+///
+/// This is the allocation-free counterpart of `Packet::encode_into`, usable
+/// without the `alloc` feature. Options must be added in ascending option
+/// number order.
+#[derive(Debug)]
+pub struct PacketWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    last_number: usize,
+}
+
+impl<'a> PacketWriter<'a> {
+    /// Starts a message with the given header and token. The token length
+    /// field of the header is taken from `token`.
+    pub fn new(buf: &'a mut [u8], header: &Header, token: &[u8]) -> Result<PacketWriter<'a>, PackageError> {
+        if token.len() > 8 {
+            return Err(PackageError::InvalidHeader);
+        }
+
+        let mut header = header.clone();
+        if header.set_token_length(token.len() as u8).is_err() {
+            return Err(PackageError::InvalidHeader);
+        }
+
+        let mut writer = PacketWriter {
+            buf,
+            pos: 0,
+            last_number: 0,
+        };
+        writer.put(&header.to_bytes())?;
+        writer.put(token)?;
+        Ok(writer)
+    }
+
+    pub fn add_option(&mut self, tp: CoAPOption, value: &[u8]) -> Result<(), PackageError> {
+        self.add_option_number(get_option_number(tp), value)
+    }
+
+    /// Adds an option by number, for options `CoAPOption` does not know.
+    pub fn add_option_number(&mut self, number: usize, value: &[u8]) -> Result<(), PackageError> {
+        if number < self.last_number {
+            return Err(PackageError::InvalidOptionOrder);
+        }
+
+        let (option_header, option_header_len) = option_header(number - self.last_number, value.len())?;
+        self.put(&option_header[..option_header_len])?;
+        self.put(value)?;
+        self.last_number = number;
+        Ok(())
+    }
+
+    /// Writes the payload, if any, and returns the length of the message.
+    pub fn finish(mut self, payload: &[u8]) -> Result<usize, PackageError> {
+        if !payload.is_empty() {
+            self.put(&[0xFF])?;
+            self.put(payload)?;
+        }
+        Ok(self.pos)
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), PackageError> {
+        let end = self.pos + bytes.len();
+        if end > self.buf.len() {
+            return Err(PackageError::BufferTooSmall);
+        }
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+}
+
+/// Reads the option header at `idx`.
+///
+/// Returns the option delta and the bounds of the option value, or `None` at
+/// the payload marker or the end of the buffer.
+fn read_option(buf: &[u8], idx: usize) -> Result<Option<(usize, usize, usize)>, ParseError> {
+    let offset = idx;
+    if idx >= buf.len() || buf[idx] == 0xFF {
+        return Ok(None);
+    }
+
+    let byte = buf[idx];
+    let mut idx = idx + 1;
+
+    let delta = match byte >> 4 {
+        13 => {
+            if idx >= buf.len() {
+                return Err(ParseError::InvalidOptionDelta { offset });
+            }
+            idx += 1;
+            buf[idx - 1] as usize + 13
+        }
+        14 => {
+            if idx + 1 >= buf.len() {
+                return Err(ParseError::InvalidOptionDelta { offset });
+            }
+            idx += 2;
+            u16::from_be_bytes([buf[idx - 2], buf[idx - 1]]) as usize + 269
+        }
+        15 => return Err(ParseError::InvalidOptionDelta { offset }),
+        delta => delta as usize,
+    };
+
+    let length = match byte & 0xF {
+        13 => {
+            if idx >= buf.len() {
+                return Err(ParseError::InvalidOptionLength { offset });
+            }
+            idx += 1;
+            buf[idx - 1] as usize + 13
+        }
+        14 => {
+            if idx + 1 >= buf.len() {
+                return Err(ParseError::InvalidOptionLength { offset });
+            }
+            idx += 2;
+            u16::from_be_bytes([buf[idx - 2], buf[idx - 1]]) as usize + 269
+        }
+        15 => return Err(ParseError::InvalidOptionLength { offset }),
+        length => length as usize,
+    };
+
+    let end = idx + length;
+    if end > buf.len() {
+        return Err(ParseError::InvalidOptionLength { offset });
+    }
+
+    Ok(Some((delta, idx, end)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::header::{MessageType, RequestType};
+
+    #[test]
+    fn test_decode_packet_ref() {
+        let buf = [0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48, 0x69, 0x04, 0x54,
+                   0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31, 0xFF, 0x48, 0x69];
+        let packet = PacketRef::from_bytes(&buf).unwrap();
+        let header = packet.header();
+        assert_eq!(header.get_type(), MessageType::Confirmable);
+        assert_eq!(header.code, MessageClass::Request(RequestType::Get));
+        assert_eq!(header.get_message_id(), 33950);
+        assert_eq!(packet.get_token(), &[0x51, 0x55, 0x77, 0xE8]);
+
+        let options: Vec<(usize, &[u8])> = packet.options().collect();
+        assert_eq!(options,
+                   vec![(11, &b"Hi"[..]), (11, &b"Test"[..]), (15, &b"a=1"[..])]);
+
+        let uri_path: Vec<&[u8]> = packet.get_option(CoAPOption::UriPath).collect();
+        assert_eq!(uri_path, vec![&b"Hi"[..], &b"Test"[..]]);
+        assert_eq!(packet.get_option(CoAPOption::ETag).count(), 0);
+        assert_eq!(packet.get_payload(), b"Hi");
+        assert_eq!(packet.as_bytes(), &buf[..]);
+
+        let owned = packet.to_packet();
+        assert_eq!(owned.to_bytes().unwrap(), buf.to_vec());
+    }
+
+    #[test]
+    fn test_packet_writer() {
+        let mut header = Header::new();
+        header.set_type(MessageType::Confirmable);
+        header.set_message_id(33950);
+
+        let mut buf = [0; 64];
+        let mut writer = PacketWriter::new(&mut buf, &header, &[0x51, 0x55, 0x77, 0xE8]).unwrap();
+        writer.add_option(CoAPOption::UriPath, b"Hi").unwrap();
+        writer.add_option(CoAPOption::UriPath, b"Test").unwrap();
+        writer.add_option(CoAPOption::UriQuery, b"a=1").unwrap();
+        assert!(writer.add_option(CoAPOption::UriPath, b"late").is_err());
+        match writer.add_option_number(65805 + 15, b"") {
+            Err(PackageError::InvalidOptionLength) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let length = writer.finish(&[]).unwrap();
+
+        assert_eq!(&buf[..length],
+                   &[0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48, 0x69, 0x04,
+                     0x54, 0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31]);
+
+        let mut small = [0; 6];
+        let writer = PacketWriter::new(&mut small, &header, &[0x51]).unwrap();
+        match writer.finish(b"data") {
+            Err(PackageError::BufferTooSmall) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_packet_ref_without_payload() {
+        let buf = [0x40, 0x01, 0x00, 0x01, 0xB1, 0x61];
+        let packet = PacketRef::from_bytes(&buf).unwrap();
+        assert_eq!(packet.options().count(), 1);
+        assert!(packet.get_payload().is_empty());
+    }
+}
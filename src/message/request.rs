@@ -1,17 +1,77 @@
 use super::IsMessage;
 use super::response::CoAPResponse;
 use super::packet::{CoAPOption, Packet};
-use super::header::{Header, MessageClass};
-use std::net::SocketAddr;
-use std::str;
+use super::uri::{percent_decode, CoAPUri};
+use super::header::{Header, MessageClass, ResponseType as Status};
+use crate::error::Error;
+#[cfg(any(feature = "json", feature = "cbor"))]
+use super::body::{self, PayloadError};
+#[cfg(any(feature = "json", feature = "cbor"))]
+use super::senml::Pack;
+#[cfg(any(feature = "json", feature = "cbor"))]
+use super::packet::ContentFormat;
+#[cfg(any(feature = "json", feature = "cbor"))]
+use serde::{de::DeserializeOwned, Serialize};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::{Any, TypeId};
+use core::fmt;
+use core::net::SocketAddr;
+use core::str;
+use core::str::FromStr;
 
 pub use super::header::RequestType as Method;
 
+/// Who a request was authenticated as.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Identity {
+    /// The sender ID and ID context of the OSCORE security context the
+    /// request was protected with.
+    Oscore {
+        sender_id: Vec<u8>,
+        id_context: Option<Vec<u8>>,
+    },
+}
+
+/// Values shared with every request a server handles, at most one of each
+/// type.
+#[derive(Clone, Default)]
+pub struct AppState {
+    values: BTreeMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl AppState {
+    pub fn new() -> AppState {
+        AppState::default()
+    }
+
+    /// Stores `value`, replacing the value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+}
+
+impl fmt::Debug for AppState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AppState({} values)", self.values.len())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CoAPRequest {
     pub message: Packet,
     pub response: Option<CoAPResponse>,
     pub source: Option<SocketAddr>,
+    /// Set by the server once the request is authenticated.
+    pub identity: Option<Identity>,
+    /// Set by the server to the state it shares with every request.
+    pub state: Option<Arc<AppState>>,
 }
 
 impl CoAPRequest {
@@ -20,6 +80,8 @@ impl CoAPRequest {
             response: None,
             message: Packet::new(),
             source: None,
+            identity: None,
+            state: None,
         }
     }
 
@@ -28,9 +90,16 @@ impl CoAPRequest {
             response: CoAPResponse::new(&packet),
             message: packet,
             source: Some(source.clone()),
+            identity: None,
+            state: None,
         }
     }
 
+    /// The value of type `T` the server shares with every request, if any.
+    pub fn state<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.state.as_ref().and_then(|state| state.get())
+    }
+
     pub fn set_method(&mut self, method: Method) {
         self.message.header.code = MessageClass::Request(method);
     }
@@ -72,6 +141,193 @@ impl CoAPRequest {
             _ => "".to_string(),
         }
     }
+
+    /// Replaces the Uri-Query options with the arguments of a URI query
+    /// string such as `a=1&b=%20`, without the leading `?`.
+    pub fn set_query(&mut self, query: &str) -> Result<(), Error> {
+        let mut arguments = Vec::new();
+        for argument in query.split('&').filter(|argument| !argument.is_empty()) {
+            arguments.push(percent_decode(argument)?);
+        }
+
+        self.clear_option(CoAPOption::UriQuery);
+        for argument in arguments {
+            self.add_option(CoAPOption::UriQuery, argument.into_bytes());
+        }
+        Ok(())
+    }
+
+    /// Adds a `key=value` Uri-Query option.
+    pub fn add_query(&mut self, key: &str, value: &str) {
+        let mut argument = String::with_capacity(key.len() + 1 + value.len());
+        argument.push_str(key);
+        argument.push('=');
+        argument.push_str(value);
+        self.add_option(CoAPOption::UriQuery, argument.into_bytes());
+    }
+
+    /// Returns the Uri-Query options as `(key, value)` pairs. An argument
+    /// without `=` has an empty value.
+    pub fn get_queries(&self) -> Vec<(String, String)> {
+        match self.get_option(CoAPOption::UriQuery) {
+            Some(options) => options
+                .iter()
+                .filter_map(|option| str::from_utf8(option).ok())
+                .map(|argument| match argument.find('=') {
+                    Some(i) => (argument[..i].to_string(), argument[i + 1..].to_string()),
+                    None => (argument.to_string(), String::new()),
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the value of the first query argument named `key`.
+    pub fn get_query(&self, key: &str) -> Option<String> {
+        self.get_queries()
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the value of the first query argument named `key`, parsed as
+    /// `T`, or `None` if it is missing or does not parse.
+    pub fn get_query_as<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get_query(key).and_then(|value| value.parse().ok())
+    }
+
+    /// Evaluates If-Match and If-None-Match (RFC 7252 §5.10.8) against the
+    /// target resource: whether it `exists`, and the ETag of its current
+    /// representation, if it has one. Fails with 4.12 Precondition Failed.
+    ///
+    /// Handlers which support conditional updates call this while holding
+    /// whatever guards the resource, so the check and the update cannot be
+    /// interleaved with another request.
+    pub fn preconditions_met(&self, exists: bool, current_etag: Option<&[u8]>) -> Result<(), Status> {
+        if let Some(if_match) = self.get_option(CoAPOption::IfMatch) {
+            // An empty If-Match matches any existing representation.
+            let matched = exists && if_match.iter().any(|value| value.is_empty() || Some(value.as_slice()) == current_etag);
+            if !matched {
+                return Err(Status::PreconditionFailed);
+            }
+        }
+        if self.get_option(CoAPOption::IfNoneMatch).is_some() && exists {
+            return Err(Status::PreconditionFailed);
+        }
+        Ok(())
+    }
+
+    /// Whether the request carries a query argument accepted by `filter`.
+    pub fn matches_query(&self, filter: &QueryFilter) -> bool {
+        self.get_queries()
+            .iter()
+            .any(|(key, value)| filter.matches(key, value))
+    }
+
+    /// Sets the Uri-Host, Uri-Port, Uri-Path and Uri-Query options from a
+    /// URI, for a request sent to the server at `destination`.
+    pub fn set_uri(&mut self, uri: &CoAPUri, destination: &SocketAddr) {
+        uri.to_options(&mut self.message, destination);
+    }
+
+    /// Composes the `coap` URI of the request from its options, for a request
+    /// received by or sent to the server at `destination`. A Uri-Port above
+    /// 65535 is rejected with `Error::InvalidUrl`.
+    pub fn get_uri(&self, destination: &SocketAddr) -> Result<CoAPUri, Error> {
+        CoAPUri::from_options(&self.message, destination, false)
+    }
+
+    /// Decodes a JSON payload. The Content-Format, if any, must be
+    /// application/json.
+    #[cfg(feature = "json")]
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, PayloadError> {
+        body::from_json(&self.message)
+    }
+
+    /// Sets the payload to `value` encoded as JSON, and the Content-Format to
+    /// application/json.
+    #[cfg(feature = "json")]
+    pub fn set_json<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PayloadError> {
+        body::to_json(&mut self.message, value)
+    }
+
+    /// Decodes a CBOR payload. The Content-Format, if any, must be
+    /// application/cbor.
+    #[cfg(feature = "cbor")]
+    pub fn cbor<T: DeserializeOwned>(&self) -> Result<T, PayloadError> {
+        body::from_cbor(&self.message)
+    }
+
+    /// Sets the payload to `value` encoded as CBOR, and the Content-Format to
+    /// application/cbor.
+    #[cfg(feature = "cbor")]
+    pub fn set_cbor<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PayloadError> {
+        body::to_cbor(&mut self.message, value)
+    }
+
+    /// Decodes a SenML pack in the JSON or CBOR representation, as named by
+    /// the Content-Format.
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    pub fn senml(&self) -> Result<Pack, PayloadError> {
+        body::from_senml(&self.message)
+    }
+
+    /// Sets the payload to `pack` in the SenML or SensML representation
+    /// `format`, and the Content-Format to `format`.
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    pub fn set_senml(&mut self, pack: &Pack, format: ContentFormat) -> Result<(), PayloadError> {
+        body::to_senml(&mut self.message, pack, format)
+    }
+}
+
+/// A query filter in the style of CoRE Link Format discovery (RFC 6690
+/// §4.1), such as `rt=temperature` or `rt=temp*`.
+///
+/// A value ending in `*` matches any value with that prefix, and a filter
+/// without `=` matches any argument with that name. A server filters the
+/// links it advertises in `/.well-known/core` with the query arguments of
+/// the request this way; see `CoAPServer::add_link`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryFilter {
+    name: String,
+    value: Option<String>,
+    prefix: bool,
+}
+
+impl QueryFilter {
+    pub fn new(filter: &str) -> QueryFilter {
+        match filter.find('=') {
+            Some(i) => {
+                let value = &filter[i + 1..];
+                let (value, prefix) = match value.strip_suffix('*') {
+                    Some(value) => (value, true),
+                    None => (value, false),
+                };
+                QueryFilter {
+                    name: filter[..i].to_string(),
+                    value: Some(value.to_string()),
+                    prefix,
+                }
+            }
+            None => QueryFilter {
+                name: filter.to_string(),
+                value: None,
+                prefix: false,
+            },
+        }
+    }
+
+    /// Whether the query argument `key=value` passes the filter.
+    pub fn matches(&self, key: &str, value: &str) -> bool {
+        if key != self.name {
+            return false;
+        }
+        match self.value {
+            None => true,
+            Some(ref expected) if self.prefix => value.starts_with(expected.as_str()),
+            Some(ref expected) => value == expected,
+        }
+    }
 }
 
 impl IsMessage for CoAPRequest {
@@ -104,8 +360,8 @@ mod test {
         let mut packet = Packet::new();
         let mut request1 = CoAPRequest::new();
 
-        packet.set_token(vec![0x17, 0x38]);
-        request1.set_token(vec![0x17, 0x38]);
+        packet.set_token(vec![0x17, 0x38]).unwrap();
+        request1.set_token(vec![0x17, 0x38]).unwrap();
 
         packet.add_option(CoAPOption::UriPath, b"test-interface".to_vec());
         request1.add_option(CoAPOption::UriPath, b"test-interface".to_vec());
@@ -119,8 +375,8 @@ mod test {
         packet.header.set_type(MessageType::Confirmable);
         request1.set_type(MessageType::Confirmable);
 
-        packet.header.set_code("0.04");
-        request1.set_code("0.04");
+        packet.header.set_code("0.04").unwrap();
+        request1.set_code("0.04").unwrap();
 
         let request2 =
             CoAPRequest::from_packet(packet, &SocketAddr::from_str("127.0.0.1:1234").unwrap());
@@ -135,16 +391,16 @@ mod test {
     fn test_method() {
         let mut request = CoAPRequest::new();
 
-        request.set_code("0.01");
+        request.set_code("0.01").unwrap();
         assert_eq!(&Method::Get, request.get_method());
 
-        request.set_code("0.02");
+        request.set_code("0.02").unwrap();
         assert_eq!(&Method::Post, request.get_method());
 
-        request.set_code("0.03");
+        request.set_code("0.03").unwrap();
         assert_eq!(&Method::Put, request.get_method());
 
-        request.set_code("0.04");
+        request.set_code("0.04").unwrap();
         assert_eq!(&Method::Delete, request.get_method());
 
         request.set_method(Method::Get);
@@ -193,4 +449,102 @@ mod test {
         request.set_path(path3);
         assert_eq!(path3, request.get_path());
     }
+
+    #[test]
+    fn test_uri() {
+        let destination = SocketAddr::from_str("127.0.0.1:5683").unwrap();
+        let uri = CoAPUri::parse("coap://example.net/a%2Fb?c=d").unwrap();
+
+        let mut request = CoAPRequest::new();
+        request.set_uri(&uri, &destination);
+        assert_eq!("a/b", request.get_path());
+        assert_eq!(
+            b"example.net".to_vec(),
+            *request.get_option(CoAPOption::UriHost).unwrap().front().unwrap()
+        );
+        assert_eq!(uri, request.get_uri(&destination).unwrap());
+        assert_eq!("coap://example.net/a%2Fb?c=d", request.get_uri(&destination).unwrap().to_string());
+    }
+
+    #[test]
+    fn test_query() {
+        let mut request = CoAPRequest::new();
+        assert!(request.get_queries().is_empty());
+
+        request.set_query("limit=10&name=a%20b&flag&&ratio=x").unwrap();
+        assert_eq!(
+            vec![
+                ("limit".to_string(), "10".to_string()),
+                ("name".to_string(), "a b".to_string()),
+                ("flag".to_string(), "".to_string()),
+                ("ratio".to_string(), "x".to_string()),
+            ],
+            request.get_queries()
+        );
+        assert_eq!(Some(10), request.get_query_as::<u32>("limit"));
+        assert_eq!(None, request.get_query_as::<u32>("ratio"));
+        assert_eq!(None, request.get_query_as::<u32>("missing"));
+        assert_eq!(Some("a b".to_string()), request.get_query("name"));
+
+        request.add_query("rt", "temperature-c");
+        assert_eq!(Some("temperature-c".to_string()), request.get_query("rt"));
+
+        assert!(request.set_query("bad=%zz").is_err());
+        assert_eq!(5, request.get_queries().len());
+
+        request.set_query("").unwrap();
+        assert!(request.get_queries().is_empty());
+    }
+
+    #[test]
+    fn test_preconditions() {
+        let mut request = CoAPRequest::new();
+        assert_eq!(request.preconditions_met(false, None), Ok(()));
+
+        request.add_option(CoAPOption::IfMatch, b"stale".to_vec());
+        request.add_option(CoAPOption::IfMatch, b"v1".to_vec());
+        assert_eq!(request.preconditions_met(true, Some(b"v1")), Ok(()));
+        assert_eq!(request.preconditions_met(true, Some(b"v2")), Err(Status::PreconditionFailed));
+        assert_eq!(request.preconditions_met(true, None), Err(Status::PreconditionFailed));
+
+        // An empty If-Match only requires the resource to exist.
+        let mut request = CoAPRequest::new();
+        request.add_option(CoAPOption::IfMatch, vec![]);
+        assert_eq!(request.preconditions_met(true, None), Ok(()));
+        assert_eq!(request.preconditions_met(false, None), Err(Status::PreconditionFailed));
+
+        let mut request = CoAPRequest::new();
+        request.add_option(CoAPOption::IfNoneMatch, vec![]);
+        assert_eq!(request.preconditions_met(false, None), Ok(()));
+        assert_eq!(request.preconditions_met(true, Some(b"v1")), Err(Status::PreconditionFailed));
+    }
+
+    #[test]
+    fn test_query_filter() {
+        let mut request = CoAPRequest::new();
+        request.set_query("rt=temperature-c&if=sensor").unwrap();
+
+        assert!(request.matches_query(&QueryFilter::new("rt=temperature-c")));
+        assert!(request.matches_query(&QueryFilter::new("rt=temp*")));
+        assert!(request.matches_query(&QueryFilter::new("rt=*")));
+        assert!(request.matches_query(&QueryFilter::new("if")));
+        assert!(!request.matches_query(&QueryFilter::new("rt=temp")));
+        assert!(!request.matches_query(&QueryFilter::new("rt=light*")));
+        assert!(!request.matches_query(&QueryFilter::new("ct")));
+    }
+
+    #[test]
+    fn test_state() {
+        let mut request = CoAPRequest::new();
+        assert_eq!(request.state::<u32>(), None);
+
+        let mut state = AppState::new();
+        state.insert(1u32);
+        state.insert(String::from("name"));
+        state.insert(2u32);
+        request.state = Some(Arc::new(state));
+        assert_eq!(request.state::<u32>(), Some(&2));
+        assert_eq!(request.state::<String>().map(String::as_str), Some("name"));
+        assert_eq!(request.state::<u64>(), None);
+    }
 }
@@ -1,6 +1,17 @@
 use super::IsMessage;
 use super::packet::Packet;
+use super::uri::CoAPUri;
 use super::header::{Header, MessageClass, MessageType};
+#[cfg(any(feature = "json", feature = "cbor"))]
+use super::body::{self, PayloadError};
+#[cfg(any(feature = "json", feature = "cbor"))]
+use super::senml::Pack;
+#[cfg(any(feature = "json", feature = "cbor"))]
+use super::packet::ContentFormat;
+#[cfg(any(feature = "json", feature = "cbor"))]
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(any(feature = "json", feature = "cbor"))]
+use super::packet::CoAPOption;
 
 pub use super::header::ResponseType as Status;
 
@@ -24,7 +35,7 @@ impl CoAPResponse {
         packet
             .header
             .set_message_id(request.header.get_message_id());
-        packet.set_token(request.get_token().clone());
+        packet.set_token(request.get_token().clone()).unwrap();
 
         packet.payload = request.payload.clone();
 
@@ -42,6 +53,7 @@ impl CoAPResponse {
             MessageClass::Response(Status::Valid) => &Status::Valid,
             MessageClass::Response(Status::Changed) => &Status::Changed,
             MessageClass::Response(Status::Content) => &Status::Content,
+            MessageClass::Response(Status::Continue) => &Status::Continue,
 
             MessageClass::Response(Status::BadRequest) => &Status::BadRequest,
             MessageClass::Response(Status::Unauthorized) => &Status::Unauthorized,
@@ -53,6 +65,8 @@ impl CoAPResponse {
             MessageClass::Response(Status::PreconditionFailed) => &Status::PreconditionFailed,
             MessageClass::Response(Status::RequestEntityTooLarge) => &Status::RequestEntityTooLarge,
             MessageClass::Response(Status::UnsupportedContentFormat) => &Status::UnsupportedContentFormat,
+            MessageClass::Response(Status::RequestEntityIncomplete) => &Status::RequestEntityIncomplete,
+            MessageClass::Response(Status::TooManyRequests) => &Status::TooManyRequests,
 
             MessageClass::Response(Status::InternalServerError) => &Status::InternalServerError,
             MessageClass::Response(Status::NotImplemented) => &Status::NotImplemented,
@@ -60,9 +74,67 @@ impl CoAPResponse {
             MessageClass::Response(Status::ServiceUnavailable) => &Status::ServiceUnavailable,
             MessageClass::Response(Status::GatewayTimeout) => &Status::GatewayTimeout,
             MessageClass::Response(Status::ProxyingNotSupported) => &Status::ProxyingNotSupported,
+            MessageClass::Response(Status::HopLimitReached) => &Status::HopLimitReached,
             _ => &Status::UnKnown,
         }
     }
+
+    /// Resolves the Location-Path and Location-Query options, as sent with a
+    /// 2.01 Created, against the URI of the request.
+    pub fn get_location(&self, request_uri: &CoAPUri) -> CoAPUri {
+        request_uri.resolve_location(&self.message)
+    }
+
+    /// Decodes a JSON payload. The Content-Format, if any, must be
+    /// application/json.
+    #[cfg(feature = "json")]
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, PayloadError> {
+        body::from_json(&self.message)
+    }
+
+    /// Sets the payload to `value` encoded as JSON, and the Content-Format to
+    /// application/json.
+    #[cfg(feature = "json")]
+    pub fn set_json<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PayloadError> {
+        body::to_json(&mut self.message, value)
+    }
+
+    /// Decodes a CBOR payload. The Content-Format, if any, must be
+    /// application/cbor.
+    #[cfg(feature = "cbor")]
+    pub fn cbor<T: DeserializeOwned>(&self) -> Result<T, PayloadError> {
+        body::from_cbor(&self.message)
+    }
+
+    /// Sets the payload to `value` encoded as CBOR, and the Content-Format to
+    /// application/cbor.
+    #[cfg(feature = "cbor")]
+    pub fn set_cbor<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PayloadError> {
+        body::to_cbor(&mut self.message, value)
+    }
+
+    /// Decodes a SenML pack in the JSON or CBOR representation, as named by
+    /// the Content-Format.
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    pub fn senml(&self) -> Result<Pack, PayloadError> {
+        body::from_senml(&self.message)
+    }
+
+    /// Sets the payload to `pack` in the SenML or SensML representation
+    /// `format`, and the Content-Format to `format`.
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    pub fn set_senml(&mut self, pack: &Pack, format: ContentFormat) -> Result<(), PayloadError> {
+        body::to_senml(&mut self.message, pack, format)
+    }
+
+    /// Answers with the response code for a request payload which failed to
+    /// decode, without a payload.
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    pub fn set_payload_error(&mut self, error: &PayloadError) {
+        self.set_status(error.status());
+        self.message.payload.clear();
+        self.message.clear_option(CoAPOption::ContentFormat);
+    }
 }
 
 impl IsMessage for CoAPResponse {
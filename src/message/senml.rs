@@ -0,0 +1,568 @@
+//! Sensor Measurement Lists (SenML, RFC 8428).
+//!
+//! A `Pack` is a list of `Record`s as sent on the wire, where base fields
+//! such as the base name and base time apply to the records that follow.
+//! `Pack::resolve` turns it into self-contained `ResolvedRecord`s.
+//!
+//! The JSON representation (application/senml+json) needs the `json`
+//! feature and the CBOR representation (application/senml+cbor) the `cbor`
+//! feature. Both are also available through `CoAPRequest::senml` and
+//! `CoAPResponse::set_senml`.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::body::PayloadError;
+
+/// The highest SenML version this implementation understands.
+pub const SENML_VERSION: u64 = 10;
+
+/// Resolved times below 2^28 seconds are relative to the current time.
+const RELATIVE_TIME_LIMIT: f64 = 268_435_456.0;
+
+/// The value of a record.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Data(Vec<u8>),
+}
+
+/// One record of a pack, with the fields as sent on the wire.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Record {
+    pub base_name: Option<String>,
+    pub base_time: Option<f64>,
+    pub base_unit: Option<String>,
+    pub base_value: Option<f64>,
+    pub base_sum: Option<f64>,
+    pub base_version: Option<u64>,
+    pub name: Option<String>,
+    pub unit: Option<String>,
+    pub value: Option<Value>,
+    pub sum: Option<f64>,
+    pub time: Option<f64>,
+    pub update_time: Option<f64>,
+}
+
+/// A record with the base fields applied, as in RFC 8428 §4.6.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvedRecord {
+    pub name: String,
+    pub unit: Option<String>,
+    pub value: Option<Value>,
+    pub sum: Option<f64>,
+    /// The absolute time in seconds since the Unix epoch.
+    pub time: f64,
+    pub update_time: Option<f64>,
+}
+
+/// A SenML pack.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pack {
+    pub records: Vec<Record>,
+}
+
+impl Pack {
+    pub fn new(records: Vec<Record>) -> Pack {
+        Pack { records }
+    }
+
+    /// Applies the base fields to every record. Relative times are taken
+    /// relative to `now`, in seconds since the Unix epoch.
+    ///
+    /// Fails if a record has no value nor sum, if a resolved name is not a
+    /// valid SenML name, or if the pack needs a newer SenML version.
+    pub fn resolve(&self, now: f64) -> Result<Vec<ResolvedRecord>, PayloadError> {
+        let mut base = Record::default();
+        let mut resolved = Vec::with_capacity(self.records.len());
+
+        for record in &self.records {
+            if let Some(version) = record.base_version {
+                if version > SENML_VERSION {
+                    return Err(invalid(format!("unsupported SenML version {}", version)));
+                }
+            }
+            if record.base_name.is_some() {
+                base.base_name = record.base_name.clone();
+            }
+            if record.base_time.is_some() {
+                base.base_time = record.base_time;
+            }
+            if record.base_unit.is_some() {
+                base.base_unit = record.base_unit.clone();
+            }
+            if record.base_value.is_some() {
+                base.base_value = record.base_value;
+            }
+            if record.base_sum.is_some() {
+                base.base_sum = record.base_sum;
+            }
+
+            let mut name = base.base_name.clone().unwrap_or_default();
+            name.push_str(record.name.as_deref().unwrap_or(""));
+            if !is_valid_name(&name) {
+                return Err(invalid(format!("invalid name {:?}", name)));
+            }
+
+            let value = match record.value {
+                Some(Value::Number(value)) => Some(Value::Number(base.base_value.unwrap_or(0.0) + value)),
+                ref value => value.clone(),
+            };
+            let sum = record.sum.map(|sum| base.base_sum.unwrap_or(0.0) + sum);
+            if value.is_none() && sum.is_none() {
+                return Err(invalid(format!("record {:?} has neither a value nor a sum", name)));
+            }
+
+            let mut time = base.base_time.unwrap_or(0.0) + record.time.unwrap_or(0.0);
+            if time < RELATIVE_TIME_LIMIT {
+                time += now;
+            }
+
+            resolved.push(ResolvedRecord {
+                name,
+                unit: record.unit.clone().or_else(|| base.base_unit.clone()),
+                value,
+                sum,
+                time,
+                update_time: record.update_time,
+            });
+        }
+        Ok(resolved)
+    }
+}
+
+fn invalid(message: String) -> PayloadError {
+    PayloadError::Invalid(message)
+}
+
+/// A name starts with a letter or digit and continues with letters, digits
+/// and `-:./_` (RFC 8428 §4.5.1).
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphanumeric() => {
+            chars.all(|c| c.is_ascii_alphanumeric() || "-:./_".contains(c))
+        }
+        _ => false,
+    }
+}
+
+/// A field value independent of the representation.
+enum Field {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Bytes(Vec<u8>),
+}
+
+/// The field names and their CBOR labels (RFC 8428 §6).
+const LABELS: &[(&str, i64)] = &[
+    ("bver", -1),
+    ("bn", -2),
+    ("bt", -3),
+    ("bu", -4),
+    ("bv", -5),
+    ("bs", -6),
+    ("n", 0),
+    ("u", 1),
+    ("v", 2),
+    ("vs", 3),
+    ("vb", 4),
+    ("s", 5),
+    ("t", 6),
+    ("ut", 7),
+    ("vd", 8),
+];
+
+impl Record {
+    fn set(&mut self, key: &str, field: Field) -> Result<(), PayloadError> {
+        match (key, field) {
+            ("bn", Field::Text(text)) => self.base_name = Some(text),
+            ("bt", Field::Number(number)) => self.base_time = Some(number),
+            ("bu", Field::Text(text)) => self.base_unit = Some(text),
+            ("bv", Field::Number(number)) => self.base_value = Some(number),
+            ("bs", Field::Number(number)) => self.base_sum = Some(number),
+            ("bver", Field::Number(number)) if number >= 0.0 && number.fract() == 0.0 => {
+                self.base_version = Some(number as u64)
+            }
+            ("n", Field::Text(text)) => self.name = Some(text),
+            ("u", Field::Text(text)) => self.unit = Some(text),
+            ("v", Field::Number(number)) => self.set_value(Value::Number(number))?,
+            ("vs", Field::Text(text)) => self.set_value(Value::String(text))?,
+            ("vb", Field::Bool(b)) => self.set_value(Value::Bool(b))?,
+            ("vd", Field::Bytes(data)) => self.set_value(Value::Data(data))?,
+            ("s", Field::Number(number)) => self.sum = Some(number),
+            ("t", Field::Number(number)) => self.time = Some(number),
+            ("ut", Field::Number(number)) => self.update_time = Some(number),
+            (key, _) if LABELS.iter().any(|&(name, _)| name == key) => {
+                return Err(invalid(format!("invalid type for field {}", key)))
+            }
+            // Unknown fields must be understood only if their name ends
+            // with an underscore (RFC 8428 §4.4).
+            (key, _) if key.ends_with('_') => return Err(invalid(format!("unsupported field {}", key))),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn set_value(&mut self, value: Value) -> Result<(), PayloadError> {
+        if self.value.is_some() {
+            return Err(invalid("record has more than one value".to_string()));
+        }
+        self.value = Some(value);
+        Ok(())
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Field)> {
+        let mut fields = Vec::new();
+        let text = |value: &Option<String>| value.clone().map(Field::Text);
+        let number = |value: &Option<f64>| value.map(Field::Number);
+
+        let all = vec![
+            ("bn", text(&self.base_name)),
+            ("bt", number(&self.base_time)),
+            ("bu", text(&self.base_unit)),
+            ("bv", number(&self.base_value)),
+            ("bs", number(&self.base_sum)),
+            ("bver", self.base_version.map(|version| Field::Number(version as f64))),
+            ("n", text(&self.name)),
+            ("u", text(&self.unit)),
+        ];
+        fields.extend(all.into_iter().filter_map(|(key, field)| field.map(|field| (key, field))));
+        match self.value {
+            Some(Value::Number(number)) => fields.push(("v", Field::Number(number))),
+            Some(Value::String(ref text)) => fields.push(("vs", Field::Text(text.clone()))),
+            Some(Value::Bool(b)) => fields.push(("vb", Field::Bool(b))),
+            Some(Value::Data(ref data)) => fields.push(("vd", Field::Bytes(data.clone()))),
+            None => {}
+        }
+        let rest = vec![
+            ("s", number(&self.sum)),
+            ("t", number(&self.time)),
+            ("ut", number(&self.update_time)),
+        ];
+        fields.extend(rest.into_iter().filter_map(|(key, field)| field.map(|field| (key, field))));
+        fields
+    }
+}
+
+#[cfg(feature = "json")]
+impl Pack {
+    /// Decodes the JSON representation.
+    pub fn from_json(payload: &[u8]) -> Result<Pack, PayloadError> {
+        use serde_json::Value as Json;
+
+        let records = match serde_json::from_slice(payload) {
+            Ok(Json::Array(records)) => records,
+            Ok(_) => return Err(invalid("a pack must be an array".to_string())),
+            Err(e) => return Err(invalid(e.to_string())),
+        };
+        let mut pack = Pack::default();
+        for record in records {
+            let object = match record {
+                Json::Object(object) => object,
+                _ => return Err(invalid("a record must be an object".to_string())),
+            };
+            let mut record = Record::default();
+            for (key, value) in object {
+                let field = match value {
+                    Json::Number(number) => Field::Number(number.as_f64().unwrap_or(f64::NAN)),
+                    Json::String(text) if key == "vd" => Field::Bytes(base64url_decode(&text)?),
+                    Json::String(text) => Field::Text(text),
+                    Json::Bool(b) => Field::Bool(b),
+                    _ => return Err(invalid(format!("invalid type for field {}", key))),
+                };
+                record.set(&key, field)?;
+            }
+            pack.records.push(record);
+        }
+        Ok(pack)
+    }
+
+    /// Encodes the JSON representation.
+    pub fn to_json(&self) -> Vec<u8> {
+        use serde_json::{Map, Value as Json};
+
+        let records = self
+            .records
+            .iter()
+            .map(|record| {
+                let mut object = Map::new();
+                for (key, field) in record.fields() {
+                    let value = match field {
+                        Field::Number(number) => json_number(number),
+                        Field::Text(text) => Json::String(text),
+                        Field::Bool(b) => Json::Bool(b),
+                        Field::Bytes(data) => Json::String(base64url_encode(&data)),
+                    };
+                    object.insert(key.to_string(), value);
+                }
+                Json::Object(object)
+            })
+            .collect();
+        serde_json::to_vec(&Json::Array(records)).unwrap_or_default()
+    }
+}
+
+/// Numbers without a fractional part are written as integers.
+#[cfg(feature = "json")]
+fn json_number(number: f64) -> serde_json::Value {
+    if number.fract() == 0.0 && number.abs() < 9_007_199_254_740_992.0 {
+        serde_json::Value::from(number as i64)
+    } else {
+        serde_json::Value::from(number)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl Pack {
+    /// Decodes the CBOR representation.
+    pub fn from_cbor(payload: &[u8]) -> Result<Pack, PayloadError> {
+        use ciborium::value::Value as Cbor;
+
+        let records = match ciborium::de::from_reader(payload) {
+            Ok(Cbor::Array(records)) => records,
+            Ok(_) => return Err(invalid("a pack must be an array".to_string())),
+            Err(e) => return Err(invalid(e.to_string())),
+        };
+        let mut pack = Pack::default();
+        for record in records {
+            let entries = match record {
+                Cbor::Map(entries) => entries,
+                _ => return Err(invalid("a record must be a map".to_string())),
+            };
+            let mut record = Record::default();
+            for (key, value) in entries {
+                let key = match key {
+                    Cbor::Integer(label) => {
+                        let label = i128::from(label);
+                        match LABELS.iter().find(|&&(_, known)| i128::from(known) == label) {
+                            Some(&(name, _)) => name.to_string(),
+                            None => continue,
+                        }
+                    }
+                    Cbor::Text(name) => name,
+                    _ => return Err(invalid("invalid record key".to_string())),
+                };
+                let field = match value {
+                    Cbor::Integer(number) => Field::Number(i128::from(number) as f64),
+                    Cbor::Float(number) => Field::Number(number),
+                    Cbor::Text(text) => Field::Text(text),
+                    Cbor::Bool(b) => Field::Bool(b),
+                    Cbor::Bytes(data) => Field::Bytes(data),
+                    _ => return Err(invalid(format!("invalid type for field {}", key))),
+                };
+                record.set(&key, field)?;
+            }
+            pack.records.push(record);
+        }
+        Ok(pack)
+    }
+
+    /// Encodes the CBOR representation.
+    pub fn to_cbor(&self) -> Result<Vec<u8>, PayloadError> {
+        use ciborium::value::Value as Cbor;
+
+        let records = self
+            .records
+            .iter()
+            .map(|record| {
+                let entries = record
+                    .fields()
+                    .into_iter()
+                    .map(|(key, field)| {
+                        let label = LABELS.iter().find(|&&(name, _)| name == key).map(|&(_, label)| label);
+                        let value = match field {
+                            Field::Number(number) if number.fract() == 0.0 && number.abs() < 9_007_199_254_740_992.0 => {
+                                Cbor::Integer((number as i64).into())
+                            }
+                            Field::Number(number) => Cbor::Float(number),
+                            Field::Text(text) => Cbor::Text(text),
+                            Field::Bool(b) => Cbor::Bool(b),
+                            Field::Bytes(data) => Cbor::Bytes(data),
+                        };
+                        (Cbor::Integer(label.unwrap_or_default().into()), value)
+                    })
+                    .collect();
+                Cbor::Map(entries)
+            })
+            .collect();
+
+        let mut payload = Vec::new();
+        ciborium::ser::into_writer(&Cbor::Array(records), &mut payload).map_err(|e| PayloadError::Encode(e.to_string()))?;
+        Ok(payload)
+    }
+}
+
+#[cfg(feature = "json")]
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Encodes base64url without padding, as used for `vd` in JSON.
+#[cfg(feature = "json")]
+fn base64url_encode(data: &[u8]) -> String {
+    let mut text = String::with_capacity((data.len() * 4).div_ceil(3));
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().fold(0u32, |acc, &b| (acc << 8) | b as u32) << (8 * (3 - chunk.len()));
+        for i in 0..=chunk.len() {
+            text.push(BASE64URL[(bits >> (18 - 6 * i)) as usize & 0x3F] as char);
+        }
+    }
+    text
+}
+
+#[cfg(feature = "json")]
+fn base64url_decode(text: &str) -> Result<Vec<u8>, PayloadError> {
+    let text = text.trim_end_matches('=');
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return Err(invalid("truncated base64url data".to_string()));
+        }
+        let mut bits = 0u32;
+        for &c in chunk {
+            let sextet = BASE64URL
+                .iter()
+                .position(|&b| b == c)
+                .ok_or_else(|| invalid("invalid base64url data".to_string()))?;
+            bits = (bits << 6) | sextet as u32;
+        }
+        bits <<= 6 * (4 - chunk.len());
+        data.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record() -> Record {
+        Record::default()
+    }
+
+    /// The multiple-datapoint example of RFC 8428 §5.1.2.
+    fn datapoints() -> Pack {
+        Pack::new(vec![
+            Record {
+                base_name: Some("urn:dev:ow:10e2073a01080063:".to_string()),
+                base_time: Some(1.276020076001e+09),
+                base_unit: Some("A".to_string()),
+                base_version: Some(5),
+                name: Some("voltage".to_string()),
+                unit: Some("V".to_string()),
+                value: Some(Value::Number(120.1)),
+                ..record()
+            },
+            Record {
+                name: Some("current".to_string()),
+                time: Some(-5.0),
+                value: Some(Value::Number(1.2)),
+                ..record()
+            },
+            Record {
+                name: Some("current".to_string()),
+                value: Some(Value::Number(1.5)),
+                ..record()
+            },
+        ])
+    }
+
+    #[test]
+    fn test_resolve() {
+        let resolved = datapoints().resolve(0.0).unwrap();
+        assert_eq!(resolved.len(), 3);
+        assert_eq!(resolved[0].name, "urn:dev:ow:10e2073a01080063:voltage");
+        assert_eq!(resolved[0].unit.as_deref(), Some("V"));
+        assert_eq!(resolved[1].unit.as_deref(), Some("A"));
+        assert_eq!(resolved[1].time, 1.276020076001e+09 - 5.0);
+        assert_eq!(resolved[2].value, Some(Value::Number(1.5)));
+
+        let relative = Pack::new(vec![Record {
+            base_value: Some(20.0),
+            name: Some("temp".to_string()),
+            value: Some(Value::Number(1.5)),
+            time: Some(-10.0),
+            ..record()
+        }]);
+        let resolved = relative.resolve(1_600_000_000.0).unwrap();
+        assert_eq!(resolved[0].time, 1_599_999_990.0);
+        assert_eq!(resolved[0].value, Some(Value::Number(21.5)));
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let no_value = Pack::new(vec![Record {
+            name: Some("temp".to_string()),
+            ..record()
+        }]);
+        assert!(no_value.resolve(0.0).is_err());
+
+        let bad_name = Pack::new(vec![Record {
+            name: Some("-temp".to_string()),
+            value: Some(Value::Bool(true)),
+            ..record()
+        }]);
+        assert!(bad_name.resolve(0.0).is_err());
+
+        let new_version = Pack::new(vec![Record {
+            base_version: Some(11),
+            name: Some("temp".to_string()),
+            value: Some(Value::Bool(true)),
+            ..record()
+        }]);
+        assert!(new_version.resolve(0.0).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        let payload = br#"[
+            {"bn":"urn:dev:ow:10e2073a01080063:","bt":1.276020076001e+09,"bu":"A","bver":5,"n":"voltage","u":"V","v":120.1},
+            {"n":"current","t":-5,"v":1.2},
+            {"n":"current","v":1.5}
+        ]"#;
+        let pack = Pack::from_json(payload).unwrap();
+        assert_eq!(pack, datapoints());
+        assert_eq!(Pack::from_json(&pack.to_json()).unwrap(), pack);
+        assert!(String::from_utf8(pack.to_json()).unwrap().contains(r#""t":-5"#));
+
+        let data = Pack::from_json(br#"[{"n":"blob","vd":"AQID_w"}]"#).unwrap();
+        assert_eq!(data.records[0].value, Some(Value::Data(vec![1, 2, 3, 255])));
+        assert_eq!(data.to_json(), br#"[{"n":"blob","vd":"AQID_w"}]"#.to_vec());
+
+        assert!(Pack::from_json(br#"[{"n":"a","v":1,"vs":"x"}]"#).is_err());
+        assert!(Pack::from_json(br#"[{"n":"a","v":"1"}]"#).is_err());
+        assert!(Pack::from_json(br#"[{"n":"a","v":1,"foo_":1}]"#).is_err());
+        assert!(Pack::from_json(br#"[{"n":"a","v":1,"foo":1}]"#).is_ok());
+        assert!(Pack::from_json(br#"{"n":"a"}"#).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_base64url() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xFB\xFF"] {
+            assert_eq!(base64url_decode(&base64url_encode(data)).unwrap(), data.to_vec());
+        }
+        assert_eq!(base64url_encode(b"foob"), "Zm9vYg");
+        assert!(base64url_decode("Zm9vY").is_err());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor() {
+        let pack = datapoints();
+        let payload = pack.to_cbor().unwrap();
+        assert_eq!(Pack::from_cbor(&payload).unwrap(), pack);
+
+        // [{0: "a", 2: 1}] with the labels of RFC 8428 §6.
+        let payload = [0x81, 0xA2, 0x00, 0x61, b'a', 0x02, 0x01];
+        let pack = Pack::from_cbor(&payload).unwrap();
+        assert_eq!(pack.records[0].name.as_deref(), Some("a"));
+        assert_eq!(pack.records[0].value, Some(Value::Number(1.0)));
+        assert_eq!(pack.to_cbor().unwrap(), payload.to_vec());
+    }
+}
@@ -0,0 +1,438 @@
+//! Conversion between `coap` and `coaps` URIs and request options, following
+//! the decomposition and composition algorithms of RFC 7252 §6.4 and §6.5.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use core::str;
+
+use super::packet::{CoAPOption, Packet};
+use crate::error::Error;
+
+/// The default port of the `coap` scheme.
+pub const COAP_PORT: u16 = 5683;
+/// The default port of the `coaps` scheme.
+pub const COAPS_PORT: u16 = 5684;
+
+/// A decomposed `coap` or `coaps` URI.
+///
+/// The host is stored without brackets and lowercased unless it is an IP
+/// address; path segments and query arguments are stored percent-decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoAPUri {
+    pub secure: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: Vec<String>,
+    pub query: Vec<String>,
+}
+
+impl CoAPUri {
+    /// Parses an absolute URI.
+    ///
+    /// URIs with another scheme, with user information, without a host or
+    /// with a fragment are rejected with `Error::InvalidUrl`.
+    pub fn parse(uri: &str) -> Result<CoAPUri, Error> {
+        let (secure, rest) = if let Some(rest) = strip_prefix_ignore_case(uri, "coap://") {
+            (false, rest)
+        } else if let Some(rest) = strip_prefix_ignore_case(uri, "coaps://") {
+            (true, rest)
+        } else {
+            return Err(Error::InvalidUrl);
+        };
+
+        if rest.contains('#') {
+            return Err(Error::InvalidUrl);
+        }
+
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, rest) = rest.split_at(authority_end);
+        let (host, port) = parse_authority(authority)?;
+        let port = port.unwrap_or(if secure { COAPS_PORT } else { COAP_PORT });
+
+        let (path, query) = match rest.find('?') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None),
+        };
+
+        let mut segments = Vec::new();
+        if !path.is_empty() && path != "/" {
+            for segment in path[1..].split('/') {
+                segments.push(percent_decode(segment)?);
+            }
+        }
+
+        let mut arguments = Vec::new();
+        if let Some(query) = query.filter(|query| !query.is_empty()) {
+            for argument in query.split('&') {
+                arguments.push(percent_decode(argument)?);
+            }
+        }
+
+        Ok(CoAPUri {
+            secure,
+            host,
+            port,
+            path: segments,
+            query: arguments,
+        })
+    }
+
+    /// Composes the URI of a request received from or sent to `destination`,
+    /// the address of the server.
+    ///
+    /// A Uri-Port longer than two bytes or above 65535 is rejected with
+    /// `Error::InvalidUrl`.
+    pub fn from_options(packet: &Packet, destination: &SocketAddr, secure: bool) -> Result<CoAPUri, Error> {
+        let host = match first_string(packet, CoAPOption::UriHost) {
+            Some(host) => host,
+            None => destination.ip().to_string(),
+        };
+        let port = match packet.get_option(CoAPOption::UriPort).and_then(|list| list.front()) {
+            Some(value) if value.len() <= 2 => decode_uint(value) as u16,
+            Some(_) => return Err(Error::InvalidUrl),
+            None => destination.port(),
+        };
+
+        Ok(CoAPUri {
+            secure,
+            host,
+            port,
+            path: strings(packet, CoAPOption::UriPath),
+            query: strings(packet, CoAPOption::UriQuery),
+        })
+    }
+
+    /// Resolves the Location-Path and Location-Query options of a response,
+    /// such as a 2.01 Created, against the URI of the request.
+    pub fn resolve_location(&self, packet: &Packet) -> CoAPUri {
+        let path = strings(packet, CoAPOption::LocationPath);
+        let query = strings(packet, CoAPOption::LocationQuery);
+
+        let mut uri = self.clone();
+        if !path.is_empty() {
+            uri.path = path;
+            uri.query = query;
+        } else if !query.is_empty() {
+            uri.query = query;
+        }
+        uri
+    }
+
+    /// Whether the host is an IPv4 or IPv6 address rather than a name.
+    pub fn host_is_ip(&self) -> bool {
+        self.host.parse::<IpAddr>().is_ok()
+    }
+
+    /// Sets the Uri-Host, Uri-Port, Uri-Path and Uri-Query options of a
+    /// request sent to `destination`.
+    ///
+    /// Uri-Host is included unless the host is an IP address, and Uri-Port
+    /// unless the port is the port of `destination`.
+    pub fn to_options(&self, packet: &mut Packet, destination: &SocketAddr) {
+        packet.clear_option(CoAPOption::UriHost);
+        packet.clear_option(CoAPOption::UriPort);
+        packet.clear_option(CoAPOption::UriPath);
+        packet.clear_option(CoAPOption::UriQuery);
+
+        if !self.host_is_ip() {
+            packet.add_option(CoAPOption::UriHost, self.host.as_bytes().to_vec());
+        }
+        if self.port != destination.port() {
+            packet.add_option(CoAPOption::UriPort, encode_uint(self.port as u32));
+        }
+        for segment in &self.path {
+            packet.add_option(CoAPOption::UriPath, segment.as_bytes().to_vec());
+        }
+        for argument in &self.query {
+            packet.add_option(CoAPOption::UriQuery, argument.as_bytes().to_vec());
+        }
+    }
+}
+
+impl fmt::Display for CoAPUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.secure { "coaps://" } else { "coap://" })?;
+
+        if self.host.parse::<Ipv6Addr>().is_ok() {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            percent_encode(f, &self.host, is_reg_name_char)?;
+        }
+
+        let default_port = if self.secure { COAPS_PORT } else { COAP_PORT };
+        if self.port != default_port {
+            write!(f, ":{}", self.port)?;
+        }
+
+        if self.path.is_empty() {
+            f.write_str("/")?;
+        }
+        for segment in &self.path {
+            f.write_str("/")?;
+            percent_encode(f, segment, is_pchar)?;
+        }
+
+        for (i, argument) in self.query.iter().enumerate() {
+            f.write_str(if i == 0 { "?" } else { "&" })?;
+            percent_encode(f, argument, |c| c != b'&' && (is_pchar(c) || c == b'/' || c == b'?'))?;
+        }
+        Ok(())
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    match s.get(..prefix.len()) {
+        Some(head) if head.eq_ignore_ascii_case(prefix) => Some(&s[prefix.len()..]),
+        _ => None,
+    }
+}
+
+fn parse_authority(authority: &str) -> Result<(String, Option<u16>), Error> {
+    if authority.contains('@') {
+        return Err(Error::InvalidUrl);
+    }
+
+    let (host, port) = if let Some(literal) = authority.strip_prefix('[') {
+        let end = literal.find(']').ok_or(Error::InvalidUrl)?;
+        if literal[..end].parse::<Ipv6Addr>().is_err() {
+            return Err(Error::InvalidUrl);
+        }
+        let port = match &literal[end + 1..] {
+            "" => None,
+            port => Some(port.strip_prefix(':').ok_or(Error::InvalidUrl)?),
+        };
+        (literal[..end].to_string(), port)
+    } else {
+        let (host, port) = match authority.find(':') {
+            Some(i) => (&authority[..i], Some(&authority[i + 1..])),
+            None => (authority, None),
+        };
+        let host = if host.parse::<Ipv4Addr>().is_ok() {
+            host.to_string()
+        } else {
+            percent_decode(host)?.to_lowercase()
+        };
+        (host, port)
+    };
+
+    if host.is_empty() {
+        return Err(Error::InvalidUrl);
+    }
+
+    let port = match port {
+        None | Some("") => None,
+        Some(port) if port.bytes().all(|c| c.is_ascii_digit()) => {
+            Some(port.parse::<u16>().map_err(|_| Error::InvalidUrl)?)
+        }
+        Some(_) => return Err(Error::InvalidUrl),
+    };
+
+    Ok((host, port))
+}
+
+/// Decodes `%XX` escapes. The result must be valid UTF-8, as all the URI
+/// options are strings.
+pub fn percent_decode(s: &str) -> Result<String, Error> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).ok_or(Error::InvalidUrl)?;
+            let hex = str::from_utf8(hex).map_err(|_| Error::InvalidUrl)?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| Error::InvalidUrl)?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| Error::InvalidUrl)
+}
+
+fn percent_encode<F: Fn(u8) -> bool>(f: &mut fmt::Formatter, s: &str, allowed: F) -> fmt::Result {
+    for &c in s.as_bytes() {
+        if allowed(c) {
+            write!(f, "{}", c as char)?;
+        } else {
+            write!(f, "%{:02X}", c)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_unreserved(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"-._~".contains(&c)
+}
+
+fn is_reg_name_char(c: u8) -> bool {
+    is_unreserved(c) || b"!$&'()*+,;=".contains(&c)
+}
+
+fn is_pchar(c: u8) -> bool {
+    is_reg_name_char(c) || c == b':' || c == b'@'
+}
+
+fn first_string(packet: &Packet, tp: CoAPOption) -> Option<String> {
+    packet
+        .get_option(tp)
+        .and_then(|list| list.front())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+fn strings(packet: &Packet, tp: CoAPOption) -> Vec<String> {
+    match packet.get_option(tp) {
+        Some(list) => list.iter().map(|value| String::from_utf8_lossy(value).into_owned()).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Encodes an unsigned integer option value in as few bytes as possible.
+pub(crate) fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+/// Decodes an unsigned integer option value of up to 4 bytes.
+pub(crate) fn decode_uint(value: &[u8]) -> u32 {
+    value.iter().fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn uri(s: &str) -> CoAPUri {
+        CoAPUri::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_good_uri() {
+        let parsed = uri("coap://127.0.0.1");
+        assert_eq!(parsed.host, "127.0.0.1");
+        assert_eq!(parsed.port, COAP_PORT);
+        assert!(parsed.path.is_empty());
+
+        assert_eq!(uri("coap://127.0.0.1:5683").port, 5683);
+        assert_eq!(uri("coap://[::1]").host, "::1");
+        assert_eq!(uri("coap://[::1]:1234").port, 1234);
+        assert_eq!(uri("coap://[bbbb::9329:f033:f558:7418]").host, "bbbb::9329:f033:f558:7418");
+        assert_eq!(uri("coap://[bbbb::9329:f033:f558:7418]:5683").port, 5683);
+        assert_eq!(uri("COAPS://Example.COM").host, "example.com");
+        assert_eq!(uri("coaps://example.com").port, COAPS_PORT);
+        assert_eq!(uri("coap://example.com:").port, COAP_PORT);
+    }
+
+    #[test]
+    fn test_parse_bad_uri() {
+        assert!(CoAPUri::parse("coap://127.0.0.1:65536").is_err());
+        assert!(CoAPUri::parse("coap://").is_err());
+        assert!(CoAPUri::parse("coap://:5683").is_err());
+        assert!(CoAPUri::parse("127.0.0.1").is_err());
+        assert!(CoAPUri::parse("http://127.0.0.1").is_err());
+        assert!(CoAPUri::parse("coap://user@127.0.0.1").is_err());
+        assert!(CoAPUri::parse("coap://127.0.0.1/a#b").is_err());
+        assert!(CoAPUri::parse("coap://[::1/").is_err());
+        assert!(CoAPUri::parse("coap://127.0.0.1/%zz").is_err());
+        assert!(CoAPUri::parse("coap://127.0.0.1/%ff").is_err());
+    }
+
+    #[test]
+    fn test_parse_path_and_query() {
+        let parsed = uri("coap://example.net/.well-known/core?rt=temp%20sensor&if=sensor");
+        assert_eq!(parsed.path, vec![".well-known", "core"]);
+        assert_eq!(parsed.query, vec!["rt=temp sensor", "if=sensor"]);
+
+        assert!(uri("coap://example.net/").path.is_empty());
+        assert_eq!(uri("coap://example.net/a/").path, vec!["a", ""]);
+        assert_eq!(uri("coap://example.net/%E2%82%AC%2F").path, vec!["\u{20AC}/"]);
+        assert_eq!(uri("coap://example.net?a").query, vec!["a"]);
+        assert!(uri("coap://example.net/?").query.is_empty());
+    }
+
+    #[test]
+    fn test_to_options() {
+        let destination: SocketAddr = "192.0.2.1:5683".parse().unwrap();
+
+        let mut packet = Packet::new();
+        uri("coap://example.net:61616/a%20b?x=1").to_options(&mut packet, &destination);
+        assert_eq!(packet.get_option(CoAPOption::UriHost).unwrap().front(), Some(&b"example.net".to_vec()));
+        assert_eq!(packet.get_option(CoAPOption::UriPort).unwrap().front(), Some(&vec![0xF0, 0xB0]));
+        assert_eq!(packet.get_option(CoAPOption::UriPath).unwrap().front(), Some(&b"a b".to_vec()));
+        assert_eq!(packet.get_option(CoAPOption::UriQuery).unwrap().front(), Some(&b"x=1".to_vec()));
+
+        let mut packet = Packet::new();
+        uri("coap://192.0.2.1/").to_options(&mut packet, &destination);
+        assert!(packet.get_option(CoAPOption::UriHost).is_none());
+        assert!(packet.get_option(CoAPOption::UriPort).is_none());
+        assert!(packet.get_option(CoAPOption::UriPath).is_none());
+
+        // Nor is an IP literal other than the destination sent as Uri-Host.
+        for literal in &["coap://192.0.2.2/", "coap://[2001:db8::1]/"] {
+            let mut packet = Packet::new();
+            uri(literal).to_options(&mut packet, &destination);
+            assert!(packet.get_option(CoAPOption::UriHost).is_none(), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn test_from_options() {
+        let destination: SocketAddr = "[2001:db8::2:1]:5683".parse().unwrap();
+
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::UriPath, b".well-known".to_vec());
+        packet.add_option(CoAPOption::UriPath, b"core".to_vec());
+        let composed = CoAPUri::from_options(&packet, &destination, false).unwrap();
+        assert_eq!(composed.to_string(), "coap://[2001:db8::2:1]/.well-known/core");
+
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::UriHost, b"example.net".to_vec());
+        packet.add_option(CoAPOption::UriPort, vec![0x16, 0x34]);
+        packet.add_option(CoAPOption::UriPath, b"/a&b".to_vec());
+        packet.add_option(CoAPOption::UriQuery, b"k=a&b".to_vec());
+        packet.add_option(CoAPOption::UriQuery, b"?/".to_vec());
+        let composed = CoAPUri::from_options(&packet, &destination, true).unwrap();
+        assert_eq!(composed.to_string(), "coaps://example.net/%2Fa&b?k=a%26b&?/");
+
+        let composed = CoAPUri::from_options(&Packet::new(), &destination, false).unwrap();
+        assert_eq!(composed.to_string(), "coap://[2001:db8::2:1]/");
+
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::UriHost, b"example.net".to_vec());
+        packet.add_option(CoAPOption::UriPort, vec![0x01, 0x16, 0x33]);
+        assert!(CoAPUri::from_options(&packet, &destination, false).is_err());
+    }
+
+    #[test]
+    fn test_resolve_location() {
+        let base = uri("coap://example.net:1234/things?x=1");
+
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::LocationPath, b"things".to_vec());
+        packet.add_option(CoAPOption::LocationPath, b"42".to_vec());
+        assert_eq!(base.resolve_location(&packet).to_string(), "coap://example.net:1234/things/42");
+
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::LocationQuery, b"y=2".to_vec());
+        assert_eq!(base.resolve_location(&packet).to_string(), "coap://example.net:1234/things?y=2");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for s in &["coap://example.net/", "coaps://[::1]:1/a/b?c=d&e", "coap://192.0.2.1:61616/%20"] {
+            assert_eq!(uri(s).to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn test_uint() {
+        assert_eq!(encode_uint(0), Vec::<u8>::new());
+        assert_eq!(encode_uint(5683), vec![0x16, 0x33]);
+        assert_eq!(decode_uint(&[]), 0);
+        assert_eq!(decode_uint(&[0x16, 0x33]), 5683);
+    }
+}
@@ -0,0 +1,138 @@
+//! Middleware around the handler of a server.
+//!
+//! Middleware sees each request for a local resource before the handler,
+//! and can change it, answer it itself, or pass it on with `Next::run` and
+//! change the response. Middleware added first runs first, and the server
+//! puts its ETag and conditional request support last, right around the
+//! handler and its `/.well-known/core` resource, if any.
+//!
+//! Requests reach middleware once they passed the checks the server makes
+//! as they arrive (rate limits, the access policy and Echo) and were
+//! unprotected with OSCORE. Observe registrations and requests for the
+//! forward or reverse proxy pass through middleware as well, and then go to
+//! the server's observer or the proxy rather than the handler; the conditional
+//! request support and discovery only see requests for local resources.
+//! Middleware which answers a registration itself keeps it from being
+//! registered.
+//!
+//! ```no_run
+//! use std::time::Instant;
+//! use coap::{CoAPRequest, CoAPResponse, CoAPServer};
+//! use coap::middleware::Next;
+//!
+//! fn timing(request: CoAPRequest, next: &Next) -> Option<CoAPResponse> {
+//!     let start = Instant::now();
+//!     let path = request.get_path();
+//!     let response = next.run(request);
+//!     println!("{} took {:?}", path, start.elapsed());
+//!     response
+//! }
+//!
+//! let mut server = CoAPServer::new("127.0.0.1:5683").unwrap();
+//! server.add_middleware(timing);
+//! ```
+
+use std::sync::Arc;
+
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+
+/// A step between the server and its handler.
+pub trait Middleware: Sync + Send {
+    /// Handles a request, usually by passing it on to `next`. Returning
+    /// `None` sends no response.
+    fn handle(&self, request: CoAPRequest, next: &Next) -> Option<CoAPResponse>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(CoAPRequest, &Next) -> Option<CoAPResponse>,
+    F: Sync + Send,
+{
+    fn handle(&self, request: CoAPRequest, next: &Next) -> Option<CoAPResponse> {
+        self(request, next)
+    }
+}
+
+/// The rest of the chain: the middleware after the current one, then the
+/// handler.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn Fn(CoAPRequest) -> Option<CoAPResponse>,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(
+        middleware: &'a [Arc<dyn Middleware>],
+        handler: &'a dyn Fn(CoAPRequest) -> Option<CoAPResponse>,
+    ) -> Next<'a> {
+        Next { middleware, handler }
+    }
+
+    /// Passes a request down the chain and returns its response. It may be
+    /// called more than once, but the handler then sees each call as a
+    /// request of its own.
+    pub fn run(&self, request: CoAPRequest) -> Option<CoAPResponse> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, &Next::new(rest, self.handler)),
+            None => (self.handler)(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::header::{MessageType, ResponseType as Status};
+    use super::super::message::packet::Packet;
+    use super::super::message::IsMessage;
+
+    fn request(path: &str) -> CoAPRequest {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let mut request = CoAPRequest::from_packet(packet, &"127.0.0.1:5683".parse().unwrap());
+        request.set_path(path);
+        request
+    }
+
+    fn handler(request: CoAPRequest) -> Option<CoAPResponse> {
+        let mut response = request.response?;
+        response.set_payload(request.message.payload);
+        Some(response)
+    }
+
+    /// Appends `tag` to the payload of requests and responses.
+    fn tag(tag: u8) -> Arc<dyn Middleware> {
+        Arc::new(move |mut request: CoAPRequest, next: &Next| {
+            request.message.payload.push(tag);
+            let mut response = next.run(request)?;
+            response.message.payload.push(tag);
+            Some(response)
+        })
+    }
+
+    fn forbid_admin(request: CoAPRequest, next: &Next) -> Option<CoAPResponse> {
+        if request.get_path() != "admin" {
+            return next.run(request);
+        }
+        let mut response = request.response?;
+        response.set_status(Status::Forbidden);
+        Some(response)
+    }
+
+    #[test]
+    fn test_chain() {
+        let middleware = vec![tag(b'a'), Arc::new(forbid_admin) as Arc<dyn Middleware>, tag(b'b')];
+        let next = Next::new(&middleware, &handler);
+
+        let response = next.run(request("data")).unwrap();
+        assert_eq!(response.message.payload, b"abba".to_vec());
+
+        let response = next.run(request("admin")).unwrap();
+        assert_eq!(*response.get_status(), Status::Forbidden);
+        assert_eq!(response.message.payload, b"a".to_vec());
+
+        let response = Next::new(&[], &handler).run(request("data")).unwrap();
+        assert!(response.message.payload.is_empty());
+    }
+}
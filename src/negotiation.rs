@@ -0,0 +1,201 @@
+//! Content negotiation with the Accept and Content-Format options
+//! (RFC 7252 §5.10.3 and §5.10.4).
+//!
+//! A `ContentNegotiation` dispatches each request to the handler registered
+//! for the content format it asks for, and answers 4.06 Not Acceptable or
+//! 4.15 Unsupported Content-Format when there is none.
+//!
+//! ```no_run
+//! use coap::{CoAPRequest, CoAPResponse, CoAPServer, ContentNegotiation};
+//! use coap::message::packet::ContentFormat;
+//!
+//! fn text(request: CoAPRequest) -> Option<CoAPResponse> {
+//!     let mut response = request.response?;
+//!     response.message.payload = b"21.5".to_vec();
+//!     Some(response)
+//! }
+//!
+//! fn json(request: CoAPRequest) -> Option<CoAPResponse> {
+//!     let mut response = request.response?;
+//!     response.message.payload = b"{\"t\":21.5}".to_vec();
+//!     Some(response)
+//! }
+//!
+//! let negotiation = ContentNegotiation::new()
+//!     .format(ContentFormat::TextPlain as u16, text)
+//!     .format(ContentFormat::ApplicationJSON as u16, json);
+//!
+//! let mut server = CoAPServer::new("127.0.0.1:5683").unwrap();
+//! server.handle(negotiation).unwrap();
+//! ```
+
+use std::sync::Arc;
+
+use super::message::header::ResponseType as Status;
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+use super::server::CoAPHandler;
+
+/// A handler which selects one of several handlers by content format.
+#[derive(Clone, Default)]
+pub struct ContentNegotiation {
+    handlers: Vec<(u16, Arc<dyn CoAPHandler>)>,
+}
+
+impl ContentNegotiation {
+    /// Creates a handler without any content format; add them with
+    /// `format`.
+    pub fn new() -> ContentNegotiation {
+        ContentNegotiation { handlers: Vec::new() }
+    }
+
+    /// Serves the content format `number` with `handler`, which produces and
+    /// consumes representations in that format. The first format added
+    /// serves requests without an Accept option or payload.
+    pub fn format<H: CoAPHandler + 'static>(mut self, number: u16, handler: H) -> ContentNegotiation {
+        self.handlers.push((number, Arc::new(handler)));
+        self
+    }
+
+    /// Selects the content format to answer `request` with.
+    ///
+    /// A request payload must be in one of the registered formats, or the
+    /// error is 4.15 Unsupported Content-Format. The response is in the
+    /// format of the Accept option, or the error is 4.06 Not Acceptable;
+    /// without Accept, it is in the format of the payload or the first
+    /// registered format.
+    pub fn negotiate(&self, request: &CoAPRequest) -> Result<u16, Status> {
+        let content_format = request.message.get_content_format_number();
+        if let Some(number) = content_format {
+            if self.handler(number).is_none() {
+                return Err(Status::UnsupportedContentFormat);
+            }
+        }
+
+        match request.message.get_accept_number() {
+            Some(number) if self.handler(number).is_some() => Ok(number),
+            Some(_) => Err(Status::NotAcceptable),
+            None => content_format
+                .or_else(|| self.handlers.first().map(|(number, _)| *number))
+                .ok_or(Status::NotAcceptable),
+        }
+    }
+
+    fn handler(&self, number: u16) -> Option<&dyn CoAPHandler> {
+        self.handlers
+            .iter()
+            .find(|(registered, _)| *registered == number)
+            .map(|(_, handler)| &**handler)
+    }
+}
+
+impl CoAPHandler for ContentNegotiation {
+    fn handle(&self, request: CoAPRequest) -> Option<CoAPResponse> {
+        let number = match self.negotiate(&request) {
+            Ok(number) => number,
+            Err(status) => {
+                let mut response = request.response?;
+                response.set_status(status);
+                response.message.payload.clear();
+                return Some(response);
+            }
+        };
+
+        let handler = self.handler(number)?;
+        let mut response = handler.handle(request)?;
+        let success = matches!(
+            *response.get_status(),
+            Status::Created | Status::Changed | Status::Content
+        );
+        if success && !response.message.payload.is_empty() && response.message.get_content_format_number().is_none() {
+            response.message.set_content_format_number(number);
+        }
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use super::*;
+    use super::super::message::header::{MessageType, RequestType as Method};
+    use super::super::message::packet::{ContentFormat, Packet};
+
+    const LINK_FORMAT: u16 = ContentFormat::ApplicationLinkFormat as u16;
+    const CUSTOM: u16 = 65000;
+    const TEXT: u16 = ContentFormat::TextPlain as u16;
+
+    fn link_format(request: CoAPRequest) -> Option<CoAPResponse> {
+        let mut response = request.response?;
+        response.message.payload = b"</a>".to_vec();
+        Some(response)
+    }
+
+    fn custom(request: CoAPRequest) -> Option<CoAPResponse> {
+        let mut response = request.response?;
+        response.message.payload = vec![1, 2, 3];
+        Some(response)
+    }
+
+    fn negotiation() -> ContentNegotiation {
+        ContentNegotiation::new().format(LINK_FORMAT, link_format).format(CUSTOM, custom)
+    }
+
+    fn request(content_format: Option<u16>, accept: Option<u16>) -> CoAPRequest {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        if let Some(number) = content_format {
+            packet.set_content_format_number(number);
+            packet.payload = b"payload".to_vec();
+        }
+        if let Some(number) = accept {
+            packet.set_accept_number(number);
+        }
+        let source: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let mut request = CoAPRequest::from_packet(packet, &source);
+        request.set_method(Method::Post);
+        request.response = CoAPResponse::new(&request.message);
+        request
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let negotiation = negotiation();
+        assert_eq!(negotiation.negotiate(&request(None, None)), Ok(LINK_FORMAT));
+        assert_eq!(negotiation.negotiate(&request(None, Some(CUSTOM))), Ok(CUSTOM));
+        assert_eq!(negotiation.negotiate(&request(Some(CUSTOM), None)), Ok(CUSTOM));
+        assert_eq!(negotiation.negotiate(&request(Some(CUSTOM), Some(LINK_FORMAT))), Ok(LINK_FORMAT));
+        assert_eq!(negotiation.negotiate(&request(None, Some(50))), Err(Status::NotAcceptable));
+        assert_eq!(negotiation.negotiate(&request(Some(50), Some(CUSTOM))), Err(Status::UnsupportedContentFormat));
+        assert_eq!(ContentNegotiation::new().negotiate(&request(None, None)), Err(Status::NotAcceptable));
+    }
+
+    #[test]
+    fn test_handle() {
+        // Handlers may be closures holding state of their own.
+        let reading = 21.5;
+        let negotiation = negotiation().format(TEXT, move |request: CoAPRequest| {
+            let mut response = request.response?;
+            response.message.payload = reading.to_string().into_bytes();
+            Some(response)
+        });
+
+        let response = negotiation.handle(request(None, Some(TEXT))).unwrap();
+        assert_eq!(response.message.payload, b"21.5".to_vec());
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::TextPlain));
+
+        let response = negotiation.handle(request(None, Some(CUSTOM))).unwrap();
+        assert_eq!(response.message.payload, vec![1, 2, 3]);
+        assert_eq!(response.message.get_content_format_number(), Some(CUSTOM));
+
+        let response = negotiation.handle(request(None, None)).unwrap();
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::ApplicationLinkFormat));
+
+        let response = negotiation.handle(request(None, Some(50))).unwrap();
+        assert_eq!(*response.get_status(), Status::NotAcceptable);
+        assert!(response.message.payload.is_empty());
+
+        let response = negotiation.handle(request(Some(50), None)).unwrap();
+        assert_eq!(*response.get_status(), Status::UnsupportedContentFormat);
+    }
+}
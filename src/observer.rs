@@ -1,17 +1,30 @@
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 use log::{debug, warn};
-use bincode;
+use rand::random;
 
 use super::message::request::{CoAPRequest, Method};
 use super::message::response::Status;
-use super::message::packet::{ObserveOption, Packet};
+use super::message::packet::{CoAPOption, ObserveOption, Packet};
 use super::message::IsMessage;
+use super::message::uri::decode_uint;
 use super::message::header::{MessageClass, MessageType, ResponseType};
 use super::server::{QueuedMessage, TxQueue};
-
-const DEFAULT_UNACKNOWLEDGE_MESSAGE_TRY_TIMES: usize = 10;
+use super::congestion::TransmissionParameters;
+
+/// The key of the resource a request is for: its path, and its query if it
+/// has one, as the query identifies a different resource.
+pub(crate) fn resource_key(request: &CoAPRequest) -> String {
+    let mut key = request.get_path();
+    if let Some(queries) = request.get_option(CoAPOption::UriQuery) {
+        let queries: Vec<String> = queries.iter().map(|query| String::from_utf8_lossy(query).into_owned()).collect();
+        key.push('?');
+        key.push_str(&queries.join("&"));
+    }
+    key
+}
 
 pub struct Observer<N: Fn() + Send + 'static> {
     registers: HashMap<String, RegisterItem>,
@@ -21,10 +34,19 @@ pub struct Observer<N: Fn() + Send + 'static> {
     tx_sender: TxQueue,
     response_notify: N,
     current_message_id: u16,
+    /// NSTART limits the unacknowledged notifications per registrant, and
+    /// ACK_TIMEOUT and MAX_RETRANSMIT their retransmission.
+    parameters: TransmissionParameters,
+    /// The most resources one IP address may observe.
+    max_observations: Option<usize>,
+    /// Resources whose last registrant deregistered since the last call to
+    /// `take_unobserved`.
+    unobserved: Vec<String>,
 }
 
 #[derive(Debug)]
 struct RegisterItem {
+    ip: IpAddr,
     register_resources: HashSet<String>,
 }
 
@@ -41,16 +63,21 @@ struct RegisterResourceItem {
     resource: String,
     token: Vec<u8>,
     unacknowledge_message: Option<u16>,
+    /// The resource changed while no notification could be sent.
+    pending: bool,
 }
 
 #[derive(Debug)]
 struct UnacknowledgeMessageItem {
     register_resource: String,
     try_times: usize,
+    /// Doubled with every retransmission.
+    timeout: Duration,
+    retransmit_at: Instant,
 }
 
 impl<N: Fn() + Send + 'static> Observer<N> {
-    pub fn new(tx_sender: TxQueue, response_notify: N) -> Observer<N> {
+    pub fn new(tx_sender: TxQueue, parameters: TransmissionParameters, response_notify: N) -> Observer<N> {
         Observer {
             registers: HashMap::new(),
             resources: HashMap::new(),
@@ -58,10 +85,19 @@ impl<N: Fn() + Send + 'static> Observer<N> {
             unacknowledge_messages: HashMap::new(),
             tx_sender: tx_sender,
             response_notify: response_notify,
-            current_message_id: 0,
+            current_message_id: random(),
+            parameters,
+            max_observations: None,
+            unobserved: Vec::new(),
         }
     }
 
+    /// Limit how many resources one IP address may observe. Further
+    /// registrations are answered without an Observe option.
+    pub fn set_max_observations(&mut self, max_observations: Option<usize>) {
+        self.max_observations = max_observations;
+    }
+
     pub fn request_handler(&mut self, request: &CoAPRequest) -> bool {
         if request.get_type() == MessageType::Acknowledgement {
             self.acknowledge(request);
@@ -69,12 +105,12 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         }
 
         match (request.get_method(), request.get_observe()) {
-            (&Method::Get, Some(observe_option)) => match observe_option[0] {
-                x if x == ObserveOption::Register as u8 => {
+            (&Method::Get, Some(observe_option)) => match decode_uint(observe_option) {
+                x if x == ObserveOption::Register as u32 => {
                     self.register(request);
                     return false;
                 }
-                x if x == ObserveOption::Deregister as u8 => {
+                x if x == ObserveOption::Deregister as u32 => {
                     self.deregister(request);
                     return true;
                 }
@@ -88,18 +124,27 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         }
     }
 
+    /// Retransmit the unacknowledged notifications which are due.
     pub fn timer_handler(&mut self) {
+        let now = Instant::now();
         let register_resource_keys: Vec<String>;
         {
             register_resource_keys = self.unacknowledge_messages
                 .iter()
+                .filter(|(_, msg)| msg.retransmit_at <= now)
                 .map(|(_, msg)| msg.register_resource.clone())
                 .collect();
         }
 
         for register_resource_key in register_resource_keys {
-            if self.try_unacknowledge_message(&register_resource_key) {
-                self.notify_register_with_newest_resource(&register_resource_key);
+            match self.try_unacknowledge_message(&register_resource_key) {
+                Some(message_id) => {
+                    self.notify_register_with_newest_resource(&register_resource_key, message_id)
+                }
+                None => {
+                    let register = self.register_resources[&register_resource_key].register.clone();
+                    self.notify_pending(&register);
+                }
             }
         }
     }
@@ -108,9 +153,40 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         self.resource_changed(request);
     }
 
+    /// Whether the resource with the key, as `resource_key` returns it, can
+    /// be observed.
+    pub fn has_resource(&self, key: &str) -> bool {
+        self.resources.contains_key(key)
+    }
+
+    /// Forget a resource nobody observes, so that the next registration for
+    /// it is not answered from stale state.
+    pub fn remove_resource(&mut self, key: &str) {
+        if self.resources.get(key).is_some_and(|resource| resource.register_resources.is_empty()) {
+            self.resources.remove(key);
+        }
+    }
+
+    /// The keys of the resources whose last registrant deregistered since
+    /// the last call.
+    pub fn take_unobserved(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.unobserved)
+    }
+
+    /// The resource a registration or deregistration is for: the one with
+    /// its path and query if there is one, or else the one with its path.
+    fn registered_key(&self, request: &CoAPRequest) -> String {
+        let key = resource_key(request);
+        if self.resources.contains_key(&key) {
+            key
+        } else {
+            request.get_path()
+        }
+    }
+
     fn register(&mut self, request: &CoAPRequest) {
         let register_address = request.source.unwrap();
-        let resource_path = request.get_path();
+        let resource_path = self.registered_key(request);
 
         debug!("register {} {}", register_address, resource_path);
 
@@ -124,21 +200,23 @@ impl<N: Fn() + Send + 'static> Observer<N> {
             return;
         }
 
-        self.record_register_resource(&register_address, &resource_path, &request.get_token());
+        let recorded = self.record_register_resource(&register_address, &resource_path, request.get_token());
 
         let resource = self.resources.get(&resource_path).unwrap();
 
         if let Some(ref response) = request.response {
             let mut response2 = response.clone();
             response2.set_payload(resource.payload.clone());
-            response2.set_observe(vec![ObserveOption::Register as u8]);
+            if recorded {
+                response2.set_observe(vec![ObserveOption::Register as u8]);
+            }
             self.send_message(&register_address, &response2.message);
         }
     }
 
     fn deregister(&mut self, request: &CoAPRequest) {
         let register_address = request.source.unwrap();
-        let resource_path = request.get_path();
+        let resource_path = self.registered_key(request);
 
         debug!("deregister {} {}", register_address, resource_path);
 
@@ -146,7 +224,7 @@ impl<N: Fn() + Send + 'static> Observer<N> {
     }
 
     fn resource_changed(&mut self, request: &CoAPRequest) {
-        let resource_path = request.get_path();
+        let resource_path = resource_key(request);
         let ref resource_payload = request.message.payload;
 
         debug!("resource_changed {} {:?}", resource_path, resource_payload);
@@ -162,28 +240,99 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         }
 
         for register_resource_key in register_resource_keys {
-            self.gen_message_id();
-            self.notify_register_with_newest_resource(&register_resource_key);
-            self.record_unacknowledge_message(&register_resource_key);
+            if self.can_notify(&register_resource_key) {
+                self.notify(&register_resource_key);
+            } else {
+                debug!("defer notification {}", register_resource_key);
+                self.register_resources
+                    .get_mut(&register_resource_key)
+                    .unwrap()
+                    .pending = true;
+            }
+        }
+    }
+
+    /// Whether a confirmable notification can be sent to a registrant
+    /// without exceeding NSTART. A registration with an unacknowledged
+    /// notification waits for it, as the retransmissions carry the newest
+    /// state anyway.
+    fn can_notify(&self, register_resource_key: &String) -> bool {
+        let register_resource = &self.register_resources[register_resource_key];
+        register_resource.unacknowledge_message.is_none()
+            && self.outstanding(&register_resource.register) < self.parameters.nstart
+    }
+
+    fn outstanding(&self, register: &String) -> usize {
+        match self.registers.get(register) {
+            Some(register) => register
+                .register_resources
+                .iter()
+                .filter(|key| self.register_resources[*key].unacknowledge_message.is_some())
+                .count(),
+            None => 0,
+        }
+    }
+
+    fn notify(&mut self, register_resource_key: &String) {
+        let message_id = self.gen_message_id();
+        self.notify_register_with_newest_resource(register_resource_key, message_id);
+        self.record_unacknowledge_message(register_resource_key, message_id);
+        self.register_resources
+            .get_mut(register_resource_key)
+            .unwrap()
+            .pending = false;
+    }
+
+    /// Send deferred notifications to a registrant as NSTART allows.
+    fn notify_pending(&mut self, register: &String) {
+        let pending: Vec<String> = match self.registers.get(register) {
+            Some(register) => register
+                .register_resources
+                .iter()
+                .filter(|key| self.register_resources[*key].pending)
+                .cloned()
+                .collect(),
+            None => return,
+        };
+
+        for register_resource_key in pending {
+            if self.can_notify(&register_resource_key) {
+                self.notify(&register_resource_key);
+            }
         }
     }
 
     fn acknowledge(&mut self, request: &CoAPRequest) {
-        self.remove_unacknowledge_message(&request.get_message_id(), &request.get_token());
+        if let Some(source) = request.source {
+            self.remove_unacknowledge_message(&request.get_message_id(), &source);
+            self.notify_pending(&Self::format_register(&source));
+        }
     }
 
-    fn record_register_resource(&mut self, address: &SocketAddr, path: &String, token: &Vec<u8>) {
+    /// Records a registration, unless the address already observes as many
+    /// resources as allowed.
+    fn record_register_resource(&mut self, address: &SocketAddr, path: &str, token: &[u8]) -> bool {
+        let register_key = Self::format_register(address);
+        let register_resource_key = Self::format_register_resource(address, path);
+        if let Some(max_observations) = self.max_observations {
+            if !self.register_resources.contains_key(&register_resource_key)
+                && self.observations_from(address.ip()) >= max_observations
+            {
+                debug!("too many observations from {}", address.ip());
+                return false;
+            }
+        }
+
         let resource = self.resources.get_mut(path).unwrap();
-        let register_key = Self::format_register(&address);
-        let register_resource_key = Self::format_register_resource(&address, path);
 
         self.register_resources
             .entry(register_resource_key.clone())
             .or_insert(RegisterResourceItem {
                 register: register_key.clone(),
-                resource: path.clone(),
-                token: token.clone(),
+                resource: path.to_string(),
+                token: token.to_vec(),
                 unacknowledge_message: None,
+                pending: false,
             });
         resource
             .register_resources
@@ -197,6 +346,7 @@ impl<N: Fn() + Send + 'static> Observer<N> {
             }
             Entry::Vacant(v) => {
                 let mut register = RegisterItem {
+                    ip: address.ip(),
                     register_resources: HashSet::new(),
                 };
                 register.register_resources.insert(register_resource_key);
@@ -204,6 +354,16 @@ impl<N: Fn() + Send + 'static> Observer<N> {
                 v.insert(register);
             }
         };
+        true
+    }
+
+    /// How many resources are observed from an IP address, on any port.
+    fn observations_from(&self, ip: IpAddr) -> usize {
+        self.registers
+            .values()
+            .filter(|register| register.ip == ip)
+            .map(|register| register.register_resources.len())
+            .sum()
     }
 
     fn remove_register_resource(
@@ -212,7 +372,7 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         path: &String,
         token: &Vec<u8>,
     ) -> bool {
-        let register_resource_key = Self::format_register_resource(&address, path);
+        let register_resource_key = Self::format_register_resource(address, path);
 
         if let Some(register_resource) = self.register_resources.get(&register_resource_key) {
             if register_resource.token != *token {
@@ -225,14 +385,11 @@ impl<N: Fn() + Send + 'static> Observer<N> {
                     .unwrap();
             }
 
-            assert_eq!(
-                self.resources
-                    .get_mut(path)
-                    .unwrap()
-                    .register_resources
-                    .remove(&register_resource_key),
-                true
-            );
+            let resource = self.resources.get_mut(path).unwrap();
+            assert_eq!(resource.register_resources.remove(&register_resource_key), true);
+            if resource.register_resources.is_empty() {
+                self.unobserved.push(path.clone());
+            }
 
             let remove_register;
             {
@@ -271,9 +428,7 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         }
     }
 
-    fn record_unacknowledge_message(&mut self, register_resource_key: &String) {
-        let message_id = self.current_message_id;
-
+    fn record_unacknowledge_message(&mut self, register_resource_key: &String, message_id: u16) {
         let register_resource = self.register_resources
             .get_mut(register_resource_key)
             .unwrap();
@@ -282,16 +437,21 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         }
 
         register_resource.unacknowledge_message = Some(message_id);
+        let timeout = self.parameters.ack_timeout.mul_f64(self.parameters.random_factor());
         self.unacknowledge_messages.insert(
             message_id,
             UnacknowledgeMessageItem {
                 register_resource: register_resource_key.clone(),
                 try_times: 1,
+                timeout,
+                retransmit_at: Instant::now() + timeout,
             },
         );
     }
 
-    fn try_unacknowledge_message(&mut self, register_resource_key: &String) -> bool {
+    /// Count another try of the unacknowledged notification, returning its
+    /// message ID, or `None` once it is given up.
+    fn try_unacknowledge_message(&mut self, register_resource_key: &String) -> Option<u16> {
         let register_resource = self.register_resources
             .get_mut(register_resource_key)
             .unwrap();
@@ -300,10 +460,12 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         let try_again;
         {
             let unacknowledge_message = self.unacknowledge_messages.get_mut(message_id).unwrap();
-            if unacknowledge_message.try_times > DEFAULT_UNACKNOWLEDGE_MESSAGE_TRY_TIMES {
+            if unacknowledge_message.try_times > self.parameters.max_retransmit as usize {
                 try_again = false;
             } else {
                 unacknowledge_message.try_times += 1;
+                unacknowledge_message.timeout *= 2;
+                unacknowledge_message.retransmit_at = Instant::now() + unacknowledge_message.timeout;
                 try_again = true;
             }
         }
@@ -316,17 +478,21 @@ impl<N: Fn() + Send + 'static> Observer<N> {
 
             register_resource.unacknowledge_message = None;
             self.unacknowledge_messages.remove(message_id);
+            return None;
         }
 
-        return try_again;
+        Some(*message_id)
     }
 
-    fn remove_unacknowledge_message(&mut self, message_id: &u16, token: &Vec<u8>) {
+    /// Remove the unacknowledged notification with `message_id` sent to
+    /// `address`. An acknowledgement is matched by its message ID and source
+    /// only, as it carries no token.
+    fn remove_unacknowledge_message(&mut self, message_id: &u16, address: &SocketAddr) {
         if let Some(message) = self.unacknowledge_messages.get_mut(message_id) {
             let register_resource = self.register_resources
                 .get_mut(&message.register_resource)
                 .unwrap();
-            if register_resource.token != *token {
+            if register_resource.register != Self::format_register(address) {
                 return;
             }
 
@@ -336,9 +502,7 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         self.unacknowledge_messages.remove(message_id);
     }
 
-    fn notify_register_with_newest_resource(&self, register_resource_key: &String) {
-        let message_id = self.current_message_id;
-
+    fn notify_register_with_newest_resource(&self, register_resource_key: &String, message_id: u16) {
         debug!("notify {} {}", register_resource_key, message_id);
 
         let ref mut message = Packet::new();
@@ -350,12 +514,11 @@ impl<N: Fn() + Send + 'static> Observer<N> {
             let register_resource = self.register_resources.get(register_resource_key).unwrap();
             let resource = self.resources.get(&register_resource.resource).unwrap();
 
-            let mut sequence_bin =
-                bincode::config().big_endian().serialize(&resource.sequence).unwrap();
+            let mut sequence_bin = resource.sequence.to_be_bytes().to_vec();
             let index = sequence_bin.iter().position(|&x| x > 0).unwrap();
             sequence_bin.drain(0..index);
 
-            message.set_token(register_resource.token.clone());
+            message.set_token(register_resource.token.clone()).unwrap();
             message.set_observe(sequence_bin);
             message.header.set_message_id(message_id);
             message.payload = resource.payload.clone();
@@ -378,7 +541,7 @@ impl<N: Fn() + Send + 'static> Observer<N> {
     }
 
     fn gen_message_id(&mut self) -> u16 {
-        self.current_message_id += 1;
+        self.current_message_id = self.current_message_id.wrapping_add(1);
         return self.current_message_id;
     }
 
@@ -386,7 +549,7 @@ impl<N: Fn() + Send + 'static> Observer<N> {
         format!("{}", address)
     }
 
-    fn format_register_resource(address: &SocketAddr, path: &String) -> String {
+    fn format_register_resource(address: &SocketAddr, path: &str) -> String {
         format!("{}${}", address, path)
     }
 }
@@ -394,7 +557,6 @@ impl<N: Fn() + Send + 'static> Observer<N> {
 
 #[cfg(test)]
 mod test {
-    use std::io::ErrorKind;
     use std::sync::mpsc;
     use std::time::Duration;
     use super::*;
@@ -512,6 +674,135 @@ mod test {
 
         let mut client = CoAPClient::new("127.0.0.1:5691").unwrap();
         let error = client.observe(path, |_msg| {}).unwrap_err();
-        assert_eq!(error.kind(), ErrorKind::NotFound);
+        match error {
+            Error::Response(Status::NotFound) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_notifications_limited_by_nstart() {
+        let (tx, rx) = mpsc::channel();
+        let mut observer = Observer::new(tx, TransmissionParameters::default(), || {});
+        let address: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+
+        let request = |method: Method, path: &str, observe: bool, token: u8| {
+            let mut packet = Packet::new();
+            packet.header.set_type(MessageType::Confirmable);
+            packet.header.code = MessageClass::Request(method);
+            packet.set_token(vec![token]).unwrap();
+            if observe {
+                packet.set_observe(vec![ObserveOption::Register as u8]);
+            }
+            let mut request = CoAPRequest::from_packet(packet, &address);
+            request.set_path(path);
+            request
+        };
+
+        for path in &["/a", "/b"] {
+            observer.request_handler(&request(Method::Put, path, false, 0));
+        }
+        observer.request_handler(&request(Method::Get, "/a", true, 1));
+        observer.request_handler(&request(Method::Get, "/b", true, 2));
+        assert_eq!(rx.try_iter().count(), 2);
+
+        observer.request_handler(&request(Method::Put, "/a", false, 0));
+        observer.request_handler(&request(Method::Put, "/b", false, 0));
+        let notifications: Vec<QueuedMessage> = rx.try_iter().collect();
+        assert_eq!(notifications.len(), 1);
+
+        // The acknowledgement lets the deferred notification go out.
+        let first = &notifications[0].message;
+        let mut ack = Packet::new();
+        ack.header.set_type(MessageType::Acknowledgement);
+        ack.header.set_message_id(first.header.get_message_id());
+        observer.request_handler(&CoAPRequest::from_packet(ack, &address));
+
+        let notifications: Vec<QueuedMessage> = rx.try_iter().collect();
+        assert_eq!(notifications.len(), 1);
+        assert_ne!(notifications[0].message.get_token(), first.get_token());
+    }
+
+    #[test]
+    fn test_retransmission_backoff() {
+        let (tx, rx) = mpsc::channel();
+        let parameters = TransmissionParameters {
+            ack_timeout: Duration::from_millis(100),
+            ack_random_factor: 1.0,
+            max_retransmit: 2,
+            ..TransmissionParameters::default()
+        };
+        let mut observer = Observer::new(tx, parameters, || {});
+        let address: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+
+        let request = |method: Method, observe: bool| {
+            let mut packet = Packet::new();
+            packet.header.set_type(MessageType::Confirmable);
+            packet.header.code = MessageClass::Request(method);
+            if observe {
+                packet.set_observe(vec![ObserveOption::Register as u8]);
+            }
+            let mut request = CoAPRequest::from_packet(packet, &address);
+            request.set_path("/a");
+            request
+        };
+        observer.request_handler(&request(Method::Put, false));
+        observer.request_handler(&request(Method::Get, true));
+        observer.request_handler(&request(Method::Put, false));
+        let notification = rx.try_iter().last().unwrap().message;
+        let retransmitted = |observer: &mut Observer<_>| {
+            observer.timer_handler();
+            let messages: Vec<QueuedMessage> = rx.try_iter().collect();
+            for message in &messages {
+                assert_eq!(message.message.header.get_message_id(), notification.header.get_message_id());
+            }
+            messages.len()
+        };
+
+        // Retransmitted after ACK_TIMEOUT, then twice as long, and given up
+        // after MAX_RETRANSMIT retransmissions.
+        assert_eq!(retransmitted(&mut observer), 0);
+        std::thread::sleep(Duration::from_millis(120));
+        assert_eq!(retransmitted(&mut observer), 1);
+        std::thread::sleep(Duration::from_millis(120));
+        assert_eq!(retransmitted(&mut observer), 0);
+        std::thread::sleep(Duration::from_millis(180));
+        assert_eq!(retransmitted(&mut observer), 1);
+        std::thread::sleep(Duration::from_millis(580));
+        assert_eq!(retransmitted(&mut observer), 0);
+        assert!(observer.unacknowledge_messages.is_empty());
+    }
+
+    #[test]
+    fn test_max_observations() {
+        let (tx, rx) = mpsc::channel();
+        let mut observer = Observer::new(tx, TransmissionParameters::default(), || {});
+        observer.set_max_observations(Some(1));
+
+        let request = |method: Method, path: &str, port: u16| {
+            let mut packet = Packet::new();
+            packet.header.set_type(MessageType::Confirmable);
+            let observe = method == Method::Get;
+            packet.header.code = MessageClass::Request(method);
+            if observe {
+                packet.set_observe(vec![ObserveOption::Register as u8]);
+            }
+            let mut request = CoAPRequest::from_packet(packet, &SocketAddr::from(([127, 0, 0, 1], port)));
+            request.set_path(path);
+            request
+        };
+        let observed = |rx: &mpsc::Receiver<QueuedMessage>| rx.try_recv().unwrap().message.get_observe().is_some();
+
+        for path in &["/a", "/b"] {
+            observer.request_handler(&request(Method::Put, path, 1000));
+        }
+        observer.request_handler(&request(Method::Get, "/a", 1000));
+        assert!(observed(&rx));
+        // Registering again is a refresh.
+        observer.request_handler(&request(Method::Get, "/a", 1000));
+        assert!(observed(&rx));
+        // Other ports of the same IP address count too.
+        observer.request_handler(&request(Method::Get, "/b", 1001));
+        assert!(!observed(&rx));
     }
 }
\ No newline at end of file
@@ -0,0 +1,755 @@
+//! Object Security for Constrained RESTful Environments (RFC 8613).
+//!
+//! OSCORE protects a request or response end to end: the code, the options
+//! which only the endpoints need (Class E) and the payload are encrypted
+//! with AES-CCM-16-64-128 into the payload of an outer message, which
+//! proxies can forward as usual. The options proxies need (Class U: Uri-Host,
+//! Uri-Port, Proxy-Scheme, Proxy-Uri, Observe and Hop-Limit) stay outside.
+//!
+//! Both endpoints derive a `SecurityContext` from a shared master secret
+//! with HKDF-SHA-256. A client protects its requests with
+//! `CoAPClient::enable_oscore`; a server with `CoAPServer::enable_oscore`
+//! unprotects requests before its handler sees them and protects the
+//! responses, and answers requests it cannot verify with an unprotected
+//! error.
+//!
+//! A context only lives in memory. An endpoint which restarts with the same
+//! master secret must never reuse a sender sequence number, nor accept a
+//! request it received before: store `sender_sequence_number` plus a margin
+//! of K, and before sending more than K messages store it again, then
+//! restore the stored value with `set_sender_sequence_number` after a
+//! restart (RFC 8613 Appendix B.1.1). Store `highest_received` alike, with
+//! every request counted as it arrives, and restore it with
+//! `set_highest_received`. `CoAPClient::oscore_context` and
+//! `CoAPServer::oscore_context` give access to the contexts in use.
+//!
+//! Observe and Proxy-Uri are not protected: the outer code of an observation
+//! would have to be FETCH, and a Proxy-Uri would have to be split into its
+//! Class E and Class U parts. Use Proxy-Scheme and Uri-Host through proxies.
+//!
+//! ```no_run
+//! use coap::{CoAPClient, CoAPServer};
+//! use coap::oscore::SecurityContext;
+//!
+//! let secret = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+//! let mut server = CoAPServer::new("127.0.0.1:5683").unwrap();
+//! server.enable_oscore(vec![SecurityContext::new(&secret, &[], None, b"s", b"c").unwrap()]);
+//!
+//! let mut client = CoAPClient::new("127.0.0.1:5683").unwrap();
+//! client.enable_oscore(SecurityContext::new(&secret, &[], None, b"c", b"s").unwrap());
+//! let response = client.build_request(coap::Method::Get, "coap://127.0.0.1:5683/secret").send().unwrap();
+//! ```
+
+use std::collections::{BTreeMap, LinkedList};
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+
+use aes::Aes128;
+use ccm::aead::{Aead, KeyInit, Payload};
+use ccm::consts::{U13, U8};
+use ccm::Ccm;
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use super::message::header::{class_to_code, Header, MessageClass, RequestType as Method, ResponseType as Status};
+use super::message::packet::{get_option_number, CoAPOption, Packet};
+use super::message::request::{CoAPRequest, Identity};
+use super::message::response::CoAPResponse;
+
+type AesCcm = Ccm<Aes128, U8, U13>;
+
+/// The COSE algorithm AES-CCM-16-64-128.
+const ALG_AEAD: u8 = 10;
+const KEY_LEN: usize = 16;
+const NONCE_LEN: usize = 13;
+/// The longest sender or recipient ID the nonce has room for.
+const MAX_ID_LEN: usize = NONCE_LEN - 6;
+/// The largest sequence number a Partial IV of 5 bytes can hold.
+const MAX_SEQUENCE_NUMBER: u64 = (1 << 40) - 1;
+/// The number of sequence numbers below the highest one received which
+/// are still accepted once.
+const REPLAY_WINDOW: u64 = 32;
+
+/// The options proxies need, which stay in the outer message.
+const CLASS_U: [CoAPOption; 6] = [
+    CoAPOption::UriHost,
+    CoAPOption::Observe,
+    CoAPOption::UriPort,
+    CoAPOption::HopLimit,
+    CoAPOption::ProxyUri,
+    CoAPOption::ProxyScheme,
+];
+
+/// Why a message could not be protected or unprotected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum OscoreError {
+    /// A sender or recipient ID is longer than 7 bytes.
+    InvalidId,
+    /// No security context matches the key ID of a request.
+    UnknownContext,
+    /// The OSCORE option or the decrypted message is malformed.
+    Decode,
+    /// The request was received before.
+    Replay,
+    /// The message was not encrypted with the recipient key, or was
+    /// tampered with.
+    Decrypt,
+    /// A successful response is not protected.
+    Unprotected,
+    /// The sender sequence numbers ran out; a new context is needed.
+    SequenceExhausted,
+}
+
+impl OscoreError {
+    /// The response code for a request which failed to unprotect
+    /// (RFC 8613 §8.2), or 5.00 Internal Server Error for errors of the
+    /// sender.
+    pub fn status(&self) -> Status {
+        match *self {
+            OscoreError::UnknownContext | OscoreError::Replay => Status::Unauthorized,
+            OscoreError::Decode => Status::BadOption,
+            OscoreError::Decrypt => Status::BadRequest,
+            OscoreError::InvalidId | OscoreError::Unprotected | OscoreError::SequenceExhausted => {
+                Status::InternalServerError
+            }
+        }
+    }
+}
+
+impl fmt::Display for OscoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OscoreError::InvalidId => write!(f, "Sender or recipient ID too long"),
+            OscoreError::UnknownContext => write!(f, "Security context not found"),
+            OscoreError::Decode => write!(f, "Failed to decode COSE"),
+            OscoreError::Replay => write!(f, "Replay detected"),
+            OscoreError::Decrypt => write!(f, "Decryption failed"),
+            OscoreError::Unprotected => write!(f, "Response not protected"),
+            OscoreError::SequenceExhausted => write!(f, "Sender sequence numbers exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for OscoreError {}
+
+/// What a response is bound to: the key ID and Partial IV of its request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestBinding {
+    kid: Vec<u8>,
+    piv: Vec<u8>,
+}
+
+/// The received sequence numbers of a recipient.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `i` is set if `highest - 1 - i` was received.
+    seen: u32,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, sequence: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if sequence > highest => true,
+            Some(highest) if sequence == highest || highest - sequence > REPLAY_WINDOW => false,
+            Some(highest) => self.seen & (1 << (highest - sequence - 1)) == 0,
+        }
+    }
+
+    fn record(&mut self, sequence: u64) {
+        match self.highest {
+            Some(highest) if sequence < highest => self.seen |= 1 << (highest - sequence - 1),
+            Some(highest) => {
+                let shift = sequence - highest;
+                self.seen = if shift > REPLAY_WINDOW {
+                    0
+                } else {
+                    ((self.seen as u64) << shift | 1 << (shift - 1)) as u32
+                };
+                self.highest = Some(sequence);
+            }
+            None => self.highest = Some(sequence),
+        }
+    }
+}
+
+/// The keys and state shared with one peer.
+pub struct SecurityContext {
+    id_context: Option<Vec<u8>>,
+    common_iv: [u8; NONCE_LEN],
+    sender_id: Vec<u8>,
+    sender_key: [u8; KEY_LEN],
+    sender_sequence: u64,
+    recipient_id: Vec<u8>,
+    recipient_key: [u8; KEY_LEN],
+    replay: ReplayWindow,
+}
+
+impl SecurityContext {
+    /// Derives a context from the master secret and salt (RFC 8613 §3.2).
+    ///
+    /// The sender ID of one endpoint is the recipient ID of the other, and
+    /// both are at most 7 bytes long. The ID context, if any, must be the
+    /// same on both endpoints.
+    pub fn new(
+        master_secret: &[u8],
+        master_salt: &[u8],
+        id_context: Option<&[u8]>,
+        sender_id: &[u8],
+        recipient_id: &[u8],
+    ) -> Result<SecurityContext, OscoreError> {
+        if sender_id.len() > MAX_ID_LEN || recipient_id.len() > MAX_ID_LEN {
+            return Err(OscoreError::InvalidId);
+        }
+        let hkdf = Hkdf::<Sha256>::new(Some(master_salt), master_secret);
+        let derive = |id: &[u8], kind: &str, out: &mut [u8]| {
+            let info = info(id, id_context, kind, out.len());
+            hkdf.expand(&info, out).expect("output length is valid for HKDF-SHA-256");
+        };
+
+        let mut context = SecurityContext {
+            id_context: id_context.map(|id_context| id_context.to_vec()),
+            common_iv: [0; NONCE_LEN],
+            sender_id: sender_id.to_vec(),
+            sender_key: [0; KEY_LEN],
+            sender_sequence: 0,
+            recipient_id: recipient_id.to_vec(),
+            recipient_key: [0; KEY_LEN],
+            replay: ReplayWindow::default(),
+        };
+        derive(sender_id, "Key", &mut context.sender_key);
+        derive(recipient_id, "Key", &mut context.recipient_key);
+        derive(&[], "IV", &mut context.common_iv);
+        Ok(context)
+    }
+
+    /// The ID the peer knows this endpoint by.
+    pub fn sender_id(&self) -> &[u8] {
+        &self.sender_id
+    }
+
+    /// The ID of the peer.
+    pub fn recipient_id(&self) -> &[u8] {
+        &self.recipient_id
+    }
+
+    /// The sequence number the next protected request will use.
+    pub fn sender_sequence_number(&self) -> u64 {
+        self.sender_sequence
+    }
+
+    /// Continues with the sequence number `sequence`, which must be above
+    /// any this context used before, such as a stored one after a restart.
+    pub fn set_sender_sequence_number(&mut self, sequence: u64) {
+        self.sender_sequence = sequence;
+    }
+
+    /// The highest sequence number received from the peer, if any.
+    pub fn highest_received(&self) -> Option<u64> {
+        self.replay.highest
+    }
+
+    /// Treats every sequence number up to `sequence` as received, so that
+    /// requests received before a restart are rejected as replays.
+    pub fn set_highest_received(&mut self, sequence: u64) {
+        self.replay = ReplayWindow {
+            highest: Some(sequence),
+            seen: u32::MAX,
+        };
+    }
+
+    /// Protects a request in place, returning what its response is bound
+    /// to.
+    pub fn protect_request(&mut self, request: &mut Packet) -> Result<RequestBinding, OscoreError> {
+        if self.sender_sequence > MAX_SEQUENCE_NUMBER {
+            return Err(OscoreError::SequenceExhausted);
+        }
+        let piv = partial_iv(self.sender_sequence);
+        self.sender_sequence += 1;
+
+        let binding = RequestBinding {
+            kid: self.sender_id.clone(),
+            piv,
+        };
+        let nonce = self.nonce(&binding.kid, &binding.piv);
+        let option = encode_option(&binding.piv, Some(&binding.kid), self.id_context.as_deref());
+        seal(request, &self.sender_key, &nonce, &binding, option, MessageClass::Request(Method::Post))?;
+        Ok(binding)
+    }
+
+    /// Unprotects a request in place, rejecting replays.
+    pub fn unprotect_request(&mut self, request: &mut Packet) -> Result<RequestBinding, OscoreError> {
+        let option = oscore_option(request).ok_or(OscoreError::Decode)?;
+        let (piv, kid, _) = decode_option(&option)?;
+        let kid = kid.ok_or(OscoreError::Decode)?;
+        if piv.is_empty() || kid != self.recipient_id {
+            return Err(OscoreError::Decode);
+        }
+        let sequence = piv.iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+        if !self.replay.is_fresh(sequence) {
+            return Err(OscoreError::Replay);
+        }
+
+        let binding = RequestBinding { kid, piv };
+        let nonce = self.nonce(&binding.kid, &binding.piv);
+        open(request, &self.recipient_key, &nonce, &binding)?;
+        self.replay.record(sequence);
+        Ok(binding)
+    }
+
+    /// Protects the response to a request in place, reusing the nonce of
+    /// the request.
+    pub fn protect_response(&mut self, response: &mut Packet, binding: &RequestBinding) -> Result<(), OscoreError> {
+        let nonce = self.nonce(&binding.kid, &binding.piv);
+        seal(response, &self.sender_key, &nonce, binding, Vec::new(), MessageClass::Response(Status::Changed))
+    }
+
+    /// Unprotects the response to a request in place.
+    ///
+    /// Error responses a server could not protect, because it could not
+    /// verify the request, are returned as they are.
+    pub fn unprotect_response(&mut self, response: &mut Packet, binding: &RequestBinding) -> Result<(), OscoreError> {
+        let option = match oscore_option(response) {
+            Some(option) => option,
+            None => {
+                return match response.header.code {
+                    MessageClass::Response(_) if class_to_code(&response.header.code) >= 0x80 => Ok(()),
+                    _ => Err(OscoreError::Unprotected),
+                };
+            }
+        };
+        let (piv, _, _) = decode_option(&option)?;
+        let nonce = if piv.is_empty() {
+            self.nonce(&binding.kid, &binding.piv)
+        } else {
+            self.nonce(&self.recipient_id, &piv)
+        };
+        open(response, &self.recipient_key, &nonce, binding)
+    }
+
+    /// The AEAD nonce for a Partial IV generated by `id_piv` (RFC 8613 §5.2).
+    fn nonce(&self, id_piv: &[u8], piv: &[u8]) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[0] = id_piv.len() as u8;
+        nonce[1 + MAX_ID_LEN - id_piv.len()..1 + MAX_ID_LEN].copy_from_slice(id_piv);
+        nonce[NONCE_LEN - piv.len()..].copy_from_slice(piv);
+        for (byte, iv) in nonce.iter_mut().zip(self.common_iv.iter()) {
+            *byte ^= iv;
+        }
+        nonce
+    }
+}
+
+/// The server side: a context per client, chosen by the key ID of each
+/// request.
+pub(crate) struct Recipients {
+    contexts: Vec<Mutex<SecurityContext>>,
+}
+
+impl Recipients {
+    pub fn new(contexts: Vec<SecurityContext>) -> Recipients {
+        Recipients {
+            contexts: contexts.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    /// The context whose recipient ID is `recipient_id`.
+    pub fn context(&self, recipient_id: &[u8]) -> Option<MutexGuard<'_, SecurityContext>> {
+        self.contexts
+            .iter()
+            .map(|context| context.lock().unwrap())
+            .find(|context| context.recipient_id == recipient_id)
+    }
+
+    /// Unprotects a request, passes it to `handle` and protects the response.
+    /// A request which cannot be unprotected is answered with an unprotected
+    /// error.
+    pub fn handle<F>(&self, mut request: CoAPRequest, handle: F) -> Option<CoAPResponse>
+    where
+        F: FnOnce(CoAPRequest) -> Option<CoAPResponse>,
+    {
+        let (index, binding) = match self.unprotect(&mut request.message) {
+            Ok(unprotected) => unprotected,
+            Err(e) => return error_response(request, e),
+        };
+
+        let context = &self.contexts[index];
+        {
+            let context = context.lock().unwrap();
+            request.identity = Some(Identity::Oscore {
+                sender_id: context.recipient_id.clone(),
+                id_context: context.id_context.clone(),
+            });
+        }
+        request.response = CoAPResponse::new(&request.message);
+        let mut response = handle(request)?;
+        context
+            .lock()
+            .unwrap()
+            .protect_response(&mut response.message, &binding)
+            .ok()?;
+        Some(response)
+    }
+
+    fn unprotect(&self, request: &mut Packet) -> Result<(usize, RequestBinding), OscoreError> {
+        let option = oscore_option(request).ok_or(OscoreError::Decode)?;
+        let (_, kid, id_context) = decode_option(&option)?;
+        let kid = kid.ok_or(OscoreError::Decode)?;
+        for (index, context) in self.contexts.iter().enumerate() {
+            let mut context = context.lock().unwrap();
+            let same_context = id_context.is_none() || id_context == context.id_context;
+            if context.recipient_id == kid && same_context {
+                return context.unprotect_request(request).map(|binding| (index, binding));
+            }
+        }
+        Err(OscoreError::UnknownContext)
+    }
+}
+
+/// Whether a request carries the OSCORE option.
+pub(crate) fn is_protected(request: &CoAPRequest) -> bool {
+    oscore_option(&request.message).is_some()
+}
+
+/// Answers a protected request with 4.02 Bad Option, as a server without
+/// security contexts does not understand the OSCORE option.
+pub(crate) fn not_supported(request: CoAPRequest) -> Option<CoAPResponse> {
+    let mut response = request.response?;
+    response.set_status(Status::BadOption);
+    response.message.payload.clear();
+    Some(response)
+}
+
+/// An unprotected error response with the reason as diagnostic payload.
+fn error_response(request: CoAPRequest, error: OscoreError) -> Option<CoAPResponse> {
+    let mut response = request.response?;
+    response.set_status(error.status());
+    response.message.payload = error.to_string().into_bytes();
+    Some(response)
+}
+
+fn oscore_option(packet: &Packet) -> Option<Vec<u8>> {
+    packet.get_option(CoAPOption::Oscore).and_then(|list| list.front()).cloned()
+}
+
+/// A sequence number as a Partial IV: big endian without leading zeros, but
+/// at least one byte.
+fn partial_iv(sequence: u64) -> Vec<u8> {
+    let bytes = sequence.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(bytes.len() - 1);
+    bytes[skip..].to_vec()
+}
+
+fn encode_option(piv: &[u8], kid: Option<&[u8]>, id_context: Option<&[u8]>) -> Vec<u8> {
+    let mut flags = piv.len() as u8;
+    if kid.is_some() {
+        flags |= 0x08;
+    }
+    if id_context.is_some() {
+        flags |= 0x10;
+    }
+    if flags == 0 {
+        return Vec::new();
+    }
+
+    let mut value = vec![flags];
+    value.extend_from_slice(piv);
+    if let Some(id_context) = id_context {
+        value.push(id_context.len() as u8);
+        value.extend_from_slice(id_context);
+    }
+    value.extend_from_slice(kid.unwrap_or(&[]));
+    value
+}
+
+/// Decodes the Partial IV, the key ID and the ID context of an OSCORE
+/// option (RFC 8613 §6.1).
+#[allow(clippy::type_complexity)]
+fn decode_option(value: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>), OscoreError> {
+    let (&flags, mut rest) = match value.split_first() {
+        Some(split) => split,
+        None => return Ok((Vec::new(), None, None)),
+    };
+    let piv_len = (flags & 0x07) as usize;
+    if flags & 0xE0 != 0 || piv_len > 5 || rest.len() < piv_len {
+        return Err(OscoreError::Decode);
+    }
+    let piv = rest[..piv_len].to_vec();
+    rest = &rest[piv_len..];
+
+    let mut id_context = None;
+    if flags & 0x10 != 0 {
+        let (&len, context) = rest.split_first().ok_or(OscoreError::Decode)?;
+        if context.len() < len as usize {
+            return Err(OscoreError::Decode);
+        }
+        id_context = Some(context[..len as usize].to_vec());
+        rest = &context[len as usize..];
+    }
+
+    let kid = if flags & 0x08 != 0 {
+        Some(rest.to_vec())
+    } else if rest.is_empty() {
+        None
+    } else {
+        return Err(OscoreError::Decode);
+    };
+    Ok((piv, kid, id_context))
+}
+
+/// Encrypts the code, the Class E options and the payload of a message
+/// into its payload, leaving the Class U options and the OSCORE option.
+fn seal(
+    packet: &mut Packet,
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    binding: &RequestBinding,
+    option: Vec<u8>,
+    outer_code: MessageClass,
+) -> Result<(), OscoreError> {
+    let class_u: Vec<usize> = CLASS_U.iter().map(|&option| get_option_number(option)).collect();
+    let mut inner = BTreeMap::new();
+    let mut outer = BTreeMap::new();
+    for (number, value) in packet.options() {
+        if number == get_option_number(CoAPOption::Oscore) {
+            continue;
+        }
+        let options = if class_u.contains(&number) { &mut outer } else { &mut inner };
+        options.entry(number).or_insert_with(LinkedList::new).push_back(value.to_vec());
+    }
+
+    let encoded = Packet::from_parts(Header::new(), Vec::new(), inner, std::mem::take(&mut packet.payload))
+        .to_bytes()
+        .map_err(|_| OscoreError::Decode)?;
+    let mut plaintext = vec![class_to_code(&packet.header.code)];
+    // Skip the header, as the token is empty.
+    plaintext.extend_from_slice(&encoded[4..]);
+
+    let aad = aad(binding);
+    packet.payload = AesCcm::new(key.into())
+        .encrypt(nonce.into(), Payload { msg: &plaintext, aad: &aad })
+        .map_err(|_| OscoreError::Decode)?;
+    outer.insert(get_option_number(CoAPOption::Oscore), std::iter::once(option).collect());
+    *packet = Packet::from_parts(packet.header.clone(), packet.get_token().clone(), outer, std::mem::take(&mut packet.payload));
+    packet.header.code = outer_code;
+    Ok(())
+}
+
+/// Decrypts the payload of a message, restoring its code, options and
+/// payload.
+fn open(packet: &mut Packet, key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], binding: &RequestBinding) -> Result<(), OscoreError> {
+    let aad = aad(binding);
+    let plaintext = AesCcm::new(key.into())
+        .decrypt(nonce.into(), Payload { msg: &packet.payload, aad: &aad })
+        .map_err(|_| OscoreError::Decrypt)?;
+    let (&code, rest) = plaintext.split_first().ok_or(OscoreError::Decode)?;
+
+    let mut encoded = vec![0x40, code, 0, 0];
+    encoded.extend_from_slice(rest);
+    let inner = Packet::from_bytes(&encoded).map_err(|_| OscoreError::Decode)?;
+
+    let mut options = BTreeMap::new();
+    for (number, value) in packet.options().chain(inner.options()) {
+        if number != get_option_number(CoAPOption::Oscore) {
+            options.entry(number).or_insert_with(LinkedList::new).push_back(value.to_vec());
+        }
+    }
+    let mut header = packet.header.clone();
+    header.code = inner.header.code.clone();
+    *packet = Packet::from_parts(header, packet.get_token().clone(), options, inner.payload);
+    Ok(())
+}
+
+/// The additional authenticated data: the COSE Enc_structure with the
+/// external AAD of RFC 8613 §5.4.
+fn aad(binding: &RequestBinding) -> Vec<u8> {
+    let mut external = Vec::new();
+    cbor_head(4, 5, &mut external);
+    cbor_head(0, 1, &mut external);
+    cbor_head(4, 1, &mut external);
+    cbor_head(0, ALG_AEAD as u64, &mut external);
+    cbor_bytes(&binding.kid, &mut external);
+    cbor_bytes(&binding.piv, &mut external);
+    // No Class I options.
+    cbor_bytes(&[], &mut external);
+
+    let mut aad = Vec::new();
+    cbor_head(4, 3, &mut aad);
+    cbor_head(3, 8, &mut aad);
+    aad.extend_from_slice(b"Encrypt0");
+    cbor_bytes(&[], &mut aad);
+    cbor_bytes(&external, &mut aad);
+    aad
+}
+
+/// The HKDF info for a key or the common IV (RFC 8613 §3.2.1).
+fn info(id: &[u8], id_context: Option<&[u8]>, kind: &str, len: usize) -> Vec<u8> {
+    let mut info = Vec::new();
+    cbor_head(4, 5, &mut info);
+    cbor_bytes(id, &mut info);
+    match id_context {
+        Some(id_context) => cbor_bytes(id_context, &mut info),
+        None => info.push(0xF6),
+    }
+    cbor_head(0, ALG_AEAD as u64, &mut info);
+    cbor_head(3, kind.len() as u64, &mut info);
+    info.extend_from_slice(kind.as_bytes());
+    cbor_head(0, len as u64, &mut info);
+    info
+}
+
+fn cbor_head(major: u8, value: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xFF => out.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xFFFF => {
+            out.push(major | 25);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 26);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+    }
+}
+
+fn cbor_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    cbor_head(2, bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    const MASTER_SECRET: &str = "0102030405060708090a0b0c0d0e0f10";
+    const MASTER_SALT: &str = "9e7ca92223786340";
+
+    // RFC 8613 Appendix C.1.
+    fn client() -> SecurityContext {
+        SecurityContext::new(&hex(MASTER_SECRET), &hex(MASTER_SALT), None, &[], &[0x01]).unwrap()
+    }
+
+    fn server() -> SecurityContext {
+        SecurityContext::new(&hex(MASTER_SECRET), &hex(MASTER_SALT), None, &[0x01], &[]).unwrap()
+    }
+
+    #[test]
+    fn test_derivation() {
+        let client = client();
+        assert_eq!(client.sender_key.to_vec(), hex("f0910ed7295e6ad4b54fc793154302ff"));
+        assert_eq!(client.recipient_key.to_vec(), hex("ffb14e093c94c9cac9471648b4f98710"));
+        assert_eq!(client.common_iv.to_vec(), hex("4622d4dd6d944168eefb54987c"));
+        let server = server();
+        assert_eq!(server.sender_key, client.recipient_key);
+        assert_eq!(server.recipient_key, client.sender_key);
+
+        let aad = aad(&RequestBinding { kid: vec![], piv: vec![0x14] });
+        assert_eq!(aad, hex("8368456e63727970743040488501810a40411440"));
+        assert!(SecurityContext::new(&[1], &[], None, &[0; 8], &[]).is_err());
+    }
+
+    // RFC 8613 Appendix C.4 and C.7.
+    const REQUEST: &str = "44015d1f00003974396c6f63616c686f737483747631";
+    const PROTECTED_REQUEST: &str = "44025d1f00003974396c6f63616c686f7374620914ff612f1092f1776f1c1668b3825e";
+    const RESPONSE: &str = "64455d1f00003974ff48656c6c6f20576f726c6421";
+    const PROTECTED_RESPONSE: &str = "64445d1f0000397490ffdbaad1e9a7e7b2a813d3c31524378303cdafae119106";
+
+    #[test]
+    fn test_request() {
+        let mut client = client();
+        client.sender_sequence = 20;
+        let mut request = Packet::from_bytes(&hex(REQUEST)).unwrap();
+        let binding = client.protect_request(&mut request).unwrap();
+        assert_eq!(request.to_bytes().unwrap(), hex(PROTECTED_REQUEST));
+        assert_eq!(binding, RequestBinding { kid: vec![], piv: vec![0x14] });
+
+        let mut recipient = server();
+        let mut received = Packet::from_bytes(&hex(PROTECTED_REQUEST)).unwrap();
+        assert_eq!(recipient.unprotect_request(&mut received), Ok(binding));
+        assert_eq!(received.to_bytes().unwrap(), hex(REQUEST));
+
+        let mut replayed = Packet::from_bytes(&hex(PROTECTED_REQUEST)).unwrap();
+        assert_eq!(recipient.unprotect_request(&mut replayed), Err(OscoreError::Replay));
+
+        let mut tampered = hex(PROTECTED_REQUEST);
+        *tampered.last_mut().unwrap() ^= 1;
+        let mut tampered = Packet::from_bytes(&tampered).unwrap();
+        assert_eq!(server().unprotect_request(&mut tampered), Err(OscoreError::Decrypt));
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut sender = client();
+        let mut request = Packet::from_bytes(&hex(REQUEST)).unwrap();
+        sender.protect_request(&mut request).unwrap();
+        assert_eq!(sender.sender_sequence_number(), 1);
+
+        let mut recipient = server();
+        assert_eq!(recipient.highest_received(), None);
+        recipient.unprotect_request(&mut request.clone()).unwrap();
+        assert_eq!(recipient.highest_received(), Some(0));
+
+        // After a restart, old requests are replays and new ones go on
+        // from the stored sequence number.
+        let mut restarted = server();
+        restarted.set_highest_received(recipient.highest_received().unwrap());
+        assert_eq!(restarted.unprotect_request(&mut request), Err(OscoreError::Replay));
+
+        let mut sender = client();
+        sender.set_sender_sequence_number(20);
+        let mut request = Packet::from_bytes(&hex(REQUEST)).unwrap();
+        sender.protect_request(&mut request).unwrap();
+        assert_eq!(request.to_bytes().unwrap(), hex(PROTECTED_REQUEST));
+        assert!(restarted.unprotect_request(&mut request).is_ok());
+    }
+
+    #[test]
+    fn test_response() {
+        let binding = RequestBinding { kid: vec![], piv: vec![0x14] };
+        let mut response = Packet::from_bytes(&hex(RESPONSE)).unwrap();
+        server().protect_response(&mut response, &binding).unwrap();
+        assert_eq!(response.to_bytes().unwrap(), hex(PROTECTED_RESPONSE));
+
+        let mut client = client();
+        client.unprotect_response(&mut response, &binding).unwrap();
+        assert_eq!(response.to_bytes().unwrap(), hex(RESPONSE));
+
+        let mut unprotected = Packet::from_bytes(&hex(RESPONSE)).unwrap();
+        assert_eq!(client.unprotect_response(&mut unprotected, &binding), Err(OscoreError::Unprotected));
+        unprotected.header.code = MessageClass::Response(Status::Unauthorized);
+        assert_eq!(client.unprotect_response(&mut unprotected, &binding), Ok(()));
+    }
+
+    #[test]
+    fn test_option() {
+        assert_eq!(encode_option(&[0x14], Some(&[]), None), vec![0x09, 0x14]);
+        assert_eq!(encode_option(&[], None, None), Vec::<u8>::new());
+        let value = encode_option(&[0x01, 0x02], Some(&[0xAA]), Some(&[0x37, 0xCB]));
+        assert_eq!(value, vec![0x1A, 0x01, 0x02, 0x02, 0x37, 0xCB, 0xAA]);
+        assert_eq!(decode_option(&value), Ok((vec![1, 2], Some(vec![0xAA]), Some(vec![0x37, 0xCB]))));
+        assert_eq!(decode_option(&[0x09]), Err(OscoreError::Decode));
+        assert_eq!(decode_option(&[0x20]), Err(OscoreError::Decode));
+        assert_eq!(partial_iv(0), vec![0]);
+        assert_eq!(partial_iv(0x1234), vec![0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        for &sequence in &[5, 3, 40, 39, 8] {
+            assert!(window.is_fresh(sequence));
+            window.record(sequence);
+            assert!(!window.is_fresh(sequence));
+        }
+        assert!(!window.is_fresh(3));
+        assert!(window.is_fresh(20));
+        assert!(!window.is_fresh(7));
+    }
+}
@@ -0,0 +1,320 @@
+//! A forward proxy for requests with Proxy-Uri or Proxy-Scheme
+//! (RFC 7252 §5.7.2), to `coap` targets and, through the cross-proxy, to
+//! `http` targets.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::str;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::debug;
+
+use super::client::{CoAPClient, RequestBuilder};
+use super::cross_proxy;
+use super::http::HttpUrl;
+use super::error::{Error, Result};
+use super::message::header::ResponseType as Status;
+use super::message::packet::CoAPOption;
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+use super::message::uri::{CoAPUri, COAP_PORT};
+use super::message::IsMessage;
+
+/// Whether the request is for the proxy rather than for the server itself.
+pub(crate) fn is_proxy_request(request: &CoAPRequest) -> bool {
+    [CoAPOption::ProxyUri, CoAPOption::ProxyScheme]
+        .iter()
+        .any(|&option| request.get_option(option).is_some_and(|list| !list.is_empty()))
+}
+
+/// Answer a proxy request with 5.05 Proxying Not Supported.
+pub(crate) fn not_supported(request: CoAPRequest) -> Option<CoAPResponse> {
+    error_response(request, Status::ProxyingNotSupported)
+}
+
+fn error_response(request: CoAPRequest, status: Status) -> Option<CoAPResponse> {
+    let mut response = request.response?;
+    response.set_status(status);
+    response.message.payload.clear();
+    Some(response)
+}
+
+/// The Hop-Limit a proxy inserts into requests without one (RFC 8768 §3).
+pub(crate) const DEFAULT_HOP_LIMIT: u8 = 16;
+
+/// The Hop-Limit to forward a request with: one less than the request's,
+/// or the default. A request whose Hop-Limit runs out is answered with 5.08
+/// Hop Limit Reached, which stops forwarding loops.
+pub(crate) fn next_hop_limit(request: &CoAPRequest) -> std::result::Result<u8, Status> {
+    match request.get_option(CoAPOption::HopLimit).and_then(|list| list.front()) {
+        None => Ok(DEFAULT_HOP_LIMIT),
+        Some(value) if value.len() != 1 || value[0] == 0 => Err(Status::BadRequest),
+        Some(value) if value[0] == 1 => Err(Status::HopLimitReached),
+        Some(value) => Ok(value[0] - 1),
+    }
+}
+
+/// The options which are end-to-end and safe to forward; the Uri-* and
+/// Proxy-* options are replaced by the target URI.
+const FORWARDED_OPTIONS: [CoAPOption; 8] = [
+    CoAPOption::IfMatch,
+    CoAPOption::ETag,
+    CoAPOption::IfNoneMatch,
+    CoAPOption::ContentFormat,
+    CoAPOption::Accept,
+    CoAPOption::Size1,
+    CoAPOption::Size2,
+    CoAPOption::Oscore,
+];
+
+/// Forwards requests to their target through a client per address family,
+/// which caches responses as their Max-Age allows.
+pub(crate) struct ForwardProxy {
+    cache_capacity: usize,
+    timeout: Duration,
+    v4: OnceLock<CoAPClient>,
+    v6: OnceLock<CoAPClient>,
+}
+
+impl ForwardProxy {
+    pub fn new(cache_capacity: usize, timeout: Duration) -> ForwardProxy {
+        ForwardProxy {
+            cache_capacity,
+            timeout,
+            v4: OnceLock::new(),
+            v6: OnceLock::new(),
+        }
+    }
+
+    /// Forwards a proxy request to its target. Targets on the host or the
+    /// link of the proxy are refused with 4.03 Forbidden unless
+    /// `local_targets` allows them.
+    pub fn handle(&self, request: CoAPRequest, local_targets: bool) -> Option<CoAPResponse> {
+        match target(&request) {
+            Ok(Target::Coap(ref uri)) if uri.secure => not_supported(request),
+            Ok(ref target) if !local_targets && is_local_target(target) => error_response(request, Status::Forbidden),
+            Ok(target) => self.relay(request, target),
+            Err(status) => error_response(request, status),
+        }
+    }
+
+    /// Forwards a request to the target and answers with the response.
+    pub fn relay(&self, request: CoAPRequest, target: Target) -> Option<CoAPResponse> {
+        let hop_limit = match next_hop_limit(&request) {
+            Ok(hop_limit) => hop_limit,
+            Err(status) => return error_response(request, status),
+        };
+
+        let mut relayed = request.response.clone()?;
+        let result = match target {
+            Target::Coap(uri) => self.forward(&request, uri, hop_limit).and_then(|response| {
+                let token = relayed.get_token().clone();
+                let message_type = relayed.get_type();
+                let message_id = relayed.get_message_id();
+                relayed.message = response.message;
+                relayed.set_type(message_type);
+                relayed.set_message_id(message_id);
+                relayed.set_token(token)
+            }),
+            Target::Http(url) => cross_proxy::forward_http(&request, &url, &mut relayed, self.timeout),
+        };
+
+        match result {
+            Ok(()) => Some(relayed),
+            Err(Error::Timeout) => error_response(request, Status::GatewayTimeout),
+            Err(e) => {
+                debug!("Forwarding failed, {}", e);
+                error_response(request, Status::BadGateway)
+            }
+        }
+    }
+
+    pub fn forward(&self, request: &CoAPRequest, uri: CoAPUri, hop_limit: u8) -> Result<CoAPResponse> {
+        let peer_addr = (uri.host.as_str(), uri.port).to_socket_addrs()?.next().ok_or(Error::NoAddress)?;
+        let client = self.client(&peer_addr)?;
+
+        let mut builder = RequestBuilder::new(client, request.get_method().clone(), Ok((uri, peer_addr)))
+            .payload(request.message.payload.clone())
+            .option(CoAPOption::HopLimit, vec![hop_limit])
+            .timeout(self.timeout);
+        for option in FORWARDED_OPTIONS {
+            for value in request.get_option(option).into_iter().flatten() {
+                builder = builder.option(option, value.clone());
+            }
+        }
+        builder.send()
+    }
+
+    fn client(&self, peer_addr: &SocketAddr) -> Result<&CoAPClient> {
+        let cell = match *peer_addr {
+            SocketAddr::V4(_) => &self.v4,
+            SocketAddr::V6(_) => &self.v6,
+        };
+        if let Some(client) = cell.get() {
+            return Ok(client);
+        }
+        let mut client = CoAPClient::new(peer_addr)?;
+        if self.cache_capacity > 0 {
+            client.enable_cache(self.cache_capacity);
+        }
+        Ok(cell.get_or_init(|| client))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Target {
+    Coap(CoAPUri),
+    Http(HttpUrl),
+}
+
+/// The target of a proxy request: the Proxy-Uri, or the Uri-* options with
+/// the Proxy-Scheme.
+fn target(request: &CoAPRequest) -> std::result::Result<Target, Status> {
+    let first = |option| {
+        request
+            .get_option(option)
+            .and_then(|list| list.front())
+            .map(|value| str::from_utf8(value).map_err(|_| Status::BadOption))
+            .transpose()
+    };
+
+    if let Some(proxy_uri) = first(CoAPOption::ProxyUri)? {
+        if let Some(url) = HttpUrl::parse(proxy_uri) {
+            return Ok(Target::Http(url));
+        }
+        return match CoAPUri::parse(proxy_uri) {
+            Ok(uri) => Ok(Target::Coap(uri)),
+            Err(_) if has_scheme(proxy_uri) => Err(Status::ProxyingNotSupported),
+            Err(_) => Err(Status::BadRequest),
+        };
+    }
+
+    let scheme = first(CoAPOption::ProxyScheme)?.unwrap_or_default();
+    let secure = match scheme.to_ascii_lowercase().as_str() {
+        "coap" => false,
+        "coaps" => true,
+        _ => return Err(Status::ProxyingNotSupported),
+    };
+    if first(CoAPOption::UriHost)?.is_none() {
+        return Err(Status::BadRequest);
+    }
+    let default_port = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, COAP_PORT));
+    CoAPUri::from_options(&request.message, &default_port, secure)
+        .map(Target::Coap)
+        .map_err(|_| Status::BadOption)
+}
+
+/// Whether the target resolves to an address of the proxy's own host or
+/// link, which a client could not reach without the proxy. A target which
+/// does not resolve is left to fail when forwarding.
+fn is_local_target(target: &Target) -> bool {
+    let addrs = match *target {
+        Target::Coap(ref uri) => (uri.host.as_str(), uri.port).to_socket_addrs(),
+        Target::Http(ref url) => (url.host.as_str(), url.port).to_socket_addrs(),
+    };
+    addrs.is_ok_and(|mut addrs| addrs.any(|addr| is_local(addr.ip())))
+}
+
+/// Whether the address is a loopback, link-local or unspecified one.
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local(IpAddr::V4(ip)),
+            None => ip.is_loopback() || ip.is_unicast_link_local() || ip.is_unspecified(),
+        },
+    }
+}
+
+/// Whether the URI starts with a well-formed scheme other than coap.
+fn has_scheme(uri: &str) -> bool {
+    match uri.find("://") {
+        Some(end) => {
+            let scheme = &uri[..end];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::header::MessageType;
+    use super::super::message::packet::Packet;
+
+    fn request(options: &[(CoAPOption, &str)]) -> CoAPRequest {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        for &(option, value) in options {
+            packet.add_option(option, value.as_bytes().to_vec());
+        }
+        CoAPRequest::from_packet(packet, &"127.0.0.1:5683".parse().unwrap())
+    }
+
+    fn coap_target(request: &CoAPRequest) -> CoAPUri {
+        match target(request) {
+            Ok(Target::Coap(uri)) => uri,
+            other => panic!("not a coap target: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_target() {
+        let uri = coap_target(&request(&[(CoAPOption::ProxyUri, "coap://example.com:5684/a?b=1")]));
+        assert_eq!(uri.to_string(), "coap://example.com:5684/a?b=1");
+
+        let uri = coap_target(&request(&[
+            (CoAPOption::ProxyScheme, "coap"),
+            (CoAPOption::UriHost, "example.com"),
+            (CoAPOption::UriPath, "a"),
+        ]));
+        assert_eq!(uri.to_string(), "coap://example.com/a");
+
+        assert_eq!(
+            target(&request(&[(CoAPOption::ProxyUri, "http://example.com/a")])),
+            Ok(Target::Http(HttpUrl::parse("http://example.com/a").unwrap()))
+        );
+        assert_eq!(target(&request(&[(CoAPOption::ProxyUri, "https://example.com/")])), Err(Status::ProxyingNotSupported));
+        assert_eq!(target(&request(&[(CoAPOption::ProxyUri, "/relative")])), Err(Status::BadRequest));
+        assert_eq!(target(&request(&[(CoAPOption::ProxyScheme, "coap")])), Err(Status::BadRequest));
+        assert_eq!(
+            target(&request(&[
+                (CoAPOption::ProxyScheme, "coap"),
+                (CoAPOption::UriHost, "example.com"),
+                (CoAPOption::UriPort, "\x01\x16\x33"),
+            ])),
+            Err(Status::BadOption)
+        );
+        assert!(coap_target(&request(&[(CoAPOption::ProxyUri, "coaps://example.com/")])).secure);
+    }
+
+    #[test]
+    fn test_is_local_target() {
+        let local = |uri: &str| is_local_target(&target(&request(&[(CoAPOption::ProxyUri, uri)])).unwrap());
+        assert!(local("coap://127.0.0.1/"));
+        assert!(local("coap://localhost:5684/"));
+        assert!(local("coap://[::1]/"));
+        assert!(local("coap://[::ffff:127.0.0.2]/"));
+        assert!(local("coap://169.254.1.1/"));
+        assert!(local("coap://[fe80::1]/"));
+        assert!(local("coap://0.0.0.0/"));
+        assert!(local("http://127.0.0.1:8080/"));
+        assert!(!local("coap://192.0.2.1/"));
+        assert!(!local("coap://[2001:db8::1]/"));
+        assert!(!local("http://192.0.2.1/"));
+    }
+
+    #[test]
+    fn test_next_hop_limit() {
+        assert_eq!(next_hop_limit(&request(&[])), Ok(DEFAULT_HOP_LIMIT));
+        let mut limited = request(&[]);
+        limited.add_option(CoAPOption::HopLimit, vec![5]);
+        assert_eq!(next_hop_limit(&limited), Ok(4));
+        limited.set_option(CoAPOption::HopLimit, [vec![1]].iter().cloned().collect());
+        assert_eq!(next_hop_limit(&limited), Err(Status::HopLimitReached));
+        limited.set_option(CoAPOption::HopLimit, [vec![0]].iter().cloned().collect());
+        assert_eq!(next_hop_limit(&limited), Err(Status::BadRequest));
+    }
+}
@@ -0,0 +1,282 @@
+//! A reverse proxy which serves path prefixes from upstream CoAP servers.
+//!
+//! Requests under a prefix are forwarded with the prefix replaced by the
+//! path of the upstream URI. Observations are proxied: the first
+//! registration for a resource, named by its path and query, registers
+//! upstream, and the server's own observer fans the notifications out to
+//! every downstream registrant. Once the last one deregisters, the upstream
+//! observation is cancelled.
+
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::client::CoAPClient;
+use super::error::{Error, Result};
+use super::message::header::{MessageClass, ResponseType as Status};
+use super::message::packet::{CoAPOption, ObserveOption};
+use super::message::request::{CoAPRequest, Method};
+use super::message::response::CoAPResponse;
+use super::message::uri::{decode_uint, CoAPUri};
+use super::message::IsMessage;
+use super::proxy::{self, ForwardProxy, Target};
+
+/// The most resources observed upstream at once. Further registrations are
+/// forwarded as plain requests, as are those made while another one is on
+/// its way upstream.
+const MAX_OBSERVATIONS: usize = 64;
+
+/// A path prefix and the upstream URI it is served from.
+struct Route {
+    prefix: Vec<String>,
+    upstream: CoAPUri,
+}
+
+/// An upstream observation, with the ID its notifications are sent with.
+enum Upstream {
+    /// The registration is on its way.
+    Registering(u64),
+    Observing(u64, Box<CoAPClient>),
+}
+
+impl Upstream {
+    fn id(&self) -> u64 {
+        match *self {
+            Upstream::Registering(id) | Upstream::Observing(id, _) => id,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Observations {
+    /// By the key of the downstream resource, each with its own client.
+    upstream: HashMap<String, Upstream>,
+    next_id: u64,
+}
+
+pub(crate) struct ReverseProxy {
+    /// Longest prefix first.
+    routes: Vec<Route>,
+    forward: ForwardProxy,
+    timeout: Duration,
+    observations: Mutex<Observations>,
+}
+
+/// Splits a path into its non-empty segments.
+fn segments(path: &str) -> Vec<String> {
+    path.split('/').filter(|s| !s.is_empty()).map(String::from).collect()
+}
+
+fn strings(request: &CoAPRequest, option: CoAPOption) -> Vec<String> {
+    request
+        .get_option(option)
+        .into_iter()
+        .flatten()
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+        .collect()
+}
+
+/// Whether the request registers an observation.
+pub(crate) fn is_registration(request: &CoAPRequest) -> bool {
+    *request.get_method() == Method::Get
+        && request
+            .get_observe()
+            .is_some_and(|value| decode_uint(value) == ObserveOption::Register as u32)
+}
+
+impl ReverseProxy {
+    /// Creates a proxy serving each path prefix from its upstream `coap` URI.
+    pub fn new(routes: &[(&str, &str)], cache_capacity: usize, timeout: Duration) -> Result<ReverseProxy> {
+        let mut parsed = Vec::new();
+        for &(prefix, upstream) in routes {
+            let upstream = CoAPUri::parse(upstream)?;
+            if upstream.secure {
+                return Err(Error::InvalidUrl);
+            }
+            parsed.push(Route {
+                prefix: segments(prefix),
+                upstream,
+            });
+        }
+        parsed.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+
+        Ok(ReverseProxy {
+            routes: parsed,
+            forward: ForwardProxy::new(cache_capacity, timeout),
+            timeout,
+            observations: Mutex::new(Observations::default()),
+        })
+    }
+
+    /// The upstream URI of a request whose path is under one of the
+    /// prefixes, with the rest of its path and its query appended.
+    pub fn route(&self, request: &CoAPRequest) -> Option<CoAPUri> {
+        if !matches!(request.message.header.code, MessageClass::Request(_)) {
+            return None;
+        }
+        let path = strings(request, CoAPOption::UriPath);
+        let route = self.routes.iter().find(|route| path.starts_with(&route.prefix))?;
+
+        let mut uri = route.upstream.clone();
+        uri.path.extend_from_slice(&path[route.prefix.len()..]);
+        uri.query.extend(strings(request, CoAPOption::UriQuery));
+        Some(uri)
+    }
+
+    /// Forwards a request to its upstream URI and answers with the response.
+    pub fn handle(&self, request: CoAPRequest, uri: CoAPUri) -> Option<CoAPResponse> {
+        self.forward.relay(request, Target::Coap(uri))
+    }
+
+    /// Observes the upstream resource of a registration for the downstream
+    /// resource `key`, unless it is already observed, passing the ID of the
+    /// observation and the payload of each notification to `notify`.
+    ///
+    /// Only one registration is on its way upstream at a time, so that
+    /// slow upstreams hold up at most one worker; others fail with 5.03
+    /// Service Unavailable without waiting, as do those beyond
+    /// `MAX_OBSERVATIONS` resources.
+    pub fn observe<F>(&self, request: &CoAPRequest, key: &str, uri: &CoAPUri, notify: F) -> Result<()>
+    where
+        F: Fn(u64, Vec<u8>) + Send + 'static,
+    {
+        let id = {
+            let mut observations = self.observations.lock().unwrap();
+            if let Some(Upstream::Observing(..)) = observations.upstream.get(key) {
+                return Ok(());
+            }
+            let registering = observations
+                .upstream
+                .values()
+                .any(|upstream| matches!(upstream, Upstream::Registering(_)));
+            if registering || observations.upstream.len() >= MAX_OBSERVATIONS {
+                return Err(Error::Response(Status::ServiceUnavailable));
+            }
+            let id = observations.next_id;
+            observations.next_id += 1;
+            observations.upstream.insert(key.to_string(), Upstream::Registering(id));
+            id
+        };
+
+        let registered = self.register(request, uri, move |payload| notify(id, payload));
+        let mut observations = self.observations.lock().unwrap();
+        match registered {
+            Ok(client) => {
+                observations.upstream.insert(key.to_string(), Upstream::Observing(id, Box::new(client)));
+                Ok(())
+            }
+            Err(e) => {
+                observations.upstream.remove(key);
+                Err(e)
+            }
+        }
+    }
+
+    fn register<F: Fn(Vec<u8>) + Send + 'static>(&self, request: &CoAPRequest, uri: &CoAPUri, notify: F) -> Result<CoAPClient> {
+        let hop_limit = proxy::next_hop_limit(request).map_err(Error::Response)?;
+        let peer_addr = (uri.host.as_str(), uri.port).to_socket_addrs()?.next().ok_or(Error::NoAddress)?;
+        let mut options = vec![(CoAPOption::HopLimit, vec![hop_limit])];
+        for query in &uri.query {
+            options.push((CoAPOption::UriQuery, query.clone().into_bytes()));
+        }
+
+        let mut client = CoAPClient::new(peer_addr)?;
+        client.observe_with_options(&uri.path.join("/"), &options, self.timeout, move |packet| {
+            if packet.header.code == MessageClass::Response(Status::Content) {
+                notify(packet.payload);
+            }
+        })?;
+        Ok(client)
+    }
+
+    /// Whether notifications of the observation `id` are for the downstream
+    /// resource `key`, rather than for one cancelled before.
+    pub fn is_current(&self, key: &str, id: u64) -> bool {
+        let observations = self.observations.lock().unwrap();
+        observations.upstream.get(key).is_some_and(|upstream| upstream.id() == id)
+    }
+
+    /// Stops proxying the observation of the downstream resource `key`,
+    /// returning its client, which cancels it upstream when dropped.
+    pub fn release(&self, key: &str) -> Option<CoAPClient> {
+        let mut observations = self.observations.lock().unwrap();
+        match observations.upstream.remove(key) {
+            Some(Upstream::Observing(_, client)) => Some(*client),
+            Some(registering) => {
+                observations.upstream.insert(key.to_string(), registering);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::header::MessageType;
+    use super::super::message::packet::Packet;
+
+    fn request(path: &str) -> CoAPRequest {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let mut request = CoAPRequest::from_packet(packet, &"127.0.0.1:5683".parse().unwrap());
+        request.set_method(Method::Get);
+        request.set_path(path);
+        request
+    }
+
+    #[test]
+    fn test_route() {
+        let proxy = ReverseProxy::new(
+            &[("/", "coap://default/"), ("/sensors", "coap://10.0.0.1/s?k=v"), ("/sensors/a", "coap://a")],
+            0,
+            Duration::from_secs(1),
+        )
+        .unwrap();
+
+        let uri = proxy.route(&request("/sensors/temp")).unwrap();
+        assert_eq!(uri.to_string(), "coap://10.0.0.1/s/temp?k=v");
+        assert_eq!(proxy.route(&request("/sensors/a/b")).unwrap().to_string(), "coap://a/b");
+        assert_eq!(proxy.route(&request("/sensorsx")).unwrap().to_string(), "coap://default/sensorsx");
+
+        let mut query = request("/sensors");
+        query.add_option(CoAPOption::UriQuery, b"x=1".to_vec());
+        assert_eq!(proxy.route(&query).unwrap().to_string(), "coap://10.0.0.1/s?k=v&x=1");
+
+        let mut empty = request("/sensors");
+        empty.message.header.code = MessageClass::Empty;
+        assert!(proxy.route(&empty).is_none());
+
+        assert!(ReverseProxy::new(&[("/", "coaps://a")], 0, Duration::from_secs(1)).is_err());
+        assert!(ReverseProxy::new(&[("/", "/relative")], 0, Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_observe_in_flight() {
+        let proxy = ReverseProxy::new(&[("/", "coap://127.0.0.1:9")], 0, Duration::from_secs(1)).unwrap();
+        let request = request("/b");
+        let uri = proxy.route(&request).unwrap();
+
+        // While one registration is on its way, others fail at once.
+        proxy.observations.lock().unwrap().upstream.insert("a".to_string(), Upstream::Registering(0));
+        match proxy.observe(&request, "b", &uri, |_, _| ()) {
+            Err(Error::Response(Status::ServiceUnavailable)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(proxy.is_current("a", 0));
+        assert!(proxy.release("a").is_none());
+        assert!(!proxy.is_current("b", 1));
+    }
+
+    #[test]
+    fn test_is_registration() {
+        let mut request = request("/a");
+        assert!(!is_registration(&request));
+        request.set_observe(vec![]);
+        assert!(is_registration(&request));
+        request.set_observe(vec![ObserveOption::Deregister as u8]);
+        assert!(!is_registration(&request));
+    }
+}
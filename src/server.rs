@@ -1,19 +1,37 @@
-use std;
-use std::io::{Error, ErrorKind};
+use std::any::Any;
+use std::fmt;
 use std::thread;
 use std::net::{ToSocketAddrs, SocketAddr};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 use mio::{EventLoop, PollOpt, EventSet, Handler, Sender, Token};
 use mio::udp::UdpSocket;
 use log::{warn, debug, error, info};
-use super::message::packet::Packet;
-use super::message::request::{CoAPRequest};
+use super::message::header::MessageClass;
+use super::message::packet::{CoAPOption, Packet};
+use super::message::request::{AppState, CoAPRequest, Method};
+use super::message::response::Status;
+use super::message::uri::encode_uint;
 use super::message::IsMessage;
 use super::message::response::CoAPResponse;
 use threadpool::ThreadPool;
-use super::observer::Observer;
+use super::observer::{self, Observer};
+use super::conditional::{self, Conditional};
+use super::discovery::{Discovery, Link};
+use super::middleware::{Middleware, Next};
+use super::access::{self, AccessPolicy};
+use super::amplification::{AmplificationLimits, RateLimitAction, RateLimiter};
+use super::echo::EchoVerifier;
+use super::proxy::{self, ForwardProxy};
+use super::reverse_proxy::{self, ReverseProxy};
+use super::error::{Error, Result};
+use super::congestion::TransmissionParameters;
+#[cfg(feature = "oscore")]
+use super::oscore::{self, Recipients, SecurityContext};
 
 const DEFAULT_WORKER_NUM: usize = 4;
+/// How often the observer looks for notifications due for retransmission.
+const OBSERVE_TIMER_MS: u64 = 100;
 
 pub type TxQueue = mpsc::Sender<QueuedMessage>;
 type RxQueue = mpsc::Receiver<QueuedMessage>;
@@ -26,6 +44,19 @@ pub enum CoAPServerError {
     EventSendError,
 }
 
+impl fmt::Display for CoAPServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CoAPServerError::NetworkError => write!(f, "network error"),
+            CoAPServerError::EventLoopError => write!(f, "event loop error"),
+            CoAPServerError::AnotherHandlerIsRunning => write!(f, "another handler is running"),
+            CoAPServerError::EventSendError => write!(f, "event send error"),
+        }
+    }
+}
+
+impl std::error::Error for CoAPServerError {}
+
 #[derive(Debug)]
 pub struct QueuedMessage {
     pub address: SocketAddr,
@@ -37,6 +68,9 @@ enum EventLoopNotifyType {
     NewResponse,
     Shutdown,
     UpdateResource,
+    /// A notification from the upstream observation with the ID.
+    UpdateUpstreamResource(u64),
+    Register,
 }
 
 #[derive(Debug)]
@@ -57,13 +91,187 @@ enum ResponseError {
     PacketInvalid,
 }
 
-pub trait CoAPHandler: Sync + Send + Copy {
+/// How the server attaches ETags to 2.05 Content responses.
+///
+/// Whatever the policy, GET requests whose ETag matches the response are
+/// answered with 2.03 Valid, and updates whose If-Match or If-None-Match
+/// fails against the last ETag sent for the resource are answered with 4.12
+/// Precondition Failed without reaching the handler.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ETagPolicy {
+    /// Only ETags set by the handler are sent.
+    #[default]
+    Handler,
+    /// Responses without an ETag get one hashed from their Content-Format
+    /// and payload.
+    Hash,
+}
+
+impl ETagPolicy {
+    /// The ETag `response` is sent with if it is a 2.05 Content, such as to
+    /// pass the ETag of the current representation of a resource to
+    /// `CoAPRequest::preconditions_met`.
+    pub fn etag(&self, response: &CoAPResponse) -> Option<Vec<u8>> {
+        conditional::etag(response, *self)
+    }
+}
+
+/// The settings of a server which apply to every request, whatever the
+/// handler.
+#[derive(Clone, Default)]
+struct ServerConfig {
+    etag_policy: ETagPolicy,
+    proxy: Option<Arc<ForwardProxy>>,
+    /// Whether the forward proxy may forward to the host's own addresses.
+    proxy_local_targets: bool,
+    reverse_proxy: Option<Arc<ReverseProxy>>,
+    echo_freshness: Option<Duration>,
+    limits: Option<AmplificationLimits>,
+    /// Shared by the requests of one `handle` call, if Echo is used.
+    echo: Option<Arc<EchoVerifier>>,
+    access: Option<Arc<AccessPolicy>>,
+    /// Of confirmable notifications.
+    parameters: TransmissionParameters,
+    /// Added by the user; `handle` appends the conditional request support
+    /// and discovery.
+    middleware: Vec<Arc<dyn Middleware>>,
+    /// How many of `middleware` were added by the user, which is all that
+    /// sees Observe registrations and requests for the proxies.
+    user_middleware: usize,
+    links: Vec<Link>,
+    state: Arc<AppState>,
+    #[cfg(feature = "oscore")]
+    oscore: Option<Arc<Recipients>>,
+}
+
+impl ServerConfig {
+    /// How long Echo values are needed, if they are used at all.
+    fn echo_retention(&self) -> Option<Duration> {
+        let verification = self
+            .limits
+            .as_ref()
+            .filter(|limits| limits.max_ratio.is_some())
+            .map(|limits| limits.verification_lifetime);
+        match (self.echo_freshness, verification) {
+            (Some(freshness), Some(verification)) => Some(freshness.max(verification)),
+            (freshness, verification) => freshness.or(verification),
+        }
+    }
+}
+
+/// Passes a request to the handler, through the OSCORE layer if it is
+/// protected.
+fn handle_local<H: CoAPHandler>(
+    handler: &H,
+    config: &ServerConfig,
+    events: &Sender<EventLoopNotify>,
+    request: CoAPRequest,
+) -> Option<CoAPResponse> {
+    #[cfg(feature = "oscore")]
+    {
+        if oscore::is_protected(&request) {
+            return match config.oscore {
+                Some(ref recipients) => {
+                    recipients.handle(request, |request| handle_authorized(handler, config, events, request))
+                }
+                None => oscore::not_supported(request),
+            };
+        }
+    }
+    handle_fresh(handler, config, events, request)
+}
+
+/// Passes an OSCORE request to the handler if the access policy allows its
+/// identity. Unprotected requests are checked as they arrive.
+#[cfg(feature = "oscore")]
+fn handle_authorized<H: CoAPHandler>(
+    handler: &H,
+    config: &ServerConfig,
+    events: &Sender<EventLoopNotify>,
+    request: CoAPRequest,
+) -> Option<CoAPResponse> {
+    if let Some(ref access) = config.access {
+        if let Err(status) = access.check(&request) {
+            return access::denial(&request, status);
+        }
+    }
+    handle_fresh(handler, config, events, request)
+}
+
+/// Passes a request through the middleware to the handler, unless it has an
+/// unsafe method and no fresh Echo value, in which case it is challenged. A
+/// PUT which is not challenged updates the observed resource.
+fn handle_fresh<H: CoAPHandler>(
+    handler: &H,
+    config: &ServerConfig,
+    events: &Sender<EventLoopNotify>,
+    request: CoAPRequest,
+) -> Option<CoAPResponse> {
+    if let (Some(freshness), Some(echo)) = (config.echo_freshness, config.echo.as_ref()) {
+        if EchoVerifier::requires_freshness(&request) && echo.verify(&request).is_none_or(|age| age > freshness) {
+            let source = request.source?;
+            return Some(echo.challenge(request.response?, &source));
+        }
+    }
+
+    if *request.get_method() == Method::Put {
+        if let Err(error) = events.send(EventLoopNotify {
+            notify_type: EventLoopNotifyType::UpdateResource,
+            request: Some(resource_update(&request)),
+        }) {
+            warn!("Notify UpdateResource failed, {:?}", error);
+        }
+    }
+    Next::new(&config.middleware, &|request| handler.handle(request)).run(request)
+}
+
+/// The update of the observed resource a PUT makes: its path, query and
+/// payload.
+fn resource_update(request: &CoAPRequest) -> CoAPRequest {
+    let mut update = CoAPRequest::new();
+    update.set_path(&request.get_path());
+    if let Some(queries) = request.get_option(CoAPOption::UriQuery) {
+        update.message.set_option(CoAPOption::UriQuery, queries.clone());
+    }
+    update.set_payload(request.message.payload.clone());
+    update
+}
+
+#[cfg(feature = "oscore")]
+fn is_protected(request: &CoAPRequest) -> bool {
+    oscore::is_protected(request)
+}
+
+#[cfg(not(feature = "oscore"))]
+fn is_protected(_request: &CoAPRequest) -> bool {
+    false
+}
+
+/// Replaces a response which is too large to send to an unverified address
+/// with an Echo challenge.
+fn limit_amplification(config: &ServerConfig, src: &SocketAddr, request_len: usize, response: CoAPResponse) -> CoAPResponse {
+    if let (Some(limits), Some(echo)) = (config.limits.as_ref(), config.echo.as_ref()) {
+        if let Some(ratio) = limits.max_ratio {
+            if response.message.encoded_len() > ratio.saturating_mul(request_len)
+                && !echo.is_verified(src, limits.verification_lifetime)
+            {
+                return echo.challenge(response, src);
+            }
+        }
+    }
+    response
+}
+
+/// Answers the requests of a server. The workers share one handler, so
+/// state it holds needs to be `Sync`, such as an `Arc<Mutex<_>>`; state set
+/// with `CoAPServer::set_state` is available from every request instead.
+pub trait CoAPHandler: Sync + Send {
     fn handle(&self, request: CoAPRequest) -> Option<CoAPResponse>;
 }
 
 impl<F> CoAPHandler for F
     where F: Fn(CoAPRequest) -> Option<CoAPResponse>,
-          F: Sync + Send + Copy
+          F: Sync + Send
 {
     fn handle(&self, request: CoAPRequest) -> Option<CoAPResponse> {
         return self(request);
@@ -75,7 +283,9 @@ struct UdpHandler<H: CoAPHandler + 'static, N: Fn() + Send + 'static> {
     tx_sender: TxQueue,
     rx_recv: RxQueue,
     worker_pool: ThreadPool,
-    coap_handler: H,
+    coap_handler: Arc<H>,
+    config: ServerConfig,
+    rate_limiter: Option<RateLimiter>,
     observer: Observer<N>,
 }
 
@@ -85,42 +295,211 @@ impl<H: CoAPHandler + 'static, N: Fn() + Send + 'static> UdpHandler<H, N> {
            rx_recv: RxQueue,
            worker_num: usize,
            coap_handler: H,
+           config: ServerConfig,
            response_notify: N)
            -> UdpHandler<H, N> {
         let response_q = tx_sender.clone();
+        let mut observer = Observer::new(response_q, config.parameters.clone(), response_notify);
+        observer.set_max_observations(config.limits.as_ref().and_then(|limits| limits.max_observations));
 
         UdpHandler {
             socket: socket,
             tx_sender: tx_sender,
             rx_recv: rx_recv,
             worker_pool: ThreadPool::new(worker_num),
-            coap_handler: coap_handler,
-            observer: Observer::new(response_q, response_notify),
+            coap_handler: Arc::new(coap_handler),
+            rate_limiter: config.limits.as_ref().and_then(|limits| limits.rate_limit.clone()).map(RateLimiter::new),
+            config,
+            observer,
+        }
+    }
+
+    /// Whether a request passes the rate limit and the access policy and, if
+    /// it registers an observation while responses to unverified addresses
+    /// are limited, its source is verified. Rejected requests are answered
+    /// here.
+    fn admit(&mut self, event_loop: &mut EventLoop<UdpHandler<H, N>>, request: &CoAPRequest) -> bool {
+        if !matches!(request.message.header.code, MessageClass::Request(_)) {
+            return true;
+        }
+        let src = request.source.unwrap();
+
+        if let Some(ref mut rate_limiter) = self.rate_limiter {
+            if let Err(wait) = rate_limiter.check(src.ip(), Instant::now()) {
+                debug!("Rate limited request from {}", src);
+                if let (RateLimitAction::Reject, Some(mut response)) = (rate_limiter.action(), request.response.clone()) {
+                    let max_age = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+                    response.set_status(Status::TooManyRequests);
+                    response.message.payload.clear();
+                    response.message.add_option(CoAPOption::MaxAge, encode_uint(max_age as u32));
+                    self.respond(event_loop, src, response.message);
+                }
+                return false;
+            }
+        }
+
+        // The identity of OSCORE requests is only known once they are
+        // unprotected, by the worker. Those for the forward proxy are
+        // relayed as they are, so they are checked here without one.
+        if let Some(access) = self.config.access.clone() {
+            if proxy::is_proxy_request(request) || !is_protected(request) {
+                if let Err(status) = access.check(request) {
+                    debug!("Denied {:?} {} to {}", request.get_method(), request.get_path(), src);
+                    if let Some(response) = access::denial(request, status) {
+                        self.respond(event_loop, src, response.message);
+                    }
+                    return false;
+                }
+            }
+        }
+
+        if let Some(echo) = self.config.echo.clone() {
+            echo.verify(request);
+            // Notifications would be sent to an address which may be spoofed.
+            if let Some(ref limits) = self.config.limits {
+                if limits.max_ratio.is_some()
+                    && reverse_proxy::is_registration(request)
+                    && !echo.is_verified(&src, limits.verification_lifetime)
+                {
+                    if let Some(response) = request.response.clone() {
+                        self.respond(event_loop, src, echo.challenge(response, &src).message);
+                    }
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Queue a response from the event loop.
+    fn respond(&self, event_loop: &mut EventLoop<UdpHandler<H, N>>, address: SocketAddr, message: Packet) {
+        if let Err(error) = self.tx_sender.send(QueuedMessage { address, message }) {
+            error!("Failed to queue response, {}", error);
+            return;
+        }
+        if let Err(error) = event_loop.reregister(&self.socket, Token(0), EventSet::writable(), PollOpt::edge()) {
+            error!("Failed to wait for the socket to be writable, {}", error);
+        }
+    }
+
+    /// Cancel the upstream observations of proxied resources nobody
+    /// observes any more.
+    fn release_unobserved(&mut self) {
+        let reverse_proxy = match self.config.reverse_proxy {
+            Some(ref reverse_proxy) => reverse_proxy.clone(),
+            None => return,
+        };
+        for key in self.observer.take_unobserved() {
+            if let Some(client) = reverse_proxy.release(&key) {
+                self.observer.remove_resource(&key);
+                // Deregistering upstream waits for the network.
+                self.worker_pool.execute(move || drop(client));
+            }
         }
     }
 
     fn request_handler(&mut self, event_loop: &mut EventLoop<UdpHandler<H, N>>) {
         match self.requset_recv() {
-            Some(rqst) => {
-                let filtered = !self.observer.request_handler(&rqst);
-                if filtered {
+            Some(mut rqst) => {
+                if !self.admit(event_loop, &rqst) {
                     return;
                 }
 
+                // Requests for the forward proxy, OSCORE requests, which
+                // may have passed no access check yet, and requests routed
+                // upstream bypass the observer, except for observations of
+                // resources it already proxies.
+                let forward = proxy::is_proxy_request(&rqst);
+                let protected = is_protected(&rqst);
+                let route = match self.config.reverse_proxy {
+                    Some(ref reverse_proxy) if !forward && !protected => reverse_proxy.route(&rqst),
+                    _ => None,
+                };
+                let register_upstream = route.is_some()
+                    && reverse_proxy::is_registration(&rqst)
+                    && !self.observer.has_resource(&observer::resource_key(&rqst));
+                let observed = !forward
+                    && !protected
+                    && (route.is_none() || (rqst.get_observe().is_some() && !register_upstream));
+                let registration = observed && reverse_proxy::is_registration(&rqst);
+                // PUTs update the resource once the worker has checked their
+                // freshness, and registrations reach the observer once they
+                // passed the middleware.
+                if observed && !registration && *rqst.get_method() != Method::Put {
+                    let handle = self.observer.request_handler(&rqst);
+                    self.release_unobserved();
+                    if !handle {
+                        return;
+                    }
+                }
+
+                rqst.state = Some(self.config.state.clone());
                 let src = rqst.source.unwrap();
-                let coap_handler = self.coap_handler;
+                let coap_handler = self.coap_handler.clone();
+                let config = self.config.clone();
                 let response_q = self.tx_sender.clone();
                 let event_sender = event_loop.channel();
 
                 self.worker_pool.execute(move || {
-                    match coap_handler.handle(rqst) {
+                    let request_len = rqst.message.encoded_len();
+                    let user_middleware = &config.middleware[..config.user_middleware];
+                    // The observer answers registrations.
+                    let register = |rqst: CoAPRequest| {
+                        let _ = event_sender.send(EventLoopNotify {
+                            notify_type: EventLoopNotifyType::Register,
+                            request: Some(rqst),
+                        });
+                        None
+                    };
+                    let response = if proxy::is_proxy_request(&rqst) {
+                        let forward = |rqst: CoAPRequest| match config.proxy {
+                            Some(ref forward_proxy) => forward_proxy.handle(rqst, config.proxy_local_targets),
+                            None => proxy::not_supported(rqst),
+                        };
+                        Next::new(user_middleware, &forward).run(rqst)
+                    } else if registration {
+                        Next::new(user_middleware, &register).run(rqst)
+                    } else if let (Some(reverse_proxy), Some(uri)) = (config.reverse_proxy.as_ref(), route) {
+                        let relay = |rqst: CoAPRequest| {
+                            if !register_upstream {
+                                return reverse_proxy.handle(rqst, uri.clone());
+                            }
+                            let resource = resource_update(&rqst);
+                            let update_sender = event_sender.clone();
+                            let key = observer::resource_key(&rqst);
+                            let observed = reverse_proxy.observe(&rqst, &key, &uri, move |id, payload| {
+                                let mut update = resource.clone();
+                                update.set_payload(payload);
+                                let _ = update_sender.send(EventLoopNotify {
+                                    notify_type: EventLoopNotifyType::UpdateUpstreamResource(id),
+                                    request: Some(update),
+                                });
+                            });
+                            match observed {
+                                // Once it knows the resource.
+                                Ok(()) => register(rqst),
+                                Err(e) => {
+                                    debug!("Observing upstream failed, {}", e);
+                                    reverse_proxy.handle(rqst, uri.clone())
+                                }
+                            }
+                        };
+                        Next::new(user_middleware, &relay).run(rqst)
+                    } else {
+                        handle_local(&*coap_handler, &config, &event_sender, rqst)
+                    };
+                    let response = response.map(|response| limit_amplification(&config, &src, request_len, response));
+                    match response {
                         Some(response) => {
                             debug!("Response: {:?}", response);
 
-                            response_q.send(QueuedMessage {
+                            if let Err(error) = response_q.send(QueuedMessage {
                                 address: src,
                                 message: response.message,
-                            }).unwrap();
+                            }) {
+                                error!("Failed to queue response, {}", error);
+                                return;
+                            }
                             match event_sender.send(EventLoopNotify {
                                 notify_type: EventLoopNotifyType::NewResponse,
                                 request: None
@@ -175,20 +554,24 @@ impl<H: CoAPHandler + 'static, N: Fn() + Send + 'static> UdpHandler<H, N> {
                     Ok(packet) => {
                         return Some(CoAPRequest::from_packet(packet, &src));
                     }
-                    Err(_) => {
-                        error!("Failed to parse request");
+                    Err(error) => {
+                        error!("Failed to parse request, {}", error);
                         return None;
                     }
                 }
             }
-            _ => {
-                error!("Failed to read from socket");
-                panic!("unexpected error");
+            Ok(None) => {
+                debug!("Spurious readable event");
+                None
+            }
+            Err(error) => {
+                error!("Failed to read from socket, {}", error);
+                None
             }
         }
     }
 
-    fn response_send(&self, q_res: & QueuedMessage) -> Result<(), ResponseError> {
+    fn response_send(&self, q_res: & QueuedMessage) -> std::result::Result<(), ResponseError> {
         match q_res.message.to_bytes() {
             Ok(bytes) => {
                 match self.socket.send_to(&bytes[..], &q_res.address) {
@@ -244,6 +627,20 @@ impl<H: CoAPHandler + 'static, N: Fn() + Send + 'static> Handler for UdpHandler<
             EventLoopNotifyType::UpdateResource => {
                 self.observer.change_resource(&msg.request.unwrap());
             }
+            EventLoopNotifyType::UpdateUpstreamResource(id) => {
+                let update = msg.request.unwrap();
+                let current = match self.config.reverse_proxy {
+                    Some(ref reverse_proxy) => reverse_proxy.is_current(&observer::resource_key(&update), id),
+                    None => false,
+                };
+                if current {
+                    self.observer.change_resource(&update);
+                }
+            }
+            EventLoopNotifyType::Register => {
+                self.observer.request_handler(&msg.request.unwrap());
+                self.release_unobserved();
+            }
         }
     }
 
@@ -251,7 +648,7 @@ impl<H: CoAPHandler + 'static, N: Fn() + Send + 'static> Handler for UdpHandler<
         match token {
             EventLoopTimer::ObserveTimer => {
                 self.observer.timer_handler();
-                event_loop.timeout_ms(EventLoopTimer::ObserveTimer, 1000).unwrap();
+                event_loop.timeout_ms(EventLoopTimer::ObserveTimer, OBSERVE_TIMER_MS).unwrap();
             }
         }
     }
@@ -262,30 +659,26 @@ pub struct CoAPServer {
     event_sender: Option<Sender<EventLoopNotify>>,
     event_thread: Option<thread::JoinHandle<()>>,
     worker_num: usize,
+    config: ServerConfig,
 }
 
 impl CoAPServer {
     /// Creates a CoAP server listening on the given address.
-    pub fn new<A: ToSocketAddrs>(addr: A) -> std::io::Result<CoAPServer> {
-        addr.to_socket_addrs().and_then(|mut iter| {
-            match iter.next() {
-                Some(ad) => {
-                    UdpSocket::bound(&ad).and_then(|s| {
-                        Ok(CoAPServer {
-                            socket: s,
-                            event_sender: None,
-                            event_thread: None,
-                            worker_num: DEFAULT_WORKER_NUM,
-                        })
-                    })
-                }
-                None => Err(Error::new(ErrorKind::Other, "no address")),
-            }
-        })
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<CoAPServer> {
+        match addr.to_socket_addrs()?.next() {
+            Some(ad) => Ok(CoAPServer {
+                socket: UdpSocket::bound(&ad)?,
+                event_sender: None,
+                event_thread: None,
+                worker_num: DEFAULT_WORKER_NUM,
+                config: ServerConfig::default(),
+            }),
+            None => Err(Error::NoAddress),
+        }
     }
 
     /// Starts handling requests with the handler
-    pub fn handle<H: CoAPHandler + 'static>(&mut self, handler: H) -> Result<(), CoAPServerError> {
+    pub fn handle<H: CoAPHandler + 'static>(&mut self, handler: H) -> std::result::Result<(), CoAPServerError> {
         let socket;
 
         // Early return error checking
@@ -305,6 +698,13 @@ impl CoAPServer {
         let (tx, rx) = mpsc::channel();
         let (tx_send, tx_recv): (TxQueue, RxQueue) = mpsc::channel();
         let worker_num = self.worker_num;
+        let mut config = self.config.clone();
+        config.echo = config.echo_retention().map(|retention| Arc::new(EchoVerifier::new(retention)));
+        config.user_middleware = config.middleware.len();
+        config.middleware.push(Arc::new(Conditional::new(config.etag_policy)));
+        if !config.links.is_empty() {
+            config.middleware.push(Arc::new(Discovery(config.links.clone())));
+        }
 
         // Setup and spawn event loop thread, which will spawn
         //   children threads which handle incomining requests
@@ -315,10 +715,10 @@ impl CoAPServer {
             tx.send(event_loop.channel()).unwrap();
 
             // setup observe timer
-            event_loop.timeout_ms(EventLoopTimer::ObserveTimer, 1000).unwrap();
+            event_loop.timeout_ms(EventLoopTimer::ObserveTimer, OBSERVE_TIMER_MS).unwrap();
 
             let event_sender = event_loop.channel();
-            event_loop.run(&mut UdpHandler::new(socket, tx_send, tx_recv, worker_num, handler, move || {
+            event_loop.run(&mut UdpHandler::new(socket, tx_send, tx_recv, worker_num, handler, config, move || {
                 match event_sender.send(EventLoopNotify {
                                 notify_type: EventLoopNotifyType::NewResponse,
                                 request: None
@@ -362,10 +762,203 @@ impl CoAPServer {
         self.worker_num = worker_num;
     }
 
-    /// Update the resource asynchronously, like PUT method in client
-    pub fn update_resource(&mut self, path: &str, payload: Vec<u8>) -> Result<(), CoAPServerError> {
+    /// Set how ETags are generated for responses. Takes effect for the next
+    /// call to `handle`.
+    pub fn set_etag_policy(&mut self, policy: ETagPolicy) {
+        self.config.etag_policy = policy;
+    }
+
+    /// Set the transmission parameters of confirmable notifications, which
+    /// are retransmitted with exponential backoff. Takes effect for the next
+    /// call to `handle`.
+    pub fn set_transmission_parameters(&mut self, parameters: TransmissionParameters) {
+        self.config.parameters = parameters;
+    }
+
+    /// Pass requests through `middleware` before the handler, the observer
+    /// or the proxies, after the middleware added before it. See the
+    /// `middleware` module for which requests it sees. Takes effect for the
+    /// next call to `handle`.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.config.middleware.push(Arc::new(middleware));
+    }
+
+    /// Remove all middleware added with `add_middleware`.
+    pub fn clear_middleware(&mut self) {
+        self.config.middleware.clear();
+    }
+
+    /// Advertise the resource at `path` with the target attributes
+    /// `attributes`, such as `("rt", "temperature-c")`, in
+    /// `/.well-known/core` (RFC 6690). An attribute with an empty value is
+    /// written without one.
+    ///
+    /// Once a link is added, the server answers GET requests for
+    /// `/.well-known/core` itself, with the links whose `href` or attributes
+    /// pass the query filters of the request, such as `?rt=temp*`. Takes
+    /// effect for the next call to `handle`.
+    pub fn add_link(&mut self, path: &str, attributes: &[(&str, &str)]) {
+        self.config.links.push(Link::new(path, attributes));
+    }
+
+    /// Stop advertising the links added with `add_link`, and pass requests
+    /// for `/.well-known/core` to the handler again.
+    pub fn clear_links(&mut self) {
+        self.config.links.clear();
+    }
+
+    /// Act as a forward proxy for requests with a Proxy-Uri or Proxy-Scheme
+    /// option, instead of answering them with 5.05 Proxying Not Supported.
+    ///
+    /// Requests are forwarded to `coap` targets, and to `http` targets as
+    /// described in `cross_proxy`; up to `cache_capacity` responses are
+    /// cached as their Max-Age allows. A target which does not respond
+    /// within `timeout` is answered with 5.04 Gateway Timeout, any other
+    /// failure with 5.02 Bad Gateway. Targets which resolve to a loopback,
+    /// link-local or unspecified address are refused with 4.03 Forbidden,
+    /// so that clients cannot reach services only the server's host or link
+    /// can; see `set_proxy_local_targets`. Takes effect for the next call
+    /// to `handle`.
+    pub fn enable_forward_proxy(&mut self, cache_capacity: usize, timeout: Duration) {
+        self.config.proxy = Some(Arc::new(ForwardProxy::new(cache_capacity, timeout)));
+    }
+
+    /// Let the forward proxy forward to loopback, link-local and unspecified
+    /// addresses, which it refuses by default. Takes effect for the next
+    /// call to `handle`.
+    pub fn set_proxy_local_targets(&mut self, allowed: bool) {
+        self.config.proxy_local_targets = allowed;
+    }
+
+    /// Answer requests with a Proxy-Uri or Proxy-Scheme option with 5.05
+    /// Proxying Not Supported, which is the default.
+    pub fn disable_forward_proxy(&mut self) {
+        self.config.proxy = None;
+    }
+
+    /// Act as a reverse proxy which serves each path prefix of `routes` from
+    /// its upstream `coap` URI, instead of passing those requests to the
+    /// handler.
+    ///
+    /// A request is routed by its longest matching prefix, which is replaced
+    /// by the path of the upstream URI. The first observation of a routed
+    /// resource, named by its path and query, registers upstream, and every
+    /// downstream observer is notified from that single registration until
+    /// the last one deregisters, which cancels it. Up to 64 resources are
+    /// observed upstream at once, registered one at a time; further
+    /// registrations, and those made while another is on its way upstream,
+    /// are forwarded as plain requests. Requests carry a Hop-Limit, so a request caught in a
+    /// forwarding loop is answered with 5.08 Hop Limit Reached. Caching and
+    /// timeouts work as for `enable_forward_proxy`. Takes effect for the
+    /// next call to `handle`.
+    ///
+    /// Fails with `Error::InvalidUrl` if an upstream URI is not a `coap`
+    /// URI.
+    pub fn enable_reverse_proxy(&mut self, routes: &[(&str, &str)], cache_capacity: usize, timeout: Duration) -> Result<()> {
+        self.config.reverse_proxy = Some(Arc::new(ReverseProxy::new(routes, cache_capacity, timeout)?));
+        Ok(())
+    }
+
+    /// Pass every request to the handler, which is the default.
+    pub fn disable_reverse_proxy(&mut self) {
+        self.config.reverse_proxy = None;
+    }
+
+    /// Only handle requests with an unsafe method (POST, PUT or DELETE) if
+    /// they carry an Echo value sent to their source within `freshness`
+    /// (RFC 9175 §2), and challenge the others with 4.01 Unauthorized and a
+    /// new Echo value. `CoAPClient` retries challenged requests
+    /// transparently. Takes effect for the next call to `handle`.
+    pub fn enable_echo(&mut self, freshness: Duration) {
+        self.config.echo_freshness = Some(freshness);
+    }
+
+    /// Handle requests without checking their freshness, which is the
+    /// default.
+    pub fn disable_echo(&mut self) {
+        self.config.echo_freshness = None;
+    }
+
+    /// Limit what the server sends to addresses which may be spoofed, so
+    /// that it cannot be used to amplify attacks on them.
+    ///
+    /// Responses larger than `max_ratio` times their request, and Observe
+    /// registrations, are answered with an Echo challenge until the source
+    /// address has returned an Echo value. Requests beyond the rate limit of
+    /// their IP address are rejected or dropped, and an IP address may only
+    /// observe up to `max_observations` resources. Takes effect for the
+    /// next call to `handle`.
+    pub fn set_amplification_limits(&mut self, limits: AmplificationLimits) {
+        self.config.limits = Some(limits);
+    }
+
+    /// Send responses of any size to any address, which is the default.
+    pub fn disable_amplification_limits(&mut self) {
+        self.config.limits = None;
+    }
+
+    /// Unprotect OSCORE requests with the security context whose recipient
+    /// ID is the key ID of the request, and protect their responses.
+    ///
+    /// The handler sees the unprotected request, with `identity` set to the
+    /// recipient ID and ID context of the context. Requests which fail to
+    /// unprotect, such as replays, are answered with an unprotected error as
+    /// in RFC 8613 §8.2; without any context, protected requests are
+    /// answered with 4.02 Bad Option. Protected requests bypass the
+    /// observer, so a protected registration is answered once by the
+    /// handler. Takes effect for the next call to `handle`.
+    #[cfg(feature = "oscore")]
+    pub fn enable_oscore(&mut self, contexts: Vec<SecurityContext>) {
+        self.config.oscore = Some(Arc::new(Recipients::new(contexts)));
+    }
+
+    /// Answer protected requests with 4.02 Bad Option, which is the default.
+    #[cfg(feature = "oscore")]
+    pub fn disable_oscore(&mut self) {
+        self.config.oscore = None;
+    }
+
+    /// The OSCORE context for the client `recipient_id`, such as to store its
+    /// sequence numbers, also while the server is running. Requests from
+    /// that client wait while it is held.
+    #[cfg(feature = "oscore")]
+    pub fn oscore_context(&self, recipient_id: &[u8]) -> Option<std::sync::MutexGuard<'_, SecurityContext>> {
+        self.config.oscore.as_ref()?.context(recipient_id)
+    }
+
+    /// Only handle the requests `policy` allows, answering the others with
+    /// 4.01 Unauthorized or 4.03 Forbidden. This covers requests for local
+    /// resources, including Observe registrations, and requests routed by
+    /// the reverse proxy; OSCORE requests are checked with the identity
+    /// they were protected under. Requests for the forward proxy are checked
+    /// against the rules of the resource `proxy`, without an identity.
+    /// Takes effect for the next call to `handle`.
+    pub fn set_access_policy(&mut self, policy: AccessPolicy) {
+        self.config.access = Some(Arc::new(policy));
+    }
+
+    /// Handle every request, which is the default.
+    pub fn clear_access_policy(&mut self) {
+        self.config.access = None;
+    }
+
+    /// Make `state` available to every request with `CoAPRequest::state`,
+    /// replacing any state of the same type. Takes effect for the next call
+    /// to `handle`.
+    pub fn set_state<T: Any + Send + Sync>(&mut self, state: T) {
+        Arc::make_mut(&mut self.config.state).insert(state);
+    }
+
+    /// Update the resource asynchronously, like PUT method in client. A
+    /// query after `?` names a resource of its own, observed by the
+    /// registrations with that query.
+    pub fn update_resource(&mut self, path: &str, payload: Vec<u8>) -> std::result::Result<(), CoAPServerError> {
         let mut request = CoAPRequest::new();
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
         request.set_path(path);
+        for argument in query.split('&').filter(|argument| !argument.is_empty()) {
+            request.add_option(CoAPOption::UriQuery, argument.as_bytes().to_vec());
+        }
         request.set_payload(payload);
 
         match self.event_sender {
@@ -384,8 +977,8 @@ impl CoAPServer {
 
     /// Return the local address that the server is listening on. This can be useful when starting
     /// a server on a random port as part of unit testing.
-    pub fn socket_addr(&self) -> std::io::Result<SocketAddr> {
-        self.socket.local_addr()
+    pub fn socket_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
     }
 }
 
@@ -425,9 +1018,9 @@ mod test {
         let mut request = CoAPRequest::new();
         request.set_version(1);
         request.set_type(MessageType::Confirmable);
-        request.set_code("0.01");
+        request.set_code("0.01").unwrap();
         request.set_message_id(1);
-        request.set_token(vec![0x51, 0x55, 0x77, 0xE8]);
+        request.set_token(vec![0x51, 0x55, 0x77, 0xE8]).unwrap();
         request.add_option(CoAPOption::UriPath, b"test-echo".to_vec());
         client.send(&request).unwrap();
 
@@ -444,7 +1037,7 @@ mod test {
         let mut packet = CoAPRequest::new();
         packet.set_version(1);
         packet.set_type(MessageType::Confirmable);
-        packet.set_code("0.01");
+        packet.set_code("0.01").unwrap();
         packet.set_message_id(1);
         packet.add_option(CoAPOption::UriPath, b"test-echo".to_vec());
         client.send(&packet).unwrap();
@@ -521,13 +1114,820 @@ mod test {
         let mut request = CoAPRequest::new();
         request.set_version(1);
         request.set_type(MessageType::Confirmable);
-        request.set_code("0.01");
+        request.set_code("0.01").unwrap();
         request.set_message_id(1);
-        request.set_token(vec![0x51, 0x55, 0x77, 0xE8]);
+        request.set_token(vec![0x51, 0x55, 0x77, 0xE8]).unwrap();
         request.add_option(CoAPOption::UriPath, b"test-echo".to_vec());
         client.send(&request).unwrap();
 
         let recv_packet = client.receive().unwrap();
         assert_eq!(recv_packet.message.payload, b"test-echo".to_vec());
     }
+
+    #[test]
+    fn test_conditional_requests() {
+        use std::sync::Mutex;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let value = Mutex::new(b"v1".to_vec());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        // Accepts every update; the preconditions are enforced for it.
+        let handler = move |request: CoAPRequest| {
+            handler_calls.fetch_add(1, Ordering::SeqCst);
+            let mut response = request.response.clone()?;
+            let mut value = value.lock().unwrap();
+            if *request.get_method() == Method::Put {
+                *value = request.message.payload.clone();
+                response.set_status(Status::Changed);
+            } else {
+                response.set_payload(value.clone());
+            }
+            Some(response)
+        };
+
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.set_etag_policy(ETagPolicy::Hash);
+        server.handle(handler).unwrap();
+
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        let url = format!("coap://{}/conditional", server.socket_addr().unwrap());
+        let response = client.build_request(Method::Get, &url).send().unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        let etag = response.get_option(CoAPOption::ETag).unwrap().front().unwrap().clone();
+
+        let response = client.build_request(Method::Get, &url).etag(etag.clone()).send().unwrap();
+        assert_eq!(*response.get_status(), Status::Valid);
+        assert!(response.message.payload.is_empty());
+
+        let response = client.put(&url).if_match(b"stale".to_vec()).payload(b"v2".to_vec()).send().unwrap();
+        assert_eq!(*response.get_status(), Status::PreconditionFailed);
+        let response = client.put(&url).if_none_match().payload(b"v2".to_vec()).send().unwrap();
+        assert_eq!(*response.get_status(), Status::PreconditionFailed);
+        let response = client.put(&url).if_match(etag).payload(b"v2".to_vec()).send().unwrap();
+        assert_eq!(*response.get_status(), Status::Changed);
+        let response = client.build_request(Method::Get, &url).send().unwrap();
+        assert_eq!(response.message.payload, b"v2".to_vec());
+
+        // The requests whose preconditions failed never reached the handler.
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    static ORIGIN_REQUESTS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    fn origin_handler(req: CoAPRequest) -> Option<CoAPResponse> {
+        ORIGIN_REQUESTS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let path = req.get_path();
+        let mut response = req.response?;
+        response.set_payload(path.into_bytes());
+        Some(response)
+    }
+
+    #[test]
+    fn test_forward_proxy() {
+        let mut origin = CoAPServer::new("127.0.0.1:0").unwrap();
+        origin.handle(origin_handler).unwrap();
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mut proxy = CoAPServer::new("127.0.0.1:0").unwrap();
+        proxy.enable_forward_proxy(8, Duration::from_millis(500));
+        proxy.set_proxy_local_targets(true);
+        proxy.handle(request_handler).unwrap();
+        let mut plain = CoAPServer::new("127.0.0.1:0").unwrap();
+        plain.handle(request_handler).unwrap();
+        let mut guarded = CoAPServer::new("127.0.0.1:0").unwrap();
+        guarded.enable_forward_proxy(0, Duration::from_millis(500));
+        guarded.handle(request_handler).unwrap();
+
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        let proxy_url = format!("coap://{}", proxy.socket_addr().unwrap());
+        let target = format!("coap://{}/a/b", origin.socket_addr().unwrap());
+
+        // By default the proxy does not reach the services of its own host.
+        let response = client
+            .build_request(Method::Get, &format!("coap://{}", guarded.socket_addr().unwrap()))
+            .option(CoAPOption::ProxyUri, target.clone().into_bytes())
+            .send()
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::Forbidden);
+        for _ in 0..2 {
+            let response = client
+                .build_request(Method::Get, &proxy_url)
+                .option(CoAPOption::ProxyUri, target.clone().into_bytes())
+                .send()
+                .unwrap();
+            assert_eq!(*response.get_status(), Status::Content);
+            assert_eq!(response.message.payload, b"a/b".to_vec());
+        }
+        // The second response came from the cache of the proxy.
+        assert_eq!(ORIGIN_REQUESTS.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // An observation of a proxied resource is forwarded rather than
+        // registered with the proxy's own resource of that path.
+        proxy.update_resource("/a/c", b"local".to_vec()).unwrap();
+        let response = client
+            .build_request(Method::Get, &proxy_url)
+            .option(CoAPOption::ProxyScheme, b"coap".to_vec())
+            .option(CoAPOption::UriHost, b"127.0.0.1".to_vec())
+            .option(CoAPOption::UriPort, encode_uint(origin.socket_addr().unwrap().port() as u32))
+            .option(CoAPOption::UriPath, b"a".to_vec())
+            .option(CoAPOption::UriPath, b"c".to_vec())
+            .option(CoAPOption::Observe, vec![message::packet::ObserveOption::Register as u8])
+            .send()
+            .unwrap();
+        assert_eq!(response.message.payload, b"a/c".to_vec());
+
+        let response = client
+            .build_request(Method::Get, &proxy_url)
+            .option(CoAPOption::ProxyUri, format!("coap://{}/", silent.local_addr().unwrap()).into_bytes())
+            .send()
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::GatewayTimeout);
+
+        let response = client
+            .build_request(Method::Get, &format!("coap://{}", plain.socket_addr().unwrap()))
+            .option(CoAPOption::ProxyUri, target.into_bytes())
+            .send()
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::ProxyingNotSupported);
+    }
+
+    #[cfg(feature = "oscore")]
+    fn identity_handler(request: CoAPRequest) -> Option<CoAPResponse> {
+        let sender_id = match request.identity {
+            Some(Identity::Oscore { ref sender_id, .. }) => sender_id.clone(),
+            None => b"anonymous".to_vec(),
+        };
+        let mut response = request.response?;
+        response.set_payload(sender_id);
+        Some(response)
+    }
+
+    #[test]
+    #[cfg(feature = "oscore")]
+    fn test_oscore() {
+        use super::super::message::uri::encode_uint;
+        use super::super::oscore::SecurityContext;
+
+        let secret = b"0123456789abcdef";
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.enable_oscore(vec![SecurityContext::new(secret, &[], None, b"s", b"c").unwrap()]);
+        server.handle(identity_handler).unwrap();
+        let server_addr = server.socket_addr().unwrap();
+        let url = format!("coap://{}/who", server_addr);
+
+        let mut client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        let response = client.build_request(Method::Get, &url).send().unwrap();
+        assert_eq!(response.message.payload, b"anonymous".to_vec());
+
+        // A request which fails to decrypt does not count as received.
+        client.enable_oscore(SecurityContext::new(b"wrong secret", &[], None, b"c", b"s").unwrap());
+        let response = client.build_request(Method::Get, &url).send().unwrap();
+        assert_eq!(*response.get_status(), Status::BadRequest);
+        client.enable_oscore(SecurityContext::new(secret, &[], None, b"c", b"s").unwrap());
+        let response = client.build_request(Method::Get, &url).send().unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, b"c".to_vec());
+        assert_eq!(client.oscore_context().unwrap().sender_sequence_number(), 1);
+        assert_eq!(server.oscore_context(b"c").unwrap().highest_received(), Some(0));
+        assert!(server.oscore_context(b"x").is_none());
+
+        // Protected messages pass through a forward proxy unchanged.
+        let mut proxy = CoAPServer::new("127.0.0.1:0").unwrap();
+        proxy.enable_forward_proxy(0, Duration::from_secs(1));
+        proxy.set_proxy_local_targets(true);
+        proxy.handle(request_handler).unwrap();
+        let response = client
+            .build_request(Method::Get, &format!("coap://{}/who", proxy.socket_addr().unwrap()))
+            .option(CoAPOption::UriHost, b"127.0.0.1".to_vec())
+            .option(CoAPOption::UriPort, encode_uint(server_addr.port() as u32))
+            .option(CoAPOption::ProxyScheme, b"coap".to_vec())
+            .send()
+            .unwrap();
+        assert_eq!(response.message.payload, b"c".to_vec());
+        let response = client.build_request(Method::Get, &url).send().unwrap();
+        assert_eq!(response.message.payload, b"c".to_vec());
+
+        client.enable_oscore(SecurityContext::new(secret, &[], None, b"x", b"s").unwrap());
+        let response = client.build_request(Method::Get, &url).send().unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+
+        let mut plain = CoAPServer::new("127.0.0.1:0").unwrap();
+        plain.handle(identity_handler).unwrap();
+        let url = format!("coap://{}/who", plain.socket_addr().unwrap());
+        let response = client.build_request(Method::Get, &url).send().unwrap();
+        assert_eq!(*response.get_status(), Status::BadOption);
+    }
+
+    #[test]
+    fn test_access_policy() {
+        use super::super::access::{AccessPolicy, Principal};
+
+        let policy = AccessPolicy::new()
+            .allow("/data", &[Method::Get], Principal::Any)
+            .allow("/data", &[], Principal::Oscore(Some(b"c".to_vec())))
+            .deny("/admin", &[], "127.0.0.0/8".parse().unwrap());
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.set_access_policy(policy);
+        #[cfg(feature = "oscore")]
+        server.enable_oscore(vec![oscore::SecurityContext::new(b"0123456789abcdef", &[], None, b"s", b"c").unwrap()]);
+        server.handle(echo_handler).unwrap();
+        let server_addr = server.socket_addr().unwrap();
+
+        let client = CoAPClient::new(server_addr).unwrap();
+        let response = exchange(&client, Method::Get, "/data").unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        let response = exchange(&client, Method::Post, "/data").unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+        assert!(response.message.payload.is_empty());
+        let response = exchange(&client, Method::Get, "/admin/users").unwrap();
+        assert_eq!(*response.get_status(), Status::Forbidden);
+        let response = exchange(&client, Method::Post, "/other").unwrap();
+        assert_eq!(*response.get_status(), Status::Changed);
+
+        #[cfg(feature = "oscore")]
+        {
+            let mut client = CoAPClient::bind("127.0.0.1:0").unwrap();
+            client.enable_oscore(oscore::SecurityContext::new(b"0123456789abcdef", &[], None, b"c", b"s").unwrap());
+            let response = client
+                .build_request(Method::Post, &format!("coap://{}/data", server_addr))
+                .send()
+                .unwrap();
+            assert_eq!(*response.get_status(), Status::Changed);
+        }
+    }
+
+    #[test]
+    fn test_access_policy_proxy() {
+        use super::super::access::{AccessPolicy, Principal};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let origin_requests = requests.clone();
+        let mut origin = CoAPServer::new("127.0.0.1:0").unwrap();
+        origin
+            .handle(move |request: CoAPRequest| {
+                origin_requests.fetch_add(1, Ordering::SeqCst);
+                request.response
+            })
+            .unwrap();
+
+        let mut proxy = CoAPServer::new("127.0.0.1:0").unwrap();
+        proxy.enable_forward_proxy(0, Duration::from_millis(500));
+        proxy.set_proxy_local_targets(true);
+        proxy.set_access_policy(
+            AccessPolicy::new()
+                .deny_proxy(&[Method::Put], "127.0.0.0/8".parse().unwrap())
+                .allow_proxy(&[], Principal::Any),
+        );
+        proxy.handle(request_handler).unwrap();
+
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        let proxy_url = format!("coap://{}", proxy.socket_addr().unwrap());
+        let target = format!("coap://{}/a", origin.socket_addr().unwrap());
+        let send = |method| {
+            client
+                .build_request(method, &proxy_url)
+                .option(CoAPOption::ProxyUri, target.clone().into_bytes())
+                .send()
+                .unwrap()
+        };
+
+        assert_eq!(*send(Method::Get).get_status(), Status::Content);
+        let response = send(Method::Put);
+        assert_eq!(*response.get_status(), Status::Forbidden);
+        assert!(response.message.payload.is_empty());
+        // The denied request never left the proxy.
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_access_policy_forged() {
+        use super::super::access::{AccessPolicy, Principal};
+
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.set_access_policy(
+            AccessPolicy::new()
+                .allow("/admin", &[Method::Get], Principal::Any)
+                .deny("/secret", &[], "127.0.0.0/8".parse().unwrap()),
+        );
+        server.enable_forward_proxy(0, Duration::from_secs(1));
+        #[cfg(feature = "oscore")]
+        server.enable_oscore(vec![oscore::SecurityContext::new(b"0123456789abcdef", &[], None, b"s", b"c").unwrap()]);
+        server.handle(echo_handler).unwrap();
+        server.update_resource("/admin", b"v1".to_vec()).unwrap();
+        server.update_resource("/secret", b"s".to_vec()).unwrap();
+        let server_addr = server.socket_addr().unwrap();
+        // The update reaches the event loop asynchronously.
+        thread::sleep(Duration::from_millis(100));
+
+        let (tx, rx) = mpsc::channel();
+        let mut observer = CoAPClient::new(server_addr).unwrap();
+        observer.observe("/admin", move |packet| tx.send(packet.payload).unwrap()).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"v1".to_vec());
+
+        // Neither an OSCORE option which protects nothing nor proxy options
+        // for the server itself get a PUT or an observation past the policy.
+        let client = CoAPClient::new(server_addr).unwrap();
+        let forged = [
+            (CoAPOption::Oscore, vec![0x09, 0x00, 0x01, b'c']),
+            (CoAPOption::ProxyScheme, b"coap".to_vec()),
+        ];
+        for (option, value) in forged {
+            let response = client
+                .build_request(Method::Put, &format!("coap://{}/admin", server_addr))
+                .option(CoAPOption::UriHost, b"127.0.0.1".to_vec())
+                .option(option, value.clone())
+                .payload(b"v2".to_vec())
+                .send()
+                .unwrap();
+            assert!(matches!(*response.get_status(), Status::Forbidden | Status::Unauthorized));
+
+            let response = client
+                .build_request(Method::Get, &format!("coap://{}/secret", server_addr))
+                .option(CoAPOption::UriHost, b"127.0.0.1".to_vec())
+                .option(CoAPOption::Observe, vec![message::packet::ObserveOption::Register as u8])
+                .option(option, value)
+                .send()
+                .unwrap();
+            assert!(matches!(*response.get_status(), Status::Forbidden | Status::Unauthorized));
+            assert_ne!(response.message.payload, b"s".to_vec());
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn test_middleware() {
+        use super::super::middleware::Next;
+
+        fn maintenance(request: CoAPRequest, next: &Next) -> Option<CoAPResponse> {
+            if *request.get_method() == Method::Get {
+                return next.run(request);
+            }
+            let mut response = request.response?;
+            response.set_status(Status::ServiceUnavailable);
+            Some(response)
+        }
+
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.set_etag_policy(ETagPolicy::Hash);
+        server.add_middleware(|request: CoAPRequest, next: &Next| {
+            let mut response = next.run(request)?;
+            // The ETag is added inside the chain.
+            assert_eq!(response.get_option(CoAPOption::ETag).is_some(), *response.get_status() == Status::Content);
+            response.add_option(CoAPOption::MaxAge, vec![30]);
+            Some(response)
+        });
+        server.add_middleware(maintenance);
+        server.handle(echo_handler).unwrap();
+
+        let client = CoAPClient::new(server.socket_addr().unwrap()).unwrap();
+        let response = exchange(&client, Method::Get, "/data").unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.get_option(CoAPOption::MaxAge).and_then(|list| list.front().cloned()), Some(vec![30]));
+        let response = exchange(&client, Method::Put, "/data").unwrap();
+        assert_eq!(*response.get_status(), Status::ServiceUnavailable);
+    }
+
+    #[test]
+    fn test_middleware_registrations_and_proxy() {
+        use super::super::middleware::Next;
+        use std::sync::Mutex;
+
+        let mut origin = CoAPServer::new("127.0.0.1:0").unwrap();
+        origin.handle(request_handler).unwrap();
+        let origin_addr = origin.socket_addr().unwrap();
+
+        // Records what it sees, and keeps "blocked" from being observed.
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.enable_forward_proxy(0, Duration::from_millis(500));
+        server.set_proxy_local_targets(true);
+        server
+            .enable_reverse_proxy(&[("/up", &format!("coap://{}", origin_addr))], 0, Duration::from_millis(500))
+            .unwrap();
+        server.add_middleware(move |request: CoAPRequest, next: &Next| {
+            let observe = request.get_observe().is_some();
+            recorded.lock().unwrap().push((request.get_path(), observe));
+            if observe && request.get_path() == "blocked" {
+                let mut response = request.response?;
+                response.set_status(Status::Forbidden);
+                return Some(response);
+            }
+            next.run(request)
+        });
+        server.handle(request_handler).unwrap();
+        let server_addr = server.socket_addr().unwrap();
+        for path in &["/open", "/blocked"] {
+            server.update_resource(path, b"1".to_vec()).unwrap();
+        }
+        // The updates reach the event loop asynchronously.
+        thread::sleep(Duration::from_millis(100));
+
+        let mut observer = CoAPClient::new(server_addr).unwrap();
+        observer.observe("/open", |_| ()).unwrap();
+        let mut blocked = CoAPClient::new(server_addr).unwrap();
+        match blocked.observe("/blocked", |_| ()) {
+            Err(Error::Response(Status::Forbidden)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        let response = client
+            .build_request(Method::Get, &format!("coap://{}", server_addr))
+            .option(CoAPOption::ProxyUri, format!("coap://{}/forward", origin_addr).into_bytes())
+            .send()
+            .unwrap();
+        assert_eq!(response.message.payload, b"forward".to_vec());
+        let response = client
+            .build_request(Method::Get, &format!("coap://{}/up/reverse", server_addr))
+            .send()
+            .unwrap();
+        assert_eq!(response.message.payload, b"reverse".to_vec());
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                ("open".to_string(), true),
+                ("blocked".to_string(), true),
+                (String::new(), false),
+                ("up/reverse".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_discovery() {
+        use super::super::message::packet::ContentFormat;
+
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.add_link("/sensors/temp", &[("rt", "temperature-c"), ("obs", "")]);
+        server.add_link("/sensors/light", &[("rt", "light-lux")]);
+        server.handle(echo_handler).unwrap();
+        let url = format!("coap://{}/.well-known/core", server.socket_addr().unwrap());
+
+        let client = CoAPClient::new(server.socket_addr().unwrap()).unwrap();
+        let response = client.build_request(Method::Get, &url).query("rt=temp*").send().unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::ApplicationLinkFormat));
+        assert_eq!(response.message.payload, b"</sensors/temp>;rt=\"temperature-c\";obs".to_vec());
+
+        // Other resources still reach the handler.
+        let response = exchange(&client, Method::Get, "/sensors/temp").unwrap();
+        assert_eq!(response.message.payload, vec![b'x'; 200]);
+    }
+
+    #[test]
+    fn test_handler_state() {
+        use std::sync::Mutex;
+
+        struct Greeting(&'static str);
+
+        // The handler owns a log, which is neither Copy nor global.
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handler_log = log.clone();
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.set_state(Greeting("hello"));
+        server
+            .handle(move |request: CoAPRequest| {
+                handler_log.lock().unwrap().push(request.get_path());
+                let greeting = request.state::<Greeting>()?.0;
+                let mut response = request.response?;
+                response.set_payload(greeting.as_bytes().to_vec());
+                Some(response)
+            })
+            .unwrap();
+
+        let client = CoAPClient::new(server.socket_addr().unwrap()).unwrap();
+        let response = exchange(&client, Method::Get, "/greeting").unwrap();
+        assert_eq!(response.message.payload, b"hello".to_vec());
+        assert_eq!(*log.lock().unwrap(), vec!["greeting".to_string()]);
+    }
+
+    fn echo_handler(request: CoAPRequest) -> Option<CoAPResponse> {
+        let is_get = *request.get_method() == Method::Get;
+        let mut response = request.response?;
+        if is_get {
+            response.set_payload(vec![b'x'; 200]);
+        } else {
+            response.set_status(Status::Changed);
+            response.set_payload(b"stored".to_vec());
+        }
+        Some(response)
+    }
+
+    /// Sends a request with a new message ID and token and receives the
+    /// response.
+    fn exchange(client: &CoAPClient, method: Method, path: &str) -> Result<CoAPResponse> {
+        let mut request = CoAPRequest::new();
+        request.set_method(method);
+        request.set_path(path);
+        request.set_type(MessageType::Confirmable);
+        request.set_message_id(client.gen_message_id());
+        request.set_token(client.gen_token()).unwrap();
+        client.send(&request)?;
+        client.receive()
+    }
+
+    #[test]
+    fn test_echo() {
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.enable_echo(Duration::from_secs(10));
+        server.handle(echo_handler).unwrap();
+        let server_addr = server.socket_addr().unwrap();
+        let url = format!("coap://{}/data", server_addr);
+
+        // Without an Echo value, unsafe requests are challenged.
+        let client = CoAPClient::new(server_addr).unwrap();
+        let response = exchange(&client, Method::Post, "/data").unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+        assert!(response.get_option(CoAPOption::Echo).is_some());
+        assert!(response.message.payload.is_empty());
+        let response = exchange(&client, Method::Get, "/data").unwrap();
+        assert_eq!(response.message.payload, vec![b'x'; 200]);
+
+        // The client retries with the Echo value.
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        let response = client.post(&url).payload(b"value".to_vec()).send().unwrap();
+        assert_eq!(response.message.payload, b"stored".to_vec());
+    }
+
+    #[test]
+    fn test_echo_observed() {
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.enable_echo(Duration::from_secs(10));
+        server.handle(echo_handler).unwrap();
+        server.update_resource("/data", b"1".to_vec()).unwrap();
+        let server_addr = server.socket_addr().unwrap();
+        // The update reaches the event loop asynchronously.
+        thread::sleep(Duration::from_millis(100));
+
+        let (tx, rx) = mpsc::channel();
+        let mut observer = CoAPClient::new(server_addr).unwrap();
+        observer.observe("/data", move |packet| tx.send(packet.payload).unwrap()).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"1".to_vec());
+
+        // A challenged PUT leaves the observed resource as it was.
+        let client = CoAPClient::new(server_addr).unwrap();
+        let response = exchange(&client, Method::Put, "/data").unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+        // Once the PUT is fresh, the change is notified.
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        let response = client.put(&format!("coap://{}/data", server_addr)).payload(b"2".to_vec()).send().unwrap();
+        assert_eq!(*response.get_status(), Status::Changed);
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"2".to_vec());
+    }
+
+    #[test]
+    fn test_amplification_limits() {
+        use super::super::amplification::{RateLimit, RateLimitAction};
+
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.set_amplification_limits(AmplificationLimits {
+            rate_limit: Some(RateLimit {
+                requests: 4,
+                period: Duration::from_secs(60),
+                action: RateLimitAction::Reject,
+            }),
+            ..AmplificationLimits::default()
+        });
+        server.handle(echo_handler).unwrap();
+        let server_addr = server.socket_addr().unwrap();
+
+        // Small responses go to any address, large ones once it is verified.
+        let client = CoAPClient::new(server_addr).unwrap();
+        let response = exchange(&client, Method::Post, "/data").unwrap();
+        assert_eq!(response.message.payload, b"stored".to_vec());
+        let response = exchange(&client, Method::Get, "/data").unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+        let response = client.build_request(Method::Get, &format!("coap://{}/data", server_addr)).send().unwrap();
+        assert_eq!(response.message.payload, vec![b'x'; 200]);
+
+        // The challenge and the retry used up the burst of four requests.
+        let response = exchange(&client, Method::Get, "/data").unwrap();
+        assert_eq!(*response.get_status(), Status::TooManyRequests);
+        assert_eq!(response.get_option(CoAPOption::MaxAge).unwrap().front().unwrap(), &vec![15]);
+
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.set_amplification_limits(AmplificationLimits {
+            rate_limit: Some(RateLimit {
+                requests: 1,
+                period: Duration::from_secs(60),
+                action: RateLimitAction::Drop,
+            }),
+            ..AmplificationLimits::default()
+        });
+        server.handle(echo_handler).unwrap();
+        let client = CoAPClient::new(server.socket_addr().unwrap()).unwrap();
+        exchange(&client, Method::Post, "/data").unwrap();
+        match exchange(&client, Method::Post, "/data") {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_observe_verification() {
+        let mut server = CoAPServer::new("127.0.0.1:0").unwrap();
+        server.set_amplification_limits(AmplificationLimits::default());
+        server.handle(echo_handler).unwrap();
+        server.update_resource("/temp", b"1".to_vec()).unwrap();
+        let server_addr = server.socket_addr().unwrap();
+
+        let client = CoAPClient::new(server_addr).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Get);
+        request.set_path("/temp");
+        request.set_observe(vec![message::packet::ObserveOption::Register as u8]);
+        request.set_type(MessageType::Confirmable);
+        request.set_message_id(client.gen_message_id());
+        request.set_token(client.gen_token()).unwrap();
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+
+        // The client registers again with the Echo value.
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut client = CoAPClient::new(server_addr).unwrap();
+        client.observe("/temp", move |packet| tx.send(packet.payload).unwrap()).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), b"1".to_vec());
+    }
+
+    #[test]
+    fn test_reverse_proxy() {
+        let mut origin = CoAPServer::new("127.0.0.1:0").unwrap();
+        origin.handle(origin_handler).unwrap();
+        let origin_url = format!("coap://{}/base", origin.socket_addr().unwrap());
+
+        // Two proxies which forward /loop to each other.
+        let mut proxy = CoAPServer::new("127.0.0.1:0").unwrap();
+        let mut other = CoAPServer::new("127.0.0.1:0").unwrap();
+        let proxy_url = format!("coap://{}", proxy.socket_addr().unwrap());
+        let other_url = format!("coap://{}", other.socket_addr().unwrap());
+        proxy
+            .enable_reverse_proxy(&[("/up", &origin_url), ("/loop", &format!("{}/loop", other_url))], 0, Duration::from_secs(1))
+            .unwrap();
+        proxy.handle(request_handler).unwrap();
+        other
+            .enable_reverse_proxy(&[("/loop", &format!("{}/loop", proxy_url))], 0, Duration::from_secs(1))
+            .unwrap();
+        other.handle(request_handler).unwrap();
+
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        let response = client.build_request(Method::Get, &format!("{}/up/a/b", proxy_url)).send().unwrap();
+        assert_eq!(response.message.payload, b"base/a/b".to_vec());
+        let response = client.build_request(Method::Get, &format!("{}/local", proxy_url)).send().unwrap();
+        assert_eq!(response.message.payload, b"local".to_vec());
+
+        let response = client
+            .build_request(Method::Get, &format!("{}/loop", proxy_url))
+            .option(CoAPOption::HopLimit, vec![3])
+            .send()
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::HopLimitReached);
+    }
+
+    #[test]
+    fn test_reverse_proxy_observe() {
+        let mut origin = CoAPServer::new("127.0.0.1:0").unwrap();
+        origin.handle(origin_handler).unwrap();
+        origin.update_resource("/temp", b"1".to_vec()).unwrap();
+
+        let mut proxy = CoAPServer::new("127.0.0.1:0").unwrap();
+        let origin_url = format!("coap://{}", origin.socket_addr().unwrap());
+        proxy.enable_reverse_proxy(&[("/up", &origin_url)], 0, Duration::from_secs(1)).unwrap();
+        proxy.handle(request_handler).unwrap();
+        let proxy_addr = proxy.socket_addr().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let mut observers = Vec::new();
+        for i in 0..2 {
+            let tx = tx.clone();
+            let mut client = CoAPClient::new(proxy_addr).unwrap();
+            client.observe("/up/temp", move |packet| tx.send((i, packet.payload)).unwrap()).unwrap();
+            assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), (i, b"1".to_vec()));
+            observers.push(client);
+        }
+
+        origin.update_resource("/temp", b"2".to_vec()).unwrap();
+        let mut notified = [false; 2];
+        while notified != [true; 2] {
+            let (i, payload) = rx.recv_timeout(Duration::new(5, 0)).unwrap();
+            assert_eq!(payload, b"2".to_vec());
+            notified[i] = true;
+        }
+    }
+
+    #[test]
+    fn test_reverse_proxy_observe_empty() {
+        let mut origin = CoAPServer::new("127.0.0.1:0").unwrap();
+        origin.handle(origin_handler).unwrap();
+        origin.update_resource("/temp", b"1".to_vec()).unwrap();
+
+        let mut proxy = CoAPServer::new("127.0.0.1:0").unwrap();
+        let origin_url = format!("coap://{}", origin.socket_addr().unwrap());
+        proxy.enable_reverse_proxy(&[("/up", &origin_url)], 0, Duration::from_secs(1)).unwrap();
+        proxy.handle(request_handler).unwrap();
+        let url = format!("coap://{}/up/temp", proxy.socket_addr().unwrap());
+
+        // An empty Observe option registers like one with the value 0.
+        let client = CoAPClient::bind("127.0.0.1:0").unwrap();
+        for _ in 0..2 {
+            let response = client
+                .build_request(Method::Get, &url)
+                .option(CoAPOption::Observe, vec![])
+                .send()
+                .unwrap();
+            assert_eq!(*response.get_status(), Status::Content);
+            assert_eq!(response.message.payload, b"1".to_vec());
+        }
+    }
+
+    #[test]
+    fn test_reverse_proxy_observe_query() {
+        let mut origin = CoAPServer::new("127.0.0.1:0").unwrap();
+        origin.handle(origin_handler).unwrap();
+        origin.update_resource("/temp?unit=c", b"21".to_vec()).unwrap();
+        origin.update_resource("/temp?unit=f", b"70".to_vec()).unwrap();
+
+        let mut proxy = CoAPServer::new("127.0.0.1:0").unwrap();
+        let origin_url = format!("coap://{}", origin.socket_addr().unwrap());
+        proxy.enable_reverse_proxy(&[("/up", &origin_url)], 0, Duration::from_secs(1)).unwrap();
+        proxy.handle(request_handler).unwrap();
+        let proxy_addr = proxy.socket_addr().unwrap();
+
+        // Each query is a resource of its own, observed upstream on its own.
+        let (tx, rx) = mpsc::channel();
+        let mut observers = Vec::new();
+        for unit in ["c", "f"] {
+            let tx = tx.clone();
+            let mut client = CoAPClient::new(proxy_addr).unwrap();
+            let options = [(CoAPOption::UriQuery, format!("unit={}", unit).into_bytes())];
+            client
+                .observe_with_options("/up/temp", &options, Duration::from_secs(1), move |packet| {
+                    tx.send((unit, packet.payload)).unwrap()
+                })
+                .unwrap();
+            observers.push(client);
+        }
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), ("c", b"21".to_vec()));
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), ("f", b"70".to_vec()));
+
+        origin.update_resource("/temp?unit=f", b"71".to_vec()).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), ("f", b"71".to_vec()));
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn test_reverse_proxy_unobserve() {
+        // The origin counts the deregistrations it is passed.
+        let deregistrations = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = deregistrations.clone();
+        let mut origin = CoAPServer::new("127.0.0.1:0").unwrap();
+        origin
+            .handle(move |request: CoAPRequest| {
+                if request.get_observe() == Some(&vec![message::packet::ObserveOption::Deregister as u8]) {
+                    counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+                request.response
+            })
+            .unwrap();
+        origin.update_resource("/temp", b"1".to_vec()).unwrap();
+
+        let mut proxy = CoAPServer::new("127.0.0.1:0").unwrap();
+        let origin_url = format!("coap://{}", origin.socket_addr().unwrap());
+        proxy.enable_reverse_proxy(&[("/up", &origin_url)], 0, Duration::from_secs(1)).unwrap();
+        proxy.handle(request_handler).unwrap();
+        let proxy_addr = proxy.socket_addr().unwrap();
+
+        let mut observers = Vec::new();
+        for _ in 0..2 {
+            let mut client = CoAPClient::new(proxy_addr).unwrap();
+            client.observe("/up/temp", |_| ()).unwrap();
+            observers.push(client);
+        }
+
+        // Only the last downstream deregistration cancels the upstream
+        // observation.
+        observers.pop().unwrap().unobserve();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(deregistrations.load(std::sync::atomic::Ordering::SeqCst), 0);
+        observers.pop().unwrap().unobserve();
+        let deadline = Instant::now() + Duration::new(5, 0);
+        while deregistrations.load(std::sync::atomic::Ordering::SeqCst) == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(deregistrations.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // The resource is observed upstream again for the next registration.
+        let (tx, rx) = mpsc::channel();
+        let mut client = CoAPClient::new(proxy_addr).unwrap();
+        client.observe("/up/temp", move |packet| tx.send(packet.payload).unwrap()).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"1".to_vec());
+        origin.update_resource("/temp", b"2".to_vec()).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"2".to_vec());
+    }
 }